@@ -0,0 +1,13 @@
+from mistralrs import Runner, Which, Architecture
+
+runner = Runner(
+    which=Which.Plain(
+        model_id="mistralai/Mistral-7B-Instruct-v0.1",
+        arch=Architecture.Mistral,
+    ),
+)
+
+res = runner.score("Rust is a systems programming language.", top_logprobs=3)
+for token in res.tokens:
+    print(repr(token.text), token.logprob)
+print(f"Perplexity: {res.perplexity}")
@@ -0,0 +1,14 @@
+from openai import OpenAI
+
+client = OpenAI(api_key="foobar", base_url="http://localhost:1234/v1/")
+
+response = client.embeddings.create(
+    model="mistral",
+    input=["What is Rust?", "Rust is a systems programming language."],
+)
+
+a, b = (data.embedding for data in response.data)
+# The embeddings are normalized by default, so the dot product is the cosine similarity.
+print(f"Embedding dimension: {len(a)}")
+print(f"Cosine similarity: {sum(x * y for x, y in zip(a, b))}")
+print(response.usage)
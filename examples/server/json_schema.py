@@ -0,0 +1,32 @@
+from openai import OpenAI
+
+client = OpenAI(api_key="foobar", base_url="http://localhost:1234/v1/")
+
+completion = client.chat.completions.create(
+    model="mistral",
+    messages=[
+        {
+            "role": "user",
+            "content": "Give me a sample address.",
+        }
+    ],
+    max_tokens=256,
+    temperature=0,
+    response_format={
+        "type": "json_schema",
+        "json_schema": {
+            "name": "address",
+            "schema": {
+                "type": "object",
+                "properties": {
+                    "street": {"type": "string"},
+                    "city": {"type": "string"},
+                    "zip": {"type": "string", "pattern": "[0-9]{5}"},
+                },
+                "required": ["street", "city", "zip"],
+            },
+        },
+    },
+)
+
+print(completion.choices[0].message.content)
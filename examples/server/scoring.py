@@ -0,0 +1,16 @@
+import requests
+
+response = requests.post(
+    "http://localhost:1234/v1/score",
+    json={
+        "model": "mistral",
+        "prompt": "Rust is a systems programming language.",
+        "top_logprobs": 3,
+    },
+)
+response.raise_for_status()
+score = response.json()
+
+for token in score["tokens"]:
+    print(repr(token["text"]), token["logprob"])
+print(f"Perplexity: {score['perplexity']}")
@@ -5,8 +5,8 @@ use mistralrs_core::{
     initialize_logging, paged_attn_supported, Constraint, DefaultSchedulerMethod,
     DeviceLayerMapMetadata, DeviceMapMetadata, DrySamplingParams, Loader, LoaderBuilder,
     MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelDType, ModelSelected, NormalRequest,
-    PagedAttentionConfig, Request, RequestMessage, Response, SamplingParams, SchedulerConfig,
-    TokenSource, Usage,
+    PagedAttentionConfig, PreemptionMode, Request, RequestMessage, RequestPriority, Response,
+    SamplingParams, SchedulerConfig, TokenSource, Usage,
 };
 use std::sync::Arc;
 use std::{fmt::Display, num::NonZeroUsize};
@@ -61,11 +61,20 @@ fn run_bench(
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repeat_last_n: None,
         max_len: Some(n_gen),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
+        seed: None,
         dry_params: Some(DrySamplingParams::default()),
+        beam_search: None,
+        typical_p: None,
+        top_a: None,
+        tfs_z: None,
+        mirostat: None,
+        sampler_order: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -83,6 +92,8 @@ fn run_bench(
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        priority: RequestPriority::default(),
+        tenant: None,
     });
 
     let mut usages = Vec::new();
@@ -115,6 +126,8 @@ fn run_bench(
                     }
                     Response::CompletionChunk(_) => unreachable!(),
                     Response::ImageGeneration(_) => unreachable!(),
+                    Response::Embedding(_) => unreachable!(),
+                    Response::Scoring(_) => unreachable!(),
                 },
                 None => unreachable!("Expected a Done response, got None",),
             }
@@ -226,11 +239,20 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repeat_last_n: None,
         max_len: Some(5),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
+        seed: None,
         dry_params: Some(DrySamplingParams::default()),
+        beam_search: None,
+        typical_p: None,
+        top_a: None,
+        tfs_z: None,
+        mirostat: None,
+        sampler_order: None,
     };
     let sender = mistralrs.get_sender().unwrap();
     let (tx, mut rx) = channel(10_000);
@@ -252,6 +274,8 @@ fn warmup_run(mistralrs: Arc<MistralRs>) {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        priority: RequestPriority::default(),
+        tenant: None,
     });
 
     sender
@@ -316,6 +340,12 @@ struct Args {
     #[arg(long = "pa-blk-size")]
     paged_attn_block_size: Option<usize>,
 
+    /// How a running sequence gives up its KV cache blocks when PagedAttention runs out of GPU memory: `swap` moves
+    /// them to the CPU swap space, falling back to recomputation once it is full, and `recompute` frees them and runs
+    /// the sequence through the model again when it resumes.
+    #[arg(long = "pa-preemption-mode", default_value = "swap", value_parser = parse_preemption_mode)]
+    paged_attn_preemption_mode: PreemptionMode,
+
     /// Disable PagedAttention on CUDA.
     #[arg(long = "no_paged_attn", default_value_t = false)]
     no_paged_attn: bool,
@@ -413,8 +443,7 @@ fn main() -> anyhow::Result<()> {
         DeviceMapMetadata::dummy()
     };
 
-    // Allocate 0.5 GB of CPU memory just as a placeholder.
-    // Nothing happens here as we have no `swap_out`, see `_preempt_by_swap`.
+    // Allocate 0.5 GB of CPU memory as swap space for sequences preempted by the scheduler.
     let cache_config = match (
         args.paged_attn_block_size,
         args.paged_attn_gpu_mem,
@@ -427,21 +456,25 @@ fn main() -> anyhow::Result<()> {
             block_size,
             512,
             MemoryGpuConfig::Utilization(0.9), // NOTE(EricLBuehler): default is to use 90% of memory
+            args.paged_attn_preemption_mode,
         )?),
         (block_size, None, None, Some(ctxt), true, false) => Some(PagedAttentionConfig::new(
             block_size,
             512,
             MemoryGpuConfig::ContextSize(ctxt),
+            args.paged_attn_preemption_mode,
         )?),
         (block_size, None, Some(f), None, true, false) => Some(PagedAttentionConfig::new(
             block_size,
             512,
             MemoryGpuConfig::Utilization(f),
+            args.paged_attn_preemption_mode,
         )?),
         (block_size, Some(m), None, None, true, false) => Some(PagedAttentionConfig::new(
             block_size,
             512,
             MemoryGpuConfig::Amount(m),
+            args.paged_attn_preemption_mode,
         )?),
         (block_size, Some(_m), Some(f), None, true, false) => {
             info!("Both memory size, and usage were specified, defaulting to the usage value.");
@@ -449,6 +482,7 @@ fn main() -> anyhow::Result<()> {
                 block_size,
                 512,
                 MemoryGpuConfig::Utilization(f),
+                args.paged_attn_preemption_mode,
             )?)
         }
         (block_size, Some(_m), None, Some(ctxt), true, false) => {
@@ -457,6 +491,7 @@ fn main() -> anyhow::Result<()> {
                 block_size,
                 512,
                 MemoryGpuConfig::ContextSize(ctxt),
+                args.paged_attn_preemption_mode,
             )?)
         }
         (block_size, None, Some(f), Some(_ctxt), true, false) => {
@@ -465,6 +500,7 @@ fn main() -> anyhow::Result<()> {
                 block_size,
                 512,
                 MemoryGpuConfig::Utilization(f),
+                args.paged_attn_preemption_mode,
             )?)
         }
         (_, _, _, _, _, _) => None,
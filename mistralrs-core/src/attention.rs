@@ -83,19 +83,18 @@ fn repeat_kv(x: Tensor, n_rep: usize) -> Result<Tensor> {
     }
 }
 
-/// Computes softmax(QK^T*sqrt(d_k))V
+/// Computes softmax(QK^T*softmax_scale)V
 fn naive_sdpa(
     q: &Tensor,
     k: &Tensor,
     v: &Tensor,
     mask: Option<&Tensor>,
-    head_dim: usize,
     sdpa_params: &SdpaParams,
 ) -> Result<Tensor> {
     let mut att = MatMul.matmul_affine_div(
         &q.contiguous()?,
         &k.t()?.contiguous()?,
-        (head_dim as f64).sqrt(),
+        1.0 / sdpa_params.softmax_scale as f64,
     )?;
     if let Some(softcap) = sdpa_params.softcap {
         att = (att / softcap as f64)?;
@@ -208,10 +207,10 @@ impl Sdpa {
                 }
             } else {
                 // Use the f16 kernels here if quantized (ISQ or GGML), and a large enough prompt
-                naive_sdpa(q, &k, &v, mask, head_dim, sdpa_params)
+                naive_sdpa(q, &k, &v, mask, sdpa_params)
             }
         } else {
-            naive_sdpa(q, &k, &v, mask, head_dim, sdpa_params)
+            naive_sdpa(q, &k, &v, mask, sdpa_params)
         }
     }
 }
@@ -0,0 +1,258 @@
+//! Beam search over the sequences of a request. Each sequence is a beam: once all running beams
+//! have run a step, every beam is replaced by its most likely continuations and the best
+//! `beam_width` of them are kept running.
+
+use crate::{
+    sampler::{BeamSearchParams, Logprobs},
+    sequence::{Sequence, StopReason},
+};
+
+pub(crate) trait Beam {
+    fn cumulative_logprob(&self) -> f32;
+    /// The number of generated tokens.
+    fn completion_len(&self) -> usize;
+}
+
+impl Beam for Sequence {
+    fn cumulative_logprob(&self) -> f32 {
+        Sequence::cumulative_logprob(self)
+    }
+    fn completion_len(&self) -> usize {
+        self.get_toks().len() - self.prompt_tokens()
+    }
+}
+
+pub(crate) enum BeamSearchStep<B> {
+    /// The search continues with these beams.
+    Continue(Vec<B>),
+    /// The search is finished. These are the best finished beams, best first.
+    Done(Vec<(B, StopReason)>),
+}
+
+pub(crate) struct BeamSearch<B: Beam = Sequence> {
+    params: BeamSearchParams,
+    n_choices: usize,
+    num_running: usize,
+    /// Beams which have run this step, with the candidates for their next token.
+    stepped: Vec<(B, Vec<Logprobs>)>,
+    /// Finished beams with their score, best first.
+    finished: Vec<(f32, B, StopReason)>,
+}
+
+impl<B: Beam> BeamSearch<B> {
+    /// A search which starts from a single beam.
+    pub fn new(params: BeamSearchParams, n_choices: usize) -> Self {
+        Self {
+            params,
+            n_choices,
+            num_running: 1,
+            stepped: Vec::new(),
+            finished: Vec::new(),
+        }
+    }
+
+    /// Record a beam which has run this step.
+    pub fn add_stepped(&mut self, beam: B, candidates: Vec<Logprobs>) {
+        self.stepped.push((beam, candidates));
+    }
+
+    /// Whether all running beams have run this step.
+    pub fn is_ready(&self) -> bool {
+        self.stepped.len() == self.num_running
+    }
+
+    fn score(&self, cumulative_logprob: f32, completion_len: usize) -> f32 {
+        #[allow(clippy::cast_precision_loss)]
+        let len = completion_len.max(1) as f32;
+        cumulative_logprob / len.powf(self.params.length_penalty)
+    }
+
+    /// Replace the beams which have run by their best continuations. `extend` creates a
+    /// continuation of a beam by a token and returns why it finished, if it did.
+    pub fn step(
+        &mut self,
+        mut extend: impl FnMut(&B, Logprobs) -> (B, Option<StopReason>),
+    ) -> BeamSearchStep<B> {
+        let beam_width = self.params.beam_width;
+        let stepped = std::mem::take(&mut self.stepped);
+        let mut candidates = stepped
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (beam, candidates))| {
+                candidates
+                    .iter()
+                    .map(move |c| (i, beam.cumulative_logprob() + c.logprob, c.clone()))
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        let mut running = Vec::with_capacity(beam_width);
+        for (rank, (i, _, candidate)) in candidates.into_iter().enumerate() {
+            let (beam, stop) = extend(&stepped[i].0, candidate);
+            match stop {
+                // Only the best `beam_width` continuations may finish, the others are worse than
+                // the running beams.
+                Some(reason) if rank < beam_width => {
+                    let score = self.score(beam.cumulative_logprob(), beam.completion_len());
+                    self.finished.push((score, beam, reason));
+                }
+                Some(_) => (),
+                None => running.push(beam),
+            }
+            if running.len() == beam_width {
+                break;
+            }
+        }
+        self.finished.sort_by(|a, b| b.0.total_cmp(&a.0));
+        self.finished.truncate(beam_width);
+
+        // Without early stopping, the search goes on while a running beam scores better than the
+        // worst finished one.
+        let is_done = running.is_empty()
+            || (self.finished.len() == beam_width
+                && (self.params.early_stopping || {
+                    let best_running = running
+                        .iter()
+                        .map(|beam| self.score(beam.cumulative_logprob(), beam.completion_len()))
+                        .fold(f32::NEG_INFINITY, f32::max);
+                    best_running <= self.finished[beam_width - 1].0
+                }));
+        if is_done {
+            BeamSearchStep::Done(
+                std::mem::take(&mut self.finished)
+                    .into_iter()
+                    .take(self.n_choices)
+                    .map(|(_, beam, reason)| (beam, reason))
+                    .collect(),
+            )
+        } else {
+            self.num_running = running.len();
+            BeamSearchStep::Continue(running)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Beam, BeamSearch, BeamSearchStep};
+    use crate::{
+        sampler::{BeamSearchParams, Logprobs},
+        sequence::StopReason,
+    };
+
+    const EOS: u32 = 0;
+
+    #[derive(Clone, Debug)]
+    struct TestBeam {
+        tokens: Vec<u32>,
+        cumulative_logprob: f32,
+    }
+
+    impl Beam for TestBeam {
+        fn cumulative_logprob(&self) -> f32 {
+            self.cumulative_logprob
+        }
+        fn completion_len(&self) -> usize {
+            self.tokens.len()
+        }
+    }
+
+    fn candidates(probs: &[(u32, f32)]) -> Vec<Logprobs> {
+        probs
+            .iter()
+            .map(|(token, p)| Logprobs {
+                token: *token,
+                logprob: p.ln(),
+                bytes: None,
+                top_logprobs: None,
+            })
+            .collect()
+    }
+
+    fn extend(beam: &TestBeam, candidate: Logprobs) -> (TestBeam, Option<StopReason>) {
+        let mut beam = beam.clone();
+        beam.tokens.push(candidate.token);
+        beam.cumulative_logprob += candidate.logprob;
+        let stop = (candidate.token == EOS).then_some(StopReason::Eos);
+        (beam, stop)
+    }
+
+    fn run_step(
+        search: &mut BeamSearch<TestBeam>,
+        beams: Vec<TestBeam>,
+        probs: impl Fn(&TestBeam) -> Vec<(u32, f32)>,
+    ) -> BeamSearchStep<TestBeam> {
+        for beam in beams {
+            let c = candidates(&probs(&beam));
+            search.add_stepped(beam, c);
+        }
+        assert!(search.is_ready());
+        search.step(extend)
+    }
+
+    #[test]
+    fn test_beam_search_finds_likelier_sequence() {
+        let params = BeamSearchParams::new_with_defaults(2, None, Some(true));
+        let mut search = BeamSearch::new(params, 1);
+        let start = TestBeam {
+            tokens: Vec::new(),
+            cumulative_logprob: 0.,
+        };
+
+        // Greedy decoding would take 1, but 2 is followed by a much likelier token.
+        let BeamSearchStep::Continue(beams) = run_step(&mut search, vec![start], |_| {
+            vec![(1, 0.5), (2, 0.4), (3, 0.1)]
+        }) else {
+            panic!("expected the search to continue");
+        };
+        assert_eq!(
+            beams.iter().map(|b| b.tokens.clone()).collect::<Vec<_>>(),
+            vec![vec![1], vec![2]]
+        );
+
+        let next = |beam: &TestBeam| match beam.tokens[..] {
+            [1] => vec![(4, 0.5), (5, 0.5)],
+            [2] => vec![(6, 1.0)],
+            _ => vec![(EOS, 1.0)],
+        };
+        let BeamSearchStep::Continue(beams) = run_step(&mut search, beams, next) else {
+            panic!("expected the search to continue");
+        };
+        assert_eq!(beams[0].tokens, vec![2, 6]);
+
+        let BeamSearchStep::Done(done) = run_step(&mut search, beams, next) else {
+            panic!("expected the search to finish");
+        };
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].0.tokens, vec![2, 6, EOS]);
+    }
+
+    #[test]
+    fn test_beam_search_early_stopping() {
+        let search_with = |early_stopping| {
+            let params = BeamSearchParams::new_with_defaults(2, None, Some(early_stopping));
+            let mut search = BeamSearch::new(params, 1);
+            let mut beams = vec![TestBeam {
+                tokens: Vec::new(),
+                cumulative_logprob: 0.,
+            }];
+            let next = |beam: &TestBeam| match beam.tokens[..] {
+                [] => vec![(EOS, 0.4), (1, 0.35), (2, 0.25)],
+                [1] => vec![(EOS, 0.9), (3, 0.1)],
+                [2] => vec![(4, 1.0)],
+                _ => vec![(EOS, 1.0)],
+            };
+            loop {
+                match run_step(&mut search, beams, next) {
+                    BeamSearchStep::Continue(running) => beams = running,
+                    BeamSearchStep::Done(done) => break done[0].0.tokens.clone(),
+                }
+            }
+        };
+
+        // Two beams have finished after two steps, but the running beam `[2, 4]` still has a
+        // better length normalized score than the worst of them.
+        assert_eq!(search_with(true), vec![1, EOS]);
+        assert_eq!(search_with(false), vec![2, 4, EOS]);
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     collections::{hash_map::Entry, HashMap},
-    hash::Hash,
+    hash::{DefaultHasher, Hash, Hasher},
     marker::PhantomData,
     ops::Deref,
     sync::{Arc, Mutex, MutexGuard},
@@ -8,6 +8,7 @@ use std::{
 
 use super::block_engine_sequence::BlockEngineSequence;
 
+#[derive(Clone)]
 pub struct LogicalTokenBlock {
     tokens: Vec<usize>,
     block_size: usize,
@@ -42,6 +43,15 @@ impl LogicalTokenBlock {
         self.tokens.pop();
         self.num_tokens -= 1;
     }
+
+    /// Hash the tokens of this block together with the hash of all blocks before it, so that equal hashes
+    /// identify equal prefixes.
+    pub fn hash_with_prefix(&self, prefix_hash: u64) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        prefix_hash.hash(&mut hasher);
+        self.tokens[..self.num_tokens].hash(&mut hasher);
+        hasher.finish()
+    }
 }
 
 #[derive(Hash, PartialEq, Eq)]
@@ -160,7 +170,7 @@ impl Allocator<CPUAllocator> {
                     block_id: id,
                     block_size,
                     refcount: 0,
-                    is_gpu: true,
+                    is_gpu: false,
                 },
             ))))
         }
@@ -179,58 +189,217 @@ pub enum AllocStatus {
 
 type SeqID = usize;
 
+/// Full GPU blocks whose KV cache has been computed, keyed by the hash of their tokens and all tokens
+/// before them. The cache holds a reference to each block, so blocks no sequence uses stay allocated
+/// until they are evicted to make room.
+#[derive(Default)]
+struct PrefixBlockCache {
+    /// The block and when it was last used.
+    blocks: HashMap<u64, (Arc<PhysicalTokenBlock>, usize)>,
+    clock: usize,
+    /// Number of prompt tokens of newly allocated sequences which are in shared blocks.
+    num_cached_tokens: HashMap<SeqID, usize>,
+    /// Indices and hashes of the full blocks of newly allocated sequences, cached once the prompt has run.
+    pending: HashMap<SeqID, Vec<(usize, u64)>>,
+}
+
 /// A BlockEngine maps each Sequence (identified by its SeqID), to physical token blocks.
 /// The physical token blocks may not match the logical token blocks because during
 /// scheduling, physical blocks are allocated to accommodate the new tokens generated.
 /// These new tokens will be added to the logical token block for each sequence.
 pub struct BlockEngine {
     num_gpu_blocks: usize,
+    block_size: usize,
     gpu_allocator: Allocator<GPUAllocator>,
     cpu_allocator: Allocator<CPUAllocator>,
     pub block_tables: HashMap<SeqID, BlockTable>,
+    prefix_cache: Option<PrefixBlockCache>,
 }
 
 pub type BlockTables = HashMap<usize, BlockTable>;
 
 impl BlockEngine {
+    /// With `prefix_caching`, sequences starting with the same full blocks of tokens share their physical blocks.
     #[must_use]
-    pub fn new(block_size: usize, num_gpu_blocks: usize, num_cpu_blocks: usize) -> Self {
+    pub fn new(
+        block_size: usize,
+        num_gpu_blocks: usize,
+        num_cpu_blocks: usize,
+        prefix_caching: bool,
+    ) -> Self {
         Self {
             num_gpu_blocks,
+            block_size,
             gpu_allocator: Allocator::<GPUAllocator>::new(block_size, num_gpu_blocks),
             cpu_allocator: Allocator::<CPUAllocator>::new(block_size, num_cpu_blocks),
             block_tables: HashMap::new(),
+            prefix_cache: prefix_caching.then(PrefixBlockCache::default),
+        }
+    }
+
+    /// This includes cached prefix blocks which no sequence is using, as they are evicted when needed.
+    pub fn num_free_gpu_blocks(&self) -> usize {
+        *self.gpu_allocator.get_num_free_blocks() + self.num_evictable_blocks()
+    }
+
+    pub fn num_free_cpu_blocks(&self) -> usize {
+        self.cpu_allocator.free_blocks.len()
+    }
+
+    fn num_evictable_blocks(&self) -> usize {
+        self.prefix_cache.as_ref().map_or(0, |cache| {
+            cache
+                .blocks
+                .values()
+                .filter(|(block, _)| block.deref_mut().refcount == 1)
+                .count()
+        })
+    }
+
+    fn allocate_gpu_block(&mut self) -> Arc<PhysicalTokenBlock> {
+        if self.gpu_allocator.free_blocks.is_empty() {
+            self.evict_prefix_block();
+        }
+        self.gpu_allocator.allocate()
+    }
+
+    /// Free the least recently used cached prefix block which no sequence is using.
+    fn evict_prefix_block(&mut self) {
+        let Some(cache) = &mut self.prefix_cache else {
+            return;
+        };
+        let lru = cache
+            .blocks
+            .iter()
+            .filter(|(_, (block, _))| block.deref_mut().refcount == 1)
+            .min_by_key(|(_, (_, last_used))| *last_used)
+            .map(|(hash, _)| *hash);
+        if let Some(hash) = lru {
+            let (block, _) = cache.blocks.remove(&hash).unwrap();
+            self.gpu_allocator.free_block(block);
+        }
+    }
+
+    /// The cached blocks matching the start of the sequence. The last token is never matched, as the model
+    /// must be run on at least one token to get the logits.
+    fn prefix_cache_hits(
+        &self,
+        seq: &impl BlockEngineSequence,
+        hashes: &[u64],
+    ) -> Vec<Arc<PhysicalTokenBlock>> {
+        let Some(cache) = &self.prefix_cache else {
+            return Vec::new();
+        };
+        let max_blocks = seq.num_tokens().saturating_sub(1) / self.block_size;
+        hashes
+            .iter()
+            .take(max_blocks)
+            .map_while(|hash| cache.blocks.get(hash).map(|(block, _)| block.clone()))
+            .collect()
+    }
+
+    fn block_hashes(&self, seq: &impl BlockEngineSequence) -> Vec<u64> {
+        if self.prefix_cache.is_some() {
+            seq.block_hashes()
+        } else {
+            Vec::new()
         }
     }
 
     pub fn can_allocate(&self, seq: &impl BlockEngineSequence) -> AllocStatus {
         let num_required_blocks = seq.get_logical_token_blocks();
-        let num_free_gpu_blocks = self.gpu_allocator.get_num_free_blocks();
-
-        if self.num_gpu_blocks > *num_free_gpu_blocks + num_required_blocks {
-            AllocStatus::Later
-        } else if self.num_gpu_blocks < num_required_blocks {
+        let hits = self.prefix_cache_hits(seq, &self.block_hashes(seq));
+        // Shared blocks are not allocated, and are no longer free to evict once shared.
+        let num_new_blocks = num_required_blocks - hits.len();
+        let num_free_gpu_blocks = self.num_free_gpu_blocks()
+            - hits
+                .iter()
+                .filter(|block| block.deref_mut().refcount == 1)
+                .count();
+
+        if self.num_gpu_blocks < num_required_blocks {
             AllocStatus::Impossible
+        } else if num_free_gpu_blocks < num_new_blocks {
+            AllocStatus::Later
         } else {
             AllocStatus::Ok
         }
     }
 
     pub fn allocate(&mut self, seq: &impl BlockEngineSequence) {
-        let mut block_table = Vec::new();
-        for _logcical_idx in 0..seq.get_logical_token_blocks() {
-            block_table.push(self.gpu_allocator.allocate());
+        let hashes = self.block_hashes(seq);
+        let mut block_table = self.prefix_cache_hits(seq, &hashes);
+        for block in &block_table {
+            block.deref_mut().refcount += 1;
+        }
+        let num_cached_blocks = block_table.len();
+        for _logcical_idx in num_cached_blocks..seq.get_logical_token_blocks() {
+            block_table.push(self.allocate_gpu_block());
+        }
+
+        if let Some(cache) = &mut self.prefix_cache {
+            cache.clock += 1;
+            for hash in &hashes[..num_cached_blocks] {
+                cache.blocks.get_mut(hash).unwrap().1 = cache.clock;
+            }
+            cache
+                .num_cached_tokens
+                .insert(seq.get_id(), num_cached_blocks * self.block_size);
+            cache.pending.insert(
+                seq.get_id(),
+                hashes
+                    .into_iter()
+                    .enumerate()
+                    .skip(num_cached_blocks)
+                    .collect(),
+            );
+        }
+        self.block_tables.insert(seq.get_id(), block_table);
+    }
+
+    /// Number of tokens at the start of a newly allocated sequence which are in blocks shared from the
+    /// prefix cache, so their KV cache does not need to be computed again.
+    pub fn num_cached_tokens(&self, id: usize) -> usize {
+        self.prefix_cache
+            .as_ref()
+            .and_then(|cache| cache.num_cached_tokens.get(&id).copied())
+            .unwrap_or(0)
+    }
+
+    /// Add the full blocks of the sequences allocated since the last call to the prefix cache. This must be
+    /// called after their prompt has been run, so that the KV cache of the blocks is computed.
+    pub fn cache_computed_blocks(&mut self) {
+        let Some(cache) = &mut self.prefix_cache else {
+            return;
+        };
+        cache.num_cached_tokens.clear();
+        for (id, blocks) in cache.pending.drain() {
+            let Some(table) = self.block_tables.get(&id) else {
+                continue;
+            };
+            for (idx, hash) in blocks {
+                let block = &table[idx];
+                // Another sequence may have cached the same prefix already.
+                if !block.deref_mut().is_gpu || cache.blocks.contains_key(&hash) {
+                    continue;
+                }
+                block.deref_mut().refcount += 1;
+                cache.blocks.insert(hash, (block.clone(), cache.clock));
+            }
         }
-        self.block_tables.insert(seq.get_id(), block_table.clone());
     }
 
     pub fn can_append_token_to_seq(&self, seq: &impl BlockEngineSequence) -> bool {
-        let free_blocks = self.gpu_allocator.get_num_free_blocks();
         // Physical blocks = logical blocks
-        seq.blocks_to_add_new_tok() <= *free_blocks
+        seq.blocks_to_add_new_tok() <= self.num_free_gpu_blocks()
     }
 
     pub fn free_sequence(&mut self, id: usize) {
+        if let Some(cache) = &mut self.prefix_cache {
+            cache.num_cached_tokens.remove(&id);
+            cache.pending.remove(&id);
+        }
+
         // Handle double free if run out of tokens
         if let Some(block_table) = self.block_tables.get(&id) {
             // Free from block table
@@ -246,7 +415,6 @@ impl BlockEngine {
         }
     }
 
-    #[allow(dead_code)]
     pub fn can_swap_out_seq(&self, seq: &impl BlockEngineSequence) -> bool {
         let blocks_required: usize = self
             .block_tables
@@ -259,7 +427,6 @@ impl BlockEngine {
 
     /// Update the block table so that the sequence does no longer reserve any GPU
     /// physical blocks, and only has CPU physical blocks.
-    #[allow(dead_code)]
     pub fn swap_out(&mut self, seq: &impl BlockEngineSequence) -> HashMap<usize, usize> {
         // GPU block to a CPU block
         let mut new_mapping = HashMap::new();
@@ -301,22 +468,37 @@ impl BlockEngine {
         &mut self,
         sequence: &impl BlockEngineSequence,
     ) -> Option<(usize, usize)> {
-        let table = self.block_tables.get_mut(&sequence.get_id())?;
+        if !self.block_tables.contains_key(&sequence.get_id()) {
+            return None;
+        }
 
         match sequence.blocks_to_add_new_tok() {
             1 => {
-                table.push(self.gpu_allocator.allocate());
+                let new_block = self.allocate_gpu_block();
+                self.block_tables
+                    .get_mut(&sequence.get_id())
+                    .unwrap()
+                    .push(new_block);
                 None
             }
             0 => {
-                let last_block = table.last_mut().unwrap();
+                let last_block = self.block_tables[&sequence.get_id()]
+                    .last()
+                    .unwrap()
+                    .clone();
                 assert!(last_block.deref_mut().is_gpu);
                 if last_block.deref_mut().refcount == 1 {
                     None
                 } else {
                     // We would be writing into shared, so COW.
-                    let new_block = self.gpu_allocator.allocate();
-                    self.gpu_allocator.free_block(last_block.clone());
+                    let new_block = self.allocate_gpu_block();
+                    self.gpu_allocator.free_block(last_block);
+                    let last_block = self
+                        .block_tables
+                        .get_mut(&sequence.get_id())
+                        .unwrap()
+                        .last_mut()
+                        .unwrap();
                     let old_number = last_block.deref_mut().block_id;
                     let new_number = new_block.deref_mut().block_id;
                     *last_block = new_block;
@@ -336,7 +518,8 @@ impl BlockEngine {
             .filter(|(id, _)| seq.get_id() == **id)
             .map(|(_, table)| table.len())
             .sum();
-        blocks_required <= self.gpu_allocator.free_blocks.len()
+        // The slot for the next token is reserved right after swapping in.
+        blocks_required + seq.blocks_to_add_new_tok() <= self.num_free_gpu_blocks()
     }
 
     /// Update the block table so that the sequence does no longer reserve any CPU
@@ -347,13 +530,13 @@ impl BlockEngine {
         let seq_id = seq.get_id();
 
         let mut new_block_table = Vec::new();
-        let block_table = self.block_tables.get(&seq_id).unwrap();
+        let block_table = self.block_tables.get(&seq_id).unwrap().clone();
 
-        for cpu_block in block_table {
+        for cpu_block in &block_table {
             let gpu_block =
                 if let Entry::Vacant(e) = new_mapping.entry(cpu_block.deref_mut().block_id) {
                     // Create a new block
-                    let gpu_block = self.cpu_allocator.allocate();
+                    let gpu_block = self.allocate_gpu_block();
                     e.insert(gpu_block.clone());
                     gpu_block
                 } else {
@@ -366,7 +549,7 @@ impl BlockEngine {
                     gpu_block
                 };
             new_block_table.push(gpu_block);
-            self.gpu_allocator.free_block(cpu_block.clone());
+            self.cpu_allocator.free_block(cpu_block.clone());
         }
         self.block_tables.insert(seq_id, new_block_table);
 
@@ -5,13 +5,14 @@ use std::{
 
 use candle_core::{DType, Device, Result, Tensor};
 
-use super::config::ModelConfigLike;
+use super::{config::ModelConfigLike, PreemptionMode};
 
 #[derive(Clone, Debug)]
 pub struct CacheConfig {
     pub block_size: usize,
     pub num_gpu_blocks: usize,
     pub num_cpu_blocks: usize,
+    pub preemption_mode: PreemptionMode,
 }
 
 pub type KVCache = (Tensor, Tensor);
@@ -48,12 +49,13 @@ impl CacheEngine {
         blocks_to_swap_out: HashMap<usize, usize>,
         blocks_to_copy: HashMap<usize, Vec<usize>>,
     ) -> Result<()> {
-        if !blocks_to_swap_in.is_empty() {
-            self.swap_in(blocks_to_swap_in)?;
-        }
+        // Swap out first, as the freed GPU blocks may be reused.
         if !blocks_to_swap_out.is_empty() {
             self.swap_out(blocks_to_swap_out)?;
         }
+        if !blocks_to_swap_in.is_empty() {
+            self.swap_in(blocks_to_swap_in)?;
+        }
         if !blocks_to_copy.is_empty() {
             self.copy(blocks_to_copy)?;
         }
@@ -19,6 +19,7 @@ pub use config::{ModelConfigLike, ModelConfigMetadata};
 pub use layers::PagedAttention;
 pub use scheduler::{
     PagedAttentionScheduler, PagedAttentionSchedulerConfig, PagedAttentionSchedulerOutput,
+    PreemptionMode,
 };
 
 use crate::MemoryUsage;
@@ -30,6 +31,7 @@ pub struct PagedAttentionConfig {
     pub(crate) block_size: Option<usize>,
     pub(crate) mem_cpu: usize,
     pub(crate) mem_gpu: MemoryGpuConfig,
+    pub(crate) preemption_mode: PreemptionMode,
 }
 
 impl PagedAttentionConfig {
@@ -37,9 +39,25 @@ impl PagedAttentionConfig {
         _block_size: Option<usize>,
         _mem_cpu: usize,
         _mem_gpu: MemoryGpuConfig,
+        _preemption_mode: PreemptionMode,
     ) -> anyhow::Result<Self> {
         anyhow::bail!("PagedAttention is only supported for CUDA, compile with feature `cuda`.")
     }
+
+    /// The config of one of `n` models which are served together and share this memory budget.
+    /// Amounts of memory are divided evenly. A utilization is measured against the memory in use
+    /// when each model is loaded and a context size is per model, so they are kept.
+    pub fn shared_by(self, n: usize) -> Self {
+        let mem_gpu = match self.mem_gpu {
+            MemoryGpuConfig::Amount(v) => MemoryGpuConfig::Amount(v / n),
+            other => other,
+        };
+        Self {
+            mem_cpu: self.mem_cpu / n,
+            mem_gpu,
+            ..self
+        }
+    }
 }
 
 pub enum AttentionImplementation {
@@ -88,6 +106,7 @@ pub fn calculate_cache_config(
     mem_gpu: MemoryGpuConfig,
     mem_cpu: usize,
     block_size: Option<usize>,
+    preemption_mode: PreemptionMode,
     dtype: DType,
     config: &dyn ModelConfigLike,
     device: &Device,
@@ -123,5 +142,6 @@ pub fn calculate_cache_config(
         block_size,
         num_gpu_blocks,
         num_cpu_blocks,
+        preemption_mode,
     })
 }
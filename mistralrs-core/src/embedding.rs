@@ -0,0 +1,68 @@
+use candle_core::{DType, IndexOp, Result, Tensor};
+
+use crate::{
+    sequence::{Sequence, SequenceState, StopReason},
+    EmbeddingData, EmbeddingParams, EmbeddingPooling, EmbeddingResponse, EmbeddingUsage, Response,
+};
+
+/// Pool the hidden states of one sequence, of shape (seq_len, hidden_size), into an embedding.
+/// Only the first `n_toks` positions are used, the rest are padding.
+fn pool(hidden_states: &Tensor, n_toks: usize, params: EmbeddingParams) -> Result<Vec<f32>> {
+    let hidden_states = hidden_states.narrow(0, 0, n_toks)?.to_dtype(DType::F32)?;
+    let pooled = match params.pooling {
+        EmbeddingPooling::Mean => hidden_states.mean(0)?,
+        EmbeddingPooling::LastToken => hidden_states.i(n_toks - 1)?,
+        EmbeddingPooling::Cls => hidden_states.i(0)?,
+    };
+    let pooled = if params.normalize {
+        let norm = pooled.sqr()?.sum_all()?.sqrt()?;
+        pooled.broadcast_div(&norm)?
+    } else {
+        pooled
+    };
+    pooled.to_vec1::<f32>()
+}
+
+/// Pool the hidden states for each sequence and send the embedding responses.
+pub async fn send_embedding_responses(
+    input_seqs: &mut [&mut Sequence],
+    hidden_states: Vec<Tensor>,
+    model: String,
+) -> Result<()> {
+    if input_seqs.len() != hidden_states.len() {
+        candle_core::bail!(
+            "Input seqs len ({}) does not match hidden states len ({})",
+            input_seqs.len(),
+            hidden_states.len()
+        );
+    }
+
+    for (seq, hidden_states) in input_seqs.iter_mut().zip(hidden_states) {
+        let Some(params) = seq.embedding_params() else {
+            candle_core::bail!("Sequence {} is not an embedding request.", seq.id());
+        };
+        let n_toks = seq.get_toks().len();
+        let embedding = pool(&hidden_states, n_toks, params)?;
+
+        seq.responder()
+            .send(Response::Embedding(EmbeddingResponse {
+                object: "list".to_string(),
+                data: vec![EmbeddingData {
+                    object: "embedding".to_string(),
+                    embedding,
+                    index: 0,
+                }],
+                model: model.clone(),
+                usage: EmbeddingUsage {
+                    prompt_tokens: n_toks,
+                    total_tokens: n_toks,
+                },
+            }))
+            .await
+            .map_err(candle_core::Error::msg)?;
+
+        seq.set_state(SequenceState::Done(StopReason::GeneratedEmbedding));
+    }
+
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use either::Either;
 use once_cell::sync::Lazy;
 use std::{
     collections::HashMap,
@@ -5,22 +6,24 @@ use std::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::{Instant, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::{mpsc::Receiver, Mutex};
 
 use crate::{
     aici::{cfg::CfgParser, recognizer::StackRecognizer, rx::RecRx},
+    beam_search::{BeamSearch, BeamSearchStep},
+    json_schema::json_schema_to_regex,
     pipeline::{
-        text_models_inputs_processor::PagedAttentionMeta, AdapterInstruction, CacheBackendMetadata,
-        CacheInstruction,
+        finish_seq, text_models_inputs_processor::PagedAttentionMeta, AdapterInstruction,
+        CacheBackendMetadata, CacheInstruction, CacheManagerMixin, ModelCategory, ModelKind,
     },
     request::NormalRequest,
     response::CompletionChoice,
     scheduler::{Scheduler, SchedulerOutput},
     sequence::{SeqStepType, StopReason},
     tools::{ToolCallingMatcher, ToolChoice},
-    CompletionResponse, RequestMessage, Response, SchedulerConfig, DEBUG,
+    CompletionResponse, RequestMessage, Response, SchedulerConfig, SchedulingPolicy, DEBUG,
 };
 use rand::SeedableRng;
 use rand_isaac::Isaac64Rng;
@@ -28,6 +31,7 @@ use tracing::{info, warn};
 
 use crate::{
     get_mut_arcmutex, handle_pipeline_forward_error, handle_seq_error,
+    metrics::EngineMetrics,
     pipeline::Pipeline,
     prefix_cacher::PrefixCacheManager,
     request::Request,
@@ -37,6 +41,11 @@ use crate::{
     Constraint, StopTokens,
 };
 
+fn time_since(start_ms: u128, now_ms: u128) -> Duration {
+    #[allow(clippy::cast_possible_truncation)]
+    Duration::from_millis(now_ms.saturating_sub(start_ms) as u64)
+}
+
 pub enum EngineInstruction {
     Terminate,
 }
@@ -60,6 +69,9 @@ pub struct Engine {
     is_debug: bool,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
+    metrics: Arc<EngineMetrics>,
+    /// Beam searches in progress, by request id.
+    beam_searches: HashMap<usize, BeamSearch>,
 }
 
 impl Engine {
@@ -68,12 +80,14 @@ impl Engine {
         rx: Receiver<Request>,
         pipeline: Arc<Mutex<dyn Pipeline>>,
         config: SchedulerConfig,
+        scheduling_policy: SchedulingPolicy,
         truncate_sequence: bool,
         no_kv_cache: bool,
         no_prefix_cache: bool,
         prefix_cache_n: usize,
         disable_eos_stop: bool,
         throughput_logging_enabled: bool,
+        metrics: Arc<EngineMetrics>,
     ) -> Self {
         let device = get_mut_arcmutex!(pipeline).device().clone();
         let is_xlora = get_mut_arcmutex!(pipeline).get_metadata().is_xlora;
@@ -82,15 +96,13 @@ impl Engine {
             // Diffusion models...
             assert_eq!(has_no_kv_cache, no_kv_cache);
         }
-        // Prefix caching is always disabled if using PagedAttention for now.
-        // TODO
-        let no_prefix_cache = matches!(config, SchedulerConfig::PagedAttentionMeta { .. })
-            || no_prefix_cache
-            || has_no_kv_cache;
+        let no_prefix_cache = no_prefix_cache || has_no_kv_cache;
+        // With PagedAttention, the scheduler shares the KV cache blocks of prefixes instead.
+        let is_paged_attn = matches!(config, SchedulerConfig::PagedAttentionMeta { .. });
         Self {
             rx,
             pipeline,
-            scheduler: config.into_scheduler(),
+            scheduler: config.into_scheduler(scheduling_policy, !no_prefix_cache),
             id: 0,
             truncate_sequence,
             no_kv_cache: no_kv_cache & !has_no_kv_cache,
@@ -98,11 +110,13 @@ impl Engine {
                 device,
                 prefix_cache_n,
                 is_xlora,
-                no_prefix_cache,
+                no_prefix_cache || is_paged_attn,
             ),
             is_debug: DEBUG.load(Ordering::Relaxed),
             disable_eos_stop,
             throughput_logging_enabled,
+            metrics,
+            beam_searches: HashMap::new(),
         }
     }
 
@@ -179,6 +193,12 @@ impl Engine {
                                 .await
                         };
 
+                        if res.is_err() {
+                            // The failed beams send the error response, so their searches are dropped.
+                            for seq in scheduled.completion.iter() {
+                                self.beam_searches.remove(&seq.request_id());
+                            }
+                        }
                         handle_pipeline_forward_error!(
                             "completion step",
                             res,
@@ -189,6 +209,10 @@ impl Engine {
                         );
 
                         let throughput_end = Instant::now();
+                        self.metrics.record_completion_step(
+                            scheduled.completion.len(),
+                            throughput_end.duration_since(throughput_start),
+                        );
                         #[allow(clippy::cast_precision_loss)]
                         if self.throughput_logging_enabled {
                             completion_ts = Some(
@@ -241,6 +265,12 @@ impl Engine {
                                 .await
                         };
 
+                        if logits.is_err() {
+                            // The failed beams send the error response, so their searches are dropped.
+                            for seq in scheduled.prompt.iter() {
+                                self.beam_searches.remove(&seq.request_id());
+                            }
+                        }
                         handle_pipeline_forward_error!(
                             "prompt step",
                             logits,
@@ -251,6 +281,14 @@ impl Engine {
                         );
 
                         let throughput_end = Instant::now();
+                        self.metrics.record_prompt_step(
+                            scheduled
+                                .prompt
+                                .iter()
+                                .map(|seq| seq.get_toks().len())
+                                .sum(),
+                            throughput_end.duration_since(throughput_start),
+                        );
                         #[allow(clippy::cast_precision_loss)]
                         if self.throughput_logging_enabled {
                             prompt_ts = Some(
@@ -267,6 +305,11 @@ impl Engine {
 
                         for seq in scheduled.prompt.iter_mut() {
                             match seq.sequence_stepping_type() {
+                                SeqStepType::OneShot if seq.is_embedding() => seq
+                                    .set_state(SequenceState::Done(StopReason::GeneratedEmbedding)),
+                                SeqStepType::OneShot if seq.is_scoring() => {
+                                    seq.set_state(SequenceState::Done(StopReason::GeneratedScore))
+                                }
                                 SeqStepType::OneShot => {
                                     seq.set_state(SequenceState::Done(StopReason::GeneratedImage))
                                 }
@@ -283,10 +326,46 @@ impl Engine {
                                 seq.len() as f32 / (now - seq.timestamp()) as f32;
                             seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
                             seq.prompt_timestamp = Some(now);
+                            if matches!(seq.sequence_stepping_type(), SeqStepType::PromptAndDecode)
+                            {
+                                self.metrics
+                                    .observe_time_to_first_token(time_since(seq.timestamp(), now));
+                            }
                         }
                         last_completion_ids = vec![];
                     }
 
+                    if scheduled.completion.len() > 0 {
+                        self.metrics.observe_inter_token_latency(
+                            scheduled.completion.len(),
+                            run_start.elapsed(),
+                        );
+                    }
+                    for seq in scheduled.prompt.iter().chain(scheduled.completion.iter()) {
+                        self.metrics.record_if_finished(seq);
+                    }
+
+                    // Beam search sequences are replaced by their continuations once all beams of
+                    // the request have run.
+                    for seq in scheduled
+                        .prompt
+                        .iter_mut()
+                        .chain(scheduled.completion.iter_mut())
+                    {
+                        let Some(candidates) = seq.take_beam_candidates() else {
+                            // A failed beam has sent the error response and never steps, so its
+                            // search is dropped. The other beams are dropped once they have run.
+                            if seq.getstate() == SequenceState::Error {
+                                self.beam_searches.remove(&seq.request_id());
+                            }
+                            continue;
+                        };
+                        if let Some(search) = self.beam_searches.get_mut(&seq.request_id()) {
+                            search.add_stepped(seq.fork(*seq.id()), candidates);
+                        }
+                        seq.set_state(SequenceState::Forked);
+                    }
+
                     if self.is_debug {
                         let ms_from_last_run = run_start.elapsed().as_secs_f64();
                         let total_len = scheduled.prompt.len() + scheduled.completion.len();
@@ -334,6 +413,7 @@ impl Engine {
                         && self.scheduler.waiting_len() == 0
                     {
                         // If there is nothing to do, sleep until a request comes in
+                        self.update_scheduler_metrics();
                         if let Some(request) = self.rx.recv().await {
                             if matches!(request, Request::Terminate) {
                                 break 'lp;
@@ -420,6 +500,20 @@ impl Engine {
                         }
 
                         let throughput_end = Instant::now();
+                        let step_time = throughput_end.duration_since(throughput_start);
+                        if is_prompt {
+                            self.metrics.record_prompt_step(
+                                guards.iter().map(|seq| seq.get_toks().len()).sum(),
+                                step_time,
+                            );
+                        } else {
+                            self.metrics.record_completion_step(guards.len(), step_time);
+                            self.metrics
+                                .observe_inter_token_latency(guards.len(), run_start.elapsed());
+                        }
+                        for seq in &guards {
+                            self.metrics.record_if_finished(seq);
+                        }
                         #[allow(clippy::cast_precision_loss)]
                         if self.throughput_logging_enabled {
                             let n_toks = if is_prompt {
@@ -445,13 +539,102 @@ impl Engine {
                                     seq.len() as f32 / (now - seq.timestamp()) as f32;
                                 seq.prompt_tok_per_sec = prompt_tok_per_sec * 1000.;
                                 seq.prompt_timestamp = Some(now);
+                                if matches!(
+                                    seq.sequence_stepping_type(),
+                                    SeqStepType::PromptAndDecode
+                                ) {
+                                    self.metrics.observe_time_to_first_token(time_since(
+                                        seq.timestamp(),
+                                        now,
+                                    ));
+                                }
                             }
                         }
                     }
                 }
             }
 
+            self.step_beam_searches().await;
             self.scheduler.free_finished_sequence_groups();
+            self.update_scheduler_metrics();
+        }
+    }
+
+    /// Extend the beam searches whose beams have all run this step. The new beams are added to
+    /// the scheduler, and finished searches send their response.
+    async fn step_beam_searches(&mut self) {
+        let ready = self
+            .beam_searches
+            .iter()
+            .filter(|(_, search)| search.is_ready())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        if ready.is_empty() {
+            return;
+        }
+        let (eos_tok, max_seq_len, tok_trie) = {
+            let pipeline = get_mut_arcmutex!(self.pipeline);
+            let metadata = pipeline.get_metadata();
+            (
+                metadata.eos_tok.clone(),
+                metadata.max_seq_len,
+                metadata.tok_trie.clone(),
+            )
+        };
+        let eos_tok = (!self.disable_eos_stop).then_some(&eos_tok[..]);
+
+        for request_id in ready {
+            let mut search = self.beam_searches.remove(&request_id).unwrap();
+            let next_id = &mut self.id;
+            let step = search.step(|beam, logprobs| {
+                let mut seq = beam.fork(*next_id);
+                *next_id += 1;
+                let is_done = seq.is_done(logprobs.token, eos_tok, max_seq_len);
+                let completion_bytes = tok_trie
+                    .as_ref()
+                    .map(|trie| trie.decode(&[logprobs.token]))
+                    .unwrap_or_default();
+                seq.add_token(logprobs, completion_bytes, &is_done);
+                (seq, is_done)
+            });
+            match step {
+                BeamSearchStep::Continue(beams) => {
+                    for seq in beams {
+                        seq.set_state(SequenceState::RunningCompletion);
+                        self.scheduler.add_seq(seq);
+                    }
+                    self.beam_searches.insert(request_id, search);
+                }
+                BeamSearchStep::Done(results) => {
+                    let pipeline = get_mut_arcmutex!(self.pipeline);
+                    for (response_index, (mut seq, reason)) in results.into_iter().enumerate() {
+                        seq.set_response_index(response_index);
+                        let res =
+                            finish_seq(&*pipeline, &mut self.prefix_cacher, &mut seq, reason, true)
+                                .await;
+                        if let Err(e) = res {
+                            // The receiver may already be gone, such as when a client disconnects.
+                            let _ = seq
+                                .responder()
+                                .send(Response::InternalError(e.into()))
+                                .await;
+                        }
+                        self.metrics.record_if_finished(&seq);
+                    }
+                }
+            }
+        }
+    }
+
+    fn update_scheduler_metrics(&mut self) {
+        self.metrics
+            .set_queue_depth(self.scheduler.waiting_len(), self.scheduler.running_len());
+        if let Some(block_engine) = self.scheduler.block_engine() {
+            let (gpu, cpu) = (
+                block_engine.num_free_gpu_blocks(),
+                block_engine.num_free_cpu_blocks(),
+            );
+            self.metrics.set_free_blocks(gpu, cpu);
         }
     }
 
@@ -461,6 +644,9 @@ impl Engine {
                 SequenceRecognizer::Regex(StackRecognizer::from(RecRx::from_rx(rx, None)?).into())
             }
             Constraint::Yacc(cfg) => SequenceRecognizer::Cfg(CfgParser::from_yacc(cfg)?.into()),
+            Constraint::JsonSchema(schema) => SequenceRecognizer::Regex(
+                StackRecognizer::from(RecRx::from_rx(&json_schema_to_regex(schema)?, None)?).into(),
+            ),
             Constraint::None => SequenceRecognizer::None,
         };
         Ok(recognizer)
@@ -480,6 +666,27 @@ impl Engine {
                     warn!("ISQ requantization failed: {e:?}");
                 }
             }
+            Request::Cancel { id } => {
+                self.beam_searches.remove(&id);
+                let canceled = self.scheduler.cancel_request(id);
+                if canceled.is_empty() {
+                    return;
+                }
+                let name = get_mut_arcmutex!(self.pipeline).name();
+                let mut guards = canceled
+                    .iter()
+                    .map(|seq| seq.lock().unwrap())
+                    .collect::<Vec<_>>();
+                // Beams which were replaced by their continuations are not part of the output.
+                for seq in guards
+                    .iter_mut()
+                    .filter(|seq| seq.getstate() != SequenceState::Forked)
+                {
+                    // The receiver may already be gone, such as when a client disconnects.
+                    let _ = seq.finish_canceled(name.clone()).await;
+                    self.metrics.record_if_finished(seq);
+                }
+            }
             Request::Terminate => panic!("This is unreachable in `handle_request`. Termination is handled in the `run` loop."),
         }
     }
@@ -502,7 +709,9 @@ impl Engine {
             RequestMessage::Chat(_)
             | RequestMessage::CompletionTokens(_)
             | RequestMessage::VisionChat { .. }
-            | RequestMessage::ImageGeneration { .. } => 1,
+            | RequestMessage::ImageGeneration { .. }
+            | RequestMessage::Embedding { .. }
+            | RequestMessage::Scoring { .. } => 1,
         };
         if is_chat
             && !get_mut_arcmutex!(self.pipeline)
@@ -535,16 +744,115 @@ impl Engine {
             None
         };
 
+        // Constrain the output to well-formed tool calls unless the request brings its own grammar.
+        let constraint = match (&request.constraint, &matcher) {
+            (Constraint::None, Some(matcher)) => {
+                match matcher.constraint(request.tools.as_deref().unwrap_or_default()) {
+                    Ok(constraint) => constraint,
+                    Err(err) => {
+                        request
+                            .response
+                            .send(Response::ValidationError(
+                                format!("Invalid tool parameters schema. {}", err).into(),
+                            ))
+                            .await
+                            .expect("Expected receiver.");
+                        return;
+                    }
+                }
+            }
+            (constraint, _) => constraint.clone(),
+        };
+
         let image_generation_format = match &request.messages {
             RequestMessage::ImageGeneration { format, .. } => Some(*format),
             _ => None,
         };
 
         let seq_step_type = match &request.messages {
-            RequestMessage::ImageGeneration { .. } => SeqStepType::OneShot,
+            RequestMessage::ImageGeneration { .. }
+            | RequestMessage::Embedding { .. }
+            | RequestMessage::Scoring { .. } => SeqStepType::OneShot,
             _ => SeqStepType::PromptAndDecode,
         };
 
+        if let Some(beam_search) = &request.sampling_params.beam_search {
+            let is_speculative = matches!(
+                get_mut_arcmutex!(self.pipeline).get_metadata().kind,
+                ModelKind::Speculative { .. } | ModelKind::PromptLookup { .. }
+            );
+            let error = if beam_search.beam_width == 0 {
+                Some("Beam width must be greater than 0.".to_string())
+            } else if request.sampling_params.n_choices > beam_search.beam_width {
+                Some(format!(
+                    "Beam search can return at most `beam_width` ({}) choices.",
+                    beam_search.beam_width
+                ))
+            } else if request.is_streaming {
+                Some("Beam search does not support streaming.".to_string())
+            } else if request.return_logprobs {
+                Some("Beam search does not support logprobs.".to_string())
+            } else if !matches!(constraint, Constraint::None) {
+                Some("Beam search does not support grammars or tools.".to_string())
+            } else if !matches!(seq_step_type, SeqStepType::PromptAndDecode) {
+                Some("Beam search is only supported for text generation.".to_string())
+            } else if self.scheduler.block_size().is_some() {
+                Some("Beam search is not supported with PagedAttention.".to_string())
+            } else if is_speculative {
+                Some("Beam search is not supported with speculative decoding.".to_string())
+            } else {
+                None
+            };
+            if let Some(error) = error {
+                request
+                    .response
+                    .send(Response::ValidationError(error.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+
+        // Echoing the prompt with logprobs also returns the logprobs of the prompt tokens, and
+        // scoring only returns those.
+        let is_scoring = matches!(request.messages, RequestMessage::Scoring { .. });
+        let prompt_logprobs = (echo_prompt && request.return_logprobs) || is_scoring;
+        if prompt_logprobs {
+            let (is_speculative, has_no_kv_cache, is_text) = {
+                let pipeline = get_mut_arcmutex!(self.pipeline);
+                let metadata = pipeline.get_metadata();
+                (
+                    matches!(
+                        metadata.kind,
+                        ModelKind::Speculative { .. } | ModelKind::PromptLookup { .. }
+                    ),
+                    metadata.has_no_kv_cache,
+                    matches!(pipeline.category(), ModelCategory::Text),
+                )
+            };
+            // The logits of every prompt position are only returned by text models which feed
+            // each prompt once.
+            let error = if self.scheduler.block_size().is_some() {
+                Some("Scoring and prompt logprobs are not supported with PagedAttention.")
+            } else if is_speculative {
+                Some("Scoring and prompt logprobs are not supported with speculative decoding.")
+            } else if !is_text {
+                Some("Scoring and prompt logprobs are only supported for text models.")
+            } else if has_no_kv_cache {
+                Some("Scoring and prompt logprobs are not supported for models without a KV cache.")
+            } else {
+                None
+            };
+            if let Some(error) = error {
+                request
+                    .response
+                    .send(Response::ValidationError(error.into()))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            }
+        }
+
         let diffusion_params = match &request.messages {
             RequestMessage::ImageGeneration {
                 generation_params, ..
@@ -552,6 +860,13 @@ impl Engine {
             _ => None,
         };
 
+        let embedding_params = match &request.messages {
+            RequestMessage::Embedding {
+                embedding_params, ..
+            } => Some(*embedding_params),
+            _ => None,
+        };
+
         let (mut prompt_tokens, prompt_text) = match request.messages {
             RequestMessage::Chat(messages)
             | RequestMessage::VisionChat {
@@ -567,7 +882,11 @@ impl Engine {
                 );
                 handle_seq_error!(template, request.response)
             }
-            RequestMessage::Completion { text, .. } => {
+            RequestMessage::Completion { text, .. }
+            | RequestMessage::Embedding { prompt: text, .. }
+            | RequestMessage::Scoring {
+                prompt: Either::Left(text),
+            } => {
                 let Some(tokenizer) = &get_mut_arcmutex!(self.pipeline).tokenizer() else {
                     request
                         .response
@@ -589,7 +908,10 @@ impl Engine {
                 )
             }
             RequestMessage::ImageGeneration { prompt, .. } => (vec![u32::MAX], prompt),
-            RequestMessage::CompletionTokens(it) => {
+            RequestMessage::CompletionTokens(it)
+            | RequestMessage::Scoring {
+                prompt: Either::Right(it),
+            } => {
                 let Some(tokenizer) = &get_mut_arcmutex!(self.pipeline).tokenizer() else {
                     request
                         .response
@@ -616,9 +938,33 @@ impl Engine {
                 .expect("Expected receiver.");
             return;
         }
+        if is_scoring && prompt_tokens.len() < 2 {
+            request
+                .response
+                .send(Response::ValidationError(
+                    "Scoring requires a prompt of at least two tokens.".into(),
+                ))
+                .await
+                .expect("Expected receiver.");
+            return;
+        }
 
         if prompt_tokens.len() > get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len {
-            if !self.truncate_sequence {
+            if is_scoring {
+                // Truncating the prompt would score another text than the one requested.
+                request
+                    .response
+                    .send(Response::ValidationError(
+                        format!(
+                            "Scoring prompt sequence length is greater than {}.",
+                            get_mut_arcmutex!(self.pipeline).get_metadata().max_seq_len
+                        )
+                        .into(),
+                    ))
+                    .await
+                    .expect("Expected receiver.");
+                return;
+            } else if !self.truncate_sequence {
                 request
                     .response
                     .send(Response::ValidationError(
@@ -642,10 +988,21 @@ impl Engine {
                 warn!("Prompt for request {} was {} tokens over the model maximum length. The last {} tokens were truncated to make space for generation.", request.id, currently_over, prompt_len - prompt_tokens.len());
             }
         }
-        let prefill_cache = handle_seq_error!(
-            self.prefix_cacher.search_for_matching_cache(&prompt_tokens),
-            request.response
-        );
+        // Embeddings and prompt logprobs need the hidden states of the whole prompt, so they cannot
+        // start from a cache.
+        let prefill_cache = if embedding_params.is_some() || prompt_logprobs {
+            None
+        } else {
+            let prefill_cache = handle_seq_error!(
+                self.prefix_cacher.search_for_matching_cache(&prompt_tokens),
+                request.response
+            );
+            if self.prefix_cacher.is_enabled() {
+                self.metrics
+                    .record_prefix_cache_lookup(prefill_cache.is_some());
+            }
+            prefill_cache
+        };
 
         let topk = request
             .sampling_params
@@ -727,6 +1084,12 @@ impl Engine {
             }
         };
 
+        // All of the best beams of a beam search are returned.
+        let best_of = if request.sampling_params.beam_search.is_some() {
+            request.sampling_params.n_choices
+        } else {
+            best_of
+        };
         let group = Arc::new(tokio::sync::Mutex::new(SequenceGroup::new(
             request.sampling_params.n_choices,
             request.is_streaming,
@@ -745,10 +1108,17 @@ impl Engine {
             tokenizer,
             request.sampling_params.frequency_penalty,
             request.sampling_params.presence_penalty,
+            request.sampling_params.repetition_penalty,
+            request.sampling_params.repeat_last_n,
             request.sampling_params.dry_params,
             topk,
             topp,
             minp,
+            request.sampling_params.typical_p.unwrap_or(1.0),
+            request.sampling_params.top_a.unwrap_or(0.0),
+            request.sampling_params.tfs_z.unwrap_or(1.0),
+            request.sampling_params.mirostat,
+            request.sampling_params.sampler_order,
             request.logits_processors.unwrap_or_default(),
         );
         let sampler = handle_seq_error!(sampler, request.response);
@@ -764,9 +1134,14 @@ impl Engine {
             return;
         }
 
-        // Add sequences
-        for response_index in 0..request.sampling_params.n_choices {
-            let recognizer = match Self::build_sequence_recognizer(&request.constraint) {
+        // Add sequences. A beam search starts from a single beam.
+        let num_seqs = if request.sampling_params.beam_search.is_some() {
+            1
+        } else {
+            request.sampling_params.n_choices
+        };
+        for response_index in 0..num_seqs {
+            let recognizer = match Self::build_sequence_recognizer(&constraint) {
                 Ok(recognizer) => recognizer,
                 Err(err) => {
                     request
@@ -794,6 +1169,9 @@ impl Engine {
                 prompt_tokens.clone(),
                 prompt_text.clone(),
                 self.id,
+                request.id,
+                request.priority,
+                request.tenant.clone(),
                 now.as_millis(),
                 num_hidden_layers,
                 request.response.clone(),
@@ -821,18 +1199,46 @@ impl Engine {
                 image_generation_format,
                 seq_step_type,
                 diffusion_params.clone(),
+                embedding_params,
             );
+            let seq = if get_mut_arcmutex!(self.pipeline).cache().is_recurrent() {
+                seq.with_recurrent_states()
+            } else {
+                seq
+            };
             let seq = if let Some(prefill_cache) = prefill_cache.clone() {
                 seq.prefill(
                     prefill_cache.normal,
                     prefill_cache.xlora,
+                    prefill_cache.recurrent_states,
                     prefill_cache.toks,
                 )
             } else {
                 seq
             };
+            let seq = match &request.sampling_params.beam_search {
+                Some(beam_search) => seq.with_beam_width(beam_search.beam_width),
+                None => seq,
+            };
+            let seq = if is_scoring {
+                seq.with_scoring()
+            } else if prompt_logprobs {
+                seq.with_prompt_logprobs()
+            } else {
+                seq
+            };
+            let seq = match request.sampling_params.seed {
+                Some(seed) => seq.with_seed(seed),
+                None => seq,
+            };
             self.id += 1;
             self.scheduler.add_seq(seq);
         }
+        if let Some(beam_search) = request.sampling_params.beam_search {
+            self.beam_searches.insert(
+                request.id,
+                BeamSearch::new(beam_search, request.sampling_params.n_choices),
+            );
+        }
     }
 }
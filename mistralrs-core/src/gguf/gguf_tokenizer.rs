@@ -36,11 +36,13 @@ struct PropsGGUF {
     tokens: Vec<String>,
     added_tokens: Option<Vec<String>>,
     scores: Option<Vec<f32>>,
+    token_types: Option<Vec<i32>>,
     merges: Option<Vec<String>>,
     unk: Option<u32>,
     eos: u32,
     bos: u32,
     add_bos_token: Option<bool>,
+    add_space_prefix: Option<bool>,
 }
 
 impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
@@ -55,11 +57,13 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
             tokens: c.get_value("tokens")?,
             added_tokens: c.get_value("added_tokens").ok(),
             scores: c.get_value("scores").ok(),
+            token_types: c.get_value("token_type").ok(),
             merges: c.get_value("merges").ok(),
             unk: c.get_value("unknown_token_id").ok(),
             eos: c.get_value("eos_token_id")?,
             bos: c.get_value("bos_token_id")?,
             add_bos_token: c.get_value("add_bos_token").ok(),
+            add_space_prefix: c.get_value("add_space_prefix").ok(),
         };
 
         Ok(props)
@@ -119,6 +123,10 @@ enum TokenizerKind {
     Bpe,
 }
 
+// `tokenizer.ggml.token_type` values:
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+
 /// Add the special tokens and return their string representations
 fn add_special_tokens(
     p: &PropsGGUF,
@@ -139,6 +147,23 @@ fn add_special_tokens(
         }
     }
 
+    // Add the remaining control (special) and user defined (non-special) tokens, e.g. the Gemma
+    // `<start_of_turn>`/`<end_of_turn>` markers, so they are never split by the model:
+    // https://github.com/ggerganov/llama.cpp/blob/master/gguf-py/gguf/constants.py
+    if let Some(token_types) = p.token_types.as_ref() {
+        for (token, token_type) in p.tokens.iter().zip(token_types) {
+            match *token_type {
+                TOKEN_TYPE_CONTROL => {
+                    tokenizer.add_special_tokens(&[AddedToken::from(token.clone(), true)]);
+                }
+                TOKEN_TYPE_USER_DEFINED => {
+                    tokenizer.add_tokens(&[AddedToken::from(token.clone(), false)]);
+                }
+                _ => {}
+            }
+        }
+    }
+
     // Destructure array of options:
     let [bos_str, eos_str, unk_str] = special_tokens;
     // Would need to unwrap bos/eos here, or change the struct types
@@ -150,7 +175,15 @@ fn add_special_tokens(
 }
 
 fn unigram_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, AddedTokensCollection)> {
-    let PropsGGUF { unk, eos, bos, .. } = *p;
+    let PropsGGUF {
+        unk,
+        eos,
+        bos,
+        add_space_prefix,
+        ..
+    } = *p;
+    // SentencePiece models such as Gemma disable the dummy prefix (`add_dummy_prefix`)
+    let add_space_prefix = add_space_prefix.unwrap_or(true);
     // Unigram (SentencePiece) default UNK is 0
     let unk = unk.unwrap_or(0);
 
@@ -172,17 +205,18 @@ fn unigram_tokenizer(p: &PropsGGUF) -> Result<(Tokenizer, TokenizerKind, AddedTo
 
     // Decoder + Normalizer config reference:
     // https://github.com/EricLBuehler/mistral.rs/pull/389#discussion_r1630620763
-    let decoder = Decoder::Sequence(vec![
+    let mut decoders = vec![
         Decoder::Replace("▁", " "),
         Decoder::ByteFallback,
         Decoder::Fuse,
-        Decoder::Strip(' ', 1, 0),
-    ]);
-
-    let normalizer = Normalizer::Sequence(vec![
-        Normalizer::Prepend("▁"),
-        Normalizer::Replace(" ", "▁"),
-    ]);
+    ];
+    let mut normalizers = vec![Normalizer::Replace(" ", "▁")];
+    if add_space_prefix {
+        decoders.push(Decoder::Strip(' ', 1, 0));
+        normalizers.insert(0, Normalizer::Prepend("▁"));
+    }
+    let decoder = Decoder::Sequence(decoders);
+    let normalizer = Normalizer::Sequence(normalizers);
 
     let mut tokenizer: Tokenizer = TokenizerX::try_builder()
         .with_model(model)
@@ -376,6 +410,8 @@ mod tests {
     use hf_hub::{api::sync::ApiBuilder, Repo, RepoType};
     use tokenizers::Tokenizer;
 
+    use super::{unigram_tokenizer, PropsGGUF, TOKEN_TYPE_CONTROL, TOKEN_TYPE_USER_DEFINED};
+
     #[allow(dead_code)]
     #[derive(Debug)]
     enum TokenizerType {
@@ -558,4 +594,67 @@ mod tests {
 
         Ok(())
     }
+
+    /// A small unigram tokenizer with a control `<start_of_turn>` token and a user defined
+    /// `<custom>` token.
+    fn unigram_props(add_space_prefix: Option<bool>) -> PropsGGUF {
+        let tokens = [
+            ("<unk>", 0., 2),
+            ("<s>", 0., TOKEN_TYPE_CONTROL),
+            ("</s>", 0., TOKEN_TYPE_CONTROL),
+            ("▁hello", -1., 1),
+            ("hello", -1., 1),
+            ("▁", -2., 1),
+            ("<start_of_turn>", 0., TOKEN_TYPE_CONTROL),
+            ("<custom>", 0., TOKEN_TYPE_USER_DEFINED),
+        ];
+        PropsGGUF {
+            model: "llama".to_string(),
+            tokens: tokens.iter().map(|(tok, _, _)| tok.to_string()).collect(),
+            added_tokens: None,
+            scores: Some(tokens.iter().map(|(_, score, _)| *score).collect()),
+            token_types: Some(tokens.iter().map(|(_, _, ty)| *ty).collect()),
+            merges: None,
+            unk: Some(0),
+            eos: 2,
+            bos: 1,
+            add_bos_token: None,
+            add_space_prefix,
+        }
+    }
+
+    #[test]
+    fn test_unigram_add_space_prefix() -> Result<()> {
+        let (tokenizer, _, _) = unigram_tokenizer(&unigram_props(None))?;
+        let ids = tokenizer
+            .encode("hello", false)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(ids.get_ids(), [3]);
+        assert_eq!(decode(&tokenizer, &[3, 3], false)?, "hello hello");
+
+        let (tokenizer, _, _) = unigram_tokenizer(&unigram_props(Some(false)))?;
+        let ids = tokenizer
+            .encode("hello", false)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(ids.get_ids(), [4]);
+        assert_eq!(decode(&tokenizer, &[4, 3], false)?, "hello hello");
+        assert_eq!(decode(&tokenizer, &[3], false)?, " hello");
+        Ok(())
+    }
+
+    #[test]
+    fn test_unigram_token_types() -> Result<()> {
+        let (tokenizer, _, _) = unigram_tokenizer(&unigram_props(Some(false)))?;
+        let ids = tokenizer
+            .encode("<start_of_turn>hello<custom>", false)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(ids.get_ids(), [6, 4, 7]);
+        // Only the control token is special.
+        assert_eq!(decode(&tokenizer, &[6, 4, 7], true)?, "hello<custom>");
+        assert_eq!(
+            decode(&tokenizer, &[6, 4, 7], false)?,
+            "<start_of_turn>hello<custom>"
+        );
+        Ok(())
+    }
 }
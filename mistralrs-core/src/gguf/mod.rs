@@ -27,6 +27,8 @@ pub enum GGUFArchitecture {
     Phi3,
     Starcoder2,
     Qwen2,
+    Gemma,
+    Gemma2,
 }
 
 // Wraps from_str() for some convenience:
@@ -0,0 +1,693 @@
+//! Compile a JSON Schema into a regular expression which can be enforced by the
+//! [`RecRx`](crate::aici::rx::RecRx) recognizer during sampling.
+//!
+//! Supported keywords:
+//! - `type` (including arrays of types), `enum`, `const`
+//! - `properties`, `required`, `additionalProperties` (objects)
+//! - `items`, `minItems`, `maxItems` (arrays)
+//! - `minLength`, `maxLength`, `pattern`, `format` (strings)
+//! - `anyOf`, `oneOf`, single-element `allOf`
+//! - `$ref` into `#/$defs/...` or `#/definitions/...` (non-recursive only)
+//!
+//! Object properties are emitted in the order they are given in the schema `properties`, which
+//! [`JsonSchema`] keeps when it is deserialized. Tool parameters are given as
+//! [`serde_json::Value`]s, so their properties are emitted sorted by name.
+//! A `pattern` must match the whole string and cannot contain anchors or word boundaries
+//! other than a leading `^` and trailing `$`, or match control characters.
+
+use std::{collections::HashSet, fmt};
+
+use anyhow::{bail, Context, Result};
+use indexmap::IndexMap;
+use regex_syntax::hir::{Class, ClassBytes, ClassBytesRange, Hir, HirKind};
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_json::Number;
+
+/// Whitespace allowed between JSON tokens.
+const WHITESPACE: &str = r"[ \t\n]*";
+/// A single character of a JSON string: one to four UTF-8 bytes or an escape sequence.
+const STRING_CHAR: &str = r#"([\x20-\x21\x23-\x5B\x5D-\x7F]|[\xC0-\xDF][\x80-\xBF]|[\xE0-\xEF][\x80-\xBF]{2}|[\xF0-\xF7][\x80-\xBF]{3}|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+const STRING: &str = r#""([^"\\\x00-\x1F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})*""#;
+const INTEGER: &str = r"-?(0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?";
+const BOOLEAN: &str = r"(true|false)";
+const NULL: &str = r"null";
+
+/// Nesting depth for values which are not constrained by the schema (`{}` or `true`).
+const FREE_VALUE_DEPTH: usize = 2;
+
+/// A JSON Schema. Unlike a [`serde_json::Value`], it keeps the order of the keys of its objects
+/// when it is deserialized, so that object properties are emitted in the order they are given.
+///
+/// Converting a [`serde_json::Value`] sorts the keys by name.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct JsonSchema(Json);
+
+impl From<serde_json::Value> for JsonSchema {
+    fn from(value: serde_json::Value) -> Self {
+        Self(value.into())
+    }
+}
+
+/// A JSON value whose objects keep the order of their keys.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+    Array(Vec<Json>),
+    Object(IndexMap<String, Json>),
+}
+
+impl Json {
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Number(n) => n.as_u64(),
+            _ => None,
+        }
+    }
+
+    /// Look up a value by a JSON Pointer, like [`serde_json::Value::pointer`].
+    fn pointer(&self, pointer: &str) -> Option<&Self> {
+        if pointer.is_empty() {
+            return Some(self);
+        }
+        let tokens = pointer.strip_prefix('/')?;
+        tokens.split('/').try_fold(self, |target, token| {
+            let token = token.replace("~1", "/").replace("~0", "~");
+            match target {
+                Self::Object(map) => map.get(&token),
+                Self::Array(values) => values.get(token.parse::<usize>().ok()?),
+                _ => None,
+            }
+        })
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+impl From<serde_json::Value> for Json {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => Self::Null,
+            serde_json::Value::Bool(b) => Self::Bool(b),
+            serde_json::Value::Number(n) => Self::Number(n),
+            serde_json::Value::String(s) => Self::String(s),
+            serde_json::Value::Array(values) => {
+                Self::Array(values.into_iter().map(Self::from).collect())
+            }
+            serde_json::Value::Object(map) => {
+                Self::Object(map.into_iter().map(|(k, v)| (k, v.into())).collect())
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Json {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct JsonVisitor;
+
+        impl<'de> Visitor<'de> for JsonVisitor {
+            type Value = Json;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("any valid JSON value")
+            }
+
+            fn visit_unit<E>(self) -> Result<Json, E> {
+                Ok(Json::Null)
+            }
+
+            fn visit_none<E>(self) -> Result<Json, E> {
+                Ok(Json::Null)
+            }
+
+            fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Json, D::Error> {
+                Json::deserialize(deserializer)
+            }
+
+            fn visit_bool<E>(self, b: bool) -> Result<Json, E> {
+                Ok(Json::Bool(b))
+            }
+
+            fn visit_i64<E>(self, n: i64) -> Result<Json, E> {
+                Ok(Json::Number(n.into()))
+            }
+
+            fn visit_u64<E>(self, n: u64) -> Result<Json, E> {
+                Ok(Json::Number(n.into()))
+            }
+
+            fn visit_f64<E: de::Error>(self, n: f64) -> Result<Json, E> {
+                Number::from_f64(n)
+                    .map(Json::Number)
+                    .ok_or_else(|| E::custom(format!("`{n}` is not a valid JSON number")))
+            }
+
+            fn visit_str<E>(self, s: &str) -> Result<Json, E> {
+                Ok(Json::String(s.to_string()))
+            }
+
+            fn visit_string<E>(self, s: String) -> Result<Json, E> {
+                Ok(Json::String(s))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Json, A::Error> {
+                let mut values = Vec::new();
+                while let Some(value) = seq.next_element()? {
+                    values.push(value);
+                }
+                Ok(Json::Array(values))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Json, A::Error> {
+                let mut entries = IndexMap::new();
+                while let Some((key, value)) = map.next_entry()? {
+                    entries.insert(key, value);
+                }
+                Ok(Json::Object(entries))
+            }
+        }
+
+        deserializer.deserialize_any(JsonVisitor)
+    }
+}
+
+/// Compile a JSON Schema to a regex accepting exactly the JSON documents which satisfy it.
+pub(crate) fn json_schema_to_regex(schema: &JsonSchema) -> Result<String> {
+    let mut compiler = SchemaCompiler {
+        root: &schema.0,
+        ref_stack: HashSet::new(),
+    };
+    compiler.compile(&schema.0)
+}
+
+/// Escape a literal so that it matches byte-for-byte. Non-ASCII bytes are emitted as
+/// `\xNN` because the recognizer operates on raw bytes.
+pub(crate) fn escape_literal(literal: &str) -> String {
+    let mut out = String::with_capacity(literal.len());
+    for byte in literal.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b' ' || byte == b'_' {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("\\x{byte:02X}"));
+        }
+    }
+    out
+}
+
+struct SchemaCompiler<'a> {
+    root: &'a Json,
+    ref_stack: HashSet<String>,
+}
+
+impl<'a> SchemaCompiler<'a> {
+    fn compile(&mut self, schema: &'a Json) -> Result<String> {
+        let obj = match schema {
+            Json::Bool(true) => return Ok(free_value(FREE_VALUE_DEPTH)),
+            Json::Bool(false) => bail!("Schema `false` cannot be satisfied."),
+            Json::Object(obj) => obj,
+            other => bail!("Expected a JSON Schema object, got `{other}`."),
+        };
+
+        if let Some(reference) = obj.get("$ref") {
+            return self.compile_ref(reference);
+        }
+        if let Some(value) = obj.get("const") {
+            return Ok(escape_literal(&serde_json::to_string(value)?));
+        }
+        if let Some(values) = obj.get("enum") {
+            let Json::Array(values) = values else {
+                bail!("`enum` must be an array.");
+            };
+            let choices = values
+                .iter()
+                .map(|v| Ok(escape_literal(&serde_json::to_string(v)?)))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(alternation(&choices));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(options) = obj.get(key) {
+                let Json::Array(options) = options else {
+                    bail!("`{key}` must be an array.");
+                };
+                let choices = options
+                    .iter()
+                    .map(|option| self.compile(option))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(alternation(&choices));
+            }
+        }
+        if let Some(all_of) = obj.get("allOf") {
+            match all_of {
+                Json::Array(options) if options.len() == 1 => return self.compile(&options[0]),
+                _ => bail!("`allOf` is only supported with a single schema."),
+            }
+        }
+
+        match obj.get("type") {
+            Some(Json::String(tp)) => self.compile_type(tp, obj),
+            Some(Json::Array(tps)) => {
+                let choices = tps
+                    .iter()
+                    .map(|tp| match tp {
+                        Json::String(tp) => self.compile_type(tp, obj),
+                        other => bail!("Expected a string in `type`, got `{other}`."),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(alternation(&choices))
+            }
+            Some(other) => bail!("Expected a string or array in `type`, got `{other}`."),
+            None if obj.contains_key("properties") => self.compile_type("object", obj),
+            None if obj.contains_key("items") => self.compile_type("array", obj),
+            None => Ok(free_value(FREE_VALUE_DEPTH)),
+        }
+    }
+
+    fn compile_ref(&mut self, reference: &Json) -> Result<String> {
+        let Json::String(reference) = reference else {
+            bail!("`$ref` must be a string.");
+        };
+        let Some(pointer) = reference.strip_prefix('#') else {
+            bail!("Only local `$ref`s are supported, got `{reference}`.");
+        };
+        if !self.ref_stack.insert(reference.clone()) {
+            bail!("Recursive `$ref` `{reference}` is not supported.");
+        }
+        let target = self
+            .root
+            .pointer(pointer)
+            .with_context(|| format!("Could not resolve `$ref` `{reference}`."))?;
+        let res = self.compile(target);
+        self.ref_stack.remove(reference);
+        res
+    }
+
+    fn compile_type(&mut self, tp: &str, obj: &'a IndexMap<String, Json>) -> Result<String> {
+        match tp {
+            "string" => compile_string(obj),
+            "integer" => Ok(INTEGER.to_string()),
+            "number" => Ok(NUMBER.to_string()),
+            "boolean" => Ok(BOOLEAN.to_string()),
+            "null" => Ok(NULL.to_string()),
+            "array" => self.compile_array(obj),
+            "object" => self.compile_object(obj),
+            other => bail!("Unsupported JSON Schema type `{other}`."),
+        }
+    }
+
+    fn compile_array(&mut self, obj: &'a IndexMap<String, Json>) -> Result<String> {
+        let item = match obj.get("items") {
+            Some(items) => self.compile(items)?,
+            None => free_value(FREE_VALUE_DEPTH - 1),
+        };
+        let min_items = get_usize(obj, "minItems")?.unwrap_or(0);
+        let max_items = get_usize(obj, "maxItems")?;
+        if max_items.is_some_and(|max| max < min_items) {
+            bail!("`maxItems` must not be less than `minItems`.");
+        }
+        let sep = format!("{WHITESPACE},{WHITESPACE}");
+
+        let elements = match (min_items, max_items) {
+            (_, Some(0)) => String::new(),
+            (0, None) => format!("(({item})({sep}({item}))*)?"),
+            (0, Some(max)) => format!("(({item})({sep}({item})){{0,{}}})?", max - 1),
+            (min, None) => format!("({item})({sep}({item})){{{},}}", min - 1),
+            (min, Some(max)) => format!("({item})({sep}({item})){{{},{}}}", min - 1, max - 1),
+        };
+        Ok(format!(r"\[{WHITESPACE}{elements}{WHITESPACE}\]"))
+    }
+
+    fn compile_object(&mut self, obj: &'a IndexMap<String, Json>) -> Result<String> {
+        let properties = match obj.get("properties") {
+            Some(Json::Object(properties)) => properties,
+            Some(_) => bail!("`properties` must be an object."),
+            None => {
+                let value = match obj.get("additionalProperties") {
+                    Some(Json::Bool(false)) => return Ok(format!(r"\{{{WHITESPACE}\}}")),
+                    Some(Json::Bool(true)) | None => free_value(FREE_VALUE_DEPTH - 1),
+                    Some(schema) => self.compile(schema)?,
+                };
+                let member = format!("{STRING}{WHITESPACE}:{WHITESPACE}({value})");
+                return Ok(format!(
+                    r"\{{{WHITESPACE}({member}({WHITESPACE},{WHITESPACE}{member})*)?{WHITESPACE}\}}"
+                ));
+            }
+        };
+        let required = match obj.get("required") {
+            Some(Json::Array(required)) => required
+                .iter()
+                .map(|r| match r {
+                    Json::String(r) => Ok(r.as_str()),
+                    other => bail!("Expected a string in `required`, got `{other}`."),
+                })
+                .collect::<Result<HashSet<_>>>()?,
+            Some(_) => bail!("`required` must be an array."),
+            None => HashSet::new(),
+        };
+
+        let mut members = Vec::new();
+        for (name, schema) in properties {
+            let value = self.compile(schema)?;
+            let key = escape_literal(&serde_json::to_string(name)?);
+            members.push((
+                format!("{key}{WHITESPACE}:{WHITESPACE}({value})"),
+                required.contains(name.as_str()),
+            ));
+        }
+        if members.is_empty() {
+            return Ok(format!(r"\{{{WHITESPACE}\}}"));
+        }
+
+        // Enumerate which member is emitted first: it must come before (or be) the first required one.
+        let sep = format!("{WHITESPACE},{WHITESPACE}");
+        let first_required = members.iter().position(|(_, required)| *required);
+        let last_first = first_required.unwrap_or(members.len() - 1);
+        let mut choices = Vec::new();
+        for first in 0..=last_first {
+            let mut choice = members[first].0.clone();
+            for (member, required) in &members[first + 1..] {
+                if *required {
+                    choice.push_str(&format!("{sep}{member}"));
+                } else {
+                    choice.push_str(&format!("({sep}{member})?"));
+                }
+            }
+            choices.push(choice);
+        }
+        let body = if first_required.is_none() {
+            format!("({})?", alternation(&choices))
+        } else {
+            alternation(&choices)
+        };
+        Ok(format!(r"\{{{WHITESPACE}{body}{WHITESPACE}\}}"))
+    }
+}
+
+fn compile_string(obj: &IndexMap<String, Json>) -> Result<String> {
+    if let Some(pattern) = obj.get("pattern") {
+        let Json::String(pattern) = pattern else {
+            bail!("`pattern` must be a string.");
+        };
+        return Ok(format!(r#""({})""#, compile_pattern(pattern)?));
+    }
+    if let Some(format) = obj.get("format") {
+        let pattern = match format.as_str() {
+            Some("date-time") => {
+                r"[0-9]{4}-(0[1-9]|1[0-2])-(0[1-9]|[12][0-9]|3[01])T([01][0-9]|2[0-3]):[0-5][0-9]:[0-5][0-9](\.[0-9]+)?(Z|[+-]([01][0-9]|2[0-3]):[0-5][0-9])"
+            }
+            Some("date") => r"[0-9]{4}-(0[1-9]|1[0-2])-(0[1-9]|[12][0-9]|3[01])",
+            Some("time") => {
+                r"([01][0-9]|2[0-3]):[0-5][0-9]:[0-5][0-9](\.[0-9]+)?(Z|[+-]([01][0-9]|2[0-3]):[0-5][0-9])?"
+            }
+            Some("uuid") => {
+                r"[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}"
+            }
+            Some(other) => bail!("Unsupported string format `{other}`."),
+            None => bail!("`format` must be a string."),
+        };
+        return Ok(format!(r#""{pattern}""#));
+    }
+    let min_length = get_usize(obj, "minLength")?;
+    let max_length = get_usize(obj, "maxLength")?;
+    match (min_length, max_length) {
+        (None, None) => Ok(STRING.to_string()),
+        (min, Some(max)) => {
+            let min = min.unwrap_or(0);
+            if max < min {
+                bail!("`maxLength` must not be less than `minLength`.");
+            }
+            Ok(format!(r#""{STRING_CHAR}{{{min},{max}}}""#))
+        }
+        (Some(min), None) => Ok(format!(r#""{STRING_CHAR}{{{min},}}""#)),
+    }
+}
+
+/// Compile a `pattern` to a regex over the raw contents of a JSON string, with the same syntax as
+/// the recognizer. Quotes and backslashes are escaped, and control characters are not supported.
+fn compile_pattern(pattern: &str) -> Result<String> {
+    let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+    let pattern = pattern.strip_suffix('$').unwrap_or(pattern);
+    let hir = regex_syntax::ParserBuilder::new()
+        .unicode(false)
+        .utf8(false)
+        .build()
+        .parse(pattern)
+        .with_context(|| format!("Unsupported `pattern` `{pattern}`."))?;
+    Ok(restrict_to_json_string(hir, pattern)?.to_string())
+}
+
+fn restrict_to_json_string(hir: Hir, pattern: &str) -> Result<Hir> {
+    let unescaped = ClassBytes::new([
+        ClassBytesRange::new(0x20, 0x21),
+        ClassBytesRange::new(0x23, 0x5B),
+        ClassBytesRange::new(0x5D, 0xFF),
+    ]);
+    Ok(match hir.into_kind() {
+        HirKind::Empty => Hir::empty(),
+        HirKind::Literal(literal) => {
+            let mut escaped = Vec::new();
+            for byte in literal.0.iter().copied() {
+                match byte {
+                    b'"' | b'\\' => escaped.extend([b'\\', byte]),
+                    0x00..=0x1F => bail!(
+                        "`pattern` `{pattern}` contains a control character, which must be escaped in JSON."
+                    ),
+                    _ => escaped.push(byte),
+                }
+            }
+            Hir::literal(escaped)
+        }
+        HirKind::Class(Class::Bytes(class)) => {
+            let mut alternatives = Vec::new();
+            let mut unescaped_class = class.clone();
+            unescaped_class.intersect(&unescaped);
+            if !unescaped_class.ranges().is_empty() {
+                alternatives.push(Hir::class(Class::Bytes(unescaped_class)));
+            }
+            for byte in [b'"', b'\\'] {
+                if class
+                    .ranges()
+                    .iter()
+                    .any(|range| (range.start()..=range.end()).contains(&byte))
+                {
+                    alternatives.push(Hir::literal([b'\\', byte]));
+                }
+            }
+            if alternatives.is_empty() {
+                bail!("`pattern` `{pattern}` has a character class which only matches control characters.");
+            }
+            Hir::alternation(alternatives)
+        }
+        HirKind::Class(Class::Unicode(_)) => {
+            bail!("`pattern` `{pattern}` has a Unicode character class, which is not supported.")
+        }
+        HirKind::Look(_) => bail!(
+            "`pattern` `{pattern}` has an anchor or word boundary which is not at its start or end."
+        ),
+        HirKind::Repetition(mut repetition) => {
+            repetition.sub = Box::new(restrict_to_json_string(*repetition.sub, pattern)?);
+            Hir::repetition(repetition)
+        }
+        HirKind::Capture(mut capture) => {
+            capture.sub = Box::new(restrict_to_json_string(*capture.sub, pattern)?);
+            Hir::capture(capture)
+        }
+        HirKind::Concat(subs) => Hir::concat(
+            subs.into_iter()
+                .map(|sub| restrict_to_json_string(sub, pattern))
+                .collect::<Result<_>>()?,
+        ),
+        HirKind::Alternation(subs) => Hir::alternation(
+            subs.into_iter()
+                .map(|sub| restrict_to_json_string(sub, pattern))
+                .collect::<Result<_>>()?,
+        ),
+    })
+}
+
+/// Any JSON value, nesting arrays and objects up to `depth` levels.
+fn free_value(depth: usize) -> String {
+    let scalars = [STRING, NUMBER, BOOLEAN, NULL];
+    if depth == 0 {
+        return alternation(&scalars.map(String::from));
+    }
+    let inner = free_value(depth - 1);
+    let array =
+        format!(r"\[{WHITESPACE}(({inner})({WHITESPACE},{WHITESPACE}({inner}))*)?{WHITESPACE}\]");
+    let member = format!("{STRING}{WHITESPACE}:{WHITESPACE}({inner})");
+    let object =
+        format!(r"\{{{WHITESPACE}({member}({WHITESPACE},{WHITESPACE}{member})*)?{WHITESPACE}\}}");
+    let mut choices = scalars.map(String::from).to_vec();
+    choices.push(array);
+    choices.push(object);
+    alternation(&choices)
+}
+
+fn alternation(choices: &[String]) -> String {
+    let choices = choices
+        .iter()
+        .map(|c| format!("({c})"))
+        .collect::<Vec<_>>()
+        .join("|");
+    format!("({choices})")
+}
+
+fn get_usize(obj: &IndexMap<String, Json>, key: &str) -> Result<Option<usize>> {
+    match obj.get(key) {
+        Some(value) => {
+            let value = value
+                .as_u64()
+                .with_context(|| format!("`{key}` must be a non-negative integer."))?;
+            Ok(Some(value.try_into()?))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::bytes::Regex;
+    use serde_json::json;
+
+    use super::{json_schema_to_regex, JsonSchema};
+
+    fn matches(schema: serde_json::Value, doc: &str) -> bool {
+        let rx = json_schema_to_regex(&schema.into()).unwrap();
+        Regex::new(&format!("^(?-u:{rx})$"))
+            .unwrap()
+            .is_match(doc.as_bytes())
+    }
+
+    #[test]
+    fn test_object_required_and_optional() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "age": {"type": "integer"},
+                "name": {"type": "string"},
+                "tags": {"type": "array", "items": {"type": "string"}, "maxItems": 2}
+            },
+            "required": ["name"]
+        });
+        assert!(matches(schema.clone(), r#"{"name": "Bob"}"#));
+        assert!(matches(
+            schema.clone(),
+            r#"{"age": 3, "name": "Bob", "tags": ["a"]}"#
+        ));
+        assert!(matches(schema.clone(), r#"{"name":"Bob","tags":[]}"#));
+        assert!(!matches(schema.clone(), r#"{"age": 3}"#));
+        assert!(!matches(
+            schema.clone(),
+            r#"{"name": "Bob", "tags": ["a", "b", "c"]}"#
+        ));
+        assert!(!matches(schema, r#"{"name": 1}"#));
+    }
+
+    #[test]
+    fn test_enum_ref_and_any_of() {
+        let schema = json!({
+            "$defs": {"color": {"enum": ["red", "green"]}},
+            "type": "object",
+            "properties": {
+                "color": {"$ref": "#/$defs/color"},
+                "value": {"anyOf": [{"type": "number"}, {"type": "null"}]}
+            },
+            "required": ["color", "value"]
+        });
+        assert!(matches(
+            schema.clone(),
+            r#"{"color": "red", "value": -1.5e3}"#
+        ));
+        assert!(matches(
+            schema.clone(),
+            r#"{"color": "green", "value": null}"#
+        ));
+        assert!(!matches(schema, r#"{"color": "blue", "value": null}"#));
+    }
+
+    #[test]
+    fn test_recursive_ref_is_rejected() {
+        let schema = json!({
+            "$defs": {"node": {"type": "object", "properties": {"next": {"$ref": "#/$defs/node"}}}},
+            "$ref": "#/$defs/node"
+        });
+        assert!(json_schema_to_regex(&schema.into()).is_err());
+    }
+
+    #[test]
+    fn test_properties_keep_schema_order() {
+        let schema: JsonSchema = serde_json::from_str(
+            r#"{
+                "type": "object",
+                "properties": {"z": {"type": "integer"}, "a": {"const": {"y": 1, "b": 2}}},
+                "required": ["z", "a"]
+            }"#,
+        )
+        .unwrap();
+        let rx = Regex::new(&format!(
+            "^(?-u:{})$",
+            json_schema_to_regex(&schema).unwrap()
+        ))
+        .unwrap();
+        assert!(rx.is_match(br#"{"z": 1, "a": {"y":1,"b":2}}"#));
+        assert!(!rx.is_match(br#"{"a": {"y":1,"b":2}, "z": 1}"#));
+
+        // A `serde_json::Value` sorts the keys.
+        let schema = json!({
+            "type": "object",
+            "properties": {"z": {"type": "integer"}, "a": {"type": "integer"}},
+            "required": ["z", "a"]
+        });
+        assert!(matches(schema, r#"{"a": 2, "z": 1}"#));
+    }
+
+    #[test]
+    fn test_string_lengths_count_characters() {
+        let schema = json!({"type": "string", "maxLength": 3});
+        assert!(matches(schema.clone(), r#""héé""#));
+        assert!(matches(schema.clone(), r#""a\nb""#));
+        assert!(!matches(schema, r#""hééé""#));
+        assert!(!matches(
+            json!({"type": "string", "minLength": 2}),
+            r#""é""#
+        ));
+    }
+
+    #[test]
+    fn test_pattern() {
+        assert!(matches(
+            json!({"type": "string", "pattern": "^[a-z]+$"}),
+            r#""abc""#
+        ));
+        assert!(matches(
+            json!({"type": "string", "pattern": r#"a"b"#}),
+            r#""a\"b""#
+        ));
+        let schema = json!({"type": "string", "pattern": ".+"});
+        assert!(matches(schema.clone(), r#""a\"b""#));
+        assert!(!matches(schema, r#""a"b""#));
+        assert!(json_schema_to_regex(&json!({"type": "string", "pattern": "a^b"}).into()).is_err());
+        assert!(
+            json_schema_to_regex(&json!({"type": "string", "pattern": "a(?=b)"}).into()).is_err()
+        );
+    }
+}
@@ -4,6 +4,8 @@ use std::ops::Add;
 
 use candle_core::{DType, Device, Result, Tensor, WithDType};
 
+use crate::pipeline::KvCache;
+
 // https://github.com/huggingface/transformers/blob/main/src/transformers/modeling_attn_mask_utils.py
 pub struct CausalMasker;
 
@@ -36,14 +38,13 @@ pub trait PastKvLenCache {
     fn get_past_kv_len(&self) -> Result<usize>;
 }
 
-impl<'a> PastKvLenCache for &'a [Option<(Tensor, Tensor)>] {
+impl<'a> PastKvLenCache for &'a [Option<KvCache>] {
     fn get_past_kv_len(&self) -> Result<usize> {
         let kv_cache_1 = &self[0];
         if kv_cache_1.is_none() {
             return Ok(0);
         }
-        let k_cache_1 = &kv_cache_1.as_ref().unwrap().0;
-        return Ok(k_cache_1.dims()[2]);
+        return Ok(kv_cache_1.as_ref().unwrap().current_seq_len());
     }
 }
 
@@ -57,14 +58,13 @@ impl<'a> PastKvLenCache for &'a [usize] {
     }
 }
 
-impl PastKvLenCache for Vec<Option<(Tensor, Tensor)>> {
+impl PastKvLenCache for Vec<Option<KvCache>> {
     fn get_past_kv_len(&self) -> Result<usize> {
         let kv_cache_1 = &self[0];
         if kv_cache_1.is_none() {
             return Ok(0);
         }
-        let k_cache_1 = &kv_cache_1.as_ref().unwrap().0;
-        return Ok(k_cache_1.dims()[2]);
+        return Ok(kv_cache_1.as_ref().unwrap().current_seq_len());
     }
 }
 
@@ -110,16 +110,12 @@ impl CausalMasker {
         )
     }
 
-    pub fn calculate_past_kv_len(
-        &self,
-        cache: &[Option<(Tensor, Tensor)>],
-    ) -> candle_core::Result<usize> {
+    pub fn calculate_past_kv_len(&self, cache: &[Option<KvCache>]) -> candle_core::Result<usize> {
         let kv_cache_1 = &cache[0];
         if kv_cache_1.is_none() {
             return Ok(0);
         }
-        let k_cache_1 = &kv_cache_1.as_ref().unwrap().0;
-        return Ok(k_cache_1.dims()[2]);
+        return Ok(kv_cache_1.as_ref().unwrap().current_seq_len());
     }
 
     pub fn make_causal_mask_as_attn_bias(
@@ -223,7 +219,7 @@ impl CausalMasker {
     pub fn make_causal_mask(
         &self,
         input_ids: &Tensor,
-        cache: &[Option<(Tensor, Tensor)>],
+        cache: &[Option<KvCache>],
     ) -> Result<Option<Tensor>> {
         let past_kv_len = self.calculate_past_kv_len(cache)?;
         let (b_sz, tgt_len) = input_ids.dims2()?;
@@ -247,7 +243,7 @@ impl CausalMasker {
     pub fn make_causal_mask_with_sliding_window(
         &self,
         input_ids: &Tensor,
-        cache: &[Option<(Tensor, Tensor)>],
+        cache: &[Option<KvCache>],
         sliding_window: Option<usize>,
     ) -> Result<Option<Tensor>> {
         if sliding_window.is_none() {
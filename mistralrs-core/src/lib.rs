@@ -34,16 +34,22 @@ pub use model_loader::{get_model_dtype, get_tgt_non_granular_index, LoaderBuilde
 
 mod model_selected;
 pub use model_selected::ModelSelected;
-pub use toml_selector::get_toml_selected_model_dtype;
+pub use toml_selector::{
+    get_toml_selected_model_dtype, TomlLoaderArgs, TomlMultiModelSelector, TomlServedModel,
+};
 
 mod amoe;
+mod beam_search;
 mod cublaslt;
 #[cfg(not(all(feature = "cuda", target_family = "unix")))]
 mod dummy_paged_attention;
+mod embedding;
 mod gguf;
+mod json_schema;
 pub mod layers;
 mod layers_masker;
 mod layers_utils;
+mod metrics;
 mod models;
 #[cfg(all(feature = "cuda", target_family = "unix"))]
 mod paged_attention;
@@ -57,6 +63,7 @@ mod request;
 mod response;
 mod sampler;
 mod scheduler;
+mod scoring;
 mod sequence;
 mod toml_selector;
 mod tools;
@@ -68,33 +75,38 @@ mod xlora_models;
 pub use amoe::{AnyMoeConfig, AnyMoeExpertType};
 pub use device_map::{DeviceLayerMapMetadata, DeviceMapMetadata, LayerDeviceMapper};
 pub use gguf::{GGUFArchitecture, GGUF_MULTI_FILE_DELIMITER};
+pub use json_schema::JsonSchema;
+pub use metrics::EngineMetrics;
 pub use mistralrs_quant::IsqType;
-pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig};
+pub use paged_attention::{MemoryGpuConfig, PagedAttentionConfig, PreemptionMode};
 pub use pipeline::{
-    chat_template::ChatTemplate, parse_isq_value, AnyMoeLoader, AnyMoePipeline,
-    DiffusionGenerationParams, DiffusionLoader, DiffusionLoaderBuilder, DiffusionLoaderType,
-    DiffusionSpecificConfig, GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoader,
-    GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader, Idefics2Loader, IsqOrganization,
-    LLaVALoader, LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths, MistralLoader,
-    MixtralLoader, ModelKind, ModelPaths, NormalLoader, NormalLoaderBuilder, NormalLoaderType,
-    NormalSpecificConfig, Phi2Loader, Phi3Loader, Phi3VLoader, Qwen2Loader, SpeculativeConfig,
-    SpeculativeLoader, SpeculativePipeline, Starcoder2Loader, TokenSource, VisionLoader,
-    VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
+    chat_template::ChatTemplate, parse_isq_value, AdaptiveGamma, AnyMoeLoader, AnyMoePipeline,
+    DeepSeekV2Loader, DiffusionGenerationParams, DiffusionLoader, DiffusionLoaderBuilder,
+    DiffusionLoaderType, DiffusionSpecificConfig, GGMLLoader, GGMLLoaderBuilder,
+    GGMLSpecificConfig, GGUFLoader, GGUFLoaderBuilder, GGUFSpecificConfig, GemmaLoader,
+    Idefics2Loader, IsqOrganization, KvCacheType, LLaVALoader, LLaVANextLoader, LlamaLoader,
+    Loader, LocalModelPaths, MambaLoader, MistralLoader, MixtralLoader, ModelKind, ModelPaths,
+    NormalLoader, NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, Phi2Loader,
+    Phi3Loader, Phi3VLoader, PromptLookupConfig, PromptLookupLoader, Qwen2Loader,
+    SpeculativeConfig, SpeculativeLoader, SpeculativePipeline, Starcoder2Loader, TokenSource,
+    VisionLoader, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
 };
 pub use request::{
-    Constraint, ImageGenerationResponseFormat, MessageContent, NormalRequest, Request,
-    RequestMessage,
+    Constraint, EmbeddingParams, EmbeddingPooling, ImageGenerationResponseFormat, MessageContent,
+    NormalRequest, Request, RequestMessage, RequestPriority,
 };
 pub use response::*;
 pub use sampler::{
-    CustomLogitsProcessor, DrySamplingParams, SamplingParams, StopTokens, TopLogprob,
+    BeamSearchParams, CustomLogitsProcessor, DrySamplingParams, MirostatParams, MirostatVersion,
+    SamplerStep, SamplingParams, StopTokens, TopLogprob, DEFAULT_SAMPLER_ORDER,
 };
-pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig};
+pub use scheduler::{DefaultSchedulerMethod, SchedulerConfig, SchedulingPolicy};
 use serde::Serialize;
 use tokio::runtime::Runtime;
-use toml_selector::{TomlLoaderArgs, TomlSelector};
+use toml_selector::TomlSelector;
 pub use tools::{
-    CalledFunction, Function, Tool, ToolCallResponse, ToolCallType, ToolChoice, ToolType,
+    CalledFunction, CalledFunctionDelta, Function, Tool, ToolCallDelta, ToolCallResponse,
+    ToolCallType, ToolChoice, ToolType,
 };
 pub use topology::{LayerTopology, Topology};
 pub use utils::debug::initialize_logging;
@@ -126,18 +138,21 @@ pub struct MistralRs {
     engine_id: usize,
     category: ModelCategory,
     config: MistralRsConfig,
+    metrics: Arc<EngineMetrics>,
 }
 
 #[derive(Clone)]
 struct RebootState {
     pipeline: Arc<tokio::sync::Mutex<dyn Pipeline>>,
     method: SchedulerConfig,
+    scheduling_policy: SchedulingPolicy,
     truncate_sequence: bool,
     no_kv_cache: bool,
     no_prefix_cache: bool,
     prefix_cache_n: usize,
     disable_eos_stop: bool,
     throughput_logging_enabled: bool,
+    metrics: Arc<EngineMetrics>,
 }
 
 #[derive(Debug)]
@@ -167,6 +182,7 @@ impl From<MistralRsError> for pyo3::PyErr {
 pub struct MistralRsBuilder {
     pipeline: Arc<tokio::sync::Mutex<dyn Pipeline>>,
     method: SchedulerConfig,
+    scheduling_policy: Option<SchedulingPolicy>,
     log: Option<String>,
     truncate_sequence: Option<bool>,
     no_kv_cache: Option<bool>,
@@ -182,6 +198,7 @@ impl MistralRsBuilder {
         Self {
             pipeline,
             method,
+            scheduling_policy: None,
             log: None,
             truncate_sequence: None,
             no_kv_cache: None,
@@ -192,6 +209,12 @@ impl MistralRsBuilder {
             throughput_logging_enabled: None,
         }
     }
+    /// The order in which the default scheduler runs waiting sequences. Defaults to
+    /// `SchedulingPolicy::default()`, and is not used by the PagedAttention scheduler.
+    pub fn with_scheduling_policy(mut self, scheduling_policy: SchedulingPolicy) -> Self {
+        self.scheduling_policy = Some(scheduling_policy);
+        self
+    }
     pub fn with_log(mut self, log: String) -> Self {
         self.log = Some(log);
         self
@@ -285,6 +308,7 @@ impl MistralRs {
         let MistralRsBuilder {
             pipeline,
             method,
+            scheduling_policy,
             log,
             truncate_sequence,
             no_kv_cache,
@@ -306,22 +330,26 @@ impl MistralRs {
         }
         setup_cublas_lt_wrapper();
 
+        let scheduling_policy = scheduling_policy.unwrap_or_default();
         let truncate_sequence = truncate_sequence.unwrap_or(false);
         let no_kv_cache = no_kv_cache.unwrap_or(false);
         let no_prefix_cache = no_prefix_cache.unwrap_or(false);
         let prefix_cache_n = prefix_cache_n.unwrap_or(16);
         let disable_eos_stop = disable_eos_stop.unwrap_or(false);
         let throughput_logging_enabled = throughput_logging_enabled.is_some();
+        let metrics = Arc::new(EngineMetrics::default());
 
         let reboot_state = RebootState {
             pipeline: pipeline.clone(),
             method: method.clone(),
+            scheduling_policy,
             truncate_sequence,
             no_kv_cache,
             no_prefix_cache,
             prefix_cache_n,
             disable_eos_stop,
             throughput_logging_enabled,
+            metrics: metrics.clone(),
         };
 
         let (tx, rx) = channel(10_000);
@@ -333,6 +361,7 @@ impl MistralRs {
         let device = pipeline.try_lock().unwrap().device();
         let config = MistralRsConfig { kind, device };
 
+        let engine_metrics = metrics.clone();
         let engine_handler = thread::spawn(move || {
             let rt = Runtime::new().unwrap();
             rt.block_on(async move {
@@ -340,12 +369,14 @@ impl MistralRs {
                     rx,
                     pipeline,
                     method,
+                    scheduling_policy,
                     truncate_sequence,
                     no_kv_cache,
                     no_prefix_cache,
                     prefix_cache_n,
                     disable_eos_stop,
                     throughput_logging_enabled,
+                    engine_metrics,
                 );
                 engine.run().await;
             });
@@ -367,6 +398,7 @@ impl MistralRs {
             engine_handler: RwLock::new(engine_handler),
             category,
             config,
+            metrics,
         })
     }
 
@@ -396,12 +428,14 @@ impl MistralRs {
                         rx,
                         reboot_state.pipeline.clone(),
                         reboot_state.method,
+                        reboot_state.scheduling_policy,
                         reboot_state.truncate_sequence,
                         reboot_state.no_kv_cache,
                         reboot_state.no_prefix_cache,
                         reboot_state.prefix_cache_n,
                         reboot_state.disable_eos_stop,
                         reboot_state.throughput_logging_enabled,
+                        reboot_state.metrics,
                     );
                     engine.run().await;
                 });
@@ -446,6 +480,11 @@ impl MistralRs {
         self.category
     }
 
+    /// Get the engine metrics in the Prometheus text exposition format.
+    pub fn get_metrics(&self) -> String {
+        self.metrics.render()
+    }
+
     pub fn next_request_id(&self) -> usize {
         let l = self.next_request_id.lock().unwrap();
         let last = &mut *l.borrow_mut();
@@ -0,0 +1,301 @@
+//! Engine metrics, rendered in the Prometheus text exposition format.
+
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex, time::Duration};
+
+use crate::sequence::{Sequence, SequenceState};
+
+/// Bucket upper bounds in seconds for the time to first token.
+const TTFT_BUCKETS: &[f64] = &[
+    0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+/// Bucket upper bounds in seconds for the latency between two generated tokens.
+const ITL_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// Cumulative count for each bound.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(&mut self.counts) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} histogram").unwrap();
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}").unwrap();
+        }
+        writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", self.count).unwrap();
+        writeln!(out, "{name}_sum {}", self.sum).unwrap();
+        writeln!(out, "{name}_count {}", self.count).unwrap();
+    }
+}
+
+struct MetricsState {
+    waiting_seqs: usize,
+    running_seqs: usize,
+    prompt_tokens: u64,
+    generated_tokens: u64,
+    prompt_tok_per_sec: f64,
+    completion_tok_per_sec: f64,
+    time_to_first_token: Histogram,
+    inter_token_latency: Histogram,
+    prefix_cache_hits: u64,
+    prefix_cache_misses: u64,
+    /// Only set when using PagedAttention.
+    free_blocks: Option<(usize, usize)>,
+    finished_seqs: BTreeMap<String, u64>,
+}
+
+/// Metrics collected by the `Engine`. These are shared with `MistralRs` so that they survive an engine reboot.
+pub struct EngineMetrics(Mutex<MetricsState>);
+
+impl Default for EngineMetrics {
+    fn default() -> Self {
+        Self(Mutex::new(MetricsState {
+            waiting_seqs: 0,
+            running_seqs: 0,
+            prompt_tokens: 0,
+            generated_tokens: 0,
+            prompt_tok_per_sec: 0.,
+            completion_tok_per_sec: 0.,
+            time_to_first_token: Histogram::new(TTFT_BUCKETS),
+            inter_token_latency: Histogram::new(ITL_BUCKETS),
+            prefix_cache_hits: 0,
+            prefix_cache_misses: 0,
+            free_blocks: None,
+            finished_seqs: BTreeMap::new(),
+        }))
+    }
+}
+
+impl EngineMetrics {
+    fn state(&self) -> std::sync::MutexGuard<'_, MetricsState> {
+        self.0.lock().expect("Metrics were poisoned")
+    }
+
+    pub(crate) fn set_queue_depth(&self, waiting: usize, running: usize) {
+        let mut state = self.state();
+        state.waiting_seqs = waiting;
+        state.running_seqs = running;
+    }
+
+    pub(crate) fn set_free_blocks(&self, gpu: usize, cpu: usize) {
+        self.state().free_blocks = Some((gpu, cpu));
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn record_prompt_step(&self, n_toks: usize, elapsed: Duration) {
+        let mut state = self.state();
+        state.prompt_tokens += n_toks as u64;
+        state.prompt_tok_per_sec = n_toks as f64 / elapsed.as_secs_f64();
+    }
+
+    /// Each sequence in a completion step generates one token.
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn record_completion_step(&self, n_seqs: usize, elapsed: Duration) {
+        let mut state = self.state();
+        state.generated_tokens += n_seqs as u64;
+        state.completion_tok_per_sec = n_seqs as f64 / elapsed.as_secs_f64();
+    }
+
+    pub(crate) fn observe_time_to_first_token(&self, elapsed: Duration) {
+        self.state()
+            .time_to_first_token
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Record the latency between tokens for each of the `n_seqs` sequences which were decoded.
+    pub(crate) fn observe_inter_token_latency(&self, n_seqs: usize, elapsed: Duration) {
+        let mut state = self.state();
+        for _ in 0..n_seqs {
+            state.inter_token_latency.observe(elapsed.as_secs_f64());
+        }
+    }
+
+    pub(crate) fn record_prefix_cache_lookup(&self, hit: bool) {
+        let mut state = self.state();
+        if hit {
+            state.prefix_cache_hits += 1;
+        } else {
+            state.prefix_cache_misses += 1;
+        }
+    }
+
+    fn record_finished(&self, reason: String) {
+        *self.state().finished_seqs.entry(reason).or_default() += 1;
+    }
+
+    /// Count the sequence by its finish reason if it is done.
+    pub(crate) fn record_if_finished(&self, seq: &Sequence) {
+        match seq.getstate() {
+            SequenceState::Done(reason) => self.record_finished(reason.to_string()),
+            SequenceState::Error => self.record_finished("error".to_string()),
+            _ => (),
+        }
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn render(&self) -> String {
+        let state = self.state();
+        let mut out = String::new();
+
+        let mut gauge = |name: &str, help: &str, value: f64| {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} gauge").unwrap();
+            writeln!(out, "{name} {value}").unwrap();
+        };
+        gauge(
+            "mistralrs_waiting_sequences",
+            "Number of sequences waiting to be scheduled.",
+            state.waiting_seqs as f64,
+        );
+        gauge(
+            "mistralrs_running_sequences",
+            "Number of running sequences.",
+            state.running_seqs as f64,
+        );
+        gauge(
+            "mistralrs_prompt_tokens_per_second",
+            "Prompt throughput of the last prompt step.",
+            state.prompt_tok_per_sec,
+        );
+        gauge(
+            "mistralrs_completion_tokens_per_second",
+            "Completion throughput of the last completion step.",
+            state.completion_tok_per_sec,
+        );
+        let lookups = state.prefix_cache_hits + state.prefix_cache_misses;
+        gauge(
+            "mistralrs_prefix_cache_hit_rate",
+            "Fraction of prefix cache lookups which were hits.",
+            if lookups == 0 {
+                0.
+            } else {
+                state.prefix_cache_hits as f64 / lookups as f64
+            },
+        );
+        if let Some((gpu, cpu)) = state.free_blocks {
+            gauge(
+                "mistralrs_paged_attn_free_gpu_blocks",
+                "Number of free PagedAttention GPU blocks.",
+                gpu as f64,
+            );
+            gauge(
+                "mistralrs_paged_attn_free_cpu_blocks",
+                "Number of free PagedAttention CPU blocks.",
+                cpu as f64,
+            );
+        }
+
+        let mut counter = |name: &str, help: &str, values: &[(Option<&str>, u64)]| {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} counter").unwrap();
+            for (label, value) in values {
+                match label {
+                    Some(label) => writeln!(out, "{name}{{{label}}} {value}").unwrap(),
+                    None => writeln!(out, "{name} {value}").unwrap(),
+                }
+            }
+        };
+        counter(
+            "mistralrs_prompt_tokens_total",
+            "Number of prompt tokens processed.",
+            &[(None, state.prompt_tokens)],
+        );
+        counter(
+            "mistralrs_generated_tokens_total",
+            "Number of tokens generated.",
+            &[(None, state.generated_tokens)],
+        );
+        counter(
+            "mistralrs_prefix_cache_hits_total",
+            "Number of prefix cache lookups which found a cache.",
+            &[(None, state.prefix_cache_hits)],
+        );
+        counter(
+            "mistralrs_prefix_cache_misses_total",
+            "Number of prefix cache lookups which did not find a cache.",
+            &[(None, state.prefix_cache_misses)],
+        );
+        let labels = state
+            .finished_seqs
+            .keys()
+            .map(|reason| format!("finish_reason=\"{reason}\""))
+            .collect::<Vec<_>>();
+        counter(
+            "mistralrs_finished_sequences_total",
+            "Number of finished sequences (choices) by finish reason.",
+            &labels
+                .iter()
+                .zip(state.finished_seqs.values())
+                .map(|(label, value)| (Some(label.as_str()), *value))
+                .collect::<Vec<_>>(),
+        );
+
+        state.time_to_first_token.render(
+            &mut out,
+            "mistralrs_time_to_first_token_seconds",
+            "Time from receiving a request to generating its first token.",
+        );
+        state.inter_token_latency.render(
+            &mut out,
+            "mistralrs_inter_token_latency_seconds",
+            "Time between two generated tokens of a sequence.",
+        );
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::EngineMetrics;
+
+    #[test]
+    fn test_render_metrics() {
+        let metrics = EngineMetrics::default();
+        metrics.set_queue_depth(2, 3);
+        metrics.record_prefix_cache_lookup(true);
+        metrics.record_prefix_cache_lookup(false);
+        metrics.record_finished("stop".to_string());
+        metrics.record_finished("stop".to_string());
+        metrics.observe_time_to_first_token(Duration::from_millis(200));
+        metrics.observe_inter_token_latency(2, Duration::from_millis(20));
+
+        let out = metrics.render();
+        assert!(out.contains("mistralrs_waiting_sequences 2\n"));
+        assert!(out.contains("mistralrs_running_sequences 3\n"));
+        assert!(out.contains("mistralrs_prefix_cache_hit_rate 0.5\n"));
+        assert!(out.contains("mistralrs_finished_sequences_total{finish_reason=\"stop\"} 2\n"));
+        assert!(out.contains("mistralrs_time_to_first_token_seconds_bucket{le=\"0.1\"} 0\n"));
+        assert!(out.contains("mistralrs_time_to_first_token_seconds_bucket{le=\"0.25\"} 1\n"));
+        assert!(out.contains("mistralrs_inter_token_latency_seconds_count 2\n"));
+        assert!(!out.contains("paged_attn"));
+    }
+}
@@ -6,8 +6,8 @@ use std::{
 use crate::{
     get_toml_selected_model_dtype,
     pipeline::{GGMLLoaderBuilder, GGMLSpecificConfig, GGUFLoaderBuilder, NormalSpecificConfig},
-    DiffusionLoaderBuilder, DiffusionSpecificConfig, GGUFSpecificConfig, Loader, ModelDType,
-    ModelSelected, NormalLoaderBuilder, TomlLoaderArgs, TomlSelector, Topology,
+    DiffusionLoaderBuilder, DiffusionSpecificConfig, GGUFSpecificConfig, KvCacheType, Loader,
+    ModelDType, ModelSelected, NormalLoaderBuilder, TomlLoaderArgs, TomlSelector, Topology,
     VisionLoaderBuilder, VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
 };
 
@@ -18,6 +18,7 @@ pub struct LoaderBuilder {
     chat_template: Option<String>,
     use_flash_attn: bool,
     prompt_batchsize: Option<NonZeroUsize>,
+    kv_cache_type: KvCacheType,
 }
 
 impl LoaderBuilder {
@@ -28,6 +29,7 @@ impl LoaderBuilder {
             chat_template: None,
             use_flash_attn: false,
             prompt_batchsize: None,
+            kv_cache_type: KvCacheType::Auto,
         }
     }
 
@@ -47,6 +49,10 @@ impl LoaderBuilder {
         self.prompt_batchsize = prompt_batchsize;
         self
     }
+    pub fn with_kv_cache_type(mut self, kv_cache_type: KvCacheType) -> Self {
+        self.kv_cache_type = kv_cache_type;
+        self
+    }
 
     pub fn build(self) -> anyhow::Result<Box<dyn Loader>> {
         loader_from_model_selected(self)
@@ -115,6 +121,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 chat_template: args.chat_template,
                 no_kv_cache: args.no_kv_cache,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
             };
             (selector, args).try_into()?
         }
@@ -131,6 +138,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
                 organization: organization.unwrap_or_default(),
                 write_uqff,
@@ -157,6 +165,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
                 write_uqff,
@@ -191,6 +200,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
                 write_uqff,
@@ -224,6 +234,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -246,6 +257,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -277,6 +289,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -300,6 +313,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -324,6 +338,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -356,6 +371,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -385,6 +401,7 @@ fn loader_from_model_selected(args: LoaderBuilder) -> anyhow::Result<Box<dyn Loa
             VisionSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
                 write_uqff,
                 from_uqff,
@@ -0,0 +1,1237 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::{collections::HashMap, f32::consts::PI, sync::Arc};
+
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_nn::{Linear, VarBuilder};
+use mistralrs_quant::{QuantMethod, QuantMethodConfig, QuantizedConfig, UnquantLinear};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amoe::AnyMoeBaseModelMixin,
+    attention::SdpaParams,
+    device_map::DeviceMapper,
+    layers::{Activation, CausalMasker, MatMul, RmsNorm, Sdpa},
+    paged_attention::{AttentionImplementation, ModelConfigMetadata},
+    pipeline::{
+        extract_logits,
+        text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
+        Cache, IsqModel, KvCache, NormalLoadingMetadata, NormalModel,
+    },
+    serde_default_fn,
+    utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
+};
+
+serde_default_fn!(f64, rope_theta_default, 10_000.);
+serde_default_fn!(f64, routed_scaling_factor_default, 1.);
+serde_default_fn!(usize, moe_layer_freq_default, 1);
+serde_default_fn!(bool, false_default, false);
+serde_default_fn!(f32, beta_fast_default, 32.);
+serde_default_fn!(f32, beta_slow_default, 1.);
+serde_default_fn!(f32, mscale_default, 1.);
+serde_default_fn!(f32, mscale_all_dim_default, 0.);
+
+/// How the routed experts of a token are selected from the gate scores.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+pub enum TopkMethod {
+    /// The experts with the highest scores.
+    #[default]
+    #[serde(rename = "greedy")]
+    Greedy,
+    /// The experts with the highest scores within the `topk_group` groups of experts with the
+    /// highest maximum score.
+    #[serde(rename = "group_limited_greedy")]
+    GroupLimitedGreedy,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum RopeScaling {
+    #[serde(rename = "yarn")]
+    Yarn {
+        factor: f32,
+        original_max_position_embeddings: usize,
+        #[serde(default = "beta_fast_default")]
+        beta_fast: f32,
+        #[serde(default = "beta_slow_default")]
+        beta_slow: f32,
+        #[serde(default = "mscale_default")]
+        mscale: f32,
+        #[serde(default = "mscale_all_dim_default")]
+        mscale_all_dim: f32,
+    },
+}
+
+/// https://huggingface.co/deepseek-ai/DeepSeek-V2-Lite/blob/main/configuration_deepseek.py
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Config {
+    pub(crate) vocab_size: usize,
+    pub(crate) hidden_size: usize,
+    pub(crate) intermediate_size: usize,
+    pub(crate) moe_intermediate_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) num_attention_heads: usize,
+    pub(crate) n_shared_experts: Option<usize>,
+    pub(crate) n_routed_experts: Option<usize>,
+    #[serde(default = "routed_scaling_factor_default")]
+    pub(crate) routed_scaling_factor: f64,
+    #[serde(default)]
+    pub(crate) topk_method: TopkMethod,
+    pub(crate) n_group: Option<usize>,
+    pub(crate) topk_group: Option<usize>,
+    pub(crate) num_experts_per_tok: Option<usize>,
+    #[serde(default = "moe_layer_freq_default")]
+    pub(crate) moe_layer_freq: usize,
+    #[serde(default)]
+    pub(crate) first_k_dense_replace: usize,
+    #[serde(default = "false_default")]
+    pub(crate) norm_topk_prob: bool,
+    pub(crate) hidden_act: Activation,
+    pub(crate) max_position_embeddings: usize,
+    pub(crate) rms_norm_eps: f64,
+    #[serde(default = "rope_theta_default")]
+    pub(crate) rope_theta: f64,
+    pub(crate) rope_scaling: Option<RopeScaling>,
+    #[serde(default = "false_default")]
+    pub(crate) attention_bias: bool,
+    pub(crate) q_lora_rank: Option<usize>,
+    pub(crate) kv_lora_rank: usize,
+    pub(crate) qk_rope_head_dim: usize,
+    pub(crate) qk_nope_head_dim: usize,
+    pub(crate) v_head_dim: usize,
+    #[serde(default = "false_default")]
+    pub(crate) use_flash_attn: bool,
+    pub(crate) quantization_config: Option<QuantizedConfig>,
+    #[serde(default = "false_default")]
+    pub(crate) tie_word_embeddings: bool,
+}
+
+impl Config {
+    fn q_head_dim(&self) -> usize {
+        self.qk_nope_head_dim + self.qk_rope_head_dim
+    }
+
+    fn is_moe_layer(&self, layer_idx: usize) -> bool {
+        self.n_routed_experts.is_some()
+            && layer_idx >= self.first_k_dense_replace
+            && layer_idx % self.moe_layer_freq == 0
+    }
+}
+
+/// Apply a layer which may be quantized, keeping the dtype of `xs`.
+fn qlinear(xs: &Tensor, layer: &dyn QuantMethod) -> Result<Tensor> {
+    let original_dtype = xs.dtype();
+    let mut xs = xs.clone();
+    if let Some(t) = layer.quantized_act_type() {
+        xs = xs.to_dtype(t)?;
+    }
+    let mut xs = MatMul.qmethod_matmul(&xs, layer)?;
+    if layer.quantized_act_type().is_some() {
+        xs = xs.to_dtype(original_dtype)?;
+    }
+    Ok(xs)
+}
+
+fn yarn_get_mscale(factor: f32, mscale: f32) -> f32 {
+    if factor <= 1. {
+        1.
+    } else {
+        0.1 * mscale * factor.ln() + 1.
+    }
+}
+
+/// The dimension at which the rotary embedding makes `num_rotations` full rotations over
+/// `max_position_embeddings` positions.
+fn yarn_find_correction_dim(
+    num_rotations: f32,
+    dim: usize,
+    base: f32,
+    max_position_embeddings: usize,
+) -> f32 {
+    (dim as f32 * (max_position_embeddings as f32 / (num_rotations * 2. * PI)).ln())
+        / (2. * base.ln())
+}
+
+/// The rotary embedding of the decoupled RoPE parts of the queries and keys, with optional YaRN
+/// scaling.
+struct RotaryEmbedding {
+    sin: Tensor,
+    cos: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(cfg: &Config, dtype: DType, dev: &Device) -> Result<Self> {
+        let dim = cfg.qk_rope_head_dim;
+        let base = cfg.rope_theta as f32;
+        let freq = |i: usize| 1. / base.powf(i as f32 / dim as f32);
+        let (inv_freq, mscale) = match &cfg.rope_scaling {
+            None => ((0..dim).step_by(2).map(freq).collect::<Vec<_>>(), 1.),
+            Some(RopeScaling::Yarn {
+                factor,
+                original_max_position_embeddings,
+                beta_fast,
+                beta_slow,
+                mscale,
+                mscale_all_dim,
+            }) => {
+                let low = yarn_find_correction_dim(
+                    *beta_fast,
+                    dim,
+                    base,
+                    *original_max_position_embeddings,
+                )
+                .floor()
+                .max(0.);
+                let high = yarn_find_correction_dim(
+                    *beta_slow,
+                    dim,
+                    base,
+                    *original_max_position_embeddings,
+                )
+                .ceil()
+                .min(dim as f32 - 1.);
+                let high = if low == high { high + 0.001 } else { high };
+                let inv_freq = (0..dim)
+                    .step_by(2)
+                    .enumerate()
+                    .map(|(j, i)| {
+                        let extrapolation = freq(i);
+                        let interpolation = extrapolation / factor;
+                        // Only the low frequency dimensions are interpolated.
+                        let ramp = ((j as f32 - low) / (high - low)).clamp(0., 1.);
+                        interpolation * ramp + extrapolation * (1. - ramp)
+                    })
+                    .collect::<Vec<_>>();
+                (
+                    inv_freq,
+                    yarn_get_mscale(*factor, *mscale) / yarn_get_mscale(*factor, *mscale_all_dim),
+                )
+            }
+        };
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+        let t = Tensor::arange(0u32, cfg.max_position_embeddings as u32, dev)?
+            .to_dtype(DType::F32)?
+            .reshape((cfg.max_position_embeddings, 1))?;
+        let freqs = t.matmul(&inv_freq)?;
+        Ok(Self {
+            sin: (freqs.sin()? * mscale as f64)?.to_dtype(dtype)?,
+            cos: (freqs.cos()? * mscale as f64)?.to_dtype(dtype)?,
+        })
+    }
+
+    /// Rotate `q`, `(bs, n_heads, seq_len, qk_rope_head_dim)`, and `k`,
+    /// `(bs, 1, seq_len, qk_rope_head_dim)`. The checkpoints use the interleaved layout.
+    fn forward(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        seqlen_offsets: &[usize],
+    ) -> Result<(Tensor, Tensor)> {
+        let seq_len = q.dim(2)?;
+        let mut q_embeds = Vec::new();
+        let mut k_embeds = Vec::new();
+        for (i, offset) in seqlen_offsets.iter().enumerate() {
+            let cos = self.cos.narrow(0, *offset, seq_len)?;
+            let sin = self.sin.narrow(0, *offset, seq_len)?;
+            q_embeds.push(candle_nn::rotary_emb::rope_i(
+                &q.i(i)?.unsqueeze(0)?.contiguous()?,
+                &cos,
+                &sin,
+            )?);
+            k_embeds.push(candle_nn::rotary_emb::rope_i(
+                &k.i(i)?.unsqueeze(0)?.contiguous()?,
+                &cos,
+                &sin,
+            )?);
+        }
+        Ok((Tensor::cat(&q_embeds, 0)?, Tensor::cat(&k_embeds, 0)?))
+    }
+}
+
+enum QProj {
+    Plain(Arc<dyn QuantMethod>),
+    /// The low rank query compression.
+    Lora {
+        a: Arc<dyn QuantMethod>,
+        norm: RmsNorm,
+        b: Arc<dyn QuantMethod>,
+    },
+}
+
+/// Multi-head latent attention.
+///
+/// The keys and values of all the heads are projected up from a shared low rank latent, and a
+/// single RoPE key part is shared by all the heads. Only the normalized latent and the rotated
+/// key part are kept in the [`KvCache`], as its keys and values, which are
+/// `kv_lora_rank + qk_rope_head_dim` values per position instead of
+/// `num_heads * (q_head_dim + v_head_dim)`.
+struct Attention {
+    q: QProj,
+    kv_a_proj_with_mqa: Arc<dyn QuantMethod>,
+    kv_a_layernorm: RmsNorm,
+    kv_b_proj: Arc<dyn QuantMethod>,
+    o_proj: Arc<dyn QuantMethod>,
+    rotary_emb: Arc<RotaryEmbedding>,
+    num_heads: usize,
+    qk_nope_head_dim: usize,
+    qk_rope_head_dim: usize,
+    v_head_dim: usize,
+    kv_lora_rank: usize,
+    sdpa_params: SdpaParams,
+}
+
+impl Attention {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+    ) -> Result<Self> {
+        let q_head_dim = cfg.q_head_dim();
+        let num_heads = cfg.num_attention_heads;
+        let q = match cfg.q_lora_rank {
+            None => QProj::Plain(mistralrs_quant::linear_no_bias(
+                cfg.hidden_size,
+                num_heads * q_head_dim,
+                &cfg.quantization_config,
+                mapper.set_device(layer_idx, vb.pp("q_proj"), loading_isq),
+            )?),
+            Some(q_lora_rank) => QProj::Lora {
+                a: mistralrs_quant::linear_b(
+                    cfg.hidden_size,
+                    q_lora_rank,
+                    cfg.attention_bias,
+                    &cfg.quantization_config,
+                    mapper.set_device(layer_idx, vb.pp("q_a_proj"), loading_isq),
+                )?,
+                norm: RmsNorm::new(
+                    q_lora_rank,
+                    cfg.rms_norm_eps,
+                    mapper.set_device(layer_idx, vb.pp("q_a_layernorm"), false),
+                )?,
+                b: mistralrs_quant::linear_no_bias(
+                    q_lora_rank,
+                    num_heads * q_head_dim,
+                    &cfg.quantization_config,
+                    mapper.set_device(layer_idx, vb.pp("q_b_proj"), loading_isq),
+                )?,
+            },
+        };
+        let kv_a_proj_with_mqa = mistralrs_quant::linear_b(
+            cfg.hidden_size,
+            cfg.kv_lora_rank + cfg.qk_rope_head_dim,
+            cfg.attention_bias,
+            &cfg.quantization_config,
+            mapper.set_device(layer_idx, vb.pp("kv_a_proj_with_mqa"), loading_isq),
+        )?;
+        let kv_a_layernorm = RmsNorm::new(
+            cfg.kv_lora_rank,
+            cfg.rms_norm_eps,
+            mapper.set_device(layer_idx, vb.pp("kv_a_layernorm"), false),
+        )?;
+        let kv_b_proj = mistralrs_quant::linear_no_bias(
+            cfg.kv_lora_rank,
+            num_heads * (cfg.qk_nope_head_dim + cfg.v_head_dim),
+            &cfg.quantization_config,
+            mapper.set_device(layer_idx, vb.pp("kv_b_proj"), loading_isq),
+        )?;
+        let o_proj = mistralrs_quant::linear_b(
+            num_heads * cfg.v_head_dim,
+            cfg.hidden_size,
+            cfg.attention_bias,
+            &cfg.quantization_config,
+            mapper.set_device(layer_idx, vb.pp("o_proj"), loading_isq),
+        )?;
+
+        let mut softmax_scale = 1.0 / (q_head_dim as f32).sqrt();
+        if let Some(RopeScaling::Yarn {
+            factor,
+            mscale_all_dim,
+            ..
+        }) = &cfg.rope_scaling
+        {
+            let mscale = yarn_get_mscale(*factor, *mscale_all_dim);
+            softmax_scale *= mscale * mscale;
+        }
+
+        Ok(Self {
+            q,
+            kv_a_proj_with_mqa,
+            kv_a_layernorm,
+            kv_b_proj,
+            o_proj,
+            rotary_emb,
+            num_heads,
+            qk_nope_head_dim: cfg.qk_nope_head_dim,
+            qk_rope_head_dim: cfg.qk_rope_head_dim,
+            v_head_dim: cfg.v_head_dim,
+            kv_lora_rank: cfg.kv_lora_rank,
+            sdpa_params: SdpaParams {
+                n_kv_groups: 1,
+                use_flash_attn: cfg.use_flash_attn,
+                softcap: None,
+                softmax_scale,
+                sliding_window: None,
+            },
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        kv_cache: &mut Option<KvCache>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+        let q_head_dim = self.qk_nope_head_dim + self.qk_rope_head_dim;
+
+        let q = match &self.q {
+            QProj::Plain(q_proj) => qlinear(xs, &**q_proj)?,
+            QProj::Lora { a, norm, b } => qlinear(&qlinear(xs, &**a)?.apply(norm)?, &**b)?,
+        };
+        let q = q
+            .reshape((b_sz, q_len, self.num_heads, q_head_dim))?
+            .transpose(1, 2)?;
+        let q_nope = q.narrow(D::Minus1, 0, self.qk_nope_head_dim)?;
+        let q_pe = q.narrow(D::Minus1, self.qk_nope_head_dim, self.qk_rope_head_dim)?;
+
+        let compressed_kv = qlinear(xs, &*self.kv_a_proj_with_mqa)?;
+        let latent = compressed_kv
+            .narrow(D::Minus1, 0, self.kv_lora_rank)?
+            .apply(&self.kv_a_layernorm)?
+            .unsqueeze(1)?;
+        let k_pe = compressed_kv
+            .narrow(D::Minus1, self.kv_lora_rank, self.qk_rope_head_dim)?
+            .reshape((b_sz, q_len, 1, self.qk_rope_head_dim))?
+            .transpose(1, 2)?;
+        let (q_pe, k_pe) = self.rotary_emb.forward(&q_pe, &k_pe, seqlen_offsets)?;
+
+        let (latent, k_pe) = Cache::update_kv_cache(kv_cache, latent.contiguous()?, k_pe, false)?;
+        let kv_len = latent.dim(2)?;
+
+        let kv = qlinear(&latent.squeeze(1)?, &*self.kv_b_proj)?
+            .reshape((
+                b_sz,
+                kv_len,
+                self.num_heads,
+                self.qk_nope_head_dim + self.v_head_dim,
+            ))?
+            .transpose(1, 2)?;
+        let k_nope = kv.narrow(D::Minus1, 0, self.qk_nope_head_dim)?;
+        let v = kv.narrow(D::Minus1, self.qk_nope_head_dim, self.v_head_dim)?;
+
+        let q = Tensor::cat(&[q_nope, q_pe], D::Minus1)?.contiguous()?;
+        let k_pe = k_pe
+            .broadcast_as((b_sz, self.num_heads, kv_len, self.qk_rope_head_dim))?
+            .contiguous()?;
+        let k = Tensor::cat(&[k_nope, k_pe], D::Minus1)?.contiguous()?;
+        // The attention implementations expect the values to have the head dimension of the
+        // queries and keys.
+        let v = if self.v_head_dim < q_head_dim {
+            v.pad_with_zeros(D::Minus1, 0, q_head_dim - self.v_head_dim)?
+        } else {
+            v
+        }
+        .contiguous()?;
+
+        let mut attn_output = Sdpa
+            .run_attention(
+                &q,
+                &k,
+                &v,
+                attention_mask,
+                Some(flash_params),
+                &self.sdpa_params,
+            )?
+            .narrow(D::Minus1, 0, self.v_head_dim)?;
+
+        attn_output = if attention_mask.is_some() {
+            attn_output.transpose(1, 2)?.reshape((b_sz, q_len, ()))?
+        } else {
+            attn_output.reshape((b_sz, q_len, ()))?
+        };
+        qlinear(&attn_output, &*self.o_proj)
+    }
+}
+
+struct Mlp {
+    gate_proj: Arc<dyn QuantMethod>,
+    up_proj: Arc<dyn QuantMethod>,
+    down_proj: Arc<dyn QuantMethod>,
+    act_fn: Activation,
+}
+
+impl Mlp {
+    fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+        intermediate_size: usize,
+    ) -> Result<Self> {
+        Ok(Self {
+            gate_proj: mistralrs_quant::linear_no_bias(
+                cfg.hidden_size,
+                intermediate_size,
+                &cfg.quantization_config,
+                mapper.set_device(layer_idx, vb.pp("gate_proj"), loading_isq),
+            )?,
+            up_proj: mistralrs_quant::linear_no_bias(
+                cfg.hidden_size,
+                intermediate_size,
+                &cfg.quantization_config,
+                mapper.set_device(layer_idx, vb.pp("up_proj"), loading_isq),
+            )?,
+            down_proj: mistralrs_quant::linear_no_bias(
+                intermediate_size,
+                cfg.hidden_size,
+                &cfg.quantization_config,
+                mapper.set_device(layer_idx, vb.pp("down_proj"), loading_isq),
+            )?,
+            act_fn: cfg.hidden_act,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let lhs = qlinear(xs, &*self.gate_proj)?.apply(&self.act_fn)?;
+        let rhs = qlinear(xs, &*self.up_proj)?;
+        qlinear(&(lhs * rhs)?, &*self.down_proj)
+    }
+}
+
+/// Fine-grained mixture of experts, where each token is routed to several small experts and
+/// always passes through the shared experts.
+struct Moe {
+    gate: Linear,
+    experts: Vec<Mlp>,
+    shared_experts: Option<Mlp>,
+    num_experts_per_tok: usize,
+    topk_method: TopkMethod,
+    n_group: usize,
+    topk_group: usize,
+    norm_topk_prob: bool,
+    routed_scaling_factor: f64,
+}
+
+impl Moe {
+    fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+        n_routed_experts: usize,
+    ) -> Result<Self> {
+        let gate = candle_nn::linear_no_bias(
+            cfg.hidden_size,
+            n_routed_experts,
+            mapper.set_device(layer_idx, vb.pp("gate"), false),
+        )?;
+        let mut experts = Vec::with_capacity(n_routed_experts);
+        let vb_e = vb.pp("experts");
+        for expert_idx in 0..n_routed_experts {
+            experts.push(Mlp::new(
+                cfg,
+                vb_e.pp(expert_idx),
+                mapper,
+                layer_idx,
+                loading_isq,
+                cfg.moe_intermediate_size,
+            )?);
+        }
+        let shared_experts = cfg
+            .n_shared_experts
+            .map(|n_shared_experts| {
+                Mlp::new(
+                    cfg,
+                    vb.pp("shared_experts"),
+                    mapper,
+                    layer_idx,
+                    loading_isq,
+                    cfg.moe_intermediate_size * n_shared_experts,
+                )
+            })
+            .transpose()?;
+        let Some(num_experts_per_tok) = cfg.num_experts_per_tok else {
+            candle_core::bail!("`num_experts_per_tok` must be set for a MoE model");
+        };
+        Ok(Self {
+            gate,
+            experts,
+            shared_experts,
+            num_experts_per_tok,
+            topk_method: cfg.topk_method,
+            n_group: cfg.n_group.unwrap_or(1),
+            topk_group: cfg.topk_group.unwrap_or(1),
+            norm_topk_prob: cfg.norm_topk_prob,
+            routed_scaling_factor: cfg.routed_scaling_factor,
+        })
+    }
+
+    /// The experts selected from the gate scores of a token.
+    fn select_experts(&self, scores: &[f32]) -> Vec<usize> {
+        let mut candidates = (0..scores.len()).collect::<Vec<_>>();
+        if let TopkMethod::GroupLimitedGreedy = self.topk_method {
+            let group_size = scores.len() / self.n_group;
+            let group_score = |g: usize| {
+                scores[g * group_size..(g + 1) * group_size]
+                    .iter()
+                    .copied()
+                    .fold(f32::NEG_INFINITY, f32::max)
+            };
+            let mut groups = (0..self.n_group).collect::<Vec<_>>();
+            groups.sort_by(|&i, &j| group_score(j).total_cmp(&group_score(i)));
+            groups.truncate(self.topk_group);
+            candidates.retain(|e| groups.contains(&(e / group_size)));
+        }
+        candidates.sort_by(|&i, &j| scores[j].total_cmp(&scores[i]));
+        candidates.truncate(self.num_experts_per_tok);
+        candidates
+    }
+}
+
+impl Module for Moe {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_size, seq_len, hidden_dim) = xs.dims3()?;
+        let xs_flat = xs.reshape(((), hidden_dim))?;
+
+        // The routing is computed in F32.
+        let router_logits = xs_flat
+            .to_dtype(DType::F32)?
+            .broadcast_matmul(&self.gate.weight().to_dtype(DType::F32)?.t()?)?;
+        let scores = candle_nn::ops::softmax_last_dim(&router_logits)?.to_vec2::<f32>()?;
+
+        // top_x contains the row indexes to evaluate for each expert.
+        let mut top_x = vec![vec![]; self.experts.len()];
+        let mut selected_rws = vec![vec![]; self.experts.len()];
+        for (row_idx, rw) in scores.iter().enumerate() {
+            let selected = self.select_experts(rw);
+            let sum_routing_weights = selected.iter().map(|&e| rw[e]).sum::<f32>();
+            for expert_idx in selected {
+                let routing_weight = if self.norm_topk_prob && self.num_experts_per_tok > 1 {
+                    rw[expert_idx] / (sum_routing_weights + 1e-20)
+                } else {
+                    rw[expert_idx] * self.routed_scaling_factor as f32
+                };
+                top_x[expert_idx].push(row_idx as u32);
+                selected_rws[expert_idx].push(routing_weight);
+            }
+        }
+
+        let mut ys = xs_flat.zeros_like()?;
+        for (expert_idx, expert_layer) in self.experts.iter().enumerate() {
+            let top_x = &top_x[expert_idx];
+            if top_x.is_empty() {
+                continue;
+            }
+            let top_x = Tensor::new(top_x.as_slice(), xs.device())?;
+            let selected_rws = Tensor::new(selected_rws[expert_idx].as_slice(), xs.device())?
+                .reshape(((), 1))?
+                .to_dtype(xs.dtype())?;
+            let current_state = xs_flat.index_select(&top_x, 0)?;
+            let current_hidden_states = expert_layer
+                .forward(&current_state)?
+                .broadcast_mul(&selected_rws)?;
+            ys = ys.index_add(&top_x, &current_hidden_states, 0)?;
+        }
+
+        let mut ys = ys.reshape((b_size, seq_len, hidden_dim))?;
+        if let Some(shared_experts) = &self.shared_experts {
+            ys = (ys + shared_experts.forward(xs)?)?;
+        }
+        Ok(ys)
+    }
+}
+
+enum MoeOrMlp {
+    Moe(Moe),
+    Mlp(Mlp),
+}
+
+impl Module for MoeOrMlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Moe(moe) => moe.forward(xs),
+            Self::Mlp(mlp) => mlp.forward(xs),
+        }
+    }
+}
+
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: MoeOrMlp,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+}
+
+impl DecoderLayer {
+    fn new(
+        rotary_emb: Arc<RotaryEmbedding>,
+        cfg: &Config,
+        vb: VarBuilder,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+    ) -> Result<Self> {
+        let self_attn = Attention::new(
+            rotary_emb,
+            cfg,
+            vb.pp("self_attn"),
+            mapper,
+            layer_idx,
+            loading_isq,
+        )?;
+        let mlp = match cfg.n_routed_experts {
+            Some(n_routed_experts) if cfg.is_moe_layer(layer_idx) => MoeOrMlp::Moe(Moe::new(
+                cfg,
+                vb.pp("mlp"),
+                mapper,
+                layer_idx,
+                loading_isq,
+                n_routed_experts,
+            )?),
+            _ => MoeOrMlp::Mlp(Mlp::new(
+                cfg,
+                vb.pp("mlp"),
+                mapper,
+                layer_idx,
+                loading_isq,
+                cfg.intermediate_size,
+            )?),
+        };
+        let input_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_device(layer_idx, vb.pp("input_layernorm"), false),
+        )?;
+        let post_attention_layernorm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_device(layer_idx, vb.pp("post_attention_layernorm"), false),
+        )?;
+        Ok(Self {
+            self_attn,
+            mlp,
+            input_layernorm,
+            post_attention_layernorm,
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offsets: &[usize],
+        kv_cache: &mut Option<KvCache>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs =
+            self.self_attn
+                .forward(&xs, attention_mask, seqlen_offsets, kv_cache, flash_params)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = xs
+            .apply(&self.post_attention_layernorm)?
+            .apply(&self.mlp)?
+            .to_dtype(residual.dtype())?;
+        residual + xs
+    }
+}
+
+/// DeepSeek-V2 model, with multi-head latent attention and fine-grained experts.
+/// https://huggingface.co/deepseek-ai/DeepSeek-V2-Lite/blob/main/modeling_deepseek.py
+/// https://arxiv.org/abs/2405.04434
+pub struct Model {
+    embed_tokens: candle_nn::Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: Arc<dyn QuantMethod>,
+    device: Device,
+    cache: Cache,
+    max_seq_len: usize,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+    cfg: ModelConfigMetadata,
+}
+
+impl Model {
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Self> {
+        if let Some(ref quant_cfg) = &cfg.quantization_config {
+            tracing::info!(
+                "Using {} quantization in {} bits.",
+                quant_cfg.quant_method.to_string(),
+                quant_cfg.bits
+            );
+        }
+        if let AttentionImplementation::PagedAttention = attention_mechanism {
+            candle_core::bail!("DeepSeek-V2 does not support PagedAttention");
+        }
+        let mapper = normal_loading_metadata.mapper;
+        let vb_m = vb.pp("model");
+
+        let embed_tokens = candle_nn::embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            mapper.set_nm_device(vb_m.pp("embed_tokens"), false),
+        )?;
+        let mut ropes = HashMap::new();
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let device = mapper
+                .device_for(layer_idx, false)
+                .unwrap_or(&normal_loading_metadata.real_device);
+            ropes.insert(
+                device.location(),
+                Arc::new(RotaryEmbedding::new(cfg, vb_m.dtype(), device)?),
+            );
+        }
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in
+            NiceProgressBar::<_, 'b'>(0..cfg.num_hidden_layers, "Loading repeating layers")
+        {
+            let device = mapper
+                .device_for(layer_idx, false)
+                .unwrap_or(&normal_loading_metadata.real_device);
+            let rotary_emb = ropes
+                .get(&device.location())
+                .expect("No RoPE for device location!")
+                .clone();
+            let layer = DecoderLayer::new(
+                rotary_emb,
+                cfg,
+                vb_l.pp(layer_idx),
+                &*mapper,
+                layer_idx,
+                normal_loading_metadata.loading_isq,
+            )?;
+            layers.push(layer)
+        }
+        let norm = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.rms_norm_eps,
+            mapper.set_nm_device(vb_m.pp("norm"), false),
+        )?;
+        let lm_head = if !cfg.tie_word_embeddings {
+            mistralrs_quant::linear_no_bias(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                &None,
+                mapper.set_nm_device(vb.pp("lm_head"), normal_loading_metadata.loading_isq),
+            )?
+        } else {
+            Arc::new(UnquantLinear::new(QuantMethodConfig::Unquantized(
+                candle_nn::Linear::new(
+                    mapper.cast_nm_device(
+                        embed_tokens.embeddings(),
+                        normal_loading_metadata.loading_isq,
+                    )?,
+                    None,
+                ),
+            ))?)
+        };
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            device: normal_loading_metadata.real_device,
+            cache: Cache::new(cfg.num_hidden_layers, false),
+            max_seq_len: cfg.max_position_embeddings,
+            mapper,
+            cfg: ModelConfigMetadata {
+                num_layers: cfg.num_hidden_layers,
+                hidden_size: cfg.hidden_size,
+                num_kv_heads: cfg.num_attention_heads,
+                num_attn_heads: cfg.num_attention_heads,
+                sliding_window: None,
+                head_dim: Some(cfg.q_head_dim()),
+            },
+        })
+    }
+
+    pub fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        let mut cache = self.cache.lock();
+        let attention_mask = CausalMasker.make_causal_mask_as_attn_bias(
+            input_ids,
+            &*cache,
+            xs.dtype(),
+            self.cfg.num_attn_heads,
+        )?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            xs = layer.forward(
+                &xs,
+                attention_mask
+                    .as_ref()
+                    .map(|m| m.to_device(xs.device()).unwrap())
+                    .as_ref(),
+                seqlen_offsets,
+                &mut cache[i],
+                flash_params,
+            )?;
+        }
+        let xs = xs.to_device(&self.device)?;
+        xs.apply(&self.norm)
+    }
+
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        context_lens: Vec<(usize, usize)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        let mut xs = self.forward_hidden_states(input_ids, seqlen_offsets, flash_params)?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        extract_logits(&MatMul.qmethod_matmul(&xs, &*self.lm_head)?, context_lens)
+    }
+}
+
+impl IsqModel for Model {
+    fn get_layers(
+        &mut self,
+    ) -> (
+        Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
+        &dyn DeviceMapper,
+    ) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            match &mut layer.self_attn.q {
+                QProj::Plain(q_proj) => tensors.push((q_proj, Some(i))),
+                QProj::Lora { a, b, .. } => {
+                    tensors.push((a, Some(i)));
+                    tensors.push((b, Some(i)));
+                }
+            }
+            tensors.push((&mut layer.self_attn.kv_a_proj_with_mqa, Some(i)));
+            tensors.push((&mut layer.self_attn.kv_b_proj, Some(i)));
+            tensors.push((&mut layer.self_attn.o_proj, Some(i)));
+            match &mut layer.mlp {
+                MoeOrMlp::Mlp(mlp) => {
+                    tensors.push((&mut mlp.gate_proj, Some(i)));
+                    tensors.push((&mut mlp.up_proj, Some(i)));
+                    tensors.push((&mut mlp.down_proj, Some(i)));
+                }
+                MoeOrMlp::Moe(moe) => {
+                    for mlp in moe.experts.iter_mut().chain(&mut moe.shared_experts) {
+                        tensors.push((&mut mlp.gate_proj, Some(i)));
+                        tensors.push((&mut mlp.up_proj, Some(i)));
+                        tensors.push((&mut mlp.down_proj, Some(i)));
+                    }
+                }
+            }
+        }
+        (tensors, &*self.mapper)
+    }
+
+    fn get_layers_moe_experts_only(
+        &mut self,
+    ) -> (
+        Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
+        &dyn DeviceMapper,
+    ) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            if let MoeOrMlp::Moe(moe) = &mut layer.mlp {
+                for mlp in &mut moe.experts {
+                    tensors.push((&mut mlp.gate_proj, Some(i)));
+                    tensors.push((&mut mlp.up_proj, Some(i)));
+                    tensors.push((&mut mlp.down_proj, Some(i)));
+                }
+            }
+        }
+        (tensors, &*self.mapper)
+    }
+
+    fn residual_tensors(&self) -> Vec<(String, Tensor)> {
+        let uvb = UnVarBuilder::new();
+
+        let uvb_m = uvb.pp("model");
+        uvb_m.pp("embed_tokens").add(&self.embed_tokens);
+        uvb_m.pp("norm").add(&self.norm);
+
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            let uvb_l = uvb_m.pp("layers").pp(layer_idx);
+            uvb_l.pp("input_layernorm").add(&layer.input_layernorm);
+            uvb_l
+                .pp("post_attention_layernorm")
+                .add(&layer.post_attention_layernorm);
+
+            let uvb_attn = uvb_l.pp("self_attn");
+            if let QProj::Lora { norm, .. } = &layer.self_attn.q {
+                uvb_attn.pp("q_a_layernorm").add(norm);
+            }
+            uvb_attn
+                .pp("kv_a_layernorm")
+                .add(&layer.self_attn.kv_a_layernorm);
+
+            if let MoeOrMlp::Moe(moe) = &layer.mlp {
+                uvb_l.pp("mlp").pp("gate").add(&moe.gate);
+            }
+        }
+
+        uvb.to_safetensors()
+    }
+
+    fn residual_tensors_moe_experts_only(&self) -> Option<Vec<(String, Tensor)>> {
+        let uvb = UnVarBuilder::new();
+
+        let uvb_m = uvb.pp("model");
+        uvb_m.pp("embed_tokens").add(&self.embed_tokens);
+        uvb_m.pp("norm").add(&self.norm);
+
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            let uvb_l = uvb_m.pp("layers").pp(layer_idx);
+            uvb_l.pp("input_layernorm").add(&layer.input_layernorm);
+            uvb_l
+                .pp("post_attention_layernorm")
+                .add(&layer.post_attention_layernorm);
+
+            let uvb_attn = uvb_l.pp("self_attn");
+            match &layer.self_attn.q {
+                QProj::Plain(q_proj) => uvb_attn.pp("q_proj").add(q_proj),
+                QProj::Lora { a, norm, b } => {
+                    uvb_attn.pp("q_a_proj").add(a);
+                    uvb_attn.pp("q_a_layernorm").add(norm);
+                    uvb_attn.pp("q_b_proj").add(b);
+                }
+            }
+            uvb_attn
+                .pp("kv_a_proj_with_mqa")
+                .add(&layer.self_attn.kv_a_proj_with_mqa);
+            uvb_attn
+                .pp("kv_a_layernorm")
+                .add(&layer.self_attn.kv_a_layernorm);
+            uvb_attn.pp("kv_b_proj").add(&layer.self_attn.kv_b_proj);
+            uvb_attn.pp("o_proj").add(&layer.self_attn.o_proj);
+
+            let uvb_mlp = uvb_l.pp("mlp");
+            match &layer.mlp {
+                MoeOrMlp::Mlp(mlp) => {
+                    uvb_mlp.pp("gate_proj").add(&mlp.gate_proj);
+                    uvb_mlp.pp("up_proj").add(&mlp.up_proj);
+                    uvb_mlp.pp("down_proj").add(&mlp.down_proj);
+                }
+                MoeOrMlp::Moe(moe) => {
+                    uvb_mlp.pp("gate").add(&moe.gate);
+                    if let Some(shared_experts) = &moe.shared_experts {
+                        let uvb_s = uvb_mlp.pp("shared_experts");
+                        uvb_s.pp("gate_proj").add(&shared_experts.gate_proj);
+                        uvb_s.pp("up_proj").add(&shared_experts.up_proj);
+                        uvb_s.pp("down_proj").add(&shared_experts.down_proj);
+                    }
+                }
+            }
+        }
+
+        Some(uvb.to_safetensors())
+    }
+}
+
+impl NormalModel for Model {
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.forward(input_ids, seqlen_offsets, context_lens, flash_params)
+    }
+    fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.forward_hidden_states(input_ids, seqlen_offsets, flash_params)
+    }
+    fn xlora_forward(
+        &self,
+        _input_ids: &Tensor,
+        _input_ids_full: &Tensor,
+        _seqlen_offsets: &[usize],
+        _seqlen_offsets_full: &[usize],
+        _start_offsets_kernel: Tensor,
+        _start_offsets_kernel_full: Tensor,
+        _no_kv_cache: bool,
+        _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
+        _context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        _flash_params: &FlashParams,
+        _flash_params_full: &FlashParams,
+    ) -> Result<Tensor> {
+        unimplemented!()
+    }
+    fn cache(&self) -> &Cache {
+        &self.cache
+    }
+    fn device(&self) -> &Device {
+        &self.device
+    }
+    fn is_xlora(&self) -> bool {
+        false
+    }
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+    fn config(&self) -> &ModelConfigMetadata {
+        &self.cfg
+    }
+}
+
+impl AnyMoeBaseModelMixin for Model {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{DType, Device, Result, Tensor};
+    use mistralrs_quant::{QuantMethod, QuantMethodConfig, UnquantLinear};
+
+    use super::{Attention, Config, QProj, RotaryEmbedding};
+    use crate::{
+        attention::SdpaParams, layers::RmsNorm, pipeline::text_models_inputs_processor::FlashParams,
+    };
+
+    fn linear(in_dim: usize, out_dim: usize) -> Result<Arc<dyn QuantMethod>> {
+        let w =
+            (Tensor::randn(0f32, 1., (out_dim, in_dim), &Device::Cpu)? / (in_dim as f64).sqrt())?;
+        Ok(Arc::new(UnquantLinear::new(
+            QuantMethodConfig::Unquantized(candle_nn::Linear::new(w, None)),
+        )?))
+    }
+
+    fn causal_mask(len: usize) -> Result<Tensor> {
+        let mask = (0..len)
+            .flat_map(|i| (0..len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0. }))
+            .collect::<Vec<_>>();
+        Tensor::from_vec(mask, (len, len), &Device::Cpu)
+    }
+
+    #[test]
+    fn test_latent_cache_matches_full_attention() -> Result<()> {
+        let cfg: Config = serde_json::from_value(serde_json::json!({
+            "vocab_size": 32,
+            "hidden_size": 16,
+            "intermediate_size": 32,
+            "moe_intermediate_size": 8,
+            "num_hidden_layers": 1,
+            "num_attention_heads": 2,
+            "hidden_act": "silu",
+            "max_position_embeddings": 32,
+            "rms_norm_eps": 1e-6,
+            "rope_scaling": {
+                "type": "yarn",
+                "factor": 4.0,
+                "original_max_position_embeddings": 8,
+                "mscale": 0.707,
+                "mscale_all_dim": 0.707
+            },
+            "q_lora_rank": 8,
+            "kv_lora_rank": 8,
+            "qk_rope_head_dim": 4,
+            "qk_nope_head_dim": 6,
+            "v_head_dim": 4
+        }))
+        .unwrap();
+        let num_heads = cfg.num_attention_heads;
+        let q_head_dim = cfg.q_head_dim();
+        let attn = Attention {
+            q: QProj::Lora {
+                a: linear(cfg.hidden_size, 8)?,
+                norm: RmsNorm::from_w(Tensor::ones(8, DType::F32, &Device::Cpu)?, 1e-6)?,
+                b: linear(8, num_heads * q_head_dim)?,
+            },
+            kv_a_proj_with_mqa: linear(cfg.hidden_size, cfg.kv_lora_rank + cfg.qk_rope_head_dim)?,
+            kv_a_layernorm: RmsNorm::from_w(
+                Tensor::ones(cfg.kv_lora_rank, DType::F32, &Device::Cpu)?,
+                1e-6,
+            )?,
+            kv_b_proj: linear(
+                cfg.kv_lora_rank,
+                num_heads * (cfg.qk_nope_head_dim + cfg.v_head_dim),
+            )?,
+            o_proj: linear(num_heads * cfg.v_head_dim, cfg.hidden_size)?,
+            rotary_emb: Arc::new(RotaryEmbedding::new(&cfg, DType::F32, &Device::Cpu)?),
+            num_heads,
+            qk_nope_head_dim: cfg.qk_nope_head_dim,
+            qk_rope_head_dim: cfg.qk_rope_head_dim,
+            v_head_dim: cfg.v_head_dim,
+            kv_lora_rank: cfg.kv_lora_rank,
+            sdpa_params: SdpaParams {
+                n_kv_groups: 1,
+                use_flash_attn: false,
+                softcap: None,
+                softmax_scale: 1.0 / (q_head_dim as f32).sqrt(),
+                sliding_window: None,
+            },
+        };
+        let flash_params = FlashParams {
+            max_q: 0,
+            max_k: 0,
+            cumulative_seqlens_q: Tensor::zeros(1, DType::U32, &Device::Cpu)?,
+            cumulative_seqlens_k: Tensor::zeros(1, DType::U32, &Device::Cpu)?,
+        };
+
+        let xs = Tensor::randn(0f32, 1., (1, 6, cfg.hidden_size), &Device::Cpu)?;
+        let full = attn.forward(&xs, Some(&causal_mask(6)?), &[0], &mut None, &flash_params)?;
+
+        // Attending to the up-projected cached latents gives the same outputs as a single pass.
+        let mut cache = None;
+        let mut outputs = vec![attn.forward(
+            &xs.narrow(1, 0, 3)?,
+            Some(&causal_mask(3)?),
+            &[0],
+            &mut cache,
+            &flash_params,
+        )?];
+        for t in 3..6 {
+            outputs.push(attn.forward(
+                &xs.narrow(1, t, 1)?,
+                None,
+                &[t],
+                &mut cache,
+                &flash_params,
+            )?);
+        }
+        let stepped = Tensor::cat(&outputs, 1)?;
+        let diff = (full - stepped)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "{diff}");
+        Ok(())
+    }
+}
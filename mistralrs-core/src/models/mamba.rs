@@ -0,0 +1,548 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_nn::{Embedding, VarBuilder};
+use mistralrs_quant::{QuantMethod, QuantMethodConfig, QuantizedConfig, UnquantLinear};
+
+use crate::{
+    amoe::AnyMoeBaseModelMixin,
+    device_map::DeviceMapper,
+    layers::{MatMul, RmsNorm},
+    paged_attention::ModelConfigMetadata,
+    pipeline::{
+        extract_logits,
+        text_models_inputs_processor::{FlashParams, PagedAttentionInputMetadata},
+        Cache, IsqModel, NormalLoadingMetadata, NormalModel, RecurrentState,
+    },
+    serde_default_fn,
+    utils::{progress::NiceProgressBar, unvarbuilder::UnVarBuilder},
+};
+
+/// The state does not grow with the sequence, so there is no inherent limit on its length.
+pub(crate) const MAX_SEQ_LEN: usize = 1 << 20;
+
+serde_default_fn!(bool, use_bias_default, false);
+serde_default_fn!(bool, use_conv_bias_default, true);
+serde_default_fn!(bool, tie_word_embeddings_default, true);
+
+#[derive(Debug, Clone, serde::Deserialize, Default, serde::Serialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub state_size: usize,
+    pub num_hidden_layers: usize,
+    pub conv_kernel: usize,
+    pub time_step_rank: usize,
+    pub layer_norm_epsilon: f64,
+    #[serde(default = "use_bias_default")]
+    pub use_bias: bool,
+    #[serde(default = "use_conv_bias_default")]
+    pub use_conv_bias: bool,
+    #[serde(default = "tie_word_embeddings_default")]
+    pub tie_word_embeddings: bool,
+    pub quantization_config: Option<QuantizedConfig>,
+}
+
+/// Apply a layer which may be quantized, keeping the dtype of `xs`.
+fn qlinear(xs: &Tensor, layer: &dyn QuantMethod) -> Result<Tensor> {
+    let original_dtype = xs.dtype();
+    let mut xs = xs.clone();
+    if let Some(t) = layer.quantized_act_type() {
+        xs = xs.to_dtype(t)?;
+    }
+    let mut xs = MatMul.qmethod_matmul(&xs, layer)?;
+    if layer.quantized_act_type().is_some() {
+        xs = xs.to_dtype(original_dtype)?;
+    }
+    Ok(xs)
+}
+
+fn softplus(xs: &Tensor) -> Result<Tensor> {
+    // Like PyTorch, use the identity above a threshold where `exp` would overflow.
+    let softplus = (xs.exp()? + 1.0)?.log()?;
+    xs.gt(20.0)?.where_cond(xs, &softplus)
+}
+
+/// The selective state space mixer of a Mamba block, shared by the safetensors and GGUF models.
+pub(crate) struct MambaMixer {
+    pub(crate) in_proj: Arc<dyn QuantMethod>,
+    /// Depthwise convolution weight, `(d_inner, conv_kernel)`.
+    pub(crate) conv_weight: Tensor,
+    pub(crate) conv_bias: Option<Tensor>,
+    pub(crate) x_proj: Arc<dyn QuantMethod>,
+    pub(crate) dt_proj: Arc<dyn QuantMethod>,
+    /// `-exp(A_log)` in F32, `(d_inner, d_state)`.
+    pub(crate) a: Tensor,
+    /// The skip connection in F32, `(d_inner)`.
+    pub(crate) d: Tensor,
+    pub(crate) out_proj: Arc<dyn QuantMethod>,
+    pub(crate) d_inner: usize,
+    pub(crate) d_state: usize,
+    pub(crate) dt_rank: usize,
+    pub(crate) conv_kernel: usize,
+}
+
+impl MambaMixer {
+    fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        mapper: &dyn DeviceMapper,
+        layer_idx: usize,
+        loading_isq: bool,
+    ) -> Result<Self> {
+        let d_inner = cfg.intermediate_size;
+        let in_proj = mistralrs_quant::linear_b(
+            cfg.hidden_size,
+            2 * d_inner,
+            cfg.use_bias,
+            &cfg.quantization_config,
+            mapper.set_device(layer_idx, vb.pp("in_proj"), loading_isq),
+        )?;
+        let x_proj = mistralrs_quant::linear_no_bias(
+            d_inner,
+            cfg.time_step_rank + 2 * cfg.state_size,
+            &cfg.quantization_config,
+            mapper.set_device(layer_idx, vb.pp("x_proj"), loading_isq),
+        )?;
+        let dt_proj = mistralrs_quant::linear(
+            cfg.time_step_rank,
+            d_inner,
+            &cfg.quantization_config,
+            mapper.set_device(layer_idx, vb.pp("dt_proj"), loading_isq),
+        )?;
+        let out_proj = mistralrs_quant::linear_b(
+            d_inner,
+            cfg.hidden_size,
+            cfg.use_bias,
+            &cfg.quantization_config,
+            mapper.set_device(layer_idx, vb.pp("out_proj"), loading_isq),
+        )?;
+
+        let vb = mapper.set_device(layer_idx, vb, false);
+        let conv_weight = vb
+            .pp("conv1d")
+            .get((d_inner, 1, cfg.conv_kernel), "weight")?
+            .squeeze(1)?;
+        let conv_bias = if cfg.use_conv_bias {
+            Some(vb.pp("conv1d").get(d_inner, "bias")?)
+        } else {
+            None
+        };
+        let a = vb
+            .get((d_inner, cfg.state_size), "A_log")?
+            .to_dtype(DType::F32)?
+            .exp()?
+            .neg()?;
+        let d = vb.get(d_inner, "D")?.to_dtype(DType::F32)?;
+        Ok(Self {
+            in_proj,
+            conv_weight,
+            conv_bias,
+            x_proj,
+            dt_proj,
+            a,
+            d,
+            out_proj,
+            d_inner,
+            d_state: cfg.state_size,
+            dt_rank: cfg.time_step_rank,
+            conv_kernel: cfg.conv_kernel,
+        })
+    }
+
+    /// Causal depthwise convolution of `xs` of shape `(bs, d_inner, seq_len)`, continuing from
+    /// the inputs in `conv_state`. Returns the output and the new convolution state.
+    fn conv(&self, xs: &Tensor, conv_state: Option<&Tensor>) -> Result<(Tensor, Tensor)> {
+        let (bs, _, seq_len) = xs.dims3()?;
+        let conv_state = match conv_state {
+            Some(conv_state) => conv_state.to_dtype(xs.dtype())?,
+            None => Tensor::zeros(
+                (bs, self.d_inner, self.conv_kernel - 1),
+                xs.dtype(),
+                xs.device(),
+            )?,
+        };
+        let xs = Tensor::cat(&[&conv_state, xs], D::Minus1)?;
+        let new_conv_state = xs.narrow(D::Minus1, seq_len, self.conv_kernel - 1)?;
+
+        let weight = self.conv_weight.to_dtype(xs.dtype())?;
+        let mut ys = xs
+            .narrow(D::Minus1, 0, seq_len)?
+            .broadcast_mul(&weight.narrow(1, 0, 1)?)?;
+        for k in 1..self.conv_kernel {
+            ys = (ys
+                + xs.narrow(D::Minus1, k, seq_len)?
+                    .broadcast_mul(&weight.narrow(1, k, 1)?)?)?;
+        }
+        if let Some(bias) = &self.conv_bias {
+            ys = ys.broadcast_add(&bias.to_dtype(xs.dtype())?.unsqueeze(1)?)?;
+        }
+        Ok((ys, new_conv_state.contiguous()?))
+    }
+
+    /// Sequential selective scan in F32 over `xs` of shape `(bs, seq_len, d_inner)`, continuing
+    /// from `ssm_state`. Returns the output and the new state.
+    fn selective_scan(&self, xs: &Tensor, ssm_state: Option<&Tensor>) -> Result<(Tensor, Tensor)> {
+        let (bs, seq_len, _) = xs.dims3()?;
+        let x_dbl = qlinear(xs, &*self.x_proj)?;
+        let dt = x_dbl.narrow(D::Minus1, 0, self.dt_rank)?;
+        let b = x_dbl
+            .narrow(D::Minus1, self.dt_rank, self.d_state)?
+            .to_dtype(DType::F32)?;
+        let c = x_dbl
+            .narrow(D::Minus1, self.dt_rank + self.d_state, self.d_state)?
+            .to_dtype(DType::F32)?;
+        let dt = softplus(&qlinear(&dt.contiguous()?, &*self.dt_proj)?.to_dtype(DType::F32)?)?;
+        let u = xs.to_dtype(DType::F32)?;
+
+        let mut h = match ssm_state {
+            Some(ssm_state) => ssm_state.to_dtype(DType::F32)?,
+            None => Tensor::zeros((bs, self.d_inner, self.d_state), DType::F32, xs.device())?,
+        };
+        let mut ys = Vec::with_capacity(seq_len);
+        for t in 0..seq_len {
+            let dt_t = dt.i((.., t))?;
+            let u_t = u.i((.., t))?;
+            // h = exp(dt * A) * h + (dt * u) B
+            let decay = dt_t.unsqueeze(2)?.broadcast_mul(&self.a)?.exp()?;
+            let input = (&dt_t * &u_t)?
+                .unsqueeze(2)?
+                .broadcast_mul(&b.i((.., t))?.unsqueeze(1)?)?;
+            h = ((decay * h)? + input)?;
+            // y = h C
+            ys.push(
+                h.matmul(&c.i((.., t))?.unsqueeze(2)?.contiguous()?)?
+                    .squeeze(2)?,
+            );
+        }
+        let ys = Tensor::stack(&ys, 1)?;
+        let ys = (ys + u.broadcast_mul(&self.d)?)?;
+        Ok((ys.to_dtype(xs.dtype())?, h))
+    }
+
+    pub(crate) fn forward(
+        &self,
+        xs: &Tensor,
+        state: &mut Option<RecurrentState>,
+    ) -> Result<Tensor> {
+        let xz = qlinear(xs, &*self.in_proj)?;
+        let x = xz.narrow(D::Minus1, 0, self.d_inner)?;
+        let z = xz.narrow(D::Minus1, self.d_inner, self.d_inner)?;
+
+        let (x, conv_state) = self.conv(
+            &x.transpose(1, 2)?.contiguous()?,
+            state.as_ref().map(|s| &s.conv),
+        )?;
+        let x = x.silu()?.transpose(1, 2)?.contiguous()?;
+
+        let (ys, ssm_state) = self.selective_scan(&x, state.as_ref().map(|s| &s.ssm))?;
+        *state = Some(RecurrentState::new(conv_state, ssm_state));
+
+        let ys = (ys * z.silu()?)?;
+        qlinear(&ys, &*self.out_proj)
+    }
+
+    pub(crate) fn residual_tensors(&self) -> Result<Vec<(String, Tensor)>> {
+        let uvb = UnVarBuilder::new();
+        uvb.pp("conv1d")
+            .add_tensor("weight", self.conv_weight.unsqueeze(1)?);
+        if let Some(bias) = &self.conv_bias {
+            uvb.pp("conv1d").add_tensor("bias", bias.clone());
+        }
+        uvb.add_tensor("A_log", self.a.neg()?.log()?);
+        uvb.add_tensor("D", self.d.clone());
+        Ok(uvb.to_safetensors())
+    }
+}
+
+pub(crate) struct MambaBlock {
+    pub(crate) norm: RmsNorm,
+    pub(crate) mixer: MambaMixer,
+}
+
+impl MambaBlock {
+    pub(crate) fn forward(
+        &self,
+        xs: &Tensor,
+        state: &mut Option<RecurrentState>,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.mixer.forward(&xs.apply(&self.norm)?, state)?;
+        xs + residual
+    }
+}
+
+/// Mamba (selective state space) model, as the Transformers `MambaForCausalLM`. It keeps a
+/// [`RecurrentState`] per layer instead of a KV cache.
+pub struct Model {
+    embeddings: Embedding,
+    layers: Vec<MambaBlock>,
+    norm_f: RmsNorm,
+    lm_head: Arc<dyn QuantMethod>,
+    device: Device,
+    cache: Cache,
+    mapper: Box<dyn DeviceMapper + Send + Sync>,
+    cfg: ModelConfigMetadata,
+}
+
+impl Model {
+    pub fn new(
+        cfg: &Config,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+    ) -> Result<Self> {
+        if let Some(ref quant_cfg) = &cfg.quantization_config {
+            tracing::info!(
+                "Using {} quantization in {} bits.",
+                quant_cfg.quant_method.to_string(),
+                quant_cfg.bits
+            );
+        }
+        let mapper = normal_loading_metadata.mapper;
+        let vb_m = vb.pp("backbone");
+
+        let embeddings = candle_nn::embedding(
+            cfg.vocab_size,
+            cfg.hidden_size,
+            mapper.set_nm_device(vb_m.pp("embeddings"), false),
+        )?;
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        let vb_l = vb_m.pp("layers");
+        for layer_idx in
+            NiceProgressBar::<_, 'b'>(0..cfg.num_hidden_layers, "Loading repeating layers")
+        {
+            let vb = vb_l.pp(layer_idx);
+            let norm = RmsNorm::new(
+                cfg.hidden_size,
+                cfg.layer_norm_epsilon,
+                mapper.set_device(layer_idx, vb.pp("norm"), false),
+            )?;
+            let mixer = MambaMixer::new(
+                cfg,
+                vb.pp("mixer"),
+                &*mapper,
+                layer_idx,
+                normal_loading_metadata.loading_isq,
+            )?;
+            layers.push(MambaBlock { norm, mixer });
+        }
+        let norm_f = RmsNorm::new(
+            cfg.hidden_size,
+            cfg.layer_norm_epsilon,
+            mapper.set_nm_device(vb_m.pp("norm_f"), false),
+        )?;
+        let lm_head = if !cfg.tie_word_embeddings {
+            mistralrs_quant::linear_no_bias(
+                cfg.hidden_size,
+                cfg.vocab_size,
+                &None,
+                mapper.set_nm_device(vb.pp("lm_head"), normal_loading_metadata.loading_isq),
+            )?
+        } else {
+            Arc::new(UnquantLinear::new(QuantMethodConfig::Unquantized(
+                candle_nn::Linear::new(
+                    mapper.cast_nm_device(
+                        embeddings.embeddings(),
+                        normal_loading_metadata.loading_isq,
+                    )?,
+                    None,
+                ),
+            ))?)
+        };
+        Ok(Self {
+            embeddings,
+            layers,
+            norm_f,
+            lm_head,
+            device: normal_loading_metadata.real_device,
+            cache: Cache::new_recurrent(cfg.num_hidden_layers),
+            mapper,
+            cfg: ModelConfigMetadata {
+                num_layers: cfg.num_hidden_layers,
+                hidden_size: cfg.hidden_size,
+                num_kv_heads: 1,
+                num_attn_heads: 1,
+                sliding_window: None,
+                head_dim: None,
+            },
+        })
+    }
+
+    pub fn forward_hidden_states(&self, input_ids: &Tensor) -> Result<Tensor> {
+        let mut xs = self.embeddings.forward(input_ids)?;
+        let mut states = self.cache.states_lock();
+        for (i, layer) in self.layers.iter().enumerate() {
+            xs = self.mapper.map(xs, i)?;
+            xs = layer.forward(&xs, &mut states[i])?;
+        }
+        let xs = xs.to_device(&self.device)?;
+        xs.apply(&self.norm_f)
+    }
+
+    pub fn forward(&self, input_ids: &Tensor, context_lens: Vec<(usize, usize)>) -> Result<Tensor> {
+        let mut xs = self.forward_hidden_states(input_ids)?;
+        if let Some(t) = self.lm_head.quantized_act_type() {
+            xs = xs.to_dtype(t)?;
+        }
+        extract_logits(&MatMul.qmethod_matmul(&xs, &*self.lm_head)?, context_lens)
+    }
+}
+
+impl IsqModel for Model {
+    fn get_layers(
+        &mut self,
+    ) -> (
+        Vec<(&mut Arc<dyn QuantMethod>, Option<usize>)>,
+        &dyn DeviceMapper,
+    ) {
+        let mut tensors = Vec::new();
+        tensors.push((&mut self.lm_head, None));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            tensors.push((&mut layer.mixer.in_proj, Some(i)));
+            tensors.push((&mut layer.mixer.x_proj, Some(i)));
+            tensors.push((&mut layer.mixer.dt_proj, Some(i)));
+            tensors.push((&mut layer.mixer.out_proj, Some(i)));
+        }
+        (tensors, &*self.mapper)
+    }
+
+    fn residual_tensors(&self) -> Vec<(String, Tensor)> {
+        let uvb = UnVarBuilder::new();
+
+        let uvb_m = uvb.pp("backbone");
+        uvb_m.pp("embeddings").add(&self.embeddings);
+        uvb_m.pp("norm_f").add(&self.norm_f);
+
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            let uvb_l = uvb_m.pp("layers").pp(layer_idx);
+            uvb_l.pp("norm").add(&layer.norm);
+            uvb_l
+                .pp("mixer")
+                .extend(layer.mixer.residual_tensors().unwrap());
+        }
+
+        uvb.to_safetensors()
+    }
+}
+
+impl NormalModel for Model {
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.forward(input_ids, context_lens)
+    }
+    fn forward_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _flash_params: &FlashParams,
+    ) -> Result<Tensor> {
+        self.forward_hidden_states(input_ids)
+    }
+    fn xlora_forward(
+        &self,
+        _input_ids: &Tensor,
+        _input_ids_full: &Tensor,
+        _seqlen_offsets: &[usize],
+        _seqlen_offsets_full: &[usize],
+        _start_offsets_kernel: Tensor,
+        _start_offsets_kernel_full: Tensor,
+        _no_kv_cache: bool,
+        _non_granular_state: &Option<crate::xlora_models::NonGranularState>,
+        _context_lens: Vec<(usize, usize)>,
+        _position_ids: Vec<usize>,
+        _flash_params: &FlashParams,
+        _flash_params_full: &FlashParams,
+    ) -> Result<Tensor> {
+        unimplemented!()
+    }
+    fn cache(&self) -> &Cache {
+        &self.cache
+    }
+    fn device(&self) -> &Device {
+        &self.device
+    }
+    fn is_xlora(&self) -> bool {
+        false
+    }
+    fn max_seq_len(&self) -> usize {
+        MAX_SEQ_LEN
+    }
+    fn config(&self) -> &ModelConfigMetadata {
+        &self.cfg
+    }
+}
+
+impl AnyMoeBaseModelMixin for Model {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use candle_core::{DType, Device, Result, Tensor};
+    use mistralrs_quant::{QuantMethod, QuantMethodConfig, UnquantLinear};
+
+    use super::MambaMixer;
+
+    fn linear(in_dim: usize, out_dim: usize, bias: bool) -> Result<Arc<dyn QuantMethod>> {
+        let w =
+            (Tensor::randn(0f32, 1., (out_dim, in_dim), &Device::Cpu)? / (in_dim as f64).sqrt())?;
+        let b = if bias {
+            Some(Tensor::randn(0f32, 1., out_dim, &Device::Cpu)?)
+        } else {
+            None
+        };
+        Ok(Arc::new(UnquantLinear::new(
+            QuantMethodConfig::Unquantized(candle_nn::Linear::new(w, b)),
+        )?))
+    }
+
+    #[test]
+    fn test_mamba_mixer_state() -> Result<()> {
+        let (hidden_size, d_inner, d_state, dt_rank, conv_kernel) = (8, 16, 4, 2, 4);
+        let mixer = MambaMixer {
+            in_proj: linear(hidden_size, 2 * d_inner, false)?,
+            conv_weight: Tensor::randn(0f32, 1., (d_inner, conv_kernel), &Device::Cpu)?,
+            conv_bias: Some(Tensor::randn(0f32, 1., d_inner, &Device::Cpu)?),
+            x_proj: linear(d_inner, dt_rank + 2 * d_state, false)?,
+            dt_proj: linear(dt_rank, d_inner, true)?,
+            a: Tensor::rand(0.5f32, 2., (d_inner, d_state), &Device::Cpu)?.neg()?,
+            d: Tensor::ones(d_inner, DType::F32, &Device::Cpu)?,
+            out_proj: linear(d_inner, hidden_size, false)?,
+            d_inner,
+            d_state,
+            dt_rank,
+            conv_kernel,
+        };
+        let xs = Tensor::randn(0f32, 1., (2, 6, hidden_size), &Device::Cpu)?;
+        let full = mixer.forward(&xs, &mut None)?;
+
+        // Continuing from the state of the prompt gives the same outputs as a single pass.
+        let mut state = None;
+        let mut outputs = vec![mixer.forward(&xs.narrow(1, 0, 3)?, &mut state)?];
+        for t in 3..6 {
+            outputs.push(mixer.forward(&xs.narrow(1, t, 1)?, &mut state)?);
+        }
+        let stepped = Tensor::cat(&outputs, 1)?;
+        let diff = (full - stepped)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "{diff}");
+        Ok(())
+    }
+}
@@ -1,12 +1,17 @@
+pub(crate) mod deepseek2;
 pub(crate) mod gemma;
 pub(crate) mod gemma2;
 pub(crate) mod llama;
+pub(crate) mod mamba;
 pub(crate) mod mistral;
 pub(crate) mod mixtral;
 pub(crate) mod phi2;
 pub(crate) mod phi3;
 pub(crate) mod phi3_5_moe;
+pub(crate) mod quantized_gemma;
+pub(crate) mod quantized_gemma2;
 pub(crate) mod quantized_llama;
+pub(crate) mod quantized_mamba;
 pub(crate) mod quantized_phi2;
 pub(crate) mod quantized_phi3;
 pub(crate) mod quantized_qwen2;
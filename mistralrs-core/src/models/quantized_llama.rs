@@ -16,7 +16,7 @@ use crate::layers::{CausalMasker, MatMul, QRmsNorm, Sdpa};
 use crate::layers_masker::PastKvLenCache;
 use crate::paged_attention::{AttentionImplementation, PagedAttention};
 use crate::pipeline::text_models_inputs_processor::PagedAttentionInputMetadata;
-use crate::pipeline::{extract_logits, Cache};
+use crate::pipeline::{extract_logits, Cache, KvCache};
 use crate::utils::gguf_metadata::ContentMetadata;
 use crate::utils::model_config as ModelConfig;
 use crate::utils::progress::NiceProgressBar;
@@ -141,7 +141,7 @@ impl LayerWeights {
         mask: Option<&Tensor>,
         start_offsets: &[usize],
         start_offsets_kernel: Tensor,
-        kv_cache: &mut Option<(Tensor, Tensor)>,
+        kv_cache: &mut Option<KvCache>,
         metadata: Option<((Tensor, Tensor), &mut PagedAttentionInputMetadata)>,
     ) -> Result<Tensor> {
         let (b_sz, seq_len, n_embd) = x.dims3()?;
@@ -392,6 +392,25 @@ impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
     }
 }
 
+/// Split the weights of `n_expert` experts packed in a single `ffn_*_exps` tensor into 2D
+/// weights of the same quantization type.
+fn unpack_experts(packed: &QTensor, n_expert: usize, device: &Device) -> Result<Vec<QTensor>> {
+    if packed.shape().dims().first() != Some(&n_expert) {
+        candle_core::bail!(
+            "Expected {n_expert} packed experts, got a tensor of shape {:?}.",
+            packed.shape()
+        );
+    }
+    packed
+        .dequantize(device)?
+        .chunk(n_expert, 0)?
+        .into_iter()
+        // Each chunk keeps the leading expert dimension of size 1, but the quantized matmul
+        // expects a 2D weight.
+        .map(|expert| QTensor::quantize(&expert.squeeze(0)?, packed.dtype()))
+        .collect()
+}
+
 impl ModelConfig::FromGGUF for ModelWeights {
     fn from_gguf<R: std::io::Seek + std::io::Read>(
         mut ct: Content<'_, R>,
@@ -496,44 +515,30 @@ impl ModelConfig::FromGGUF for ModelWeights {
                         let feed_forward_up_exps =
                             ct.tensor(&format!("{prefix}.ffn_up_exps.weight"), device)?;
 
-                        let dequant_ffn_gate = feed_forward_gate_exps
-                            .dequantize(device)?
-                            .chunk(n_expert, 0)?;
-                        let dequant_ffn_down = feed_forward_down_exps
-                            .dequantize(device)?
-                            .chunk(n_expert, 0)?;
-                        let dequant_ffn_up = feed_forward_up_exps
-                            .dequantize(device)?
-                            .chunk(n_expert, 0)?;
-
-                        assert_eq!(dequant_ffn_up.len(), dequant_ffn_down.len());
-                        assert_eq!(dequant_ffn_gate.len(), dequant_ffn_down.len());
-                        assert_eq!(dequant_ffn_gate.len(), n_expert);
-
-                        let gate_type = feed_forward_gate_exps.dtype();
-                        let down_type = feed_forward_down_exps.dtype();
-                        let up_type = feed_forward_up_exps.dtype();
-
-                        for (ff_w1, (ff_w2, ff_w3)) in dequant_ffn_gate
+                        let gate_exps = unpack_experts(&feed_forward_gate_exps, n_expert, device)?;
+                        let down_exps = unpack_experts(&feed_forward_down_exps, n_expert, device)?;
+                        let up_exps = unpack_experts(&feed_forward_up_exps, n_expert, device)?;
+
+                        for (ff_w1, (ff_w2, ff_w3)) in gate_exps
                             .into_iter()
-                            .zip(dequant_ffn_down.into_iter().zip(dequant_ffn_up))
+                            .zip(down_exps.into_iter().zip(up_exps))
                         {
                             experts.push(Mlp {
                                 feed_forward_w1: Arc::new(GgufMatMul::new(
                                     QuantMethodConfig::Gguf {
-                                        q_weight: Arc::new(QTensor::quantize(&ff_w1, gate_type)?),
+                                        q_weight: Arc::new(ff_w1),
                                         b: None,
                                     },
                                 )?),
                                 feed_forward_w2: Arc::new(GgufMatMul::new(
                                     QuantMethodConfig::Gguf {
-                                        q_weight: Arc::new(QTensor::quantize(&ff_w2, down_type)?),
+                                        q_weight: Arc::new(ff_w2),
                                         b: None,
                                     },
                                 )?),
                                 feed_forward_w3: Arc::new(GgufMatMul::new(
                                     QuantMethodConfig::Gguf {
-                                        q_weight: Arc::new(QTensor::quantize(&ff_w3, up_type)?),
+                                        q_weight: Arc::new(ff_w3),
                                         b: None,
                                     },
                                 )?),
@@ -700,3 +705,34 @@ impl ModelWeights {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{quantized::GgmlDType, quantized::QTensor, Device, Result, Tensor};
+
+    use super::unpack_experts;
+
+    #[test]
+    fn test_unpack_experts() -> Result<()> {
+        // 3 experts with (4, 64) weights, each filled with its index.
+        let weights = Tensor::arange(0f32, 3., &Device::Cpu)?
+            .reshape((3, 1, 1))?
+            .broadcast_as((3, 4, 64))?
+            .contiguous()?;
+        let packed = QTensor::quantize(&weights, GgmlDType::Q8_0)?;
+        let experts = unpack_experts(&packed, 3, &Device::Cpu)?;
+        assert_eq!(experts.len(), 3);
+        for (i, expert) in experts.iter().enumerate() {
+            assert_eq!(expert.dtype(), GgmlDType::Q8_0);
+            assert_eq!(expert.shape().dims(), [4, 64]);
+            let expected = weights.get(i)?;
+            let error = (expert.dequantize(&Device::Cpu)? - expected)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?;
+            assert!(error.to_scalar::<f32>()? < 1e-2);
+        }
+        assert!(unpack_experts(&packed, 2, &Device::Cpu).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use candle_core::{Device, Result, Tensor};
+use candle_nn::{Embedding, Module};
+use mistralrs_quant::{GgufMatMul, QuantMethod, QuantMethodConfig};
+
+use crate::device_map::DeviceMapper;
+use crate::gguf::Content;
+use crate::layers::{MatMul, RmsNorm};
+use crate::models::mamba::{MambaBlock, MambaMixer, MAX_SEQ_LEN};
+use crate::paged_attention::AttentionImplementation;
+use crate::pipeline::{extract_logits, Cache};
+use crate::utils::gguf_metadata::ContentMetadata;
+use crate::utils::model_config as ModelConfig;
+use crate::utils::progress::NiceProgressBar;
+use crate::DeviceMapMetadata;
+use crate::Topology;
+
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<MambaBlock>,
+    norm: RmsNorm,
+    output: Arc<dyn QuantMethod>,
+    pub device: Device,
+    pub cache: Cache,
+    pub max_seq_len: usize,
+    mapper: Option<Box<dyn DeviceMapper + Send + Sync>>,
+}
+
+// mamba `llm` fields:
+// https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+// NOTE: Types here do not match spec
+pub(crate) struct PropsGGUF {
+    pub block_count: usize,
+    pub embedding_length: usize,
+    pub rms_norm_eps: f32,
+    pub max_seq_len: usize,
+    pub conv_kernel: usize,
+    pub inner_size: usize,
+    pub state_size: usize,
+    pub time_step_rank: usize,
+}
+
+impl TryFrom<ContentMetadata<'_>> for PropsGGUF {
+    type Error = anyhow::Error;
+
+    fn try_from(c: ContentMetadata) -> std::result::Result<Self, Self::Error> {
+        c.verify_arch("mamba")?;
+
+        let required = [
+            "block_count",
+            "embedding_length",
+            "attention.layer_norm_rms_epsilon",
+            "ssm.conv_kernel",
+            "ssm.inner_size",
+            "ssm.state_size",
+            "ssm.time_step_rank",
+        ];
+        c.has_required_keys(&required)?;
+
+        // NOTE: Values are not aligned with GGUFv3 types
+        // TODO: Normalize value types to spec
+        let props = Self {
+            block_count: c.get_value::<u32>("block_count")? as usize,
+            embedding_length: c.get_value::<u32>("embedding_length")? as usize,
+            rms_norm_eps: c.get_value("attention.layer_norm_rms_epsilon")?,
+            max_seq_len: c
+                .get_value::<u64>("context_length")
+                .ok()
+                .unwrap_or(MAX_SEQ_LEN as u64) as usize,
+            conv_kernel: c.get_value::<u32>("ssm.conv_kernel")? as usize,
+            inner_size: c.get_value::<u32>("ssm.inner_size")? as usize,
+            state_size: c.get_value::<u32>("ssm.state_size")? as usize,
+            time_step_rank: c.get_value::<u32>("ssm.time_step_rank")? as usize,
+        };
+
+        Ok(props)
+    }
+}
+
+impl ModelConfig::FromGGUF for ModelWeights {
+    fn from_gguf<R: std::io::Seek + std::io::Read>(
+        mut ct: Content<'_, R>,
+        device: &Device,
+        mapper: DeviceMapMetadata,
+        topology: Option<&'_ Topology>,
+        _attention_mechanism: AttentionImplementation,
+    ) -> Result<Self> {
+        // Parameter extraction from metadata.
+        let metadata = ContentMetadata {
+            path_prefix: "mamba",
+            metadata: ct.get_metadata(),
+        };
+        let PropsGGUF {
+            block_count,
+            embedding_length,
+            rms_norm_eps,
+            max_seq_len,
+            conv_kernel,
+            inner_size,
+            state_size,
+            time_step_rank,
+        } = PropsGGUF::try_from(metadata).or_else(|err| candle_core::bail!("{err}"))?;
+
+        let qtok_embeddings = ct.tensor("token_embd.weight", device)?;
+        let tok_embeddings = qtok_embeddings.dequantize(device)?;
+        let norm = RmsNorm::from_w(
+            ct.tensor("output_norm.weight", device)?
+                .dequantize(device)?,
+            rms_norm_eps as f64,
+        )?;
+        let output = if !ct.has_tensor("output.weight") {
+            ct.tensor("token_embd.weight", device)?
+        } else {
+            ct.tensor("output.weight", device)?
+        };
+        let mut layers = Vec::with_capacity(block_count);
+
+        let mapper = mapper.into_mapper(block_count, device, topology)?;
+
+        for layer_idx in NiceProgressBar::<_, 'b'>(0..block_count, "Loading repeating layers") {
+            let prefix = format!("blk.{layer_idx}");
+            let device = mapper.device_for(layer_idx, false).unwrap_or(device);
+
+            let ssm_in = ct.tensor(&format!("{prefix}.ssm_in.weight"), device)?;
+            let ssm_x = ct.tensor(&format!("{prefix}.ssm_x.weight"), device)?;
+            let ssm_dt = ct.tensor(&format!("{prefix}.ssm_dt.weight"), device)?;
+            let ssm_dt_bias = ct
+                .tensor(&format!("{prefix}.ssm_dt.bias"), device)?
+                .dequantize(device)?;
+            let ssm_out = ct.tensor(&format!("{prefix}.ssm_out.weight"), device)?;
+            // The GGUF conversion stores `A` as `-exp(A_log)` and the convolution weight without
+            // the unit dimension.
+            let mixer = MambaMixer {
+                in_proj: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(ssm_in),
+                    b: None,
+                })?),
+                conv_weight: ct
+                    .tensor(&format!("{prefix}.ssm_conv1d.weight"), device)?
+                    .dequantize(device)?,
+                conv_bias: Some(
+                    ct.tensor(&format!("{prefix}.ssm_conv1d.bias"), device)?
+                        .dequantize(device)?,
+                ),
+                x_proj: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(ssm_x),
+                    b: None,
+                })?),
+                dt_proj: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(ssm_dt),
+                    b: Some(ssm_dt_bias),
+                })?),
+                a: ct
+                    .tensor(&format!("{prefix}.ssm_a"), device)?
+                    .dequantize(device)?,
+                d: ct
+                    .tensor(&format!("{prefix}.ssm_d"), device)?
+                    .dequantize(device)?,
+                out_proj: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                    q_weight: Arc::new(ssm_out),
+                    b: None,
+                })?),
+                d_inner: inner_size,
+                d_state: state_size,
+                dt_rank: time_step_rank,
+                conv_kernel,
+            };
+            let norm = RmsNorm::from_w(
+                ct.tensor(&format!("{prefix}.attn_norm.weight"), device)?
+                    .dequantize(device)?,
+                rms_norm_eps as f64,
+            )?;
+            layers.push(MambaBlock { norm, mixer });
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output: Arc::new(GgufMatMul::new(QuantMethodConfig::Gguf {
+                q_weight: Arc::new(output),
+                b: None,
+            })?),
+            device: device.clone(),
+            cache: Cache::new_recurrent(block_count),
+            max_seq_len,
+            mapper: Some(mapper),
+        })
+    }
+}
+
+impl ModelWeights {
+    pub fn forward(&self, x: &Tensor, context_lens: Vec<(usize, usize)>) -> Result<Tensor> {
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        let mut states = self.cache.states_lock();
+        for (i, layer) in self.layers.iter().enumerate() {
+            if let Some(ref mapper) = self.mapper {
+                layer_in = mapper.map(layer_in, i)?;
+            }
+            layer_in = layer.forward(&layer_in, &mut states[i])?;
+        }
+        let layer_in = layer_in.to_device(&self.device)?;
+        let x = self.norm.forward(&layer_in)?;
+        extract_logits(
+            &MatMul.qmethod_matmul(&x.contiguous()?, &*self.output)?,
+            context_lens,
+        )
+    }
+}
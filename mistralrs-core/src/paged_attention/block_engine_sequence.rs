@@ -2,4 +2,8 @@ pub trait BlockEngineSequence {
     fn blocks_to_add_new_tok(&self) -> usize;
     fn get_id(&self) -> usize;
     fn get_logical_token_blocks(&self) -> usize;
+    fn num_tokens(&self) -> usize;
+    /// Hashes identifying each full logical token block together with all tokens before it, used to share
+    /// blocks between sequences with a common prefix. Empty if the blocks of this sequence must not be shared.
+    fn block_hashes(&self) -> Vec<u64>;
 }
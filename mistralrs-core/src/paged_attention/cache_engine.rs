@@ -4,15 +4,16 @@ use std::{
 };
 
 use candle_core::{DType, Device, Result, Tensor};
-use mistralrs_paged_attn::{copy_blocks, swap_blocks};
+use mistralrs_paged_attn::copy_blocks;
 
-use super::config::ModelConfigLike;
+use super::{config::ModelConfigLike, PreemptionMode};
 
 #[derive(Clone, Debug)]
 pub struct CacheConfig {
     pub block_size: usize,
     pub num_gpu_blocks: usize,
     pub num_cpu_blocks: usize,
+    pub preemption_mode: PreemptionMode,
 }
 
 pub type KVCache = (Tensor, Tensor);
@@ -37,7 +38,7 @@ impl CacheEngine {
                 dtype,
                 device,
             )?)),
-            cpu_cache: Self::allocate_cpu_cache(model_config, cache_config, dtype, device)?,
+            cpu_cache: Self::allocate_cpu_cache(model_config, cache_config, dtype)?,
             num_layers: model_config.num_layers(),
         })
     }
@@ -88,11 +89,11 @@ impl CacheEngine {
         Ok(gpu_cache)
     }
 
+    /// The swap space for preempted sequences, which always lives in host memory.
     fn allocate_cpu_cache(
         model_config: &dyn ModelConfigLike,
         cache_config: &CacheConfig,
         dtype: DType,
-        device: &Device,
     ) -> Result<Vec<KVCache>> {
         let key_block_shape =
             Self::calculate_key_block_shape(model_config, dtype, cache_config.block_size);
@@ -109,7 +110,7 @@ impl CacheEngine {
                     key_block_shape.3,
                 ),
                 dtype,
-                device,
+                &Device::Cpu,
             )?;
             let value_blocks = Tensor::zeros(
                 (
@@ -119,7 +120,7 @@ impl CacheEngine {
                     value_block_shape.2,
                 ),
                 dtype,
-                device,
+                &Device::Cpu,
             )?;
             cpu_cache.push((key_blocks, value_blocks));
         }
@@ -162,12 +163,13 @@ impl CacheEngine {
         blocks_to_swap_out: HashMap<usize, usize>,
         blocks_to_copy: HashMap<usize, Vec<usize>>,
     ) -> Result<()> {
-        if !blocks_to_swap_in.is_empty() {
-            self.swap_in(blocks_to_swap_in)?;
-        }
+        // Swap out first, as the freed GPU blocks may be reused.
         if !blocks_to_swap_out.is_empty() {
             self.swap_out(blocks_to_swap_out)?;
         }
+        if !blocks_to_swap_in.is_empty() {
+            self.swap_in(blocks_to_swap_in)?;
+        }
         if !blocks_to_copy.is_empty() {
             self.copy(blocks_to_copy)?;
         }
@@ -180,9 +182,9 @@ impl CacheEngine {
             let gpu_cache = self.get_kv_cache();
             let (dst_key_cache, dst_value_cache) = gpu_cache.get(i).unwrap();
             // Swap (copy) key blocks
-            unsafe { swap_blocks(src_key_cache.clone(), dst_key_cache, src_to_dst.clone())? };
-            // Swap (copy) key blocks
-            unsafe { swap_blocks(src_value_cache.clone(), dst_value_cache, src_to_dst.clone())? };
+            swap_blocks(src_key_cache, dst_key_cache, &src_to_dst)?;
+            // Swap (copy) value blocks
+            swap_blocks(src_value_cache, dst_value_cache, &src_to_dst)?;
         }
         Ok(())
     }
@@ -195,9 +197,9 @@ impl CacheEngine {
 
             let (dst_key_cache, dst_value_cache) = self.cpu_cache.get(i).unwrap();
             // Swap (copy) key blocks
-            unsafe { swap_blocks(src_key_cache.clone(), dst_key_cache, src_to_dst.clone())? };
-            // Swap (copy) key blocks
-            unsafe { swap_blocks(src_value_cache.clone(), dst_value_cache, src_to_dst.clone())? };
+            swap_blocks(&src_key_cache, dst_key_cache, &src_to_dst)?;
+            // Swap (copy) value blocks
+            swap_blocks(&src_value_cache, dst_value_cache, &src_to_dst)?;
         }
         Ok(())
     }
@@ -215,3 +217,12 @@ impl CacheEngine {
         Ok(())
     }
 }
+
+/// Copy whole blocks (along the first dimension) between caches, which may be on different devices.
+fn swap_blocks(src: &Tensor, dst: &Tensor, src_to_dst: &HashMap<usize, usize>) -> Result<()> {
+    for (src_block, dst_block) in src_to_dst {
+        let block = src.narrow(0, *src_block, 1)?.to_device(dst.device())?;
+        dst.slice_set(&block, 0, *dst_block)?;
+    }
+    Ok(())
+}
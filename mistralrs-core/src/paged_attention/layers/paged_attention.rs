@@ -79,6 +79,18 @@ impl PagedAttention {
         let (_, key_value_heads, _, _) = key.shape().dims4()?;
 
         let att = match attention_mask {
+            // Prompts which start with shared prefix blocks also attend to the cached keys and values.
+            _ if input_metadata.prefix_block_tables.is_some() => {
+                Some(self.prefix_prompt_attention(
+                    query,
+                    key,
+                    value,
+                    key_cache.as_ref().unwrap(),
+                    value_cache.as_ref().unwrap(),
+                    input_metadata.prefix_block_tables.as_ref().unwrap(),
+                    softcapping,
+                )?)
+            }
             None => None,
             Some(mask) => {
                 //Only perform key/value repeat in prefiling stage, this will reduce kvcache
@@ -180,4 +192,81 @@ impl PagedAttention {
             softcapping.unwrap_or(1.0f64) as f32,
         )
     }
+    /// Prompt attention for sequences whose prompt starts with blocks shared from the prefix cache.
+    /// The keys and values of the shared blocks are gathered from the cache and put in front of the
+    /// new ones, and the causal mask is built from the absolute positions of the new tokens.
+    ///
+    /// query: shape = [batch_size, num_heads, seq_len, head_size]
+    /// key, value: shape = [batch_size, num_kv_heads, seq_len, head_size]
+    /// prefix_block_tables: the cached block ids of each sequence, if any.
+    #[allow(clippy::too_many_arguments)]
+    fn prefix_prompt_attention(
+        &self,
+        query: &Tensor,
+        key: &Tensor,
+        value: &Tensor,
+        key_cache: &Tensor,
+        value_cache: &Tensor,
+        prefix_block_tables: &[Option<Tensor>],
+        softcapping: Option<f64>,
+    ) -> Result<Tensor> {
+        let (batch_size, attention_heads, seq_len, head_size) = query.dims4()?;
+        let (_, key_value_heads, _, _) = key.dims4()?;
+
+        let mut outputs = Vec::with_capacity(batch_size);
+        for (i, blocks) in prefix_block_tables.iter().enumerate().take(batch_size) {
+            let q = query.get(i)?;
+            let (k, v, num_cached) = match blocks {
+                Some(blocks) => {
+                    // key_cache: [num_blocks, num_kv_heads, head_size/x, block_size, x]
+                    let k_cached = key_cache
+                        .index_select(blocks, 0)?
+                        .permute((1, 0, 3, 2, 4))?
+                        .reshape((key_value_heads, (), head_size))?;
+                    // value_cache: [num_blocks, num_kv_heads, head_size, block_size]
+                    let v_cached = value_cache
+                        .index_select(blocks, 0)?
+                        .permute((1, 0, 3, 2))?
+                        .reshape((key_value_heads, (), head_size))?;
+                    let num_cached = k_cached.dim(1)?;
+                    (
+                        Tensor::cat(&[&k_cached, &key.get(i)?], 1)?,
+                        Tensor::cat(&[&v_cached, &value.get(i)?], 1)?,
+                        num_cached,
+                    )
+                }
+                None => (key.get(i)?, value.get(i)?, 0),
+            };
+            let n_rep = attention_heads / key_value_heads;
+            let k = crate::layers::repeat_kv(k.unsqueeze(0)?, n_rep)?.squeeze(0)?;
+            let v = crate::layers::repeat_kv(v.unsqueeze(0)?, n_rep)?.squeeze(0)?;
+
+            let att = (q.contiguous()?.matmul(&k.t()?.contiguous()?)? * self.scale as f64)?;
+            let att = match softcapping {
+                None => att,
+                Some(sc) => ((att / sc)?.tanh()? * sc)?,
+            };
+
+            let kv_len = num_cached + seq_len;
+            let mask: Vec<f32> = (0..seq_len)
+                .flat_map(|r| {
+                    let pos = num_cached + r;
+                    (0..kv_len).map(move |j| {
+                        let visible = j <= pos && self.sliding_window.map_or(true, |w| pos - j < w);
+                        if visible {
+                            0.
+                        } else {
+                            f32::NEG_INFINITY
+                        }
+                    })
+                })
+                .collect();
+            let mask =
+                Tensor::from_slice(&mask, (seq_len, kv_len), q.device())?.to_dtype(att.dtype())?;
+            let att = att.broadcast_add(&mask)?;
+            let att = candle_nn::ops::softmax_last_dim(&att)?;
+            outputs.push(att.matmul(&v.contiguous()?)?);
+        }
+        Tensor::stack(&outputs, 0)
+    }
 }
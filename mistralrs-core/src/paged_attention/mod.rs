@@ -19,6 +19,7 @@ pub use config::{ModelConfigLike, ModelConfigMetadata};
 pub use layers::PagedAttention;
 pub use scheduler::{
     PagedAttentionScheduler, PagedAttentionSchedulerConfig, PagedAttentionSchedulerOutput,
+    PreemptionMode,
 };
 
 use crate::MemoryUsage;
@@ -30,6 +31,7 @@ pub struct PagedAttentionConfig {
     pub(crate) block_size: Option<usize>,
     pub(crate) mem_cpu: usize,
     pub(crate) mem_gpu: MemoryGpuConfig,
+    pub(crate) preemption_mode: PreemptionMode,
 }
 
 impl PagedAttentionConfig {
@@ -37,13 +39,30 @@ impl PagedAttentionConfig {
         block_size: Option<usize>,
         mem_cpu: usize,
         mem_gpu: MemoryGpuConfig,
+        preemption_mode: PreemptionMode,
     ) -> anyhow::Result<Self> {
         Ok(Self {
             block_size,
             mem_cpu,
             mem_gpu,
+            preemption_mode,
         })
     }
+
+    /// The config of one of `n` models which are served together and share this memory budget.
+    /// Amounts of memory are divided evenly. A utilization is measured against the memory in use
+    /// when each model is loaded and a context size is per model, so they are kept.
+    pub fn shared_by(self, n: usize) -> Self {
+        let mem_gpu = match self.mem_gpu {
+            MemoryGpuConfig::Amount(v) => MemoryGpuConfig::Amount(v / n),
+            other => other,
+        };
+        Self {
+            mem_cpu: self.mem_cpu / n,
+            mem_gpu,
+            ..self
+        }
+    }
 }
 
 pub enum AttentionImplementation {
@@ -92,6 +111,7 @@ pub fn calculate_cache_config(
     mem_gpu: MemoryGpuConfig,
     mem_cpu: usize,
     block_size: Option<usize>,
+    preemption_mode: PreemptionMode,
     dtype: DType,
     config: &dyn ModelConfigLike,
     device: &Device,
@@ -127,5 +147,6 @@ pub fn calculate_cache_config(
         block_size,
         num_gpu_blocks,
         num_cpu_blocks,
+        preemption_mode,
     })
 }
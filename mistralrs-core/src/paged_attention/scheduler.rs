@@ -11,6 +11,7 @@ type DstBlocksTo = Vec<usize>;
 
 use std::{
     collections::{HashMap, VecDeque},
+    str::FromStr,
     sync::{atomic::Ordering, Arc, Mutex},
 };
 
@@ -19,6 +20,7 @@ use tracing::warn;
 use crate::{
     get_mut_arcmutex,
     paged_attention::BlockEngine,
+    request::RequestPriority,
     scheduler::{Scheduler, SchedulerOutput},
     sequence::{Sequence, SequenceState, StopReason},
     TERMINATE_ALL_NEXT_STEP,
@@ -26,82 +28,168 @@ use crate::{
 
 use super::{block_engine::AllocStatus, BlockEngineSequence, BlockTables, CacheConfig};
 
-pub struct PagedAttentionSchedulerOutput {
+/// The parts of a sequence the scheduler needs besides its blocks. Keeping the scheduler generic over this
+/// allows its block accounting to be tested without a model.
+pub trait SchedulerSequence: BlockEngineSequence {
+    fn set_state(&self, state: SequenceState);
+    fn is_finished_paged_attn(&self) -> bool;
+    fn is_embedding(&self) -> bool;
+    fn priority(&self) -> RequestPriority;
+    fn timestamp(&self) -> u128;
+    fn request_id(&self) -> usize;
+}
+
+impl SchedulerSequence for Sequence {
+    fn set_state(&self, state: SequenceState) {
+        Sequence::set_state(self, state)
+    }
+    fn is_finished_paged_attn(&self) -> bool {
+        Sequence::is_finished_paged_attn(self)
+    }
+    fn is_embedding(&self) -> bool {
+        Sequence::is_embedding(self)
+    }
+    fn priority(&self) -> RequestPriority {
+        Sequence::priority(self)
+    }
+    fn timestamp(&self) -> u128 {
+        Sequence::timestamp(self)
+    }
+    fn request_id(&self) -> usize {
+        Sequence::request_id(self)
+    }
+}
+
+pub struct PagedAttentionSchedulerOutput<S = Sequence> {
     /// Either ALL prompt or ALL completion.
-    pub scheduled: Vec<Arc<Mutex<Sequence>>>,
+    pub scheduled: Vec<Arc<Mutex<S>>>,
     pub blocks_to_swap_in: HashMap<CPUBlockFrom, GPUBlockTo>,
     pub blocks_to_swap_out: HashMap<GPUBlockFrom, CPUBlockTo>,
     pub blocks_to_copy: HashMap<SrcBlockFrom, DstBlocksTo>,
 }
 
+/// How a running sequence gives up its GPU blocks when they run out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PreemptionMode {
+    /// Move the KV cache blocks to CPU memory and copy them back when the sequence resumes. If the CPU swap
+    /// space is full, fall back to recomputation.
+    #[default]
+    Swap,
+    /// Free the blocks and run the prompt and generated tokens through the model again when the sequence resumes.
+    Recompute,
+}
+
+impl FromStr for PreemptionMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "swap" => Ok(Self::Swap),
+            "recompute" => Ok(Self::Recompute),
+            other => Err(format!(
+                "Unexpected preemption mode `{other}`, expected `swap` or `recompute`."
+            )),
+        }
+    }
+}
+
 pub struct PagedAttentionSchedulerConfig {
     pub max_num_seqs: usize,
+    pub preemption_mode: PreemptionMode,
+    /// Share the KV cache blocks of common prompt prefixes between sequences.
+    pub prefix_caching: bool,
 }
 
-pub struct PagedAttentionScheduler {
-    waiting: VecDeque<Arc<Mutex<Sequence>>>,
-    running: VecDeque<Arc<Mutex<Sequence>>>,
-    swapped_out: VecDeque<Arc<Mutex<Sequence>>>,
+pub struct PagedAttentionScheduler<S: SchedulerSequence = Sequence> {
+    waiting: VecDeque<Arc<Mutex<S>>>,
+    running: VecDeque<Arc<Mutex<S>>>,
+    swapped_out: VecDeque<Arc<Mutex<S>>>,
     config: PagedAttentionSchedulerConfig,
     pub block_engine: BlockEngine,
     block_size: usize,
 }
 
-impl PagedAttentionScheduler {
+impl<S: SchedulerSequence> PagedAttentionScheduler<S> {
     pub fn new(config: PagedAttentionSchedulerConfig, cache_config: CacheConfig) -> Self {
         Self {
             waiting: VecDeque::new(),
             running: VecDeque::new(),
             swapped_out: VecDeque::new(),
-            config,
             block_engine: BlockEngine::new(
                 cache_config.block_size,
                 cache_config.num_gpu_blocks,
                 cache_config.num_cpu_blocks,
+                config.prefix_caching,
             ),
+            config,
             block_size: cache_config.block_size,
         }
     }
 
-    pub fn schedule(&mut self) -> PagedAttentionSchedulerOutput {
+    pub fn add_seq(&mut self, seq: S) {
+        self.waiting.push_back(Arc::new(Mutex::new(seq)));
+    }
+
+    pub fn schedule(&mut self) -> PagedAttentionSchedulerOutput<S> {
+        // The prompts scheduled last time have been run, so their blocks can be shared now.
+        self.block_engine.cache_computed_blocks();
+
+        let mut blocks_to_swap_out = HashMap::new();
+
         // If there are no swapped seqs (they have higher priority), add seqs that are in the
         // waiting queue to the running queue.
         if self.swapped_out.is_empty() {
+            self.sort_waiting_by_priority_fcfs();
+
             let mut scheduled = VecDeque::new();
             let mut did_ignore = false;
-            while !self.waiting.is_empty() {
-                let seq = self.waiting.front().unwrap().clone();
+            while let Some(seq) = self.waiting.pop_front() {
+                // Embedding sequences run a different forward pass, so do not batch them with others.
+                if scheduled.front().is_some_and(|first: &Arc<Mutex<S>>| {
+                    get_mut_arcmutex!(first).is_embedding() != get_mut_arcmutex!(seq).is_embedding()
+                }) {
+                    self.waiting.push_front(seq);
+                    break;
+                }
 
                 // If adding this seq means we will have too many, stop as no more could be added.
                 if self.config.max_num_seqs == self.running.len() + 1 {
+                    self.waiting.push_front(seq);
                     break;
                 }
 
                 // If we cannot allocate either now or in the future, either do not continue or remove the sequence.
                 let can_allocate = self.block_engine.can_allocate(&*get_mut_arcmutex!(seq));
+                let mut ignored = false;
                 match can_allocate {
-                    AllocStatus::Later => break, // If we can only allocate later, do not bother iterating over the rest.
+                    AllocStatus::Later => {
+                        // Make room by preempting running sequences of a lower priority. If there are none,
+                        // do not bother iterating over the rest.
+                        if !self.preempt_lower_priority_for(&seq, &mut blocks_to_swap_out) {
+                            self.waiting.push_front(seq);
+                            break;
+                        }
+                    }
                     AllocStatus::Impossible => {
-                        let id = *get_mut_arcmutex!(seq).id();
-                        let len = get_mut_arcmutex!(seq).get_toks().len();
+                        let id = get_mut_arcmutex!(seq).get_id();
+                        let len = get_mut_arcmutex!(seq).num_tokens();
                         warn!(
                             "Sequence {id} with length of {len} tokens is too long and exceeds capacity of block engine. Sequence will be ignored.",
                         );
                         get_mut_arcmutex!(seq).set_state(SequenceState::FinishedIgnored);
+                        ignored = true;
                         did_ignore = true;
                     }
-                    _ => {}
+                    AllocStatus::Ok => {}
                 }
 
-                if !did_ignore {
+                if !ignored {
                     get_mut_arcmutex!(seq).set_state(SequenceState::RunningPrompt);
                     let seq_handle = get_mut_arcmutex!(seq);
-                    self._allocate(&seq_handle);
+                    self._allocate(&*seq_handle);
                 }
 
-                let seq = self.waiting.pop_front().unwrap();
                 self.running.push_back(seq.clone());
-                if !did_ignore {
+                if !ignored {
                     scheduled.push_back(seq);
                 }
             }
@@ -112,22 +200,21 @@ impl PagedAttentionScheduler {
                     scheduled: scheduled.into(),
                     blocks_to_swap_in: HashMap::new(),
                     blocks_to_copy: HashMap::new(),
-                    blocks_to_swap_out: HashMap::new(),
+                    blocks_to_swap_out,
                 };
             }
         }
 
-        let mut blocks_to_swap_out = HashMap::new();
         let mut blocks_to_swap_in = HashMap::new();
         let mut blocks_to_copy = HashMap::new();
 
-        // Reserve token slots for the running sequence groups, preempting the lowest (earliest) first.
+        // Reserve token slots for the running sequence groups, preempting the lowest priority (latest) first.
         // Preempt lowest priority sequences that are in the running queue, forming a
         // new running queue that has the actually running sequences. Remember the preempted
         // sequences, which will be put into the waiting or swapped out state depending on
         // the preemption method (recompute or swap, respectively).
 
-        // Sorts by creation time, in descending order so that earliest are latest (first come first serve).
+        // Sorts by priority and then creation time, so that the lowest priority and latest are at the back.
         self.sort_running_by_priority_fcfs();
 
         let mut running = VecDeque::new();
@@ -168,7 +255,7 @@ impl PagedAttentionScheduler {
         // Try to swap in the swapped out sequences and add these to the
         // running state if possible.
 
-        // Sorts by creation time, in descending order so that earliest are latest (first come first serve).
+        // Sorts by priority and then creation time, so that the highest priority and earliest are swapped in first.
         self.sort_swapped_out_by_priority_fcfs();
 
         if !did_preempt {
@@ -176,7 +263,9 @@ impl PagedAttentionScheduler {
                 let seq = self.swapped_out.front().unwrap();
 
                 // If the GPU cannot handle the group being swapped in, stop
-                if !self.block_engine.can_swap_in_seq(&*get_mut_arcmutex!(seq)) {
+                if !self.block_engine.can_swap_in_seq(&*get_mut_arcmutex!(seq))
+                    || self.config.max_num_seqs <= self.running.len()
+                {
                     break;
                 }
 
@@ -212,6 +301,29 @@ impl PagedAttentionScheduler {
         }
     }
 
+    /// Remove all sequences of a request from every queue and free their blocks. The sequences may still be
+    /// referenced elsewhere, such as by the output of the last step, so they are returned behind their locks.
+    pub fn cancel_request(&mut self, request_id: usize) -> Vec<Arc<Mutex<S>>> {
+        let mut canceled = Vec::new();
+        for queue in [&mut self.waiting, &mut self.running, &mut self.swapped_out] {
+            queue.retain(|seq| {
+                if get_mut_arcmutex!(seq).request_id() == request_id {
+                    canceled.push(seq.clone());
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+
+        for seq in &canceled {
+            let id = get_mut_arcmutex!(seq).get_id();
+            // Waiting sequences were never allocated, which is a no-op for the block engine.
+            self._free(id);
+        }
+        canceled
+    }
+
     pub fn free_finished_sequence_groups(&mut self) {
         let mut to_free_ids = Vec::new();
         self.running.retain(|seq| {
@@ -229,9 +341,9 @@ impl PagedAttentionScheduler {
     }
 }
 
-impl PagedAttentionScheduler {
+impl<S: SchedulerSequence> PagedAttentionScheduler<S> {
     #[allow(dead_code)]
-    fn remove_seq(&mut self, seq_id: usize) -> Arc<Mutex<Sequence>> {
+    fn remove_seq(&mut self, seq_id: usize) -> Arc<Mutex<S>> {
         // Remove it if it is in waiting
         if let Some(idx) = self
             .waiting
@@ -261,7 +373,7 @@ impl PagedAttentionScheduler {
 
     fn _append_token_slot_to_seq(
         &mut self,
-        seq: &Sequence,
+        seq: &S,
         blocks_to_copy: &mut HashMap<usize, Vec<usize>>,
     ) {
         let op = self.block_engine.append_token_slot_to_seq(seq);
@@ -274,22 +386,59 @@ impl PagedAttentionScheduler {
         }
     }
 
+    #[allow(dead_code)]
     fn _abort_seq(&mut self, seq_id: usize) {
         let removed = self.remove_seq(seq_id);
         get_mut_arcmutex!(removed).set_state(SequenceState::FinishedAborted);
         self._free(seq_id);
     }
 
-    /// Preempt either by recomputation (for single sequence), or by swapping (for multiple).
-    fn _preempt(
+    /// Preempt running sequences of a lower priority than `seq`, lowest priority and latest first, until
+    /// there are enough free GPU blocks to allocate it. Returns whether `seq` can now be allocated.
+    fn preempt_lower_priority_for(
         &mut self,
-        seq: Arc<Mutex<Sequence>>,
-        _blocks_to_swap_out: &mut HashMap<usize, usize>,
-    ) {
-        self._preempt_by_recompute(seq)
+        seq: &Arc<Mutex<S>>,
+        blocks_to_swap_out: &mut HashMap<usize, usize>,
+    ) -> bool {
+        let priority = get_mut_arcmutex!(seq).priority();
+        loop {
+            if matches!(
+                self.block_engine.can_allocate(&*get_mut_arcmutex!(seq)),
+                AllocStatus::Ok
+            ) {
+                return true;
+            }
+            let victim = self
+                .running
+                .iter()
+                .enumerate()
+                .filter(|(_, other)| get_mut_arcmutex!(other).priority() < priority)
+                .min_by_key(|(_, other)| {
+                    let other = get_mut_arcmutex!(other);
+                    (other.priority(), std::cmp::Reverse(other.timestamp()))
+                })
+                .map(|(i, _)| i);
+            let Some(victim) = victim else {
+                return false;
+            };
+            let victim = self.running.remove(victim).unwrap();
+            self._preempt(victim, blocks_to_swap_out);
+        }
+    }
+
+    /// Preempt by swapping the blocks out to the CPU, or by recomputation if configured or if the CPU
+    /// swap space is full.
+    fn _preempt(&mut self, seq: Arc<Mutex<S>>, blocks_to_swap_out: &mut HashMap<usize, usize>) {
+        let can_swap = self.config.preemption_mode == PreemptionMode::Swap
+            && self.block_engine.can_swap_out_seq(&*get_mut_arcmutex!(seq));
+        if can_swap {
+            self._preempt_by_swap(seq, blocks_to_swap_out)
+        } else {
+            self._preempt_by_recompute(seq)
+        }
     }
 
-    fn _preempt_by_recompute(&mut self, seq: Arc<Mutex<Sequence>>) {
+    fn _preempt_by_recompute(&mut self, seq: Arc<Mutex<S>>) {
         get_mut_arcmutex!(seq).set_state(SequenceState::Waiting);
         self._free(get_mut_arcmutex!(seq).get_id());
         self.waiting.push_front(seq);
@@ -297,15 +446,9 @@ impl PagedAttentionScheduler {
 
     fn _preempt_by_swap(
         &mut self,
-        seq: Arc<Mutex<Sequence>>,
+        seq: Arc<Mutex<S>>,
         blocks_to_swap_out: &mut HashMap<usize, usize>,
     ) {
-        if !self.block_engine.can_swap_out_seq(&*get_mut_arcmutex!(seq)) {
-            // If we cannot swap it out, abort the sequence group.
-            let id = get_mut_arcmutex!(seq).get_id();
-            self._abort_seq(id);
-            return;
-        }
         let new_to_swap = self.block_engine.swap_out(&*get_mut_arcmutex!(seq));
         blocks_to_swap_out.extend(new_to_swap);
         get_mut_arcmutex!(seq).set_state(SequenceState::Swapped);
@@ -313,7 +456,7 @@ impl PagedAttentionScheduler {
         self.swapped_out.push_back(seq);
     }
 
-    fn _allocate(&mut self, seq: &Sequence) {
+    fn _allocate(&mut self, seq: &S) {
         self.block_engine.allocate(seq)
     }
 
@@ -321,24 +464,33 @@ impl PagedAttentionScheduler {
         self.block_engine.free_sequence(seq_id);
     }
 
+    /// Highest priority first, earliest first within a priority.
+    fn sort_by_priority_fcfs(queue: &mut VecDeque<Arc<Mutex<S>>>) {
+        queue.make_contiguous().sort_by_key(|seq| {
+            let seq = get_mut_arcmutex!(seq);
+            (std::cmp::Reverse(seq.priority()), seq.timestamp())
+        });
+    }
+
     fn sort_running_by_priority_fcfs(&mut self) {
-        self.running
-            .make_contiguous()
-            .sort_by_key(|seq| get_mut_arcmutex!(seq).timestamp());
-        self.running.make_contiguous().reverse();
+        Self::sort_by_priority_fcfs(&mut self.running);
     }
 
     fn sort_swapped_out_by_priority_fcfs(&mut self) {
-        self.swapped_out
+        Self::sort_by_priority_fcfs(&mut self.swapped_out);
+    }
+
+    /// Only reorders between priorities, so sequences preempted by recomputation stay ahead within theirs.
+    fn sort_waiting_by_priority_fcfs(&mut self) {
+        self.waiting
             .make_contiguous()
-            .sort_by_key(|seq| get_mut_arcmutex!(seq).timestamp());
-        self.swapped_out.make_contiguous().reverse();
+            .sort_by_key(|seq| std::cmp::Reverse(get_mut_arcmutex!(seq).priority()));
     }
 }
 
 impl Scheduler for PagedAttentionScheduler {
     fn add_seq(&mut self, seq: Sequence) {
-        self.waiting.push_back(Arc::new(Mutex::new(seq)));
+        self.add_seq(seq)
     }
     fn schedule(&mut self) -> SchedulerOutput<'_> {
         SchedulerOutput::PagedAttention {
@@ -360,7 +512,321 @@ impl Scheduler for PagedAttentionScheduler {
     fn free_finished_sequence_groups(&mut self) {
         self.free_finished_sequence_groups()
     }
+    fn cancel_request(&mut self, request_id: usize) -> Vec<Arc<Mutex<Sequence>>> {
+        self.cancel_request(request_id)
+    }
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         Some(&mut self.block_engine)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex, RwLock};
+
+    use super::{
+        PagedAttentionScheduler, PagedAttentionSchedulerConfig, PreemptionMode, SchedulerSequence,
+    };
+    use crate::{
+        paged_attention::{BlockEngineSequence, CacheConfig, LogicalTokenBlock},
+        request::RequestPriority,
+        sequence::{SequenceState, StopReason},
+    };
+
+    const BLOCK_SIZE: usize = 4;
+
+    /// Mirrors the logical blocks of a `Sequence`, which always has room for the next token.
+    struct TestSeq {
+        id: usize,
+        tokens: Vec<usize>,
+        priority: RequestPriority,
+        state: RwLock<SequenceState>,
+    }
+
+    impl TestSeq {
+        /// A sequence which shares no prefix with the others.
+        fn new(id: usize, num_tokens: usize, priority: RequestPriority) -> Self {
+            Self::with_tokens(
+                id,
+                (0..num_tokens).map(|t| id * 1000 + t).collect(),
+                priority,
+            )
+        }
+
+        fn with_tokens(id: usize, tokens: Vec<usize>, priority: RequestPriority) -> Self {
+            Self {
+                id,
+                tokens,
+                priority,
+                state: RwLock::new(SequenceState::Waiting),
+            }
+        }
+
+        fn state(&self) -> SequenceState {
+            *self.state.read().unwrap()
+        }
+    }
+
+    impl BlockEngineSequence for TestSeq {
+        fn blocks_to_add_new_tok(&self) -> usize {
+            usize::from(self.tokens.len() % BLOCK_SIZE == 0)
+        }
+        fn get_id(&self) -> usize {
+            self.id
+        }
+        fn get_logical_token_blocks(&self) -> usize {
+            self.tokens.len() / BLOCK_SIZE + 1
+        }
+        fn num_tokens(&self) -> usize {
+            self.tokens.len()
+        }
+        fn block_hashes(&self) -> Vec<u64> {
+            let mut hash = 0;
+            self.tokens
+                .chunks_exact(BLOCK_SIZE)
+                .map(|toks| {
+                    let mut block = LogicalTokenBlock::new(BLOCK_SIZE);
+                    toks.iter().for_each(|tok| block.append_token_id(*tok));
+                    hash = block.hash_with_prefix(hash);
+                    hash
+                })
+                .collect()
+        }
+    }
+
+    impl SchedulerSequence for TestSeq {
+        fn set_state(&self, state: SequenceState) {
+            *self.state.write().unwrap() = state;
+        }
+        fn is_finished_paged_attn(&self) -> bool {
+            matches!(
+                self.state(),
+                SequenceState::FinishedAborted
+                    | SequenceState::FinishedIgnored
+                    | SequenceState::Done(_)
+            )
+        }
+        fn is_embedding(&self) -> bool {
+            false
+        }
+        fn priority(&self) -> RequestPriority {
+            self.priority
+        }
+        fn timestamp(&self) -> u128 {
+            self.id as u128
+        }
+        fn request_id(&self) -> usize {
+            self.id
+        }
+    }
+
+    fn scheduler(
+        preemption_mode: PreemptionMode,
+        num_cpu_blocks: usize,
+    ) -> PagedAttentionScheduler<TestSeq> {
+        PagedAttentionScheduler::new(
+            PagedAttentionSchedulerConfig {
+                max_num_seqs: 8,
+                preemption_mode,
+                prefix_caching: false,
+            },
+            CacheConfig {
+                block_size: BLOCK_SIZE,
+                num_gpu_blocks: 4,
+                num_cpu_blocks,
+                preemption_mode,
+            },
+        )
+    }
+
+    /// Schedule a step and generate a token for each scheduled sequence.
+    fn step(
+        scheduler: &mut PagedAttentionScheduler<TestSeq>,
+    ) -> (Vec<Arc<Mutex<TestSeq>>>, usize, usize) {
+        let output = scheduler.schedule();
+        for seq in &output.scheduled {
+            let mut seq = seq.lock().unwrap();
+            let tok = seq.id * 1000 + seq.tokens.len();
+            seq.tokens.push(tok);
+        }
+        (
+            output.scheduled,
+            output.blocks_to_swap_out.len(),
+            output.blocks_to_swap_in.len(),
+        )
+    }
+
+    #[test]
+    fn test_preempt_by_swap() {
+        let mut scheduler = scheduler(PreemptionMode::Swap, 4);
+        scheduler.add_seq(TestSeq::new(0, 6, RequestPriority::Low));
+        let (scheduled, _, _) = step(&mut scheduler);
+        let low = scheduled[0].clone();
+        assert_eq!(scheduler.block_engine.num_free_gpu_blocks(), 2);
+
+        // Needs 3 blocks, so the low priority sequence is swapped out to make room.
+        scheduler.add_seq(TestSeq::new(1, 10, RequestPriority::High));
+        let (scheduled, swapped_out, _) = step(&mut scheduler);
+        assert_eq!(scheduled.len(), 1);
+        assert_eq!(scheduled[0].lock().unwrap().id, 1);
+        assert_eq!(swapped_out, 2);
+        assert_eq!(low.lock().unwrap().state(), SequenceState::Swapped);
+        assert_eq!(scheduler.block_engine.num_free_gpu_blocks(), 1);
+        assert_eq!(scheduler.block_engine.num_free_cpu_blocks(), 2);
+
+        // Not enough GPU blocks to swap it back in yet.
+        let (scheduled, _, swapped_in) = step(&mut scheduler);
+        let high = scheduled[0].clone();
+        assert_eq!(scheduled.len(), 1);
+        assert_eq!(swapped_in, 0);
+
+        high.lock()
+            .unwrap()
+            .set_state(SequenceState::Done(StopReason::Eos));
+        scheduler.free_finished_sequence_groups();
+        assert_eq!(scheduler.block_engine.num_free_gpu_blocks(), 4);
+
+        let (scheduled, _, swapped_in) = step(&mut scheduler);
+        assert_eq!(scheduled.len(), 1);
+        assert_eq!(swapped_in, 2);
+        assert_eq!(
+            low.lock().unwrap().state(),
+            SequenceState::RunningCompletion
+        );
+        assert_eq!(scheduler.block_engine.num_free_gpu_blocks(), 2);
+        assert_eq!(scheduler.block_engine.num_free_cpu_blocks(), 4);
+    }
+
+    #[test]
+    fn test_preempt_by_recompute() {
+        // Without CPU swap space, swapping falls back to recomputation.
+        for (mode, num_cpu_blocks) in [(PreemptionMode::Recompute, 4), (PreemptionMode::Swap, 0)] {
+            let mut scheduler = scheduler(mode, num_cpu_blocks);
+            scheduler.add_seq(TestSeq::new(0, 6, RequestPriority::Low));
+            let (scheduled, _, _) = step(&mut scheduler);
+            let low = scheduled[0].clone();
+
+            scheduler.add_seq(TestSeq::new(1, 10, RequestPriority::High));
+            let (scheduled, swapped_out, _) = step(&mut scheduler);
+            let high = scheduled[0].clone();
+            assert_eq!(scheduled.len(), 1);
+            assert_eq!(swapped_out, 0);
+            assert_eq!(low.lock().unwrap().state(), SequenceState::Waiting);
+            assert_eq!(scheduler.block_engine.num_free_gpu_blocks(), 1);
+            assert_eq!(scheduler.block_engine.num_free_cpu_blocks(), num_cpu_blocks);
+
+            high.lock()
+                .unwrap()
+                .set_state(SequenceState::Done(StopReason::Eos));
+            scheduler.free_finished_sequence_groups();
+
+            // The prompt and the generated token are run again.
+            let (scheduled, _, _) = step(&mut scheduler);
+            assert_eq!(scheduled.len(), 1);
+            assert_eq!(low.lock().unwrap().state(), SequenceState::RunningPrompt);
+            assert_eq!(scheduler.block_engine.num_free_gpu_blocks(), 2);
+        }
+    }
+
+    #[test]
+    fn test_no_preemption_within_priority() {
+        let mut scheduler = scheduler(PreemptionMode::Swap, 4);
+        scheduler.add_seq(TestSeq::new(0, 6, RequestPriority::Normal));
+        step(&mut scheduler);
+
+        scheduler.add_seq(TestSeq::new(1, 10, RequestPriority::Normal));
+        let (scheduled, swapped_out, _) = step(&mut scheduler);
+        assert_eq!(scheduled.len(), 1);
+        assert_eq!(scheduled[0].lock().unwrap().id, 0);
+        assert_eq!(swapped_out, 0);
+        assert_eq!(scheduler.waiting.len(), 1);
+    }
+
+    #[test]
+    fn test_prefix_caching() {
+        let mut scheduler = PagedAttentionScheduler::new(
+            PagedAttentionSchedulerConfig {
+                max_num_seqs: 8,
+                preemption_mode: PreemptionMode::Swap,
+                prefix_caching: true,
+            },
+            CacheConfig {
+                block_size: BLOCK_SIZE,
+                num_gpu_blocks: 6,
+                num_cpu_blocks: 0,
+                preemption_mode: PreemptionMode::Swap,
+            },
+        );
+        let system_prompt = (0..2 * BLOCK_SIZE).collect::<Vec<_>>();
+        let prompt = |id: usize, len: usize| {
+            let mut tokens = system_prompt.clone();
+            tokens.extend((0..len).map(|t| id * 1000 + t));
+            TestSeq::with_tokens(id, tokens, RequestPriority::Normal)
+        };
+
+        scheduler.add_seq(prompt(0, 1));
+        let (scheduled, _, _) = step(&mut scheduler);
+        let first = scheduled[0].clone();
+        assert_eq!(scheduler.block_engine.num_cached_tokens(0), 0);
+        assert_eq!(scheduler.block_engine.num_free_gpu_blocks(), 3);
+
+        // The system prompt blocks are shared once the first prompt has run.
+        scheduler.add_seq(prompt(1, 2));
+        let (scheduled, _, _) = step(&mut scheduler);
+        let second = scheduled[0].clone();
+        assert_eq!(second.lock().unwrap().id, 1);
+        assert_eq!(scheduler.block_engine.num_cached_tokens(1), 2 * BLOCK_SIZE);
+        assert_eq!(scheduler.block_engine.num_free_gpu_blocks(), 2);
+        let first_table = &scheduler.block_engine.block_tables[&0];
+        let second_table = &scheduler.block_engine.block_tables[&1];
+        for i in 0..2 {
+            assert!(Arc::ptr_eq(&first_table[i], &second_table[i]));
+        }
+        assert!(!Arc::ptr_eq(&first_table[2], &second_table[2]));
+
+        // Cached blocks stay allocated after their sequences finish, and are evicted when needed.
+        for seq in [first, second] {
+            seq.lock()
+                .unwrap()
+                .set_state(SequenceState::Done(StopReason::Eos));
+        }
+        scheduler.free_finished_sequence_groups();
+        assert_eq!(scheduler.block_engine.num_free_gpu_blocks(), 6);
+        scheduler.add_seq(TestSeq::new(2, 4 * BLOCK_SIZE, RequestPriority::Normal));
+        let (scheduled, _, _) = step(&mut scheduler);
+        assert_eq!(scheduled.len(), 1);
+        assert_eq!(scheduler.block_engine.num_free_gpu_blocks(), 1);
+    }
+
+    #[test]
+    fn test_cancel_request_with_outstanding_reference() {
+        let mut scheduler = scheduler(PreemptionMode::Swap, 4);
+        scheduler.add_seq(TestSeq::new(0, 6, RequestPriority::Normal));
+        scheduler.add_seq(TestSeq::new(1, 2, RequestPriority::Normal));
+        let (scheduled, _, _) = step(&mut scheduler);
+        assert_eq!(scheduled.len(), 2);
+        assert_eq!(scheduler.block_engine.num_free_gpu_blocks(), 1);
+
+        // The output of the last step still references the sequence, which is returned nonetheless.
+        let canceled = scheduler.cancel_request(0);
+        assert_eq!(canceled.len(), 1);
+        let first = scheduled
+            .iter()
+            .find(|seq| seq.lock().unwrap().id == 0)
+            .unwrap();
+        assert!(Arc::ptr_eq(&canceled[0], first));
+        canceled[0]
+            .lock()
+            .unwrap()
+            .set_state(SequenceState::Done(StopReason::Canceled));
+        assert_eq!(
+            first.lock().unwrap().state(),
+            SequenceState::Done(StopReason::Canceled)
+        );
+        assert_eq!(scheduler.block_engine.num_free_gpu_blocks(), 3);
+
+        let (scheduled, _, _) = step(&mut scheduler);
+        assert_eq!(scheduled.len(), 1);
+        assert_eq!(scheduled[0].lock().unwrap().id, 1);
+    }
+}
@@ -25,7 +25,7 @@ use crate::{
     sequence::{SeqStepType, Sequence, SequenceGroup, SequenceRecognizer},
     utils::progress::NiceProgressBar,
     DeviceMapMetadata, Loader, ModelCategory, ModelKind, ModelPaths, PagedAttentionConfig,
-    Pipeline, Response, TokenSource, TryIntoDType,
+    Pipeline, RequestPriority, Response, TokenSource, TryIntoDType,
 };
 
 use super::{
@@ -244,6 +244,13 @@ impl Pipeline for AnyMoePipeline {
         get_mut_arcmutex!(self.target).forward_inputs(inputs)
     }
 
+    fn forward_inputs_hidden_states(
+        &mut self,
+        inputs: Box<dyn Any>,
+    ) -> Result<ForwardInputsResult, candle_core::Error> {
+        get_mut_arcmutex!(self.target).forward_inputs_hidden_states(inputs)
+    }
+
     async fn sample_causal_gen(
         &self,
         seqs: &mut [&mut Sequence],
@@ -361,9 +368,16 @@ impl AnyMoePipelineMixin for AnyMoePipeline {
             None,
             None,
             None,
+            None,
+            None,
             -1,
             0.0,
             0.0,
+            1.0,
+            0.0,
+            1.0,
+            None,
+            None,
             vec![],
         )
         .map_err(candle_core::Error::msg)?;
@@ -549,6 +563,9 @@ fn new_dummy_seq(
         prompt,
         0,
         0,
+        RequestPriority::default(),
+        None,
+        0,
         1,
         dummy_sender,
         dummy_sampler,
@@ -571,5 +588,6 @@ fn new_dummy_seq(
         None,
         SeqStepType::PromptAndDecode,
         None,
+        None,
     )
 }
@@ -1,6 +1,11 @@
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+};
 
-use candle_core::{Tensor, D};
+use candle_core::{DType, Device, Result, Tensor, D};
+use float8::F8E4M3;
+use serde::Deserialize;
 
 use crate::{get_mut_arcmutex, sequence::Sequence};
 
@@ -17,7 +22,618 @@ pub trait CacheManager<T: CacheManagerMixin + MetadataMixin + ?Sized> {
     fn set_none_cache(&self, pipeline: &T, modify_draft_cache: bool);
 }
 
-pub type LayerCaches = Vec<Option<(Tensor, Tensor)>>;
+pub type LayerCaches = Vec<Option<KvCache>>;
+
+/// The per layer states of a recurrent model such as Mamba, which take the place of the KV cache.
+pub type LayerStates = Vec<Option<RecurrentState>>;
+
+/// The number of positions by which the buffers of a [`KvCache`] grow.
+pub const KV_CACHE_CHUNK_SIZE: usize = 512;
+
+/// The number of values of the head dimension sharing a scale in a quantized [`KvCache`].
+pub const KV_CACHE_BLOCK_SIZE: usize = 32;
+
+/// The type in which a [`KvCache`] stores keys and values.
+///
+/// The quantized types reduce the memory used to store the cache, but the cached keys and values of
+/// a layer are dequantized in full each time the layer attends, which costs time proportional to
+/// the cached length on every decoding step. The peak memory usage is the quantized cache plus the
+/// keys and values of one layer dequantized to the model dtype, through an F32 intermediate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum KvCacheType {
+    /// The dtype of the model activations.
+    #[default]
+    #[serde(rename = "auto")]
+    Auto,
+    /// 8-bit integers with a scale per block of [`KV_CACHE_BLOCK_SIZE`] values, like Q8_0.
+    #[serde(rename = "q8_0")]
+    Q8_0,
+    /// F8E4M3 with a scale per block of [`KV_CACHE_BLOCK_SIZE`] values.
+    #[serde(rename = "f8e4m3")]
+    F8E4M3,
+}
+
+impl FromStr for KvCacheType {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "q8_0" | "int8" => Ok(Self::Q8_0),
+            "f8e4m3" | "fp8" => Ok(Self::F8E4M3),
+            other => Err(format!(
+                "Expected KV cache type `auto`, `q8_0` or `f8e4m3`, got `{other}`"
+            )),
+        }
+    }
+}
+
+/// The number of values sharing a scale, which is the whole head dimension if it is not divisible
+/// by [`KV_CACHE_BLOCK_SIZE`].
+fn block_size(head_dim: usize) -> usize {
+    if head_dim % KV_CACHE_BLOCK_SIZE == 0 {
+        KV_CACHE_BLOCK_SIZE
+    } else {
+        head_dim
+    }
+}
+
+/// Quantize `x` of shape `(bs, n_kv_heads, seq_len, head_dim)`, returning the quantized values
+/// and the scales of shape `(bs, n_kv_heads, seq_len, n_blocks)`.
+fn quantize(x: &Tensor, ty: KvCacheType) -> Result<(Tensor, Tensor)> {
+    let (bs, n_kv_heads, seq_len, head_dim) = x.dims4()?;
+    let block_size = block_size(head_dim);
+    let x = x.to_dtype(DType::F32)?.reshape((
+        bs,
+        n_kv_heads,
+        seq_len,
+        head_dim / block_size,
+        block_size,
+    ))?;
+    let max_value = match ty {
+        KvCacheType::Auto => candle_core::bail!("Cannot quantize to the `auto` KV cache type."),
+        KvCacheType::Q8_0 => 127.,
+        KvCacheType::F8E4M3 => f64::from(F8E4M3::MAX.to_f32()),
+    };
+    let scales = (x.abs()?.max_keepdim(D::Minus1)? / max_value)?.to_dtype(DType::F16)?;
+    // Quantize with the rounded scales, avoiding a division by zero for blocks of zeros.
+    let q = x
+        .broadcast_div(&scales.to_dtype(DType::F32)?.maximum(f32::MIN_POSITIVE)?)?
+        .reshape((bs, n_kv_heads, seq_len, head_dim))?;
+    let q = match ty {
+        KvCacheType::Q8_0 => (q.round()?.clamp(-127f32, 127f32)? + 128.)?.to_dtype(DType::U8)?,
+        _ => q.clamp(-max_value, max_value)?.to_dtype(DType::F8E4M3)?,
+    };
+    Ok((q, scales.squeeze(D::Minus1)?.contiguous()?))
+}
+
+/// Dequantize the values `q` with their `scales` to `dtype`.
+fn dequantize(q: &Tensor, scales: &Tensor, ty: KvCacheType, dtype: DType) -> Result<Tensor> {
+    let (bs, n_kv_heads, seq_len, head_dim) = q.dims4()?;
+    let n_blocks = scales.dim(D::Minus1)?;
+    let mut x = q.to_dtype(DType::F32)?;
+    if ty == KvCacheType::Q8_0 {
+        x = (x - 128.)?;
+    }
+    x.reshape((bs, n_kv_heads, seq_len, n_blocks, head_dim / n_blocks))?
+        .broadcast_mul(&scales.to_dtype(DType::F32)?.unsqueeze(D::Minus1)?)?
+        .reshape((bs, n_kv_heads, seq_len, head_dim))?
+        .to_dtype(dtype)
+}
+
+/// A buffer of keys or values of shape `(bs, n_kv_heads, capacity, head_dim)`.
+#[derive(Debug, Clone)]
+struct KvBuffer {
+    /// The values, which are quantized unless the type is [`KvCacheType::Auto`].
+    data: Tensor,
+    /// The scales of the quantized values, of shape `(bs, n_kv_heads, capacity, n_blocks)`.
+    scales: Option<Tensor>,
+    ty: KvCacheType,
+    /// The dtype of the keys or values before quantization.
+    dtype: DType,
+}
+
+impl KvBuffer {
+    /// A buffer holding exactly `x`.
+    fn new(x: Tensor, ty: KvCacheType) -> Result<Self> {
+        let dtype = x.dtype();
+        let (data, scales) = match ty {
+            KvCacheType::Auto => (x, None),
+            ty => {
+                let (q, scales) = quantize(&x, ty)?;
+                (q, Some(scales))
+            }
+        };
+        Ok(Self {
+            data,
+            scales,
+            ty,
+            dtype,
+        })
+    }
+
+    /// A zeroed buffer for `capacity` positions like those of `x`.
+    fn zeros(x: &Tensor, capacity: usize, ty: KvCacheType) -> Result<Self> {
+        let mut dims = x.dims().to_vec();
+        dims[2] = capacity;
+        let (data_dtype, scales) = match ty {
+            KvCacheType::Auto => (x.dtype(), None),
+            KvCacheType::Q8_0 | KvCacheType::F8E4M3 => {
+                let mut scales_dims = dims.clone();
+                scales_dims[3] /= block_size(dims[3]);
+                let scales = Tensor::zeros(scales_dims, DType::F16, x.device())?;
+                let data_dtype = if ty == KvCacheType::Q8_0 {
+                    DType::U8
+                } else {
+                    DType::F8E4M3
+                };
+                (data_dtype, Some(scales))
+            }
+        };
+        Ok(Self {
+            data: Tensor::zeros(dims, data_dtype, x.device())?,
+            scales,
+            ty,
+            dtype: x.dtype(),
+        })
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.dims()[2]
+    }
+
+    /// Apply `f` to the values and the scales.
+    fn map(&self, f: impl Fn(&Tensor) -> Result<Tensor>) -> Result<Self> {
+        Ok(Self {
+            data: f(&self.data)?,
+            scales: self.scales.as_ref().map(&f).transpose()?,
+            ..self.clone()
+        })
+    }
+
+    /// Write `x` at position `pos`.
+    fn write(&self, x: &Tensor, pos: usize) -> Result<()> {
+        match &self.scales {
+            None => self.data.slice_set(&x.contiguous()?, 2, pos),
+            Some(scales) => {
+                let (q, x_scales) = quantize(x, self.ty)?;
+                self.data.slice_set(&q, 2, pos)?;
+                scales.slice_set(&x_scales, 2, pos)
+            }
+        }
+    }
+
+    /// The `len` positions starting at `start`, wrapping around, in the original dtype.
+    ///
+    /// A quantized buffer is dequantized in full, rather than in the attention kernels, so that
+    /// every attention implementation can use it. See [`KvCacheType`] for the cost.
+    fn get(&self, start: usize, len: usize) -> Result<Tensor> {
+        let data = ordered(&self.data, start, len)?;
+        match &self.scales {
+            None => Ok(data),
+            Some(scales) => dequantize(&data, &ordered(scales, start, len)?, self.ty, self.dtype),
+        }
+    }
+
+    /// Grow the buffer by `extra` zeroed positions.
+    fn grow(&self, extra: usize, slow_cat: bool) -> Result<Self> {
+        let slow_cat = slow_cat || self.scales.is_some();
+        self.map(|buf| {
+            let mut dims = buf.dims().to_vec();
+            dims[2] = extra;
+            cat_seq(
+                buf,
+                &Tensor::zeros(dims, buf.dtype(), buf.device())?,
+                slow_cat,
+            )
+        })
+    }
+
+    /// Concatenate buffers of the same type along the batch dimension.
+    fn cat(bufs: &[&Self]) -> Result<Self> {
+        let data = bufs.iter().map(|b| &b.data).collect::<Vec<_>>();
+        let scales = bufs
+            .iter()
+            .map(|b| b.scales.as_ref())
+            .collect::<Option<Vec<_>>>();
+        Ok(Self {
+            data: Tensor::cat(&data, 0)?,
+            scales: scales.map(|s| Tensor::cat(&s, 0)).transpose()?,
+            ..bufs[0].clone()
+        })
+    }
+
+    /// Split the buffer into `n` buffers along the batch dimension, which share its storage.
+    fn chunk(&self, n: usize) -> Result<Vec<Self>> {
+        let data = self.data.chunk(n, 0)?;
+        let scales = self.scales.as_ref().map(|s| s.chunk(n, 0)).transpose()?;
+        Ok(data
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| Self {
+                data,
+                scales: scales.as_ref().map(|s| s[i].clone()),
+                ..self.clone()
+            })
+            .collect())
+    }
+}
+
+/// The KV cache of a layer.
+///
+/// Keys and values are stored in preallocated buffers of shape
+/// `(bs, n_kv_heads, capacity, head_dim)`, whose capacity grows in chunks of
+/// [`KV_CACHE_CHUNK_SIZE`] positions, and new positions are written in place. For sliding window
+/// attention, the buffers become a ring buffer of `sliding_window` positions once the window is
+/// full.
+///
+/// The buffers may be quantized, see [`KvCacheType`], in which case the keys and values are
+/// quantized when written and dequantized when read for attention.
+///
+/// Cloning a cache shares its buffers, see [`KvCache::share`] for a clone which can be extended
+/// independently.
+#[derive(Debug, Clone)]
+pub struct KvCache {
+    k: KvBuffer,
+    v: KvBuffer,
+    /// The number of cached positions.
+    len: usize,
+    /// The index in the buffers of the oldest cached position, only nonzero for a ring buffer.
+    start: usize,
+    /// Whether the buffers may be used by another cache, in which case they are copied before
+    /// being written to.
+    shared: bool,
+}
+
+/// The capacity to allocate for `len` positions, which is at most `max_capacity` if they fit.
+fn chunked_capacity(len: usize, max_capacity: usize) -> usize {
+    let capacity = len.div_ceil(KV_CACHE_CHUNK_SIZE) * KV_CACHE_CHUNK_SIZE;
+    if len <= max_capacity {
+        capacity.min(max_capacity)
+    } else {
+        capacity
+    }
+}
+
+/// Concatenate along the sequence dimension.
+fn cat_seq(a: &Tensor, b: &Tensor, slow_cat: bool) -> Result<Tensor> {
+    if !slow_cat {
+        candle_nn::ops::kvconcat(a, b, 2)?.contiguous()
+    } else {
+        Tensor::cat(&[a, b], 2)?.contiguous()
+    }
+}
+
+/// The `len` positions of a buffer starting at `start`, wrapping around.
+fn ordered(buf: &Tensor, start: usize, len: usize) -> Result<Tensor> {
+    let capacity = buf.dim(2)?;
+    if start + len <= capacity {
+        buf.narrow(2, start, len)
+    } else {
+        Tensor::cat(
+            &[
+                buf.narrow(2, start, capacity - start)?,
+                buf.narrow(2, 0, start + len - capacity)?,
+            ],
+            2,
+        )
+    }
+}
+
+impl KvCache {
+    /// Create a cache holding `k` and `v`, of shape `(bs, n_kv_heads, seq_len, head_dim)`.
+    pub fn new(k: &Tensor, v: &Tensor) -> Result<Self> {
+        Self::with_max_capacity(k, v, usize::MAX)
+    }
+
+    fn with_max_capacity(k: &Tensor, v: &Tensor, max_capacity: usize) -> Result<Self> {
+        let capacity = chunked_capacity(k.dim(2)?, max_capacity);
+        Self::with_capacity(k, v, capacity, KvCacheType::Auto)
+    }
+
+    fn with_capacity(k: &Tensor, v: &Tensor, capacity: usize, ty: KvCacheType) -> Result<Self> {
+        let mut cache = Self {
+            k: KvBuffer::zeros(k, capacity, ty)?,
+            v: KvBuffer::zeros(v, capacity, ty)?,
+            len: k.dim(2)?,
+            start: 0,
+            shared: false,
+        };
+        cache.write(k, v, 0)?;
+        Ok(cache)
+    }
+
+    /// The number of cached positions.
+    pub fn current_seq_len(&self) -> usize {
+        self.len
+    }
+
+    /// The number of positions which can be cached without growing the buffers.
+    pub fn capacity(&self) -> usize {
+        self.k.capacity()
+    }
+
+    pub fn device(&self) -> &Device {
+        self.k.data.device()
+    }
+
+    pub fn cache_type(&self) -> KvCacheType {
+        self.k.ty
+    }
+
+    /// The cached keys, oldest first.
+    pub fn k(&self) -> Result<Tensor> {
+        self.k.get(self.start, self.len)
+    }
+
+    /// The cached values, oldest first.
+    pub fn v(&self) -> Result<Tensor> {
+        self.v.get(self.start, self.len)
+    }
+
+    /// A copy of this cache which stores the keys and values as `ty`, with the same capacity.
+    pub fn to_type(&self, ty: KvCacheType) -> Result<Self> {
+        Self::with_capacity(&self.k()?, &self.v()?, self.capacity(), ty)
+    }
+
+    /// A clone of this cache which copies the buffers before writing to them, so that it can be
+    /// extended without affecting this cache.
+    pub fn share(&self) -> Self {
+        Self {
+            shared: true,
+            ..self.clone()
+        }
+    }
+
+    pub fn to_device(&self, device: &Device) -> Result<Self> {
+        Ok(Self {
+            k: self.k.map(|buf| buf.to_device(device))?,
+            v: self.v.map(|buf| buf.to_device(device))?,
+            ..self.clone()
+        })
+    }
+
+    /// Remove the last `n` cached positions.
+    pub fn truncate(&mut self, n: usize) {
+        self.len -= n;
+    }
+
+    /// Concatenate the caches of sequences with the same number of cached positions along the
+    /// batch dimension.
+    pub fn cat(caches: &[Self]) -> Result<Self> {
+        let first = &caches[0];
+        if caches.iter().all(|c| {
+            c.capacity() == first.capacity()
+                && c.start == first.start
+                && c.len == first.len
+                && c.cache_type() == first.cache_type()
+        }) {
+            let k = caches.iter().map(|c| &c.k).collect::<Vec<_>>();
+            let v = caches.iter().map(|c| &c.v).collect::<Vec<_>>();
+            Ok(Self {
+                k: KvBuffer::cat(&k)?,
+                v: KvBuffer::cat(&v)?,
+                len: first.len,
+                start: first.start,
+                shared: false,
+            })
+        } else {
+            let k = caches.iter().map(Self::k).collect::<Result<Vec<_>>>()?;
+            let v = caches.iter().map(Self::v).collect::<Result<Vec<_>>>()?;
+            Self::new(&Tensor::cat(&k, 0)?, &Tensor::cat(&v, 0)?)
+        }
+    }
+
+    /// Split a batched cache into the caches of its `n` sequences, which share its buffers.
+    pub fn chunk(&self, n: usize) -> Result<Vec<Self>> {
+        let k = self.k.chunk(n)?;
+        let v = self.v.chunk(n)?;
+        Ok(k.into_iter()
+            .zip(v)
+            .map(|(k, v)| Self {
+                k,
+                v,
+                ..self.clone()
+            })
+            .collect())
+    }
+
+    fn write(&mut self, k: &Tensor, v: &Tensor, pos: usize) -> Result<()> {
+        self.k.write(k, pos)?;
+        self.v.write(v, pos)
+    }
+
+    /// Copy the buffers if they may be used by another cache.
+    fn make_exclusive(&mut self) -> Result<()> {
+        if self.shared {
+            let copy = |buf: &Tensor| -> Result<Tensor> {
+                let new = buf.zeros_like()?;
+                new.slice_set(buf, 2, 0)?;
+                Ok(new)
+            };
+            self.k = self.k.map(copy)?;
+            self.v = self.v.map(copy)?;
+            self.shared = false;
+        }
+        Ok(())
+    }
+
+    /// Replace the buffers with new ones holding exactly `k` and `v`.
+    fn replace(&mut self, k: Tensor, v: Tensor) -> Result<()> {
+        self.len = k.dim(2)?;
+        self.start = 0;
+        self.shared = false;
+        self.k = KvBuffer::new(k, self.k.ty)?;
+        self.v = KvBuffer::new(v, self.v.ty)?;
+        Ok(())
+    }
+
+    /// The last `sliding_window - 1` cached keys and values, to which a new position is appended
+    /// once the window is full.
+    fn last_window(&self, sliding_window: usize) -> Result<(Tensor, Tensor)> {
+        let start = self.len - (sliding_window - 1);
+        Ok((
+            self.k()?.narrow(2, start, sliding_window - 1)?,
+            self.v()?.narrow(2, start, sliding_window - 1)?,
+        ))
+    }
+
+    /// Append `k` and `v` after the cached positions, growing the buffers up to `max_capacity`
+    /// positions if they fit, and return all the cached keys and values.
+    fn append_linear(
+        &mut self,
+        k: &Tensor,
+        v: &Tensor,
+        max_capacity: usize,
+        slow_cat: bool,
+    ) -> Result<(Tensor, Tensor)> {
+        let len = self.len + k.dim(2)?;
+        if self.start != 0 {
+            let (start, len) = (self.start, self.len);
+            let in_order = |buf: &Tensor| ordered(buf, start, len)?.contiguous();
+            self.k = self.k.map(in_order)?;
+            self.v = self.v.map(in_order)?;
+            self.start = 0;
+            self.shared = false;
+        }
+        if len > self.capacity() {
+            let extra = chunked_capacity(len, max_capacity) - self.capacity();
+            self.k = self.k.grow(extra, slow_cat)?;
+            self.v = self.v.grow(extra, slow_cat)?;
+            self.shared = false;
+        } else {
+            self.make_exclusive()?;
+        }
+        self.write(k, v, self.len)?;
+        self.len = len;
+        Ok((self.k.get(0, len)?, self.v.get(0, len)?))
+    }
+
+    /// Append `k` and `v` and return all the cached keys and values.
+    pub fn append(&mut self, k: &Tensor, v: &Tensor, slow_cat: bool) -> Result<(Tensor, Tensor)> {
+        self.append_linear(k, v, usize::MAX, slow_cat)
+    }
+
+    /// Append `k` and `v` for attention over the last `sliding_window` positions, and return the
+    /// keys, values and attention mask to attend with.
+    pub fn append_sliding_window(
+        &mut self,
+        k: &Tensor,
+        v: &Tensor,
+        attention_mask: Option<&Tensor>,
+        sliding_window: usize,
+        slow_cat: bool,
+    ) -> Result<(Tensor, Tensor, Option<Tensor>)> {
+        // A single position without a mask attends to all the cached positions regardless of
+        // their order, so the buffers are used as a ring buffer once the window is full.
+        if k.dim(2)? == 1 && attention_mask.is_none() {
+            if self.capacity() == sliding_window {
+                self.make_exclusive()?;
+                self.write(k, v, (self.start + self.len) % sliding_window)?;
+                if self.len == sliding_window {
+                    self.start = (self.start + 1) % sliding_window;
+                    return Ok((
+                        self.k.get(0, sliding_window)?,
+                        self.v.get(0, sliding_window)?,
+                        None,
+                    ));
+                }
+                self.len += 1;
+                return Ok((self.k()?, self.v()?, None));
+            }
+            if self.len >= sliding_window {
+                // The buffers now hold exactly the window.
+                let (prev_k, prev_v) = self.last_window(sliding_window)?;
+                let k = cat_seq(&prev_k, k, slow_cat)?;
+                let v = cat_seq(&prev_v, v, slow_cat)?;
+                self.replace(k.clone(), v.clone())?;
+                return Ok((k, v, None));
+            }
+        }
+
+        let mut mask = attention_mask.cloned();
+        if self.len > sliding_window {
+            let (prev_k, prev_v) = self.last_window(sliding_window)?;
+            if let Some(ref mut mask) = mask {
+                let mask_len = mask.dim(1)?;
+                *mask = mask.narrow(1, mask_len - (sliding_window - 1), sliding_window - 1)?;
+                *mask = Tensor::cat(
+                    &[&*mask, &mask.narrow(1, mask_len - 1, 1)?.ones_like()?],
+                    D::Minus1,
+                )?;
+            }
+            let k = cat_seq(&prev_k, k, slow_cat)?;
+            let v = cat_seq(&prev_v, v, slow_cat)?;
+            self.replace(k.clone(), v.clone())?;
+            return Ok((k, v, mask));
+        }
+        let (k, v) = self.append_linear(k, v, sliding_window, slow_cat)?;
+        Ok((k, v, mask))
+    }
+}
+
+/// The fixed size state of a recurrent (state space model) layer. Unlike a [`KvCache`], it does
+/// not grow with the sequence, and a new state replaces the old one at every step.
+#[derive(Debug, Clone)]
+pub struct RecurrentState {
+    /// The last `conv_kernel - 1` inputs of the causal convolution, `(bs, d_inner, conv_kernel - 1)`.
+    pub conv: Tensor,
+    /// The hidden state of the selective scan, `(bs, d_inner, d_state)`.
+    pub ssm: Tensor,
+}
+
+impl RecurrentState {
+    pub fn new(conv: Tensor, ssm: Tensor) -> Self {
+        Self { conv, ssm }
+    }
+
+    /// The initial state of a single sequence with the shape of this one.
+    pub fn zeros_like(&self) -> Result<Self> {
+        Ok(Self {
+            conv: self.conv.narrow(0, 0, 1)?.zeros_like()?,
+            ssm: self.ssm.narrow(0, 0, 1)?.zeros_like()?,
+        })
+    }
+
+    pub fn device(&self) -> &Device {
+        self.ssm.device()
+    }
+
+    pub fn to_device(&self, device: &Device) -> Result<Self> {
+        Ok(Self {
+            conv: self.conv.to_device(device)?,
+            ssm: self.ssm.to_device(device)?,
+        })
+    }
+
+    /// Concatenate the states of several sequences along the batch dimension.
+    pub fn cat(states: &[Self]) -> Result<Self> {
+        let conv = states.iter().map(|s| &s.conv).collect::<Vec<_>>();
+        let ssm = states.iter().map(|s| &s.ssm).collect::<Vec<_>>();
+        Ok(Self {
+            conv: Tensor::cat(&conv, 0)?,
+            ssm: Tensor::cat(&ssm, 0)?,
+        })
+    }
+
+    /// Split the state of a batch into `n` states along the batch dimension.
+    pub fn chunk(&self, n: usize) -> Result<Vec<Self>> {
+        Ok(self
+            .conv
+            .chunk(n, 0)?
+            .into_iter()
+            .zip(self.ssm.chunk(n, 0)?)
+            .map(|(conv, ssm)| Self { conv, ssm })
+            .collect())
+    }
+}
+
+/// Clone the caches such that each is copied before being written to.
+pub(crate) fn share_layer_caches(caches: &LayerCaches) -> LayerCaches {
+    caches
+        .iter()
+        .map(|cache| cache.as_ref().map(KvCache::share))
+        .collect()
+}
 
 #[derive(Debug, Clone)]
 pub struct Cache {
@@ -25,6 +641,7 @@ pub struct Cache {
     xlora_cache: Option<Arc<Mutex<LayerCaches>>>,
     draft_cache: Arc<Mutex<LayerCaches>>,
     scalings_cache: Option<Arc<Mutex<Option<Tensor>>>>,
+    states: Option<Arc<Mutex<LayerStates>>>,
 }
 
 impl Cache {
@@ -42,6 +659,16 @@ impl Cache {
             } else {
                 None
             },
+            states: None,
+        }
+    }
+
+    /// A cache for a recurrent model, which keeps a [`RecurrentState`] per layer instead of a
+    /// [`KvCache`].
+    pub(crate) fn new_recurrent(len: usize) -> Self {
+        Self {
+            states: Some(Arc::new(Mutex::new(vec![None; len]))),
+            ..Self::new(len, false)
         }
     }
 
@@ -68,89 +695,66 @@ impl Cache {
             .expect("No X-LoRA scalings cache."))
     }
 
+    /// # Panics
+    /// If there are no recurrent states
+    pub(crate) fn states_lock(&self) -> MutexGuard<'_, LayerStates> {
+        get_mut_arcmutex!(self.states.as_ref().expect("No recurrent states."))
+    }
+
     pub(crate) fn is_xlora(&self) -> bool {
         self.xlora_cache.is_some()
     }
 
+    /// Whether the model keeps recurrent states, which cannot be truncated or rolled back like a
+    /// [`KvCache`].
+    pub fn is_recurrent(&self) -> bool {
+        self.states.is_some()
+    }
+
     /// Update the KV cache and return (k,v)
     pub(crate) fn update_kv_cache(
-        cache: &mut Option<(Tensor, Tensor)>,
+        cache: &mut Option<KvCache>,
         k: Tensor,
         v: Tensor,
         slow_cat: bool,
-    ) -> Result<(Tensor, Tensor), candle_core::Error> {
-        let (k, v) = match &*cache {
-            None => (k, v),
-            Some((k_cache, v_cache)) => {
-                if !slow_cat {
-                    let k = candle_nn::ops::kvconcat(k_cache, &k, 2)?.contiguous()?;
-                    let v = candle_nn::ops::kvconcat(v_cache, &v, 2)?.contiguous()?;
-                    (k, v)
-                } else {
-                    let k = Tensor::cat(&[k_cache, &k], 2)?.contiguous()?;
-                    let v = Tensor::cat(&[v_cache, &v], 2)?.contiguous()?;
-                    (k, v)
-                }
+    ) -> Result<(Tensor, Tensor)> {
+        match cache {
+            None => {
+                *cache = Some(KvCache::new(&k, &v)?);
+                Ok((k, v))
             }
-        };
-        *cache = Some((k.clone(), v.clone()));
-        Ok((k, v))
+            Some(cache) => cache.append(&k, &v, slow_cat),
+        }
     }
 
     /// Update the KV cache and return (k,v,attn_mask)
     pub(crate) fn update_kv_cache_sliding_window(
-        cache: &mut Option<(Tensor, Tensor)>,
+        cache: &mut Option<KvCache>,
         k: Tensor,
         v: Tensor,
         attention_mask: Option<&Tensor>,
         sliding_window: Option<usize>,
         slow_cat: bool,
-    ) -> Result<(Tensor, Tensor, Option<Tensor>), candle_core::Error> {
-        let (k, v, attention_mask) = match cache.clone() {
-            None => (k, v, attention_mask.cloned()),
-            Some((mut prev_k, mut prev_v)) => {
-                let mut mask = attention_mask.cloned();
-                if let Some(sliding_window) = sliding_window {
-                    let kv_seq_len = prev_k.dim(2)?;
-                    if kv_seq_len > sliding_window {
-                        prev_k = prev_k.narrow(
-                            2,
-                            kv_seq_len - (sliding_window - 1),
-                            sliding_window - 1,
-                        )?;
-                        prev_v = prev_v.narrow(
-                            2,
-                            kv_seq_len - (sliding_window - 1),
-                            sliding_window - 1,
-                        )?;
-                        if let Some(ref mut mask) = mask {
-                            let mask_len = mask.dim(1)?;
-                            *mask = mask.narrow(
-                                1,
-                                mask_len - (sliding_window - 1),
-                                sliding_window - 1,
-                            )?;
-                            *mask = Tensor::cat(
-                                &[&*mask, &mask.narrow(1, mask_len - 1, 1)?.ones_like()?],
-                                D::Minus1,
-                            )?;
-                        }
-                    }
-                }
-                let (k, v) = if !slow_cat {
-                    let k = candle_nn::ops::kvconcat(&prev_k, &k, 2)?;
-                    let v = candle_nn::ops::kvconcat(&prev_v, &v, 2)?;
-                    (k, v)
-                } else {
-                    let k = Tensor::cat(&[prev_k, k], 2)?.contiguous()?;
-                    let v = Tensor::cat(&[prev_v, v], 2)?.contiguous()?;
-                    (k, v)
-                };
-                (k, v, mask)
+    ) -> Result<(Tensor, Tensor, Option<Tensor>)> {
+        match cache {
+            None => {
+                *cache = Some(KvCache::with_max_capacity(
+                    &k,
+                    &v,
+                    sliding_window.unwrap_or(usize::MAX),
+                )?);
+                Ok((k, v, attention_mask.cloned()))
             }
-        };
-        *cache = Some((k.clone(), v.clone()));
-        Ok((k, v, attention_mask))
+            Some(cache) => match sliding_window {
+                Some(sliding_window) => {
+                    cache.append_sliding_window(&k, &v, attention_mask, sliding_window, slow_cat)
+                }
+                None => {
+                    let (k, v) = cache.append(&k, &v, slow_cat)?;
+                    Ok((k, v, attention_mask.cloned()))
+                }
+            },
+        }
     }
 }
 
@@ -170,8 +774,7 @@ fn clone_in_cache(
 ) {
     let mut new_cache = Vec::new();
     'outer: for layer in 0..num_hidden_layers {
-        let mut k_vec = Vec::new();
-        let mut v_vec = Vec::new();
+        let mut layer_caches = Vec::new();
         for seq in &mut *seqs {
             let src_cache = match src {
                 SeqCache::Normal => seq.cache(),
@@ -187,21 +790,15 @@ fn clone_in_cache(
             let cache = cache
                 .as_ref()
                 .expect("Not handling completions in `clone_in_cache`.");
-            k_vec.push(cache.0.clone());
-            v_vec.push(cache.1.clone());
+            layer_caches.push(cache.clone());
         }
-        new_cache.push(Some((
-            if k_vec.len() > 1 {
-                Tensor::cat(&k_vec, 0).unwrap()
-            } else {
-                k_vec[0].clone()
-            },
-            if v_vec.len() > 1 {
-                Tensor::cat(&v_vec, 0).unwrap()
-            } else {
-                v_vec[0].clone()
-            },
-        )));
+        // A single sequence's cache is used as is, so that the new positions are written in
+        // place.
+        new_cache.push(Some(if layer_caches.len() > 1 {
+            KvCache::cat(&layer_caches).unwrap()
+        } else {
+            layer_caches.pop().unwrap()
+        }));
     }
     *cache = new_cache;
 }
@@ -211,21 +808,23 @@ fn clone_out_cache(
     cache: &mut LayerCaches,
     seqs: &mut [&mut crate::sequence::Sequence],
     target: SeqCache,
+    kv_cache_type: KvCacheType,
 ) {
     for layer in 0..num_hidden_layers {
-        let cache = cache.get(layer).unwrap();
+        let cache = cache.get_mut(layer).unwrap();
         // This case for llama 3.2 vision cross attn
         if cache.is_none() {
             continue;
         }
 
-        let k_cache = cache.as_ref().unwrap().0.clone();
-        let v_cache = cache.as_ref().unwrap().1.clone();
-
-        let k_caches = k_cache.chunk(seqs.len(), 0).unwrap();
-        debug_assert_eq!(k_caches.len(), seqs.len());
-        let v_caches = v_cache.chunk(seqs.len(), 0).unwrap();
-        debug_assert_eq!(v_caches.len(), seqs.len());
+        let cache = cache.as_mut().unwrap();
+        // The models create caches in the activation dtype, so the positions of the first forward
+        // pass are quantized here, after attending to them unquantized.
+        if cache.cache_type() != kv_cache_type {
+            *cache = cache.to_type(kv_cache_type).unwrap();
+        }
+        let caches = cache.chunk(seqs.len()).unwrap();
+        debug_assert_eq!(caches.len(), seqs.len());
 
         for (seq_i, seq) in seqs.iter_mut().enumerate() {
             let output_cache = match target {
@@ -234,9 +833,44 @@ fn clone_out_cache(
                 SeqCache::Draft => seq.draft_cache(),
             };
             let seq_cache = &mut output_cache[layer];
-            let k = k_caches.get(seq_i).unwrap().clone();
-            let v = v_caches.get(seq_i).unwrap().clone();
-            *seq_cache = Some((k, v));
+            *seq_cache = Some(caches.get(seq_i).unwrap().clone());
+        }
+    }
+}
+
+fn clone_in_states(states: &mut LayerStates, seqs: &mut [&mut crate::sequence::Sequence]) {
+    for (layer, state) in states.iter_mut().enumerate() {
+        let mut seq_states = seqs
+            .iter_mut()
+            .map(|seq| seq.recurrent_states()[layer].clone())
+            .collect::<Vec<_>>();
+        // Prompts start from a zero state, unless they were prefilled from the prefix cache.
+        let Some(first) = seq_states.iter().flatten().next() else {
+            *state = None;
+            continue;
+        };
+        let zeros = first.zeros_like().unwrap();
+        *state = Some(if seq_states.len() == 1 {
+            seq_states.pop().unwrap().unwrap()
+        } else {
+            let seq_states = seq_states
+                .into_iter()
+                .map(|s| s.unwrap_or_else(|| zeros.clone()))
+                .collect::<Vec<_>>();
+            RecurrentState::cat(&seq_states).unwrap()
+        });
+    }
+}
+
+fn clone_out_states(states: &LayerStates, seqs: &mut [&mut crate::sequence::Sequence]) {
+    for (layer, state) in states.iter().enumerate() {
+        let Some(state) = state else {
+            continue;
+        };
+        let seq_states = state.chunk(seqs.len()).unwrap();
+        debug_assert_eq!(seq_states.len(), seqs.len());
+        for (seq, seq_state) in seqs.iter_mut().zip(seq_states) {
+            seq.recurrent_states()[layer] = Some(seq_state);
         }
     }
 }
@@ -257,6 +891,10 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
             );
             return;
         }
+        if pipeline.cache().is_recurrent() {
+            clone_in_states(&mut pipeline.cache().states_lock(), seqs);
+            return;
+        }
         clone_in_cache(
             pipeline.get_metadata().num_hidden_layers,
             &mut pipeline.cache().lock(),
@@ -291,14 +929,20 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
                 &mut pipeline.cache().lock(),
                 seqs,
                 SeqCache::Draft,
+                pipeline.get_metadata().kv_cache_type,
             );
             return;
         }
+        if pipeline.cache().is_recurrent() {
+            clone_out_states(&pipeline.cache().states_lock(), seqs);
+            return;
+        }
         clone_out_cache(
             pipeline.get_metadata().num_hidden_layers,
             &mut pipeline.cache().lock(),
             seqs,
             SeqCache::Normal,
+            pipeline.get_metadata().kv_cache_type,
         );
         if pipeline.get_metadata().is_xlora && !pipeline.get_metadata().has_no_kv_cache {
             clone_out_cache(
@@ -306,6 +950,7 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
                 &mut pipeline.cache().xlora_lock(),
                 seqs,
                 SeqCache::XLora,
+                pipeline.get_metadata().kv_cache_type,
             );
         }
         if pipeline.get_metadata().is_xlora {
@@ -327,5 +972,103 @@ impl<T: CacheManagerMixin + MetadataMixin + ?Sized> CacheManager<T> for DefaultC
         if pipeline.cache().is_xlora() {
             *pipeline.cache().xlora_lock() = new_cache;
         }
+        if pipeline.cache().is_recurrent() {
+            *pipeline.cache().states_lock() = vec![None; pipeline.get_metadata().num_hidden_layers];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Result, Tensor};
+
+    use super::{KvCache, KvCacheType, KV_CACHE_CHUNK_SIZE};
+
+    /// Keys or values of a single head with a head dim of 1, holding their position.
+    fn positions(start: u32, end: u32) -> Result<Tensor> {
+        Tensor::arange(start, end, &Device::Cpu)?
+            .to_dtype(DType::F32)?
+            .reshape((1, 1, (), 1))
+    }
+
+    fn to_vec(x: &Tensor) -> Result<Vec<f32>> {
+        x.flatten_all()?.to_vec1()
+    }
+
+    #[test]
+    fn test_kv_cache_append() -> Result<()> {
+        let mut cache = KvCache::new(&positions(0, 3)?, &positions(0, 3)?)?;
+        assert_eq!(cache.capacity(), KV_CACHE_CHUNK_SIZE);
+        let (k, v) = cache.append(&positions(3, 4)?, &positions(3, 4)?, true)?;
+        assert_eq!(to_vec(&k)?, [0., 1., 2., 3.]);
+        assert_eq!(to_vec(&v)?, [0., 1., 2., 3.]);
+
+        // Growing the buffers keeps the cached positions.
+        let end = u32::try_from(KV_CACHE_CHUNK_SIZE).unwrap() + 1;
+        let (k, _) = cache.append(&positions(4, end)?, &positions(4, end)?, true)?;
+        assert_eq!(cache.capacity(), 2 * KV_CACHE_CHUNK_SIZE);
+        assert_eq!(to_vec(&k)?, to_vec(&positions(0, end)?)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_kv_cache_sliding_window() -> Result<()> {
+        let mut cache = KvCache::with_max_capacity(&positions(0, 3)?, &positions(0, 3)?, 4)?;
+        assert_eq!(cache.capacity(), 4);
+        for pos in 3..10 {
+            let x = positions(pos, pos + 1)?;
+            let (k, _, _) = cache.append_sliding_window(&x, &x, None, 4, true)?;
+            // The ring buffer is not in order, which does not matter for a single position.
+            let mut k = to_vec(&k)?;
+            k.sort_by(f32::total_cmp);
+            assert_eq!(k, to_vec(&positions(pos.saturating_sub(3), pos + 1)?)?);
+        }
+        assert_eq!(cache.capacity(), 4);
+        assert_eq!(to_vec(&cache.k()?)?, [6., 7., 8., 9.]);
+
+        cache.truncate(2);
+        let x = positions(10, 11)?;
+        cache.append_sliding_window(&x, &x, None, 4, true)?;
+        assert_eq!(to_vec(&cache.k()?)?, [6., 7., 10.]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_kv_cache_share() -> Result<()> {
+        let cache = KvCache::new(&positions(0, 2)?, &positions(0, 2)?)?;
+        let mut a = cache.share();
+        let mut b = cache.share();
+        a.append(&positions(2, 3)?, &positions(2, 3)?, true)?;
+        b.append(&positions(5, 6)?, &positions(5, 6)?, true)?;
+        assert_eq!(to_vec(&a.k()?)?, [0., 1., 2.]);
+        assert_eq!(to_vec(&b.k()?)?, [0., 1., 5.]);
+        assert_eq!(to_vec(&cache.k()?)?, [0., 1.]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_kv_cache_quantized() -> Result<()> {
+        // Two heads with a head dim of 64, so two blocks per position, with values in [-1, 1].
+        let x = |start: u32, end: u32| -> Result<Tensor> {
+            Tensor::arange(start * 128, end * 128, &Device::Cpu)?
+                .to_dtype(DType::F32)?
+                .sin()?
+                .reshape((1, (), 2, 64))?
+                .transpose(1, 2)
+        };
+        for (ty, tolerance) in [(KvCacheType::Q8_0, 0.01), (KvCacheType::F8E4M3, 0.07)] {
+            let mut cache = KvCache::new(&x(0, 3)?, &x(0, 3)?)?.to_type(ty)?;
+            assert_eq!(cache.cache_type(), ty);
+            assert_eq!(cache.capacity(), KV_CACHE_CHUNK_SIZE);
+            let (k, v) = cache.append(&x(3, 5)?, &x(3, 5)?, true)?;
+            assert_eq!(k.dtype(), DType::F32);
+            assert_eq!(k.dims(), [1, 2, 5, 64]);
+            let expected = x(0, 5)?;
+            for x in [k, v, cache.k()?] {
+                let error = (x - &expected)?.abs()?.flatten_all()?.max(0)?;
+                assert!(error.to_scalar::<f32>()? < tolerance);
+            }
+        }
+        Ok(())
     }
 }
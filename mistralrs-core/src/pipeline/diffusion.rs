@@ -2,7 +2,7 @@ use super::loaders::{DiffusionModelPaths, DiffusionModelPathsInner};
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, Cache, CacheManagerMixin, DiffusionLoaderType,
     DiffusionModel, DiffusionModelLoader, FluxLoader, ForwardInputsResult, GeneralMetadata,
-    IsqPipelineMixin, Loader, MetadataMixin, ModelCategory, ModelKind, ModelPaths,
+    IsqPipelineMixin, KvCacheType, Loader, MetadataMixin, ModelCategory, ModelKind, ModelPaths,
     PreProcessingMixin, Processor, TokenSource,
 };
 use crate::diffusion_models::processor::{DiffusionProcessor, ModelInputs};
@@ -227,6 +227,7 @@ impl Loader for DiffusionLoader {
                 cache_config: None,
                 cache_engine: None,
                 prompt_batchsize: None,
+                kv_cache_type: KvCacheType::Auto,
             }),
             dummy_cache: Cache::new(0, false),
         })))
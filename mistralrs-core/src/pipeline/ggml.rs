@@ -1,8 +1,8 @@
 use super::cache_manager::DefaultCacheManager;
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, QuantizationKind, TokenSource,
-    XLoraPaths,
+    CacheManager, GeneralMetadata, KvCacheType, Loader, ModelKind, ModelPaths, QuantizationKind,
+    TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, ForwardInputsResult,
@@ -80,6 +80,7 @@ pub struct GGMLLoader {
 pub struct GGMLSpecificConfig {
     pub gqa: usize,
     pub prompt_batchsize: Option<NonZeroUsize>,
+    pub kv_cache_type: KvCacheType,
     pub topology: Option<Topology>,
 }
 
@@ -387,6 +388,7 @@ impl Loader for GGMLLoader {
                 cache_config: None,
                 cache_engine: None,
                 prompt_batchsize: self.config.prompt_batchsize,
+                kv_cache_type: self.config.kv_cache_type,
             }),
         })))
     }
@@ -1,8 +1,8 @@
 use super::cache_manager::DefaultCacheManager;
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, PrettyName, QuantizationKind,
-    TokenSource, XLoraPaths,
+    CacheManager, GeneralMetadata, KvCacheType, Loader, ModelKind, ModelPaths, PrettyName,
+    QuantizationKind, TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, ForwardInputsResult,
@@ -33,7 +33,10 @@ use crate::{
     Pipeline, Topology, TryIntoDType,
 };
 use crate::{
+    models::quantized_gemma::ModelWeights as QGemma,
+    models::quantized_gemma2::ModelWeights as QGemma2,
     models::quantized_llama::ModelWeights as QLlama,
+    models::quantized_mamba::ModelWeights as QMamba,
     models::quantized_phi2::ModelWeights as QPhi,
     models::quantized_phi3::ModelWeights as QPhi3,
     models::quantized_qwen2::ModelWeights as QQwen2,
@@ -65,6 +68,9 @@ enum Model {
     Phi3(QPhi3),
     Starcoder2(QStarcoder2),
     Qwen2(QQwen2),
+    Mamba(QMamba),
+    Gemma(QGemma),
+    Gemma2(QGemma2),
 }
 
 pub struct GGUFPipeline {
@@ -95,6 +101,7 @@ pub struct GGUFLoader {
 /// Config for a GGUF loader.
 pub struct GGUFSpecificConfig {
     pub prompt_batchsize: Option<NonZeroUsize>,
+    pub kv_cache_type: KvCacheType,
     pub topology: Option<Topology>,
 }
 
@@ -399,6 +406,9 @@ impl Loader for GGUFLoader {
         let paged_attn_config = if matches!(self.kind, ModelKind::GgufAdapter { .. }) {
             warn!("Adapter models do not currently support PagedAttention, running without");
             None
+        } else if paged_attn_config.is_some() && matches!(arch, GGUFArchitecture::Mamba) {
+            warn!("Recurrent models have no KV cache for PagedAttention, running without");
+            None
         } else {
             paged_attn_config
         };
@@ -441,6 +451,9 @@ impl Loader for GGUFLoader {
                     Model::Starcoder2(QStarcoder2::try_from(model_config)?)
                 }
                 GGUFArchitecture::Qwen2 => Model::Qwen2(QQwen2::try_from(model_config)?),
+                GGUFArchitecture::Mamba => Model::Mamba(QMamba::try_from(model_config)?),
+                GGUFArchitecture::Gemma => Model::Gemma(QGemma::try_from(model_config)?),
+                GGUFArchitecture::Gemma2 => Model::Gemma2(QGemma2::try_from(model_config)?),
                 a => bail!("Unsupported architecture `{a:?}` for GGUF"),
             },
             ModelKind::GgufAdapter { adapter, .. } => match arch {
@@ -460,6 +473,7 @@ impl Loader for GGUFLoader {
                 paged_attn_config.mem_gpu,
                 paged_attn_config.mem_cpu,
                 paged_attn_config.block_size,
+                paged_attn_config.preemption_mode,
                 DType::F32,
                 model_config,
                 device,
@@ -483,6 +497,9 @@ impl Loader for GGUFLoader {
             Model::XLoraPhi3(ref p) => p.max_seq_len,
             Model::Starcoder2(ref p) => p.max_seq_len,
             Model::Qwen2(ref p) => p.max_seq_len,
+            Model::Mamba(ref p) => p.max_seq_len,
+            Model::Gemma(ref p) => p.max_seq_len,
+            Model::Gemma2(ref p) => p.max_seq_len,
         };
         let tok_trie: Arc<TokTrie> = build_tok_trie(tokenizer.clone()).into();
         let num_hidden_layers = match model {
@@ -493,6 +510,9 @@ impl Loader for GGUFLoader {
             Model::XLoraPhi3(ref model) => model.cache.lock().len(),
             Model::Starcoder2(ref model) => model.cache.lock().len(),
             Model::Qwen2(ref model) => model.cache.lock().len(),
+            Model::Mamba(ref model) => model.cache.states_lock().len(),
+            Model::Gemma(ref model) => model.cache.lock().len(),
+            Model::Gemma2(ref model) => model.cache.lock().len(),
         };
 
         if chat_template.bos_token.is_none() && bos.is_some() {
@@ -534,6 +554,7 @@ impl Loader for GGUFLoader {
                 cache_config,
                 cache_engine,
                 prompt_batchsize: self.config.prompt_batchsize,
+                kv_cache_type: self.config.kv_cache_type,
             }),
         })))
     }
@@ -589,6 +610,9 @@ impl CacheManagerMixin for GGUFPipeline {
             Model::XLoraPhi3(ref model) => &model.cache,
             Model::Starcoder2(ref model) => &model.cache,
             Model::Qwen2(ref model) => &model.cache,
+            Model::Mamba(ref model) => &model.cache,
+            Model::Gemma(ref model) => &model.cache,
+            Model::Gemma2(ref model) => &model.cache,
         }
     }
 }
@@ -622,6 +646,9 @@ impl MetadataMixin for GGUFPipeline {
             Model::XLoraPhi3(ref model) => model.device.clone(),
             Model::Starcoder2(ref model) => model.device.clone(),
             Model::Qwen2(ref model) => model.device.clone(),
+            Model::Mamba(ref model) => model.device.clone(),
+            Model::Gemma(ref model) => model.device.clone(),
+            Model::Gemma2(ref model) => model.device.clone(),
         }
     }
     fn tokenizer(&self) -> Option<Arc<Tokenizer>> {
@@ -728,6 +755,21 @@ impl Pipeline for GGUFPipeline {
                 context_lens,
                 paged_attn_meta,
             )?,
+            Model::Gemma(ref model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+                paged_attn_meta,
+            )?,
+            Model::Gemma2(ref model) => model.forward(
+                &input_ids,
+                &seqlen_offsets,
+                seqlen_offsets_kernel,
+                context_lens,
+                paged_attn_meta,
+            )?,
+            Model::Mamba(ref model) => model.forward(&input_ids, context_lens)?,
         };
         Ok(ForwardInputsResult::CausalGeneration { logits })
     }
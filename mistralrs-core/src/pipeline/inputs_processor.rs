@@ -92,6 +92,9 @@ pub mod text_models_inputs_processor {
         pub context_lens: Option<Tensor>,
         pub slot_mappings: Tensor,
         pub max_context_len: Option<usize>,
+        /// For prompts, the blocks shared from the prefix cache for each sequence, if any. The new tokens of
+        /// the prompt attend to the cached KV of these blocks. `None` if no sequence has cached blocks.
+        pub prefix_block_tables: Option<Vec<Option<Tensor>>>,
     }
 
     #[derive(Clone, Debug)]
@@ -127,6 +130,21 @@ pub mod text_models_inputs_processor {
         last_n_context_len: Option<(usize, usize)>,
         mut paged_attn_metadata: Option<&mut PagedAttentionMeta<'_>>,
     ) -> Result<InputMetadata> {
+        // Tokens in blocks shared from the prefix cache already have their KV cache computed.
+        let num_cached = input_seqs
+            .iter()
+            .map(|seq| {
+                paged_attn_metadata
+                    .as_ref()
+                    .map_or(0, |meta| meta.block_engine.num_cached_tokens(*seq.id()))
+            })
+            .collect::<Vec<_>>();
+        let toks = toks
+            .into_iter()
+            .zip(&num_cached)
+            .map(|(mut toks, num_cached)| toks.split_off(*num_cached))
+            .collect::<Vec<_>>();
+
         let max_len = toks
             .iter()
             .map(|seq| seq.len())
@@ -141,19 +159,30 @@ pub mod text_models_inputs_processor {
         let mut slot_mappings = Vec::new();
         let mut block_tables = Vec::new();
         let mut paged_attn_context_lens = Vec::new();
+        let mut prefix_block_tables = Vec::new();
         let mut seqlens_q = vec![0];
         let mut seqlens_k = vec![0];
-        for (seq, mut ctxt) in input_seqs.iter().zip(toks) {
+        // Sequences which return prompt logprobs need the logits of every position. They are not
+        // batched with other sequences, see `get_prompt_input`.
+        let all_logits = last_n_context_len.is_none()
+            && input_seqs.iter().any(|seq| seq.needs_prompt_logprobs());
+        for ((seq, mut ctxt), num_cached) in input_seqs.iter().zip(toks).zip(num_cached.iter()) {
+            let chunk_offset_toks = chunk_offset_toks + num_cached;
             let prompt_len = ctxt.len();
             let offset = last_n_context_len.unwrap_or_default();
             seqlen_offsets.push(offset.1 + chunk_offset_toks);
 
             position_ids.push(ctxt.len() + chunk_offset_toks);
             ctxt.extend(repeat(padding_tok).take(max_len.saturating_sub(ctxt.len())));
-            context_lens.push((
-                ctxt.len() - last_n_context_len.map(|(a, _)| a).unwrap_or(1),
-                last_n_context_len.map(|(a, _)| a).unwrap_or(1),
-            ));
+            // The logits are taken from the end of the prompt, not of the padding.
+            if all_logits {
+                context_lens.push((0, max_len));
+            } else {
+                context_lens.push((
+                    prompt_len.saturating_sub(last_n_context_len.map(|(a, _)| a).unwrap_or(1)),
+                    last_n_context_len.map(|(a, _)| a).unwrap_or(1),
+                ));
+            }
 
             seqlens_q.push(ctxt.len() as u32);
             seqlens_k.push((ctxt.len() + chunk_offset_toks) as u32);
@@ -166,6 +195,7 @@ pub mod text_models_inputs_processor {
                 if table.is_none() {
                     // Will be None during profiling.
                     slot_mappings.push([_PAD_SLOT_ID].repeat(prompt_len));
+                    prefix_block_tables.push(None);
                     continue;
                 }
                 let table = table
@@ -174,6 +204,17 @@ pub mod text_models_inputs_processor {
                     .map(|block| block.deref_mut().block_id)
                     .collect::<Vec<_>>();
 
+                let num_cached_blocks = num_cached / paged_attn_metadata.block_size;
+                prefix_block_tables.push(if num_cached_blocks > 0 {
+                    let blocks = table[..num_cached_blocks]
+                        .iter()
+                        .map(|block| *block as u32)
+                        .collect::<Vec<_>>();
+                    Some(Tensor::new(blocks, device)?)
+                } else {
+                    None
+                });
+
                 let start_idx = if let Some(sliding_window) = paged_attn_metadata.sliding_window {
                     if prompt_len > sliding_window {
                         chunk_offset_toks.min(prompt_len - sliding_window)
@@ -214,7 +255,7 @@ pub mod text_models_inputs_processor {
         }
 
         let mut tmp = Vec::new();
-        if last_n_context_len.is_some() {
+        if last_n_context_len.is_some() || num_cached.iter().any(|n| *n > 0) {
             for pos in (0..seqs_tensors.len())
                 .map(|i| {
                     (*seqlen_offsets.get(i).unwrap() as i64
@@ -292,6 +333,10 @@ pub mod text_models_inputs_processor {
                 block_tables: Some(block_tables),
                 context_lens: Some(context_lens),
                 max_context_len: Some(max_context_len),
+                prefix_block_tables: prefix_block_tables
+                    .iter()
+                    .any(Option::is_some)
+                    .then_some(prefix_block_tables),
             })
         } else {
             None
@@ -440,6 +485,7 @@ pub mod text_models_inputs_processor {
                 block_tables: Some(block_tables),
                 context_lens: Some(context_lens),
                 max_context_len: Some(*max_context_len),
+                prefix_block_tables: None,
             })
         } else {
             None
@@ -517,20 +563,38 @@ pub mod text_models_inputs_processor {
                     "PagedAttention does not yet support prompt batching.",
                 ))));
             }
-            Box::new(std::iter::once(
-                make_prompt_chunk(
-                    0,
-                    toks,
-                    &input_seqs.iter().map(|s| &**s).collect::<Vec<_>>(),
-                    device,
-                    last_n_context_len,
-                    paged_attn_metadata,
-                )
-                .map(|inputs| InnerInputProcessorOutput {
-                    inputs,
-                    seq_indices: (0..input_seqs.len()).collect(),
-                }),
-            ))
+            // Sequences returning prompt logprobs need the logits of every position, which are
+            // only computed for them. The default scheduler never batches them with other
+            // sequences, but PagedAttention does, so they are run separately.
+            let groups = if paged_attn_metadata.is_some() {
+                let (all_logits, last_logits): (Vec<_>, Vec<_>) =
+                    (0..input_seqs.len()).partition(|i| input_seqs[*i].needs_prompt_logprobs());
+                [all_logits, last_logits]
+                    .into_iter()
+                    .filter(|group| !group.is_empty())
+                    .collect::<Vec<_>>()
+            } else {
+                vec![(0..input_seqs.len()).collect::<Vec<_>>()]
+            };
+            let mut toks = toks.into_iter().map(Some).collect::<Vec<_>>();
+            let chunks = groups
+                .into_iter()
+                .map(|seq_ns| {
+                    make_prompt_chunk(
+                        0,
+                        seq_ns.iter().map(|i| toks[*i].take().unwrap()).collect(),
+                        &seq_ns.iter().map(|i| &*input_seqs[*i]).collect::<Vec<_>>(),
+                        device,
+                        last_n_context_len,
+                        paged_attn_metadata.as_deref_mut(),
+                    )
+                    .map(|inputs| InnerInputProcessorOutput {
+                        inputs,
+                        seq_indices: seq_ns,
+                    })
+                })
+                .collect::<Vec<_>>();
+            Box::new(chunks.into_iter())
         }
     }
 
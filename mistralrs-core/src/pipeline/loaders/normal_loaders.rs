@@ -63,6 +63,18 @@ pub trait NormalModel: IsqModel + AnyMoeBaseModelMixin {
         flash_params: &FlashParams,
         flash_params_full: &FlashParams,
     ) -> candle_core::Result<Tensor>;
+    /// Hidden states after the final norm for every position, of shape (bs, seq_len, hidden_size).
+    fn forward_hidden_states(
+        &self,
+        _input_ids: &Tensor,
+        _seqlen_offsets: &[usize],
+        _start_offsets_kernel: Tensor,
+        _position_ids: Vec<usize>,
+        _metadata: Option<(Vec<(Tensor, Tensor)>, &mut PagedAttentionInputMetadata)>,
+        _flash_params: &FlashParams,
+    ) -> candle_core::Result<Tensor> {
+        candle_core::bail!("Embeddings are not supported for this model.")
+    }
     fn is_xlora(&self) -> bool;
     fn device(&self) -> &Device;
     fn cache(&self) -> &Cache;
@@ -111,6 +123,10 @@ pub trait NormalModelLoader: IsqModelLoader {
     fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>>;
     /// Get total num_hidden_layers for the layers which will be device mapped.
     fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize>;
+    /// Whether the model can be run with PagedAttention.
+    fn supports_paged_attention(&self, _config: &str) -> Result<bool> {
+        Ok(true)
+    }
 }
 
 #[cfg_attr(feature = "pyo3_macros", pyclass(eq, eq_int))]
@@ -137,6 +153,10 @@ pub enum NormalLoaderType {
     Starcoder2,
     #[serde(rename = "phi3.5moe")]
     Phi3_5MoE,
+    #[serde(rename = "mamba")]
+    Mamba,
+    #[serde(rename = "deepseekv2")]
+    DeepSeekV2,
 }
 
 // https://github.com/huggingface/transformers/blob/cff06aac6fad28019930be03f5d467055bf62177/src/transformers/models/auto/modeling_auto.py#L448
@@ -154,6 +174,8 @@ impl NormalLoaderType {
             "Qwen2ForCausalLM" => Ok(Self::Qwen2),
             "Starcoder2ForCausalLM" => Ok(Self::Starcoder2),
             "PhiMoEForCausalLM" => Ok(Self::Phi3_5MoE),
+            "MambaForCausalLM" => Ok(Self::Mamba),
+            "DeepseekV2ForCausalLM" => Ok(Self::DeepSeekV2),
             other => anyhow::bail!(
                 "Unsupported Huggging Face Transformers -CausalLM model class `{other}`. Please raise an issue."
             ),
@@ -175,7 +197,9 @@ impl FromStr for NormalLoaderType {
             "gemma2" => Ok(Self::Gemma2),
             "starcoder2" => Ok(Self::Starcoder2),
             "phi3.5moe" => Ok(Self::Phi3_5MoE),
-            a => Err(format!("Unknown architecture `{a}`. Possible architectures: `mistral`, `gemma`, `mixtral`, `llama`, `phi2`, `phi3`, `qwen2`, `gemma2`, `starcoder2`, `phi3.5moe`.")),
+            "mamba" => Ok(Self::Mamba),
+            "deepseekv2" => Ok(Self::DeepSeekV2),
+            a => Err(format!("Unknown architecture `{a}`. Possible architectures: `mistral`, `gemma`, `mixtral`, `llama`, `phi2`, `phi3`, `qwen2`, `gemma2`, `starcoder2`, `phi3.5moe`, `mamba`, `deepseekv2`.")),
         }
     }
 }
@@ -183,9 +207,11 @@ impl FromStr for NormalLoaderType {
 impl Display for NormalLoaderType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Self::DeepSeekV2 => write!(f, "deepseekv2"),
             Self::Gemma => write!(f, "gemma"),
             Self::Gemma2 => write!(f, "gemma2"),
             Self::Llama => write!(f, "llama"),
+            Self::Mamba => write!(f, "mamba"),
             Self::Mistral => write!(f, "mistral"),
             Self::Mixtral => write!(f, "mixtral"),
             Self::Phi2 => write!(f, "phi2"),
@@ -229,6 +255,8 @@ impl AutoLoader {
             NormalLoaderType::Gemma2 => Ok(Box::new(Gemma2Loader)),
             NormalLoaderType::Starcoder2 => Ok(Box::new(Starcoder2Loader)),
             NormalLoaderType::Phi3_5MoE => Ok(Box::new(Phi3_5MoELoader)),
+            NormalLoaderType::Mamba => Ok(Box::new(MambaLoader)),
+            NormalLoaderType::DeepSeekV2 => Ok(Box::new(DeepSeekV2Loader)),
         }
     }
 }
@@ -281,6 +309,9 @@ impl NormalModelLoader for AutoLoader {
     fn is_gptx(&self, config: &str) -> Result<bool> {
         Self::get_loader(config)?.is_gptx(config)
     }
+    fn supports_paged_attention(&self, config: &str) -> Result<bool> {
+        Self::get_loader(config)?.supports_paged_attention(config)
+    }
 }
 
 impl IsqModelLoader for AutoLoader {
@@ -1512,3 +1543,159 @@ impl IsqModelLoader for Phi3_5MoELoader {
         ])
     }
 }
+
+// ======================== Mamba loader
+
+/// [`NormalLoader`] for a Mamba model.
+///
+/// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
+pub struct MambaLoader;
+
+impl NormalModelLoader for MambaLoader {
+    fn load(
+        &self,
+        config: &str,
+        _use_flash_attn: bool,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        _attention_mechanism: AttentionImplementation,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::mamba::Model::new(
+            &serde_json::from_str(config)?,
+            vb,
+            normal_loading_metadata,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[((String, String), LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        todo!()
+    }
+    fn is_gptx(&self, _: &str) -> Result<bool> {
+        Ok(true)
+    }
+    fn get_config_repr(&self, config: &str, _use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(serde_json::from_str::<models::mamba::Config>(
+            config,
+        )?))
+    }
+    fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize> {
+        Ok(serde_json::from_str::<models::mamba::Config>(config)?.num_hidden_layers)
+    }
+}
+
+impl IsqModelLoader for MambaLoader {
+    fn isq_layer_regexes(&self, _config: &str) -> Result<Vec<Regex>> {
+        Ok(vec![
+            Regex::new(r"lm_head\.(weight|bias)$")?,
+            // Mixer
+            Regex::new(r"layers\.(\d+)\.mixer\.in_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mixer\.x_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mixer\.dt_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mixer\.out_proj\.(weight|bias)$")?,
+        ])
+    }
+}
+
+// ======================== DeepSeek-V2 loader
+
+/// [`NormalLoader`] for a DeepSeek-V2 model.
+///
+/// [`NormalLoader`]: https://ericlbuehler.github.io/mistral.rs/mistralrs/struct.NormalLoader.html
+pub struct DeepSeekV2Loader;
+
+impl DeepSeekV2Loader {
+    fn deserialize(config: &str, use_flash_attn: bool) -> Result<models::deepseek2::Config> {
+        let mut config: models::deepseek2::Config = serde_json::from_str(config)?;
+        config.use_flash_attn = use_flash_attn;
+        Ok(config)
+    }
+}
+
+impl NormalModelLoader for DeepSeekV2Loader {
+    fn load(
+        &self,
+        config: &str,
+        use_flash_attn: bool,
+        vb: VarBuilder,
+        normal_loading_metadata: NormalLoadingMetadata,
+        attention_mechanism: AttentionImplementation,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        Ok(Box::new(models::deepseek2::Model::new(
+            &Self::deserialize(config, use_flash_attn)?,
+            vb,
+            normal_loading_metadata,
+            attention_mechanism,
+        )?))
+    }
+    fn load_xlora(
+        &self,
+        _config: &str,
+        _use_flash_attn: bool,
+        _vb: VarBuilder,
+        _lora_config: &[((String, String), LoraConfig)],
+        _xlora_config: Option<XLoraConfig>,
+        _xlora_ordering: Ordering,
+        _normal_loading_metadata: NormalLoadingMetadata,
+        _preload_adapters: &Option<HashMap<String, (VarBuilder, LoraConfig)>>,
+    ) -> Result<Box<dyn NormalModel + Send + Sync>> {
+        todo!()
+    }
+    fn is_gptx(&self, _: &str) -> Result<bool> {
+        Ok(true)
+    }
+    fn get_config_repr(&self, config: &str, use_flash_attn: bool) -> Result<Box<dyn Debug>> {
+        Ok(Box::new(Self::deserialize(config, use_flash_attn)?))
+    }
+    fn get_total_device_mapping_num_layers(&self, config: &str) -> Result<usize> {
+        Ok(Self::deserialize(config, false)?.num_hidden_layers)
+    }
+    fn supports_paged_attention(&self, _config: &str) -> Result<bool> {
+        // The compressed latent cache does not fit the per-head paged KV cache blocks.
+        Ok(false)
+    }
+}
+
+impl IsqModelLoader for DeepSeekV2Loader {
+    fn isq_layer_regexes(&self, _config: &str) -> Result<Vec<Regex>> {
+        Ok(vec![
+            Regex::new(r"lm_head\.(weight|bias)$")?,
+            // Attention
+            Regex::new(r"layers\.(\d+)\.self_attn\.q_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.q_a_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.q_b_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.kv_a_proj_with_mqa\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.kv_b_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.self_attn\.o_proj\.(weight|bias)$")?,
+            // MLP
+            Regex::new(r"layers\.(\d+)\.mlp\.gate_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.up_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.down_proj\.(weight|bias)$")?,
+            // MoE
+            Regex::new(r"layers\.(\d+)\.mlp\.experts\.(\d+)\.gate_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.experts\.(\d+)\.up_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.experts\.(\d+)\.down_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.shared_experts\.gate_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.shared_experts\.up_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.shared_experts\.down_proj\.(weight|bias)$")?,
+        ])
+    }
+
+    fn isq_layer_regexes_moqe(&self, _config: &str) -> Result<Vec<Regex>> {
+        Ok(vec![
+            Regex::new(r"lm_head\.(weight|bias)$")?,
+            // MoE
+            Regex::new(r"layers\.(\d+)\.mlp\.experts\.(\d+)\.gate_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.experts\.(\d+)\.up_proj\.(weight|bias)$")?,
+            Regex::new(r"layers\.(\d+)\.mlp\.experts\.(\d+)\.down_proj\.(weight|bias)$")?,
+        ])
+    }
+}
@@ -19,9 +19,12 @@ pub use super::diffusion_models::DiffusionGenerationParams;
 use crate::aici::toktree::TokTrie;
 use crate::amoe::{AnyMoeConfig, AnyMoeExpertType, AnyMoeTrainingInputs, AnyMoeTrainingResult};
 use crate::diffusion_models::response::send_responses;
+use crate::embedding::send_embedding_responses;
 use crate::paged_attention::{CacheConfig, CacheEngine};
 use crate::prefix_cacher::PrefixCacheManager;
+use crate::scoring::send_scoring_responses;
 pub use amoe::{AnyMoeLoader, AnyMoePipeline};
+pub(crate) use cache_manager::share_layer_caches;
 use chat_template::ChatTemplate;
 pub use diffusion::{DiffusionLoader, DiffusionLoaderBuilder, DiffusionSpecificConfig};
 pub use ggml::{GGMLLoader, GGMLLoaderBuilder, GGMLSpecificConfig};
@@ -30,12 +33,13 @@ use image::DynamicImage;
 pub use inputs_processor::InputProcessorOutput;
 pub use isq::{parse_isq_value, IsqModel, IsqOrganization};
 pub use loaders::{
-    AdapterKind, AutoLoader, DiffusionLoaderType, DiffusionModel, DiffusionModelLoader, FluxLoader,
-    Gemma2Loader, GemmaLoader, Idefics2Loader, LLaVALoader, LLaVANextLoader, LlamaLoader, Loader,
-    LocalModelPaths, MistralLoader, MixtralLoader, ModelKind, ModelPaths, NormalLoaderType,
-    NormalLoadingMetadata, NormalModel, NormalModelLoader, Phi2Loader, Phi3Loader, Phi3VLoader,
-    Phi3_5MoELoader, PrettyName, QuantizationKind, Qwen2Loader, Starcoder2Loader, TokenSource,
-    VLlamaLoader, VisionLoaderType, VisionModel, VisionModelLoader,
+    AdapterKind, AutoLoader, DeepSeekV2Loader, DiffusionLoaderType, DiffusionModel,
+    DiffusionModelLoader, FluxLoader, Gemma2Loader, GemmaLoader, Idefics2Loader, LLaVALoader,
+    LLaVANextLoader, LlamaLoader, Loader, LocalModelPaths, MambaLoader, MistralLoader,
+    MixtralLoader, ModelKind, ModelPaths, NormalLoaderType, NormalLoadingMetadata, NormalModel,
+    NormalModelLoader, Phi2Loader, Phi3Loader, Phi3VLoader, Phi3_5MoELoader, PrettyName,
+    QuantizationKind, Qwen2Loader, Starcoder2Loader, TokenSource, VLlamaLoader, VisionLoaderType,
+    VisionModel, VisionModelLoader,
 };
 use mistralrs_quant::IsqType;
 pub use normal::{NormalLoader, NormalLoaderBuilder, NormalSpecificConfig};
@@ -44,7 +48,11 @@ pub(crate) use processing::{
     apply_chat_template, BasicProcessor, MessagesAction, Processor, ProcessorCreator,
 };
 use rand_isaac::Isaac64Rng;
-pub use speculative::{SpeculativeConfig, SpeculativeLoader, SpeculativePipeline};
+pub(crate) use sampling::finish_seq;
+pub use speculative::{
+    AdaptiveGamma, PromptLookupConfig, PromptLookupLoader, SpeculativeConfig, SpeculativeLoader,
+    SpeculativePipeline,
+};
 use std::any::Any;
 use std::collections::HashMap;
 use std::num::NonZeroUsize;
@@ -57,7 +65,9 @@ use candle_core::{DType, Device, IndexOp, Tensor, Var};
 
 use crate::sequence::Sequence;
 
-pub use self::cache_manager::{Cache, CacheManager, LayerCaches};
+pub use self::cache_manager::{
+    Cache, CacheManager, KvCache, KvCacheType, LayerCaches, LayerStates, RecurrentState,
+};
 pub use self::inputs_processor::{
     text_models_inputs_processor, InputsProcessor, InputsProcessorType,
 };
@@ -79,6 +89,7 @@ pub struct GeneralMetadata {
     pub cache_config: Option<CacheConfig>,
     pub cache_engine: Option<CacheEngine>,
     pub prompt_batchsize: Option<NonZeroUsize>,
+    pub kv_cache_type: KvCacheType,
 }
 
 pub enum AdapterInstruction {
@@ -216,6 +227,8 @@ pub enum CacheBackendMetadata<'a> {
 pub enum ForwardInputsResult {
     CausalGeneration { logits: Tensor },
     Image { images: Vec<DynamicImage> },
+    // Hidden states after the final norm, of shape (bs, seq_len, hidden_size)
+    Embeddings { hidden_states: Tensor },
 }
 
 impl ForwardInputsResult {
@@ -227,6 +240,47 @@ impl ForwardInputsResult {
             Self::Image { images } => Ok(Self::Image {
                 images: vec![images[bs_idx].clone()],
             }),
+            Self::Embeddings { hidden_states } => Ok(Self::Embeddings {
+                hidden_states: hidden_states.i(bs_idx)?,
+            }),
+        }
+    }
+
+    /// Record the prompt logprobs of a sequence from the logits of every position of a prompt
+    /// chunk starting at `chunk_offset`, keeping only the logits of the last position.
+    fn take_prompt_logits(
+        self,
+        seq: &mut Sequence,
+        chunk_offset: usize,
+    ) -> candle_core::Result<Self> {
+        match self {
+            Self::CausalGeneration { logits } if logits.rank() == 2 && logits.dim(0)? > 1 => {
+                // The logits past the end of the prompt are of the padding.
+                let chunk_len = logits
+                    .dim(0)?
+                    .min(seq.get_toks().len().saturating_sub(chunk_offset))
+                    .max(1);
+                seq.add_prompt_logprobs(&logits.narrow(0, 0, chunk_len)?, chunk_offset)?;
+                Ok(Self::CausalGeneration {
+                    logits: logits.narrow(0, chunk_len - 1, 1)?,
+                })
+            }
+            other => Ok(other),
+        }
+    }
+
+    /// Combine the result for a sequence with the result of its next prompt chunk.
+    fn add_chunk(self, next: Self) -> candle_core::Result<Self> {
+        match (self, next) {
+            (
+                Self::Embeddings { hidden_states },
+                Self::Embeddings {
+                    hidden_states: next,
+                },
+            ) => Ok(Self::Embeddings {
+                hidden_states: Tensor::cat(&[hidden_states, next], 0)?,
+            }),
+            (_, next) => Ok(next),
         }
     }
 
@@ -236,6 +290,9 @@ impl ForwardInputsResult {
                 logits: logits.to_device(device)?,
             }),
             Self::Image { .. } => Ok(self.clone()),
+            Self::Embeddings { hidden_states } => Ok(Self::Embeddings {
+                hidden_states: hidden_states.to_device(device)?,
+            }),
         }
     }
 }
@@ -256,6 +313,14 @@ pub trait Pipeline:
         inputs: Box<dyn Any>,
     ) -> Result<ForwardInputsResult, candle_core::Error>;
 
+    /// Run the inputs through the model, returning `ForwardInputsResult::Embeddings`.
+    fn forward_inputs_hidden_states(
+        &mut self,
+        _inputs: Box<dyn Any>,
+    ) -> Result<ForwardInputsResult, candle_core::Error> {
+        candle_core::bail!("Embeddings are not supported for {}.", self.name())
+    }
+
     #[allow(clippy::too_many_arguments)]
     async fn step(
         &mut self,
@@ -266,6 +331,8 @@ pub trait Pipeline:
         rng: Arc<std::sync::Mutex<Isaac64Rng>>,
         backend_metadata: CacheBackendMetadata<'_>,
     ) -> Result<(), candle_core::Error> {
+        // The schedulers never batch embedding sequences with other sequences.
+        let is_embedding = input_seqs.first().is_some_and(|seq| seq.is_embedding());
         match backend_metadata {
             CacheBackendMetadata::DefaultInstructions { pre_op, post_op } => {
                 let inputs_iter = self.get_processor().inputs_processor().process_inputs(
@@ -341,10 +408,25 @@ pub trait Pipeline:
                         }
                     }
 
-                    let raw_logits = self.forward_inputs(inputs)?;
+                    let raw_logits = if is_embedding {
+                        self.forward_inputs_hidden_states(inputs)?
+                    } else {
+                        self.forward_inputs(inputs)?
+                    };
 
+                    let chunk_offset = i * self
+                        .get_metadata()
+                        .prompt_batchsize
+                        .map_or(0, NonZeroUsize::get);
                     for (logit_idx, seq_idx) in seq_indices.into_iter().enumerate() {
-                        logits[seq_idx] = Some(raw_logits.index_bs(logit_idx)?);
+                        let mut chunk = raw_logits.index_bs(logit_idx)?;
+                        if is_prompt {
+                            chunk = chunk.take_prompt_logits(input_seqs[seq_idx], chunk_offset)?;
+                        }
+                        logits[seq_idx] = Some(match logits[seq_idx].take() {
+                            Some(prev) => prev.add_chunk(chunk)?,
+                            None => chunk,
+                        });
                     }
                 }
 
@@ -368,46 +450,74 @@ pub trait Pipeline:
 
                 match &logits[0] {
                     ForwardInputsResult::CausalGeneration { .. } => {
-                        self.sample_causal_gen(
+                        // Scoring sequences are done once the logprobs of their prompt are computed.
+                        let mut scoring_seqs = Vec::new();
+                        let mut sampled_seqs = Vec::new();
+                        let mut sampled_logits = Vec::new();
+                        for (seq, r) in input_seqs.iter_mut().zip(logits) {
+                            #[allow(irrefutable_let_patterns)]
+                            let ForwardInputsResult::CausalGeneration { logits } = r
+                            else {
+                                unreachable!("All results must have same type, `CausalGeneration`")
+                            };
+                            if seq.is_scoring() {
+                                scoring_seqs.push(&mut **seq);
+                            } else {
+                                sampled_seqs.push(&mut **seq);
+                                sampled_logits.push(logits);
+                            }
+                        }
+                        send_scoring_responses(&mut scoring_seqs, self.tokenizer(), self.name())
+                            .await?;
+                        if !sampled_seqs.is_empty() {
+                            self.sample_causal_gen(
+                                &mut sampled_seqs,
+                                sampled_logits,
+                                prefix_cacher,
+                                disable_eos_stop,
+                                rng,
+                            )
+                            .await?;
+                        }
+                    }
+                    ForwardInputsResult::Image { .. } => {
+                        send_responses(
                             input_seqs,
                             logits
                                 .into_iter()
                                 .map(|r| {
                                     #[allow(irrefutable_let_patterns)]
-                                    let ForwardInputsResult::CausalGeneration { logits } = r
+                                    let ForwardInputsResult::Image { images } = r
                                     else {
                                         unreachable!(
                                             "All results must have same type, `CausalGeneration`"
                                         )
                                     };
-                                    logits
+                                    images
+                                        .into_iter()
+                                        .next()
+                                        .expect("Must have at least 1 element.")
                                 })
                                 .collect::<Vec<_>>(),
-                            prefix_cacher,
-                            disable_eos_stop,
-                            rng,
                         )
                         .await?;
                     }
-                    ForwardInputsResult::Image { .. } => {
-                        send_responses(
+                    ForwardInputsResult::Embeddings { .. } => {
+                        send_embedding_responses(
                             input_seqs,
                             logits
                                 .into_iter()
                                 .map(|r| {
-                                    #[allow(irrefutable_let_patterns)]
-                                    let ForwardInputsResult::Image { images } = r
+                                    let ForwardInputsResult::Embeddings { hidden_states } = r
                                     else {
                                         unreachable!(
-                                            "All results must have same type, `CausalGeneration`"
+                                            "All results must have same type, `Embeddings`"
                                         )
                                     };
-                                    images
-                                        .into_iter()
-                                        .next()
-                                        .expect("Must have at least 1 element.")
+                                    hidden_states
                                 })
                                 .collect::<Vec<_>>(),
+                            self.name(),
                         )
                         .await?;
                     }
@@ -447,10 +557,22 @@ pub trait Pipeline:
                         seq_indices,
                     } = inputs.map_err(candle_core::Error::msg)?;
 
-                    let raw_logits = self.forward_inputs(inputs)?;
+                    let raw_logits = if is_embedding {
+                        self.forward_inputs_hidden_states(inputs)?
+                    } else {
+                        self.forward_inputs(inputs)?
+                    };
 
+                    // PagedAttention does not support prompt chunking.
                     for (logit_idx, seq_idx) in seq_indices.into_iter().enumerate() {
-                        logits[seq_idx] = Some(raw_logits.index_bs(logit_idx)?);
+                        let mut chunk = raw_logits.index_bs(logit_idx)?;
+                        if is_prompt {
+                            chunk = chunk.take_prompt_logits(input_seqs[seq_idx], 0)?;
+                        }
+                        logits[seq_idx] = Some(match logits[seq_idx].take() {
+                            Some(prev) => prev.add_chunk(chunk)?,
+                            None => chunk,
+                        });
                     }
                 }
 
@@ -505,6 +627,25 @@ pub trait Pipeline:
                         )
                         .await?;
                     }
+                    ForwardInputsResult::Embeddings { .. } => {
+                        send_embedding_responses(
+                            input_seqs,
+                            logits
+                                .into_iter()
+                                .map(|r| {
+                                    let ForwardInputsResult::Embeddings { hidden_states } = r
+                                    else {
+                                        unreachable!(
+                                            "All results must have same type, `Embeddings`"
+                                        )
+                                    };
+                                    hidden_states
+                                })
+                                .collect::<Vec<_>>(),
+                            self.name(),
+                        )
+                        .await?;
+                    }
                 }
                 Ok(())
             }
@@ -778,4 +919,73 @@ mod tests {
 
         test_with_inputs(&templates, &expected_outputs, inputs);
     }
+
+    #[test]
+    fn test_take_prompt_logits_over_chunks() {
+        use super::ForwardInputsResult;
+        use crate::sequence::TestSequence;
+        use candle_core::{Device, Tensor};
+
+        let tokens = vec![1u32, 3, 2, 4, 0];
+        let (seq, _rx) = TestSequence {
+            tokens: tokens.clone(),
+            ..Default::default()
+        }
+        .build();
+        let mut seq = seq.with_prompt_logprobs();
+
+        // The logits at each position favor the next prompt token.
+        let logits = |positions: &[usize]| {
+            let rows = positions
+                .iter()
+                .flat_map(|&pos| {
+                    let next = tokens.get(pos + 1).copied().unwrap_or(0);
+                    (0..5u32).map(move |tok| if tok == next { 10f32 } else { 0. })
+                })
+                .collect::<Vec<_>>();
+            Tensor::from_vec(rows, (positions.len(), 5), &Device::Cpu).unwrap()
+        };
+        let take = |seq: &mut crate::sequence::Sequence, positions: &[usize]| {
+            let ForwardInputsResult::CausalGeneration { logits } =
+                ForwardInputsResult::CausalGeneration {
+                    logits: logits(positions),
+                }
+                .take_prompt_logits(seq, positions[0])
+                .unwrap()
+            else {
+                unreachable!()
+            };
+            logits
+        };
+
+        // A prompt of 5 tokens in chunks of 2 tokens, the last one padded.
+        assert_eq!(take(&mut seq, &[0, 1]).dims(), [1, 5]);
+        assert_eq!(seq.prompt_logprobs().len(), 2);
+        assert!(seq.needs_prompt_logprobs());
+        take(&mut seq, &[2, 3]);
+        let last = take(&mut seq, &[4, 5]);
+        // Only the logits of the last prompt position are kept for sampling.
+        assert_eq!(
+            last.to_vec2::<f32>().unwrap(),
+            logits(&[4]).to_vec2::<f32>().unwrap()
+        );
+
+        // Every prompt token after the first one has a logprob, and none of the padding.
+        assert!(!seq.needs_prompt_logprobs());
+        let prompt_logprobs = seq.prompt_logprobs();
+        assert_eq!(
+            prompt_logprobs.iter().map(|l| l.token).collect::<Vec<_>>(),
+            tokens[1..]
+        );
+        assert!(prompt_logprobs.iter().all(|l| l.logprob > -1e-3));
+
+        // A sequence which did not ask for them records no prompt logprobs.
+        let (mut seq, _rx) = TestSequence {
+            tokens: tokens.clone(),
+            ..Default::default()
+        }
+        .build();
+        take(&mut seq, &[0, 1]);
+        assert!(seq.prompt_logprobs().is_empty());
+    }
 }
@@ -1,16 +1,17 @@
 use super::cache_manager::DefaultCacheManager;
 use super::{
     get_model_paths, get_xlora_paths, text_models_inputs_processor::ModelInputs, AdapterKind,
-    CacheManager, GeneralMetadata, Loader, ModelKind, ModelPaths, NormalModel, NormalModelLoader,
-    TokenSource, XLoraPaths,
+    CacheManager, GeneralMetadata, KvCacheType, Loader, ModelKind, ModelPaths, NormalModel,
+    NormalModelLoader, TokenSource, XLoraPaths,
 };
 use super::{
     AdapterActivationMixin, AnyMoePipelineMixin, CacheManagerMixin, ForwardInputsResult,
     IsqOrganization, IsqPipelineMixin, MetadataMixin, ModelCategory, PreProcessingMixin,
 };
 use super::{
-    AutoLoader, Gemma2Loader, GemmaLoader, LlamaLoader, MistralLoader, MixtralLoader,
-    NormalLoaderType, Phi2Loader, Phi3Loader, Phi3_5MoELoader, Qwen2Loader, Starcoder2Loader,
+    AutoLoader, DeepSeekV2Loader, Gemma2Loader, GemmaLoader, LlamaLoader, MambaLoader,
+    MistralLoader, MixtralLoader, NormalLoaderType, Phi2Loader, Phi3Loader, Phi3_5MoELoader,
+    Qwen2Loader, Starcoder2Loader,
 };
 use crate::aici::bintokens::build_tok_trie;
 use crate::aici::toktree::TokTrie;
@@ -102,6 +103,7 @@ pub struct NormalLoaderBuilder {
 pub struct NormalSpecificConfig {
     pub use_flash_attn: bool,
     pub prompt_batchsize: Option<NonZeroUsize>,
+    pub kv_cache_type: KvCacheType,
     pub topology: Option<Topology>,
     pub organization: IsqOrganization,
     pub write_uqff: Option<PathBuf>,
@@ -194,6 +196,8 @@ impl NormalLoaderBuilder {
             Some(NormalLoaderType::Gemma2) => Box::new(Gemma2Loader),
             Some(NormalLoaderType::Starcoder2) => Box::new(Starcoder2Loader),
             Some(NormalLoaderType::Phi3_5MoE) => Box::new(Phi3_5MoELoader),
+            Some(NormalLoaderType::Mamba) => Box::new(MambaLoader),
+            Some(NormalLoaderType::DeepSeekV2) => Box::new(DeepSeekV2Loader),
             None => Box::new(AutoLoader),
         };
         Ok(Box::new(NormalLoader {
@@ -286,6 +290,10 @@ impl Loader for NormalLoader {
             warn!("Device mapping or device topology and PagedAttention are incompatible, disabling PagedAttention.");
             paged_attn_config = None;
         }
+        if paged_attn_config.is_some() && !self.inner.supports_paged_attention(&config)? {
+            warn!("This model architecture does not support PagedAttention, disabling PagedAttention.");
+            paged_attn_config = None;
+        }
 
         let mapper = mapper.into_mapper(
             self.inner.get_total_device_mapping_num_layers(&config)?,
@@ -406,6 +414,9 @@ impl Loader for NormalLoader {
         let paged_attn_config = if matches!(self.kind, ModelKind::Adapter { .. }) {
             warn!("Adapter models do not currently support PagedAttention, running without");
             None
+        } else if paged_attn_config.is_some() && model.cache().is_recurrent() {
+            warn!("Recurrent models have no KV cache for PagedAttention, running without");
+            None
         } else {
             paged_attn_config
         };
@@ -415,6 +426,7 @@ impl Loader for NormalLoader {
                 paged_attn_config.mem_gpu,
                 paged_attn_config.mem_cpu,
                 paged_attn_config.block_size,
+                paged_attn_config.preemption_mode,
                 dtype,
                 model.config(),
                 device,
@@ -455,6 +467,7 @@ impl Loader for NormalLoader {
                 cache_config,
                 cache_engine,
                 prompt_batchsize: self.config.prompt_batchsize,
+                kv_cache_type: self.config.kv_cache_type,
             }),
             topology: self.config.topology.clone(),
             silent,
@@ -618,6 +631,45 @@ impl Pipeline for NormalPipeline {
         };
         Ok(ForwardInputsResult::CausalGeneration { logits })
     }
+    fn forward_inputs_hidden_states(
+        &mut self,
+        inputs: Box<dyn Any>,
+    ) -> Result<ForwardInputsResult, candle_core::Error> {
+        let ModelInputs {
+            input_ids,
+            seqlen_offsets,
+            seqlen_offsets_kernel,
+            position_ids,
+            mut paged_attn_meta,
+            flash_meta,
+            ..
+        } = *inputs.downcast().expect("Downcast failed.");
+        if self.model.is_xlora() {
+            candle_core::bail!("Embeddings are not supported for X-LoRA models.");
+        }
+        let paged_attn_meta = match (
+            self.get_metadata().cache_engine.as_ref(),
+            &mut paged_attn_meta,
+        ) {
+            (Some(engine), Some(meta)) => Some((engine.get_kv_cache().clone(), meta)),
+            (Some(_), None) => {
+                candle_core::bail!("Forward step expected a PagedAttention input metadata. This was not provided, please ensure that the scheduler config is correctly configured for PagedAttention.")
+            }
+            (None, Some(_)) => {
+                candle_core::bail!("Forward step got a PagedAttention input metadata but there is no cache engine. Please raise an issue.")
+            }
+            (None, None) => None,
+        };
+        let hidden_states = self.model.forward_hidden_states(
+            &input_ids,
+            &seqlen_offsets,
+            seqlen_offsets_kernel,
+            position_ids,
+            paged_attn_meta,
+            &flash_meta,
+        )?;
+        Ok(ForwardInputsResult::Embeddings { hidden_states })
+    }
     async fn sample_causal_gen(
         &self,
         seqs: &mut [&mut Sequence],
@@ -1,12 +1,13 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use candle_core::{DType, Device, Result, Tensor};
+use candle_core::{DType, Device, Result, Tensor, D};
 use rand_isaac::Isaac64Rng;
+use tokenizers::Tokenizer;
 
 use crate::{
     get_bias_if_not_allowed,
     prefix_cacher::PrefixCacheManager,
-    sampler::Logprobs,
+    sampler::{Logprobs, TopLogprob},
     sequence::{Sequence, SequenceRecognizer},
 };
 
@@ -43,13 +44,22 @@ pub(crate) async fn finish_or_add_toks_to_seq(
         if rate_limit_allowed {
             if let Some(delta) = crate::handle_seq_error_ok!(seq.get_delta(), seq.responder()) {
                 if seq.get_mut_group().is_chat {
+                    let (content, tool_calls) =
+                        seq.get_tool_call_delta(delta.clone(), is_done.is_some());
+                    let finish_reason = if seq.has_streamed_tool_calls() {
+                        is_done.map(|_| "tool_calls".to_string())
+                    } else {
+                        is_done.map(|x| x.to_string())
+                    };
                     seq.add_streaming_chunk_choice_to_group(crate::ChunkChoice {
                         delta: crate::Delta {
-                            content: delta.clone(),
+                            content,
                             role: "assistant".to_string(),
+                            tool_calls,
                         },
                         index: seq.get_response_index(),
-                        finish_reason: is_done.map(|x| x.to_string()),
+                        finish_reason,
+                        stop_sequence: is_done.and_then(|x| seq.matched_stop_sequence(&x)),
                         logprobs: if seq.return_logprobs() {
                             Some(crate::ResponseLogprob {
                                 token: delta,
@@ -67,6 +77,7 @@ pub(crate) async fn finish_or_add_toks_to_seq(
                             text: delta.clone(),
                             index: seq.get_response_index(),
                             finish_reason: is_done.map(|x| x.to_string()),
+                            stop_sequence: is_done.and_then(|x| seq.matched_stop_sequence(&x)),
                             logprobs: if seq.return_logprobs() {
                                 Some(crate::ResponseLogprob {
                                     token: delta,
@@ -105,138 +116,260 @@ pub(crate) async fn finish_or_add_toks_to_seq(
             }
         }
     } else if let Some(reason) = is_done {
-        /*
-        ***********************
-        Finish the sequence now
-        ***********************
-        */
-        {
-            seq.set_state(crate::sequence::SequenceState::Done(reason));
-            let (tokenizer, pipeline_name) = {
-                let pipeline_name = this.name();
-                let tokenizer = this.tokenizer();
-                (tokenizer, pipeline_name)
-            };
+        finish_seq(this, prefix_cacher, seq, reason, use_prefix_cacher).await?;
+    }
 
-            let logprobs = if seq.return_logprobs() {
-                let mut logprobs = Vec::new();
-                for logprob in seq.logprobs() {
-                    let resp_logprob = crate::ResponseLogprob {
-                        token: crate::handle_seq_error_ok!(
-                            tokenizer
-                            .as_ref()
-                            .ok_or(candle_core::Error::Msg(
-                                "`finish_or_add_toks_to_seq` requires the pipeline to have a tokenizer"
-                                    .to_string(),
-                            ))?.decode(&[logprob.token], false),
-                            seq.responder()
-                        ),
-                        bytes: logprob.bytes.clone().map(|b| b.into_bytes()),
-                        logprob: logprob.logprob,
-                        top_logprobs: logprob.top_logprobs.clone().unwrap(),
-                    };
-                    logprobs.push(resp_logprob);
-                }
-                Some(logprobs)
-            } else {
-                None
-            };
+    Ok(())
+}
 
-            let text = match reason {
-                crate::sequence::StopReason::Length(_)
-                | crate::sequence::StopReason::ModelLength(_)
-                | crate::sequence::StopReason::Eos
-                | crate::sequence::StopReason::StopTok(_)
-                | crate::sequence::StopReason::Canceled => {
-                    String::from_utf8_lossy(seq.completion_bytes())
-                        .trim_start()
-                        .to_string()
-                }
-                crate::sequence::StopReason::StopString {
-                    completion_bytes_pos,
-                    ..
-                } => {
-                    let txt = String::from_utf8_lossy(seq.completion_bytes());
-                    txt[..completion_bytes_pos].trim_start().to_string()
-                }
-                crate::sequence::StopReason::GeneratedImage => {
-                    candle_core::bail!("Stop reason was `GeneratedImage`.")
-                }
-            };
+fn top_logprobs_map(top_logprobs: &[TopLogprob]) -> HashMap<String, f32> {
+    top_logprobs
+        .iter()
+        .map(|top| {
+            (
+                top.bytes.clone().unwrap_or_else(|| top.token.to_string()),
+                top.logprob,
+            )
+        })
+        .collect()
+}
 
-            if seq.get_mut_group().is_chat {
-                let mut tool_calls = Vec::new();
-                let mut text_new = Some(text.clone());
-                if let Some(ref matcher) = seq.tools {
-                    let calls = matcher.get_call(&text).map_err(candle_core::Error::msg)?;
-                    if !calls.is_empty() {
-                        text_new = None;
-                    }
-                    tool_calls = calls;
-                }
-                let choice = crate::Choice {
-                    finish_reason: reason.to_string(),
-                    index: seq.get_response_index(),
-                    message: crate::ResponseMessage {
-                        content: text_new,
-                        role: "assistant".to_string(),
-                        tool_calls,
-                    },
-                    logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
-                };
-                seq.add_choice_to_group(choice);
-            } else {
-                let choice = crate::CompletionChoice {
-                    finish_reason: reason.to_string(),
-                    index: seq.get_response_index(),
-                    text,
-                    logprobs: None,
-                };
-                seq.add_completion_choice_to_group(choice);
-            }
+/// The char offset of each prompt token in the decoded prompt, which is what is echoed. Special
+/// tokens such as BOS are not part of the text. Each token is decoded after a few of the tokens
+/// before it, so that tokenizers which strip the leading space of the first token get the same text
+/// as when decoding the whole prompt.
+fn prompt_text_offsets(tokenizer: &Tokenizer, toks: &[u32]) -> anyhow::Result<Vec<usize>> {
+    const CONTEXT_TOKS: usize = 4;
+    let decoded_len = |toks: &[u32]| {
+        tokenizer
+            .decode(toks, true)
+            .map(|text| text.chars().count())
+            .map_err(anyhow::Error::msg)
+    };
+    let mut offsets = Vec::with_capacity(toks.len());
+    let mut offset = 0;
+    for i in 0..toks.len() {
+        offsets.push(offset);
+        let start = i.saturating_sub(CONTEXT_TOKS);
+        offset += decoded_len(&toks[start..=i])?.saturating_sub(decoded_len(&toks[start..i])?);
+    }
+    Ok(offsets)
+}
 
-            if use_prefix_cacher {
-                prefix_cacher.add_sequence(seq);
-                prefix_cacher.evict_to_cpu()?;
-            }
+/// Build the OpenAI style logprobs of a completion choice. If the prompt is echoed, its tokens come
+/// first, and the text offsets account for it.
+fn completion_logprobs(
+    tokenizer: &Tokenizer,
+    seq: &Sequence,
+    logprobs: Vec<crate::ResponseLogprob>,
+) -> anyhow::Result<crate::CompletionLogprobs> {
+    let mut tokens = Vec::new();
+    let mut token_logprobs = Vec::new();
+    let mut top_logprobs = Vec::new();
+    let mut text_offset = Vec::new();
 
-            let group = seq.get_mut_group();
-            if group.is_chat {
-                group
-                    .maybe_send_chat_done_response(
-                        crate::ChatCompletionResponse {
-                            id: seq.id().to_string(),
-                            choices: group.get_choices().to_vec(),
-                            created: seq.creation_time(),
-                            model: pipeline_name,
-                            system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
-                            object: "chat.completion".to_string(),
-                            usage: group.get_usage(),
-                        },
-                        seq.responder(),
-                    )
-                    .await
-                    .map_err(candle_core::Error::msg)?;
-            } else {
-                group
-                    .maybe_send_completion_done_response(
-                        crate::CompletionResponse {
-                            id: seq.id().to_string(),
-                            choices: group.get_completion_choices().to_vec(),
-                            created: seq.creation_time(),
-                            model: pipeline_name,
-                            system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
-                            object: "text_completion".to_string(),
-                            usage: group.get_usage(),
-                        },
-                        seq.responder(),
-                    )
-                    .await
-                    .map_err(candle_core::Error::msg)?;
+    // The echoed prompt is the prefix of the choice text.
+    let prefix_len = seq.prefix().map_or(0, |prefix| prefix.chars().count());
+
+    if seq.returns_prompt_logprobs() {
+        let prompt_logprobs = seq.prompt_logprobs();
+        let prompt_toks = &seq.get_toks()[..seq.prompt_tokens()];
+        let offsets = prompt_text_offsets(tokenizer, prompt_toks)?;
+        for (i, (tok, offset)) in prompt_toks.iter().zip(offsets).enumerate() {
+            let token = tokenizer
+                .decode(&[*tok], false)
+                .map_err(anyhow::Error::msg)?;
+            // The first token has nothing to be predicted from.
+            let logprob = i.checked_sub(1).and_then(|i| prompt_logprobs.get(i));
+            text_offset.push(offset.min(prefix_len));
+            tokens.push(token);
+            token_logprobs.push(logprob.map(|logprob| logprob.logprob));
+            top_logprobs.push(logprob.map(|logprob| {
+                top_logprobs_map(logprob.top_logprobs.as_deref().unwrap_or_default())
+            }));
+        }
+    }
+
+    // The completion text does not include its leading whitespace.
+    let completion = logprobs
+        .iter()
+        .map(|logprob| logprob.token.as_str())
+        .collect::<String>();
+    let trimmed = completion.chars().count() - completion.trim_start().chars().count();
+    let mut completion_offset = 0usize;
+    for logprob in logprobs {
+        text_offset.push(prefix_len + completion_offset.saturating_sub(trimmed));
+        completion_offset += logprob.token.chars().count();
+        token_logprobs.push(Some(logprob.logprob));
+        top_logprobs.push(Some(top_logprobs_map(&logprob.top_logprobs)));
+        tokens.push(logprob.token);
+    }
+
+    Ok(crate::CompletionLogprobs {
+        tokens,
+        token_logprobs,
+        top_logprobs,
+        text_offset,
+    })
+}
+
+/// The text of a finished sequence. A matched stop string and the text after it are cut off at the
+/// byte position of the match in the completion bytes.
+fn finished_text(completion_bytes: &[u8], reason: crate::sequence::StopReason) -> Result<String> {
+    Ok(match reason {
+        crate::sequence::StopReason::Length(_)
+        | crate::sequence::StopReason::ModelLength(_)
+        | crate::sequence::StopReason::Eos
+        | crate::sequence::StopReason::StopTok(_)
+        | crate::sequence::StopReason::Canceled => String::from_utf8_lossy(completion_bytes)
+            .trim_start()
+            .to_string(),
+        crate::sequence::StopReason::StopString {
+            completion_bytes_pos,
+            ..
+        } => String::from_utf8_lossy(&completion_bytes[..completion_bytes_pos])
+            .trim_start()
+            .to_string(),
+        crate::sequence::StopReason::GeneratedImage => {
+            candle_core::bail!("Stop reason was `GeneratedImage`.")
+        }
+        crate::sequence::StopReason::GeneratedEmbedding => {
+            candle_core::bail!("Stop reason was `GeneratedEmbedding`.")
+        }
+        crate::sequence::StopReason::GeneratedScore => {
+            candle_core::bail!("Stop reason was `GeneratedScore`.")
+        }
+    })
+}
+
+/// Finish the sequence now: add its choice to the group and send the response once all choices are in.
+pub(crate) async fn finish_seq(
+    this: &dyn Pipeline,
+    prefix_cacher: &mut PrefixCacheManager,
+    seq: &mut Sequence,
+    reason: crate::sequence::StopReason,
+    use_prefix_cacher: bool,
+) -> Result<()> {
+    seq.set_state(crate::sequence::SequenceState::Done(reason));
+    let (tokenizer, pipeline_name) = {
+        let pipeline_name = this.name();
+        let tokenizer = this.tokenizer();
+        (tokenizer, pipeline_name)
+    };
+
+    let logprobs = if seq.return_logprobs() {
+        let mut logprobs = Vec::new();
+        for logprob in seq.logprobs() {
+            let resp_logprob = crate::ResponseLogprob {
+                token: crate::handle_seq_error_ok!(
+                    tokenizer
+                        .as_ref()
+                        .ok_or(candle_core::Error::Msg(
+                            "`finish_or_add_toks_to_seq` requires the pipeline to have a tokenizer"
+                                .to_string(),
+                        ))?
+                        .decode(&[logprob.token], false),
+                    seq.responder()
+                ),
+                bytes: logprob.bytes.clone().map(|b| b.into_bytes()),
+                logprob: logprob.logprob,
+                top_logprobs: logprob.top_logprobs.clone().unwrap(),
+            };
+            logprobs.push(resp_logprob);
+        }
+        Some(logprobs)
+    } else {
+        None
+    };
+
+    let text = finished_text(seq.completion_bytes(), reason)?;
+
+    if seq.get_mut_group().is_chat {
+        let mut tool_calls = Vec::new();
+        let mut text_new = Some(text.clone());
+        if let Some(ref matcher) = seq.tools {
+            let calls = matcher.get_call(&text).map_err(candle_core::Error::msg)?;
+            if !calls.is_empty() {
+                text_new = None;
             }
+            tool_calls = calls;
         }
-        this.reset_non_granular_state();
+        let choice = crate::Choice {
+            finish_reason: reason.to_string(),
+            stop_sequence: seq.matched_stop_sequence(&reason),
+            index: seq.get_response_index(),
+            message: crate::ResponseMessage {
+                content: text_new,
+                role: "assistant".to_string(),
+                tool_calls,
+            },
+            logprobs: logprobs.map(|l| crate::Logprobs { content: Some(l) }),
+        };
+        seq.add_choice_to_group(choice);
+    } else {
+        let logprobs = match logprobs {
+            Some(logprobs) => {
+                let tokenizer = tokenizer.as_ref().ok_or(candle_core::Error::Msg(
+                    "`finish_or_add_toks_to_seq` requires the pipeline to have a tokenizer"
+                        .to_string(),
+                ))?;
+                Some(crate::handle_seq_error_ok!(
+                    completion_logprobs(tokenizer, seq, logprobs),
+                    seq.responder()
+                ))
+            }
+            None => None,
+        };
+        let choice = crate::CompletionChoice {
+            finish_reason: reason.to_string(),
+            stop_sequence: seq.matched_stop_sequence(&reason),
+            index: seq.get_response_index(),
+            text,
+            logprobs,
+        };
+        seq.add_completion_choice_to_group(choice);
+    }
+
+    if use_prefix_cacher {
+        prefix_cacher.add_sequence(seq);
+        prefix_cacher.evict_to_cpu()?;
+    }
+
+    let group = seq.get_mut_group();
+    if group.is_chat {
+        group
+            .maybe_send_chat_done_response(
+                crate::ChatCompletionResponse {
+                    id: seq.id().to_string(),
+                    choices: group.get_choices().to_vec(),
+                    created: seq.creation_time(),
+                    model: pipeline_name,
+                    system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                    object: "chat.completion".to_string(),
+                    usage: group.get_usage(),
+                },
+                seq.responder(),
+            )
+            .await
+            .map_err(candle_core::Error::msg)?;
+    } else {
+        group
+            .maybe_send_completion_done_response(
+                crate::CompletionResponse {
+                    id: seq.id().to_string(),
+                    choices: group.get_completion_choices().to_vec(),
+                    created: seq.creation_time(),
+                    model: pipeline_name,
+                    system_fingerprint: crate::SYSTEM_FINGERPRINT.to_string(),
+                    object: "text_completion".to_string(),
+                    usage: group.get_usage(),
+                },
+                seq.responder(),
+            )
+            .await
+            .map_err(candle_core::Error::msg)?;
     }
+    this.reset_non_granular_state();
 
     Ok(())
 }
@@ -249,12 +382,28 @@ pub async fn sample_and_add_toks(
     disable_eos_stop: bool,
     rng: Arc<std::sync::Mutex<Isaac64Rng>>,
 ) -> Result<()> {
-    let seqs_len = seqs.len();
-    debug_assert_eq!(logits_seq.len(), seqs_len);
+    debug_assert_eq!(logits_seq.len(), seqs.len());
 
-    let use_async_pool = seqs_len > 1;
+    // Beam search sequences are not sampled, the engine extends them by their most likely tokens.
+    let mut sampled_seqs = Vec::with_capacity(seqs.len());
+    let mut sampled_logits = Vec::with_capacity(seqs.len());
+    for (logits_per_seq, seq) in std::iter::zip(logits_seq, seqs.iter_mut()) {
+        match seq.beam_width() {
+            Some(beam_width) => {
+                let candidates = beam_candidates(logits_per_seq, 2 * beam_width);
+                let candidates = crate::handle_seq_error_stateaware_ok!(candidates, seq);
+                seq.set_beam_candidates(candidates);
+            }
+            None => {
+                sampled_seqs.push(seq);
+                sampled_logits.push(logits_per_seq);
+            }
+        }
+    }
+
+    let use_async_pool = sampled_seqs.len() > 1;
 
-    let sampling_futures: Vec<_> = std::iter::zip(logits_seq, seqs.iter_mut())
+    let sampling_futures: Vec<_> = std::iter::zip(sampled_logits, sampled_seqs.iter_mut())
         .map(|(logits_per_seq, seq)| {
             let return_logprobs = seq.return_logprobs();
             sample_sequence(
@@ -270,7 +419,7 @@ pub async fn sample_and_add_toks(
         .collect();
     let sampled_vec = futures::future::join_all(sampling_futures).await;
 
-    for (sampled, seq) in std::iter::zip(sampled_vec, seqs.iter_mut()) {
+    for (sampled, seq) in std::iter::zip(sampled_vec, sampled_seqs.iter_mut()) {
         let next_token = crate::handle_seq_error_stateaware_ok!(sampled, seq);
 
         let metadata = this.get_metadata();
@@ -286,6 +435,26 @@ pub async fn sample_and_add_toks(
     Ok(())
 }
 
+/// The `n` most likely next tokens with their logprobs, in no particular order.
+fn beam_candidates(logits: Tensor, n: usize) -> Result<Vec<Logprobs>> {
+    let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+    let logprobs = candle_nn::ops::log_softmax(&logits, D::Minus1)?.to_vec1::<f32>()?;
+    let mut tokens = (0..logprobs.len()).collect::<Vec<_>>();
+    if n < tokens.len() {
+        tokens.select_nth_unstable_by(n - 1, |a, b| logprobs[*b].total_cmp(&logprobs[*a]));
+        tokens.truncate(n);
+    }
+    Ok(tokens
+        .into_iter()
+        .map(|token| Logprobs {
+            token: token as u32,
+            logprob: logprobs[token],
+            bytes: None,
+            top_logprobs: None,
+        })
+        .collect())
+}
+
 /// Async sample optionally adding to trie.
 #[allow(clippy::too_many_arguments)]
 pub async fn sample_sequence(
@@ -298,6 +467,7 @@ pub async fn sample_sequence(
     sample_speculative: bool,
 ) -> Result<Logprobs> {
     let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
+    let rng = seq.rng().unwrap_or(rng);
 
     let sampler = seq.sampler();
     let ctx_clone = seq.get_toks().to_vec();
@@ -401,16 +571,23 @@ pub struct SpeculativeSample {
     pub sample: Logprobs,
 }
 
-/// Async sample without modifying sequence.
+/// Async sample without modifying sequence. The sample after each of the `draft_tokens` is
+/// penalized with the draft tokens up to it in the context.
 pub async fn sample_target_sequence_speculative(
     logits: Tensor,
     seq: &mut Sequence,
     return_logprobs: bool,
     rng: Arc<std::sync::Mutex<Isaac64Rng>>,
     n_toks: usize,
+    draft_tokens: &[u32],
 ) -> Result<Vec<SpeculativeSample>> {
     let mut sampled = Vec::new();
-    for chunk in logits.chunk(n_toks, 1)? {
+    let mut n_added = 0;
+    for (i, chunk) in logits.chunk(n_toks, 1)?.into_iter().enumerate() {
+        if let Some(tok) = i.checked_sub(1).and_then(|i| draft_tokens.get(i)) {
+            seq.add_tmp_tok(*tok);
+            n_added += 1;
+        }
         sampled.push(SpeculativeSample {
             sample: sample_sequence(
                 chunk,
@@ -424,5 +601,186 @@ pub async fn sample_target_sequence_speculative(
             .await?,
         });
     }
+    seq.remove_tmp_tok(n_added);
     Ok(sampled)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use candle_core::{DType, Device, Tensor};
+    use rand::SeedableRng;
+    use rand_isaac::Isaac64Rng;
+    use tokenizers::{models::wordlevel::WordLevel, AddedToken, Tokenizer};
+
+    use super::{completion_logprobs, finished_text, sample_sequence};
+    use crate::{
+        sampler::Sampler,
+        sequence::{Sequence, StopReason, TestSequence},
+        ResponseLogprob,
+    };
+
+    /// A word level tokenizer whose first token is a BOS special token.
+    fn tokenizer() -> Tokenizer {
+        let vocab = ["<s>", "hello", "world", "again", "<unk>"]
+            .iter()
+            .zip(0u32..)
+            .map(|(tok, id)| (tok.to_string(), id))
+            .collect::<HashMap<_, _>>();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.add_special_tokens(&[AddedToken::from("<s>", true)]);
+        tokenizer
+    }
+
+    fn echo_text_offsets(prompt_toks: Vec<u32>) -> (Vec<String>, Vec<usize>) {
+        let n_prompt_toks = prompt_toks.len();
+        let (seq, _rx) = TestSequence {
+            tokens: prompt_toks,
+            prompt: "hello world".to_string(),
+            prefix: Some("hello world".to_string()),
+            return_logprobs: true,
+            ..Default::default()
+        }
+        .build();
+        let mut seq = seq.with_prompt_logprobs();
+        seq.add_prompt_logprobs(
+            &Tensor::zeros((n_prompt_toks, 5), DType::F32, &Device::Cpu).unwrap(),
+            0,
+        )
+        .unwrap();
+        let completion = vec![ResponseLogprob {
+            token: " again".to_string(),
+            logprob: -1.,
+            bytes: None,
+            top_logprobs: Vec::new(),
+        }];
+        let logprobs = completion_logprobs(&tokenizer(), &seq, completion).unwrap();
+        assert_eq!(logprobs.token_logprobs.len(), n_prompt_toks + 1);
+        assert!(logprobs.token_logprobs[0].is_none());
+        (logprobs.tokens, logprobs.text_offset)
+    }
+
+    #[test]
+    fn test_echo_text_offsets_without_bos() {
+        let (tokens, offsets) = echo_text_offsets(vec![1, 2]);
+        assert_eq!(tokens, ["hello", "world", " again"]);
+        // "world" starts at the space before it, and the completion after the echoed "hello world".
+        assert_eq!(offsets, [0, 5, 11]);
+    }
+
+    #[test]
+    fn test_echo_text_offsets_with_bos() {
+        let (tokens, offsets) = echo_text_offsets(vec![0, 1, 2]);
+        assert_eq!(tokens, ["<s>", "hello", "world", " again"]);
+        // The BOS token is not part of the echoed text.
+        assert_eq!(offsets, [0, 0, 5, 11]);
+    }
+
+    #[test]
+    fn test_finished_text_stop_string() {
+        let bytes = " café</s> and more".as_bytes();
+        // The position of the stop string is in bytes, `é` is 2 bytes long.
+        let reason = StopReason::StopString {
+            stop_string_idx: 0,
+            completion_bytes_pos: 6,
+        };
+        assert_eq!(finished_text(bytes, reason).unwrap(), "café");
+        assert_eq!(
+            finished_text(bytes, StopReason::Length(5)).unwrap(),
+            "café</s> and more"
+        );
+        assert!(finished_text(bytes, StopReason::GeneratedScore).is_err());
+    }
+
+    /// A sequence sampled with a temperature of 1, seeded with `seed` if it is some.
+    fn sampled_seq(seed: Option<u64>, response_index: usize) -> Sequence {
+        let sampler = Sampler::new(
+            Some(1.0),
+            0,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            0.0,
+            1.0,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        let (seq, _rx) = TestSequence {
+            sampler: Some(sampler),
+            n_choices: 2,
+            response_index,
+            ..Default::default()
+        }
+        .build();
+        match seed {
+            Some(seed) => seq.with_seed(seed),
+            None => seq,
+        }
+    }
+
+    /// Sample a token of each sequence from the same uniform logits `n` times, with the RNG of the
+    /// engine for unseeded sequences.
+    fn sample(seqs: &mut [Sequence], engine_seed: u64, n: usize) -> Vec<Vec<u32>> {
+        let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(engine_seed)));
+        let logits = Tensor::zeros((1, 1, 64), DType::F32, &Device::Cpu).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut toks = vec![Vec::new(); seqs.len()];
+        for _ in 0..n {
+            for (seq, toks) in seqs.iter_mut().zip(&mut toks) {
+                let sample = runtime
+                    .block_on(sample_sequence(
+                        logits.clone(),
+                        seq,
+                        false,
+                        rng.clone(),
+                        false,
+                        false,
+                        false,
+                    ))
+                    .unwrap();
+                toks.push(sample.token);
+            }
+        }
+        toks
+    }
+
+    #[test]
+    fn test_seeded_sampling() {
+        let alone = sample(&mut [sampled_seq(Some(7), 0)], 0, 16).remove(0);
+        // The engine RNG and the unseeded sequence sampled in between do not change the tokens.
+        let batched = sample(&mut [sampled_seq(None, 0), sampled_seq(Some(7), 0)], 1, 16);
+        assert_eq!(batched[1], alone);
+
+        // The choices of a request get different but reproducible tokens.
+        let choices = sample(
+            &mut [sampled_seq(Some(7), 0), sampled_seq(Some(7), 1)],
+            2,
+            16,
+        );
+        assert_eq!(choices[0], alone);
+        assert_ne!(choices[1], alone);
+        assert_eq!(sample(&mut [sampled_seq(Some(7), 1)], 3, 16)[0], choices[1]);
+        // The seed of a choice is not the seed of another request.
+        assert_ne!(sample(&mut [sampled_seq(Some(8), 0)], 0, 16)[0], choices[1]);
+    }
+}
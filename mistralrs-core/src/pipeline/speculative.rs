@@ -5,7 +5,7 @@ use std::{
 };
 
 use anyhow::Result as anyhowResult;
-use candle_core::{Device, IndexOp, Result, Tensor};
+use candle_core::{Device, Result, Tensor};
 use mistralrs_quant::IsqType;
 use rand_isaac::Isaac64Rng;
 use tokenizers::Tokenizer;
@@ -17,7 +17,7 @@ use crate::{
         sampling::{
             finish_or_add_toks_to_seq, sample_sequence, sample_target_sequence_speculative,
         },
-        AdapterInstruction, Cache,
+        AdapterInstruction, Cache, KvCache, LayerCaches,
     },
     prefix_cacher::PrefixCacheManager,
     sequence::{Sequence, SequenceRecognizer},
@@ -26,10 +26,10 @@ use crate::{
 };
 
 use super::{
-    cache_manager::DefaultCacheManager, chat_template::ChatTemplate, sampling::SpeculativeSample,
-    AdapterActivationMixin, AnyMoePipelineMixin, CacheBackendMetadata, CacheInstruction,
-    CacheManager, CacheManagerMixin, ForwardInputsResult, GeneralMetadata, IsqPipelineMixin,
-    MetadataMixin, ModelCategory, ModelPaths, PreProcessingMixin,
+    cache_manager::DefaultCacheManager, chat_template::ChatTemplate, AdapterActivationMixin,
+    AnyMoePipelineMixin, CacheBackendMetadata, CacheInstruction, CacheManager, CacheManagerMixin,
+    ForwardInputsResult, GeneralMetadata, IsqPipelineMixin, MetadataMixin, ModelCategory,
+    ModelPaths, PreProcessingMixin,
 };
 
 /// A loader for a speculative pipeline using 2 [`Loader`]s.
@@ -148,6 +148,91 @@ impl Loader for SpeculativeLoader {
     }
 }
 
+/// A loader for a speculative pipeline which drafts tokens by prompt lookup instead of with a
+/// draft model.
+pub struct PromptLookupLoader {
+    pub target: Box<dyn Loader>,
+    pub config: PromptLookupConfig,
+}
+
+impl Loader for PromptLookupLoader {
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_hf(
+        &self,
+        revision: Option<String>,
+        token_source: TokenSource,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        if paged_attn_config.is_some() {
+            warn!(
+                "Speculative decoding does not currently support PagedAttention, running without"
+            );
+        }
+
+        let target = self.target.load_model_from_hf(
+            revision,
+            token_source,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            None,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            SpeculativePipeline::new_prompt_lookup(target, self.config)?,
+        )))
+    }
+
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    fn load_model_from_path(
+        &self,
+        paths: &Box<dyn ModelPaths>,
+        dtype: &dyn TryIntoDType,
+        device: &Device,
+        silent: bool,
+        mapper: DeviceMapMetadata,
+        in_situ_quant: Option<IsqType>,
+        paged_attn_config: Option<PagedAttentionConfig>,
+    ) -> anyhowResult<Arc<tokio::sync::Mutex<dyn Pipeline + Send + Sync>>> {
+        if paged_attn_config.is_some() {
+            warn!(
+                "Speculative decoding does not currently support PagedAttention, running without"
+            );
+        }
+
+        let target = self.target.load_model_from_path(
+            paths,
+            dtype,
+            device,
+            silent,
+            mapper,
+            in_situ_quant,
+            None,
+        )?;
+        Ok(Arc::new(tokio::sync::Mutex::new(
+            SpeculativePipeline::new_prompt_lookup(target, self.config)?,
+        )))
+    }
+    fn get_id(&self) -> String {
+        format!(
+            "Prompt lookup: tgt = `{}`, gamma = `{}`",
+            self.target.get_id(),
+            self.config.gamma,
+        )
+    }
+    fn get_kind(&self) -> ModelKind {
+        ModelKind::PromptLookup {
+            target: Box::new(self.target.get_kind()),
+        }
+    }
+}
+
 /// Speculative decoding pipeline: <https://arxiv.org/pdf/2211.17192>
 ///
 /// # Algorithm
@@ -159,19 +244,126 @@ impl Loader for SpeculativeLoader {
 /// - Else (q_i(x) > p_i(x)) accept that token with prob p_i(x)/q_i(x)
 ///     - If rejected, sample token from from p'_i(x) = norm(max(0, p(x) − q(x))) and do not take any more'
 ///
+/// Without a draft model, the draft tokens are found by prompt lookup: the tokens which followed
+/// an earlier occurrence of the last n-gram of the sequence. <https://github.com/apoorvumang/prompt-lookup-decoding>
 pub struct SpeculativePipeline {
     target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
-    draft: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+    draft: Draft,
     gamma: usize,
+    adaptive_gamma: Option<AdaptiveGamma>,
     metadata: Arc<GeneralMetadata>,
     category: ModelCategory,
 }
 
+enum Draft {
+    /// A draft model which is run `gamma` times.
+    Model(Arc<tokio::sync::Mutex<dyn Pipeline>>),
+    /// Up to `gamma` tokens found by prompt lookup.
+    PromptLookup { min_ngram: usize, max_ngram: usize },
+}
+
 #[derive(Copy, Clone)]
 /// Metadata for a speculative pipeline
 pub struct SpeculativeConfig {
     /// γ completions to run of the draft model
     pub gamma: usize,
+    /// Adapt γ to the acceptance rate of each sequence, starting from `gamma`
+    pub adaptive_gamma: Option<AdaptiveGamma>,
+}
+
+#[derive(Copy, Clone)]
+/// Metadata for a speculative pipeline using prompt lookup
+pub struct PromptLookupConfig {
+    /// Maximum number of draft tokens
+    pub gamma: usize,
+    /// Adapt γ to the acceptance rate of each sequence, starting from `gamma`
+    pub adaptive_gamma: Option<AdaptiveGamma>,
+    /// Smallest n-gram to look up
+    pub min_ngram: usize,
+    /// Largest n-gram to look up, larger n-grams are tried first
+    pub max_ngram: usize,
+}
+
+#[derive(Copy, Clone)]
+/// Bounds for γ when it is adapted to the acceptance rate of each sequence. γ grows by 2 after a
+/// step in which every draft token was accepted, and shrinks by 1 otherwise.
+pub struct AdaptiveGamma {
+    pub min_gamma: usize,
+    pub max_gamma: usize,
+}
+
+impl AdaptiveGamma {
+    fn validate(&self, gamma: usize) -> Result<()> {
+        if self.min_gamma == 0 || self.min_gamma > gamma || gamma > self.max_gamma {
+            candle_core::bail!(
+                "Adaptive gamma requires `1 <= min_gamma <= gamma <= max_gamma`, got `min_gamma` = {}, `gamma` = {} and `max_gamma` = {}.",
+                self.min_gamma,
+                gamma,
+                self.max_gamma
+            );
+        }
+        Ok(())
+    }
+
+    /// γ for the next step of a sequence.
+    fn next_gamma(&self, gamma: usize, n_drafted: usize, n_accepted: usize) -> usize {
+        if n_drafted == 0 {
+            gamma
+        } else if n_accepted == n_drafted {
+            (gamma + 2).min(self.max_gamma)
+        } else {
+            gamma.saturating_sub(1).max(self.min_gamma)
+        }
+    }
+}
+
+/// Remove the last `n` positions of the cache of every layer.
+fn narrow_cache(cache: &mut LayerCaches, n: usize) {
+    for cache in cache.iter_mut().flatten() {
+        cache.truncate(n);
+    }
+}
+
+/// The draft tokens run by the target model, padded or truncated to `n` so that every sequence
+/// runs the same number. The samples after the padding are not used.
+fn padded_prefill_tokens(draft_tokens: &[Vec<u32>], n: usize) -> Vec<Vec<u32>> {
+    draft_tokens
+        .iter()
+        .map(|toks| {
+            let mut toks = toks.clone();
+            toks.resize(n, 0);
+            toks
+        })
+        .collect()
+}
+
+/// The number of target samples which are accepted: those up to and including the first one
+/// which differs from its draft token.
+fn n_accepted(draft_tokens: &[u32], target_tokens: &[u32]) -> usize {
+    target_tokens
+        .iter()
+        .enumerate()
+        .position(|(i, tok)| draft_tokens.get(i) != Some(tok))
+        .map_or(target_tokens.len(), |i| i + 1)
+}
+
+/// Find the tokens which followed the most recent earlier occurrence of the last n-gram of
+/// `toks`, trying the largest n-grams first. At most `max_toks` tokens are returned.
+fn prompt_lookup(toks: &[u32], min_ngram: usize, max_ngram: usize, max_toks: usize) -> &[u32] {
+    for n in (min_ngram.max(1)..=max_ngram).rev() {
+        if toks.len() <= n {
+            continue;
+        }
+        let ngram = &toks[toks.len() - n..];
+        if let Some(start) = toks[..toks.len() - 1]
+            .windows(n)
+            .rposition(|window| window == ngram)
+        {
+            let end = (start + n + max_toks).min(toks.len());
+            return &toks[start + n..end];
+        }
+    }
+    &[]
 }
 
 impl SpeculativePipeline {
@@ -199,6 +391,11 @@ impl SpeculativePipeline {
         {
             candle_core::bail!("Target and draft models' tokenizer vocab do not match. This is required for speculative decoding.");
         }
+        if get_mut_arcmutex!(target).cache().is_recurrent()
+            || get_mut_arcmutex!(draft).cache().is_recurrent()
+        {
+            candle_core::bail!("Recurrent models such as Mamba cannot roll back their state to the accepted tokens, so they do not support speculative decoding.");
+        }
         if get_mut_arcmutex!(target).category() != get_mut_arcmutex!(draft).category() {
             candle_core::bail!("Target and draft models' category do not match. This is required for speculative decoding.");
         }
@@ -213,13 +410,52 @@ impl SpeculativePipeline {
         {
             candle_core::bail!("Target and draft models' input processors do not match. This is required for speculative decoding.");
         }
+        if let Some(adaptive_gamma) = &config.adaptive_gamma {
+            adaptive_gamma.validate(config.gamma)?;
+        }
         let metadata = get_mut_arcmutex!(target).get_metadata().clone();
         let category = get_mut_arcmutex!(target).category();
         // TODO: some checks or relaxation here?
         Ok(Self {
             target,
-            draft,
+            draft: Draft::Model(draft),
             gamma: config.gamma,
+            adaptive_gamma: config.adaptive_gamma,
+            metadata,
+            category,
+        })
+    }
+
+    pub fn new_prompt_lookup(
+        target: Arc<tokio::sync::Mutex<dyn Pipeline>>,
+        config: PromptLookupConfig,
+    ) -> Result<Self> {
+        if config.gamma == 0 {
+            candle_core::bail!("Prompt lookup requires `gamma` to be at least 1.");
+        }
+        if config.min_ngram == 0 || config.min_ngram > config.max_ngram {
+            candle_core::bail!(
+                "Prompt lookup requires `1 <= min_ngram <= max_ngram`, got `min_ngram` = {} and `max_ngram` = {}.",
+                config.min_ngram,
+                config.max_ngram
+            );
+        }
+        if get_mut_arcmutex!(target).cache().is_recurrent() {
+            candle_core::bail!("Recurrent models such as Mamba cannot roll back their state to the accepted tokens, so they do not support prompt lookup decoding.");
+        }
+        if let Some(adaptive_gamma) = &config.adaptive_gamma {
+            adaptive_gamma.validate(config.gamma)?;
+        }
+        let metadata = get_mut_arcmutex!(target).get_metadata().clone();
+        let category = get_mut_arcmutex!(target).category();
+        Ok(Self {
+            target,
+            draft: Draft::PromptLookup {
+                min_ngram: config.min_ngram,
+                max_ngram: config.max_ngram,
+            },
+            gamma: config.gamma,
+            adaptive_gamma: config.adaptive_gamma,
             metadata,
             category,
         })
@@ -238,29 +474,32 @@ impl PreProcessingMixin for SpeculativePipeline {
 impl IsqPipelineMixin for SpeculativePipeline {
     fn re_isq_model(&mut self, dtype: IsqType) -> anyhow::Result<()> {
         get_mut_arcmutex!(self.target).re_isq_model(dtype)?;
-        get_mut_arcmutex!(self.draft).re_isq_model(dtype)
+        if let Draft::Model(draft) = &self.draft {
+            get_mut_arcmutex!(draft).re_isq_model(dtype)?;
+        }
+        Ok(())
     }
 }
 
 impl CacheManagerMixin for SpeculativePipeline {
-    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_in_cache(
-            &*get_mut_arcmutex!(self.draft),
-            seqs,
-            modify_draft_cache,
-        );
+    fn clone_in_cache(&self, seqs: &mut [&mut Sequence], _modify_draft_cache: bool) {
+        // The cache of the draft model is the draft cache of the sequences.
+        if let Draft::Model(draft) = &self.draft {
+            DefaultCacheManager.clone_in_cache(&*get_mut_arcmutex!(draft), seqs, true);
+        }
         DefaultCacheManager.clone_in_cache(&*get_mut_arcmutex!(self.target), seqs, false);
     }
-    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], modify_draft_cache: bool) {
-        DefaultCacheManager.clone_out_cache(
-            &*get_mut_arcmutex!(self.draft),
-            seqs,
-            modify_draft_cache,
-        );
+    fn clone_out_cache(&self, seqs: &mut [&mut Sequence], _modify_draft_cache: bool) {
+        // The cache of the draft model is the draft cache of the sequences.
+        if let Draft::Model(draft) = &self.draft {
+            DefaultCacheManager.clone_out_cache(&*get_mut_arcmutex!(draft), seqs, true);
+        }
         DefaultCacheManager.clone_out_cache(&*get_mut_arcmutex!(self.target), seqs, false);
     }
     fn set_none_cache(&self, reset_non_granular: bool, modify_draft_cache: bool) {
-        DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(self.draft), modify_draft_cache);
+        if let Draft::Model(draft) = &self.draft {
+            DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(draft), modify_draft_cache);
+        }
         DefaultCacheManager.set_none_cache(&*get_mut_arcmutex!(self.target), false);
         if reset_non_granular {
             self.reset_non_granular_state()
@@ -275,7 +514,9 @@ impl AdapterActivationMixin for SpeculativePipeline {
     /// Returns the number of activated adapters.
     fn activate_adapters(&mut self, adapters: Vec<String>) -> anyhow::Result<usize> {
         let mut res = 0;
-        res += get_mut_arcmutex!(self.draft).activate_adapters(adapters.clone())?;
+        if let Draft::Model(draft) = &self.draft {
+            res += get_mut_arcmutex!(draft).activate_adapters(adapters.clone())?;
+        }
         res += get_mut_arcmutex!(self.target).activate_adapters(adapters)?;
         Ok(res)
     }
@@ -289,16 +530,25 @@ impl MetadataMixin for SpeculativePipeline {
         get_mut_arcmutex!(self.target).tokenizer()
     }
     fn name(&self) -> String {
-        format!(
-            "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
-            get_mut_arcmutex!(self.target).name(),
-            get_mut_arcmutex!(self.draft).name(),
-            self.gamma,
-        )
+        match &self.draft {
+            Draft::Model(draft) => format!(
+                "Speculative: tgt = `{}`, draft = `{}`, gamma = `{}`",
+                get_mut_arcmutex!(self.target).name(),
+                get_mut_arcmutex!(draft).name(),
+                self.gamma,
+            ),
+            Draft::PromptLookup { .. } => format!(
+                "Prompt lookup: tgt = `{}`, gamma = `{}`",
+                get_mut_arcmutex!(self.target).name(),
+                self.gamma,
+            ),
+        }
     }
     fn reset_non_granular_state(&self) {
         get_mut_arcmutex!(self.target).reset_non_granular_state();
-        get_mut_arcmutex!(self.draft).reset_non_granular_state();
+        if let Draft::Model(draft) = &self.draft {
+            get_mut_arcmutex!(draft).reset_non_granular_state();
+        }
     }
     fn get_metadata(&self) -> Arc<GeneralMetadata> {
         self.metadata.clone()
@@ -360,6 +610,9 @@ impl Pipeline for SpeculativePipeline {
                             }
                             AdapterInstruction::None => 0,
                         };
+                        // The caches are narrowed per sequence after the step, so the caches of
+                        // the pipelines are never up to date.
+                        self.clone_in_cache(input_seqs, false)
                     }
                     CacheInstruction::Reset {
                         reset_non_granular,
@@ -382,79 +635,130 @@ impl Pipeline for SpeculativePipeline {
                     _ => unreachable!("Unreachable PRE cache op."),
                 }
 
-                assert_eq!(input_seqs.len(), 1);
-
-                let seq = &mut input_seqs[0];
-
-                // ======================= Run draft model gamma times producing tokens ============================
-                // ======================= Sample the `gamma` logits. ============================
-                let mut draft_samples = Vec::new();
-                for i in 0..self.gamma {
-                    let is_xlora = get_mut_arcmutex!(self.draft).get_metadata().is_xlora;
-                    let device = get_mut_arcmutex!(self.draft).device();
-                    let has_no_kv_cache =
-                        get_mut_arcmutex!(self.draft).get_metadata().has_no_kv_cache;
-                    let inputs = self
-                        .get_processor()
-                        .inputs_processor()
-                        .process_inputs(
-                            self.tokenizer(),
-                            &mut [seq],
-                            is_prompt && i == 0, // Only prompt (no kv cache) if first
-                            is_xlora,
-                            &device,
-                            has_no_kv_cache,
-                            None,
-                            None,
-                            None, // TODO: get block tables/handle it
-                            None, // TODO: do we support???
-                        )
-                        .nth(0)
-                        .unwrap()
-                        .unwrap();
-                    let logits = get_mut_arcmutex!(self.draft).forward_inputs(Box::new(inputs))?;
-                    #[allow(irrefutable_let_patterns)]
-                    let ForwardInputsResult::CausalGeneration { logits } = logits
-                    else {
-                        candle_core::bail!(
-                            "Speculative decoding requires `CausalGeneration` forward results"
-                        );
-                    };
-
-                    let sample = sample_sequence(
-                        logits.clone(),
-                        seq,
-                        seq.return_logprobs(),
-                        rng.clone(),
-                        false, // todo tune
-                        false, // do not add to tok trie yet
-                        true,
-                    )
-                    .await?;
-                    seq.add_tmp_tok(sample.token);
-                    draft_samples.push(SpeculativeSample { sample });
+                if input_seqs.len() > 1 {
+                    if let Draft::Model(draft) = &self.draft {
+                        if get_mut_arcmutex!(draft).get_metadata().is_xlora {
+                            candle_core::bail!("Speculative decoding with an X-LoRA draft model only supports one sequence at a time.");
+                        }
+                    }
                 }
-                seq.remove_tmp_tok(self.gamma);
 
-                // ======================= Add all draft tokens but the last one. Add the last from the seq. ============================
-                let mut draft_prefill_tokens = if is_prompt {
-                    seq.get_toks().to_vec()
-                } else {
-                    vec![*seq.get_toks().last().unwrap()]
-                };
-                for (i, sample) in draft_samples.iter().enumerate() {
-                    if i == draft_samples.len() - 1 {
-                        continue;
+                let gammas = input_seqs
+                    .iter()
+                    .map(|seq| seq.speculative_gamma().unwrap_or(self.gamma))
+                    .collect::<Vec<_>>();
+
+                // ======================= Produce the draft tokens ============================
+                // The target model runs the same number of draft tokens for every sequence, and
+                // samples `n_target` positions. `draft_tokens` are the tokens which are checked
+                // against the target samples, and may be fewer.
+                let (draft_tokens, prefill_draft_tokens, n_target) = match &self.draft {
+                    Draft::Model(draft) => {
+                        // ======================= Run draft model gamma times producing tokens ============================
+                        // ======================= Sample the `gamma` logits. ============================
+                        let max_gamma = *gammas.iter().max().unwrap();
+                        let mut draft_tokens = vec![Vec::new(); input_seqs.len()];
+                        for i in 0..max_gamma {
+                            let is_xlora = get_mut_arcmutex!(draft).get_metadata().is_xlora;
+                            let device = get_mut_arcmutex!(draft).device();
+                            let has_no_kv_cache =
+                                get_mut_arcmutex!(draft).get_metadata().has_no_kv_cache;
+                            let inputs = self
+                                .get_processor()
+                                .inputs_processor()
+                                .process_inputs(
+                                    self.tokenizer(),
+                                    input_seqs,
+                                    is_prompt && i == 0, // Only prompt (no kv cache) if first
+                                    is_xlora,
+                                    &device,
+                                    has_no_kv_cache,
+                                    None,
+                                    None,
+                                    None, // TODO: get block tables/handle it
+                                    None, // TODO: do we support???
+                                )
+                                .nth(0)
+                                .unwrap()
+                                .unwrap();
+                            let logits =
+                                get_mut_arcmutex!(draft).forward_inputs(Box::new(inputs))?;
+                            #[allow(irrefutable_let_patterns)]
+                            let ForwardInputsResult::CausalGeneration { logits } = logits
+                            else {
+                                candle_core::bail!(
+                                    "Speculative decoding requires `CausalGeneration` forward results"
+                                );
+                            };
+
+                            for (seq_i, seq) in input_seqs.iter_mut().enumerate() {
+                                let sample = sample_sequence(
+                                    logits.narrow(0, seq_i, 1)?,
+                                    seq,
+                                    seq.return_logprobs(),
+                                    rng.clone(),
+                                    false, // todo tune
+                                    false, // do not add to tok trie yet
+                                    true,
+                                )
+                                .await?;
+                                seq.add_tmp_tok(sample.token);
+                                draft_tokens[seq_i].push(sample.token);
+                            }
+                        }
+                        for seq in input_seqs.iter_mut() {
+                            seq.remove_tmp_tok(max_gamma);
+                        }
+                        // The last draft token is checked against the last target sample, so it
+                        // is not run.
+                        let prefill_draft_tokens =
+                            padded_prefill_tokens(&draft_tokens, max_gamma - 1);
+                        let draft_tokens = draft_tokens
+                            .into_iter()
+                            .zip(&gammas)
+                            .map(|(mut toks, gamma)| {
+                                toks.truncate(*gamma);
+                                toks
+                            })
+                            .collect::<Vec<_>>();
+                        (draft_tokens, prefill_draft_tokens, max_gamma)
                     }
-                    draft_prefill_tokens.push(sample.sample.token);
+                    Draft::PromptLookup {
+                        min_ngram,
+                        max_ngram,
+                    } => {
+                        let draft_tokens = input_seqs
+                            .iter()
+                            .zip(&gammas)
+                            .map(|(seq, gamma)| {
+                                prompt_lookup(seq.get_toks(), *min_ngram, *max_ngram, *gamma)
+                                    .to_vec()
+                            })
+                            .collect::<Vec<_>>();
+                        // The target model also samples the token after the last draft token.
+                        let n_target = draft_tokens.iter().map(Vec::len).max().unwrap() + 1;
+                        let prefill_draft_tokens =
+                            padded_prefill_tokens(&draft_tokens, n_target - 1);
+                        (draft_tokens, prefill_draft_tokens, n_target)
+                    }
+                };
+
+                // ======================= Add the draft tokens which are run by the target model. Add the last from the seq. ============================
+                for (seq, prefill_draft_tokens) in input_seqs.iter_mut().zip(prefill_draft_tokens) {
+                    let mut draft_prefill_tokens = if is_prompt {
+                        seq.get_toks().to_vec()
+                    } else {
+                        vec![*seq.get_toks().last().unwrap()]
+                    };
+                    draft_prefill_tokens.extend(prefill_draft_tokens);
+                    seq.set_prefill_toks(draft_prefill_tokens);
                 }
-                seq.set_prefill_toks(draft_prefill_tokens);
 
                 // ======================= Run the model with all draft tokens. ============================
 
                 let initial_cache_len = get_mut_arcmutex!(self.target).cache().lock()[0]
                     .as_ref()
-                    .map(|(k, _)| k.dims()[2])
+                    .map(KvCache::current_seq_len)
                     .unwrap_or(0);
 
                 // ========= Run the model ============
@@ -468,12 +772,12 @@ impl Pipeline for SpeculativePipeline {
                     .inputs_processor()
                     .process_inputs(
                         self.tokenizer(),
-                        &mut [seq],
+                        input_seqs,
                         true, // use the "prefill" tokens
                         is_xlora,
                         &device,
                         has_no_kv_cache,
-                        Some((self.gamma, initial_cache_len)), // Get the last gamma, see above
+                        Some((n_target, initial_cache_len)), // Get the last n_target, see above
                         None,
                         None, // TODO: get block tables/handle it
                         None, // TODO: do we support???
@@ -491,72 +795,6 @@ impl Pipeline for SpeculativePipeline {
                     );
                 };
 
-                // Reset the prefill tokens
-                seq.reset_prefill_toks();
-
-                // ======================= Rejection sampling. ============================
-                // Map from each target sample to corresponding in draft sample
-                let samples = sample_target_sequence_speculative(
-                    logits.clone(),
-                    seq,
-                    seq.return_logprobs(),
-                    rng.clone(),
-                    self.gamma,
-                )
-                .await?;
-
-                let mut accepted_tokens = Vec::new();
-                for (target_sample, draft_sample) in zip(samples, draft_samples) {
-                    let tok = target_sample.sample.token;
-                    accepted_tokens.push(target_sample.sample);
-                    if draft_sample.sample.token != tok {
-                        break;
-                    }
-                }
-
-                // ======================= Narrow caches to account for rejections ============================
-                let n_not_accepted = self.gamma - accepted_tokens.len();
-                for (k, v) in get_mut_arcmutex!(self.draft)
-                    .cache()
-                    .lock()
-                    .iter_mut()
-                    .flatten()
-                {
-                    *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                    *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-                    for (k, v) in get_mut_arcmutex!(self.draft)
-                        .cache()
-                        .xlora_lock()
-                        .iter_mut()
-                        .flatten()
-                    {
-                        *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                        *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                    }
-                }
-                for (k, v) in get_mut_arcmutex!(self.target)
-                    .cache()
-                    .lock()
-                    .iter_mut()
-                    .flatten()
-                {
-                    *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                    *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                }
-                if get_mut_arcmutex!(self.draft).get_metadata().is_xlora {
-                    for (k, v) in get_mut_arcmutex!(self.target)
-                        .cache()
-                        .xlora_lock()
-                        .iter_mut()
-                        .flatten()
-                    {
-                        *k = k.i((.., .., ..k.dims()[2] - n_not_accepted, ..))?;
-                        *v = v.i((.., .., ..v.dims()[2] - n_not_accepted, ..))?;
-                    }
-                }
-
                 let eos_owned = get_mut_arcmutex!(self.target)
                     .get_metadata()
                     .eos_tok
@@ -566,42 +804,86 @@ impl Pipeline for SpeculativePipeline {
                 } else {
                     Some(&eos_owned[..])
                 };
-                // Add the tokens to the seq and the trie
-                for accepted in accepted_tokens {
-                    // Do not use the prefix cacher
-                    finish_or_add_toks_to_seq(
-                        self,
-                        prefix_cacher,
+
+                let mut n_not_accepted = Vec::with_capacity(input_seqs.len());
+                for (seq_i, (seq, draft_tokens)) in
+                    input_seqs.iter_mut().zip(draft_tokens).enumerate()
+                {
+                    // Reset the prefill tokens
+                    seq.reset_prefill_toks();
+
+                    // ======================= Rejection sampling. ============================
+                    // Map from each target sample to corresponding in draft sample
+                    let samples = sample_target_sequence_speculative(
+                        logits.narrow(0, seq_i, 1)?,
                         seq,
-                        accepted.clone(),
-                        eos_tok,
-                        false,
+                        seq.return_logprobs(),
+                        rng.clone(),
+                        n_target,
+                        &draft_tokens,
                     )
                     .await?;
-                    match seq.recognizer {
-                        SequenceRecognizer::Regex(ref mut rx) => {
-                            get_mut_arcmutex!(self.target)
-                                .get_metadata()
-                                .tok_trie
-                                .as_ref()
-                                .ok_or(candle_core::Error::Msg(
-                                    "`SpeculativePipeline::step` requires a token trie".to_string(),
-                                ))?
-                                .append_token(rx.as_mut(), accepted.token)
-                                .map_err(candle_core::Error::msg)?;
-                        }
-                        SequenceRecognizer::Cfg(ref mut cfg) => {
-                            get_mut_arcmutex!(self.target)
-                                .get_metadata()
-                                .tok_trie
-                                .as_ref()
-                                .ok_or(candle_core::Error::Msg(
-                                    "`SpeculativePipeline::step` requires a token trie".to_string(),
-                                ))?
-                                .append_token(cfg.as_mut(), accepted.token)
-                                .map_err(candle_core::Error::msg)?;
+
+                    let target_tokens = samples
+                        .iter()
+                        .map(|target_sample| target_sample.sample.token)
+                        .collect::<Vec<_>>();
+                    let accepted_tokens = samples
+                        .into_iter()
+                        .take(n_accepted(&draft_tokens, &target_tokens))
+                        .map(|target_sample| target_sample.sample)
+                        .collect::<Vec<_>>();
+                    n_not_accepted.push(n_target - accepted_tokens.len());
+
+                    // ======================= Record the acceptance and adapt gamma ============================
+                    let gamma = gammas[seq_i];
+                    let n_accepted_draft = zip(&draft_tokens, &accepted_tokens)
+                        .take_while(|(draft, accepted)| **draft == accepted.token)
+                        .count();
+                    let next_gamma = self.adaptive_gamma.map_or(gamma, |adaptive_gamma| {
+                        adaptive_gamma.next_gamma(gamma, draft_tokens.len(), n_accepted_draft)
+                    });
+                    seq.add_speculative_step(next_gamma, draft_tokens.len(), n_accepted_draft);
+
+                    // Add the tokens to the seq and the trie
+                    for accepted in accepted_tokens {
+                        // Do not use the prefix cacher
+                        finish_or_add_toks_to_seq(
+                            self,
+                            prefix_cacher,
+                            seq,
+                            accepted.clone(),
+                            eos_tok,
+                            false,
+                        )
+                        .await?;
+                        match seq.recognizer {
+                            SequenceRecognizer::Regex(ref mut rx) => {
+                                get_mut_arcmutex!(self.target)
+                                    .get_metadata()
+                                    .tok_trie
+                                    .as_ref()
+                                    .ok_or(candle_core::Error::Msg(
+                                        "`SpeculativePipeline::step` requires a token trie"
+                                            .to_string(),
+                                    ))?
+                                    .append_token(rx.as_mut(), accepted.token)
+                                    .map_err(candle_core::Error::msg)?;
+                            }
+                            SequenceRecognizer::Cfg(ref mut cfg) => {
+                                get_mut_arcmutex!(self.target)
+                                    .get_metadata()
+                                    .tok_trie
+                                    .as_ref()
+                                    .ok_or(candle_core::Error::Msg(
+                                        "`SpeculativePipeline::step` requires a token trie"
+                                            .to_string(),
+                                    ))?
+                                    .append_token(cfg.as_mut(), accepted.token)
+                                    .map_err(candle_core::Error::msg)?;
+                            }
+                            SequenceRecognizer::None => {}
                         }
-                        SequenceRecognizer::None => {}
                     }
                 }
 
@@ -621,10 +903,32 @@ impl Pipeline for SpeculativePipeline {
                 */
 
                 match post_op {
-                    CacheInstruction::Out => {
+                    // The sequences accept different numbers of tokens, so the caches are always
+                    // cloned out to narrow them per sequence.
+                    CacheInstruction::Out | CacheInstruction::Nothing(_) => {
                         self.clone_out_cache(input_seqs, true);
+                        // ======================= Narrow caches to account for rejections ============================
+                        let is_draft_model = matches!(self.draft, Draft::Model(_));
+                        for (seq, n_not_accepted) in input_seqs.iter_mut().zip(&n_not_accepted) {
+                            narrow_cache(seq.cache(), *n_not_accepted);
+                            if is_draft_model {
+                                narrow_cache(seq.draft_cache(), *n_not_accepted);
+                            }
+                            if seq.is_xlora() {
+                                narrow_cache(seq.xlora_cache(), *n_not_accepted);
+                            }
+                        }
+                        // The X-LoRA cache of the draft model stays in the pipeline, there is
+                        // only one sequence.
+                        if let Draft::Model(draft) = &self.draft {
+                            if get_mut_arcmutex!(draft).get_metadata().is_xlora {
+                                narrow_cache(
+                                    &mut get_mut_arcmutex!(draft).cache().xlora_lock(),
+                                    n_not_accepted[0],
+                                );
+                            }
+                        }
                     }
-                    CacheInstruction::Nothing(_) => (),
                     CacheInstruction::Reset {
                         reset_non_granular,
                         adapter_inst: _,
@@ -633,7 +937,7 @@ impl Pipeline for SpeculativePipeline {
                 }
 
                 // Done! We have:
-                // - Run the draft model gamma times
+                // - Run the draft model gamma times, or looked up the draft tokens
                 // - Reset draft model cache fully
                 // - Sampled draft model's distributions
                 // - Run target model
@@ -658,3 +962,122 @@ impl Pipeline for SpeculativePipeline {
 
 // TODO
 impl AnyMoePipelineMixin for SpeculativePipeline {}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Result, Tensor};
+
+    use super::{n_accepted, narrow_cache, padded_prefill_tokens, prompt_lookup, AdaptiveGamma};
+    use crate::{pipeline::KvCache, sequence::TestSequence};
+
+    /// Keys or values of a single head with a head dim of 1, holding their position.
+    fn positions(start: u32, end: u32) -> Result<Tensor> {
+        Tensor::arange(start, end, &Device::Cpu)?
+            .to_dtype(DType::F32)?
+            .reshape((1, 1, (), 1))
+    }
+
+    fn to_vec(x: &Tensor) -> Result<Vec<f32>> {
+        x.flatten_all()?.to_vec1()
+    }
+
+    #[test]
+    fn test_adaptive_gamma() {
+        let adaptive_gamma = AdaptiveGamma {
+            min_gamma: 2,
+            max_gamma: 8,
+        };
+        assert_eq!(adaptive_gamma.next_gamma(4, 4, 4), 6);
+        assert_eq!(adaptive_gamma.next_gamma(7, 7, 7), 8);
+        assert_eq!(adaptive_gamma.next_gamma(4, 4, 3), 3);
+        assert_eq!(adaptive_gamma.next_gamma(2, 2, 0), 2);
+        // Nothing was drafted.
+        assert_eq!(adaptive_gamma.next_gamma(4, 0, 0), 4);
+    }
+
+    #[test]
+    fn test_prompt_lookup() {
+        // The last bigram `[2, 3]` occurred before, followed by `[4, 5]`.
+        let toks = [1, 2, 3, 4, 5, 9, 2, 3];
+        assert_eq!(prompt_lookup(&toks, 1, 3, 4), &[4, 5, 9, 2]);
+        assert_eq!(prompt_lookup(&toks, 1, 3, 2), &[4, 5]);
+
+        // The most recent occurrence is used.
+        let toks = [7, 1, 7, 2, 7];
+        assert_eq!(prompt_lookup(&toks, 1, 1, 1), &[2]);
+
+        // Larger n-grams are preferred over more recent smaller ones.
+        let toks = [1, 2, 5, 3, 2, 6, 1, 2];
+        assert_eq!(prompt_lookup(&toks, 1, 2, 1), &[5]);
+
+        // No match.
+        assert!(prompt_lookup(&[1, 2, 3], 1, 3, 4).is_empty());
+        assert!(prompt_lookup(&[1, 2, 2, 3], 2, 3, 4).is_empty());
+    }
+
+    #[test]
+    fn test_batched_step_narrows_caches() -> Result<()> {
+        // Two sequences with 3 cached positions and gammas of 2 and 4. The draft model drafts
+        // `max_gamma` tokens for both, and the target model runs the last token and all but the
+        // last draft token, so 4 positions for both.
+        let mut seqs = [0u32, 100]
+            .into_iter()
+            .map(|start| {
+                let (mut seq, _rx) = TestSequence {
+                    layers: 2,
+                    ..Default::default()
+                }
+                .build();
+                let x = positions(start, start + 3)?;
+                *seq.cache() = vec![Some(KvCache::new(&x, &x)?); 2];
+                Ok(seq)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let drafted = [vec![5, 6, 10, 11], vec![1, 2, 3, 4]];
+        let prefill = padded_prefill_tokens(&drafted, 3);
+        assert_eq!(prefill, [vec![5, 6, 10], vec![1, 2, 3]]);
+        let draft_tokens = [&drafted[0][..2], &drafted[1][..]];
+
+        // The first sequence accepts both draft tokens and the target sample after them, the
+        // second only the first draft token and the corrected second one.
+        let n_not_accepted = [
+            4 - n_accepted(draft_tokens[0], &[5, 6, 7, 8]),
+            4 - n_accepted(draft_tokens[1], &[1, 9, 3, 4]),
+        ];
+        assert_eq!(n_not_accepted, [1, 2]);
+
+        // Run the batch and narrow the caches of the sequences.
+        for layer in 0..2 {
+            let caches = seqs
+                .iter_mut()
+                .map(|seq| seq.cache()[layer].clone().unwrap())
+                .collect::<Vec<_>>();
+            let mut batch = KvCache::cat(&caches)?;
+            let x = Tensor::cat(&[positions(3, 7)?, positions(103, 107)?], 0)?;
+            batch.append(&x, &x, true)?;
+            for (seq, cache) in seqs.iter_mut().zip(batch.chunk(2)?) {
+                seq.cache()[layer] = Some(cache);
+            }
+        }
+        for (seq, n_not_accepted) in seqs.iter_mut().zip(n_not_accepted) {
+            narrow_cache(seq.cache(), n_not_accepted);
+        }
+        // Every layer keeps the positions of the tokens in the sequence after the step, whose
+        // last token is not run yet.
+        for (seq, len) in seqs.iter_mut().zip([6, 5]) {
+            assert!(seq
+                .cache()
+                .iter()
+                .all(|cache| cache.as_ref().unwrap().current_seq_len() == len));
+        }
+        assert_eq!(
+            to_vec(&seqs[0].cache()[1].as_ref().unwrap().k()?)?,
+            [0., 1., 2., 3., 4., 5.]
+        );
+        assert_eq!(
+            to_vec(&seqs[1].cache()[1].as_ref().unwrap().v()?)?,
+            [100., 101., 102., 103., 104.]
+        );
+        Ok(())
+    }
+}
@@ -1,9 +1,13 @@
 use std::sync::{Arc, Mutex};
 
-use candle_core::{Device, Result, Tensor};
+use candle_core::{Device, Result};
 use radix_trie::{Trie, TrieCommon, TrieKey};
 
-use crate::{get_mut_arcmutex, pipeline::LayerCaches, sequence::Sequence};
+use crate::{
+    get_mut_arcmutex,
+    pipeline::{share_layer_caches, KvCache, LayerCaches, LayerStates, RecurrentState},
+    sequence::Sequence,
+};
 
 #[derive(PartialEq, Eq)]
 struct Tokens(Vec<u32>);
@@ -23,11 +27,16 @@ impl From<Vec<u32>> for Tokens {
     }
 }
 
-type EvictionCacheGroup = (Arc<Mutex<LayerCaches>>, Option<Arc<Mutex<LayerCaches>>>);
+type EvictionCacheGroup = (
+    Arc<Mutex<LayerCaches>>,
+    Option<Arc<Mutex<LayerCaches>>>,
+    Option<Arc<Mutex<LayerStates>>>,
+);
 
 pub struct PrefixCacheManager {
     caches: Trie<Tokens, Arc<Mutex<LayerCaches>>>,
     xlora_caches: Option<Trie<Tokens, Arc<Mutex<LayerCaches>>>>,
+    recurrent_states: Trie<Tokens, Arc<Mutex<LayerStates>>>,
     device: Device,
     pub n_on_device: usize,
     no_prefix_cache: bool,
@@ -38,6 +47,7 @@ pub struct PrefixCacheManager {
 pub struct MatchingCache {
     pub normal: LayerCaches,
     pub xlora: Option<LayerCaches>,
+    pub recurrent_states: Option<LayerStates>,
     pub toks: Vec<u32>,
 }
 
@@ -46,6 +56,7 @@ impl PrefixCacheManager {
         PrefixCacheManager {
             caches: Trie::new(),
             xlora_caches: if is_xlora { Some(Trie::new()) } else { None },
+            recurrent_states: Trie::new(),
             device,
             n_on_device,
             no_prefix_cache,
@@ -53,6 +64,10 @@ impl PrefixCacheManager {
         }
     }
 
+    pub fn is_enabled(&self) -> bool {
+        !self.no_prefix_cache
+    }
+
     /// This always keeps the cache on the device. If later on, a new seq cannot be allocated due to memory shortage,
     /// some caches will be evicted.
     pub fn add_sequence(&mut self, seq: &mut Sequence) {
@@ -62,26 +77,74 @@ impl PrefixCacheManager {
         let cache = Arc::new(Mutex::new(seq.cache().clone()));
         self.caches
             .insert(seq.get_toks().to_vec().into(), cache.clone());
-        if seq.is_xlora() {
+        let xlora_cache = if seq.is_xlora() {
             let xlora_cache = Arc::new(Mutex::new(seq.xlora_cache().clone()));
             self.xlora_caches
                 .as_mut()
                 .unwrap()
                 .insert(seq.get_toks().to_vec().into(), xlora_cache.clone());
-            self.eviction_cache_ptrs.push((cache, Some(xlora_cache)));
+            Some(xlora_cache)
         } else {
-            self.eviction_cache_ptrs.push((cache, None));
-        }
+            None
+        };
+        let recurrent_states = if seq.is_recurrent() {
+            let states = Arc::new(Mutex::new(seq.recurrent_states().clone()));
+            self.recurrent_states
+                .insert(seq.get_toks().to_vec().into(), states.clone());
+            Some(states)
+        } else {
+            None
+        };
+        self.eviction_cache_ptrs
+            .push((cache, xlora_cache, recurrent_states));
+    }
+
+    /// Whether the cached KV caches or recurrent states of a sequence are on the CPU. Models
+    /// with recurrent states have no KV caches.
+    fn is_on_cpu((cache, _, recurrent_states): &EvictionCacheGroup) -> bool {
+        let is_cpu = match recurrent_states {
+            Some(states) => get_mut_arcmutex!(states.as_ref())
+                .iter()
+                .flatten()
+                .next()
+                .map(|state| state.device().is_cpu()),
+            None => get_mut_arcmutex!(cache.as_ref())
+                .iter()
+                .flatten()
+                .next()
+                .map(|cache| cache.device().is_cpu()),
+        };
+        is_cpu.unwrap_or(true)
     }
 
     fn cache_to<'a>(
-        cache: impl Iterator<Item = &'a mut Option<(Tensor, Tensor)>>,
+        cache: impl Iterator<Item = &'a mut Option<KvCache>>,
         device: &Device,
     ) -> Result<()> {
-        for layer in cache {
-            if let Some((ref q, ref k)) = layer {
-                *layer = Some((q.to_device(device)?, k.to_device(device)?));
-            }
+        for layer in cache.flatten() {
+            *layer = layer.to_device(device)?;
+        }
+        Ok(())
+    }
+
+    fn states_to<'a>(
+        states: impl Iterator<Item = &'a mut Option<RecurrentState>>,
+        device: &Device,
+    ) -> Result<()> {
+        for layer in states.flatten() {
+            *layer = layer.to_device(device)?;
+        }
+        Ok(())
+    }
+
+    /// Move the KV caches and recurrent states of a sequence to the CPU.
+    fn group_to_cpu((cache, xlora_cache, recurrent_states): &EvictionCacheGroup) -> Result<()> {
+        Self::cache_to(get_mut_arcmutex!(cache).iter_mut(), &Device::Cpu)?;
+        if let Some(xlora_cache) = xlora_cache {
+            Self::cache_to(get_mut_arcmutex!(xlora_cache).iter_mut(), &Device::Cpu)?;
+        }
+        if let Some(states) = recurrent_states {
+            Self::states_to(get_mut_arcmutex!(states).iter_mut(), &Device::Cpu)?;
         }
         Ok(())
     }
@@ -92,40 +155,19 @@ impl PrefixCacheManager {
         if self.no_prefix_cache {
             return Ok(0);
         }
-        let mut n_on_device = 0;
-        for (cache, _) in &self.eviction_cache_ptrs {
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                n_on_device += 1;
-            }
-        }
+        let n_on_device = self
+            .eviction_cache_ptrs
+            .iter()
+            .filter(|group| !Self::is_on_cpu(group))
+            .count();
         let mut n_evicted = 0;
         // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
+        for group in &self.eviction_cache_ptrs {
             if n_on_device - n_evicted == self.n_on_device {
                 break;
             }
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
-
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
-                }
+            if !Self::is_on_cpu(group) {
+                Self::group_to_cpu(group)?;
                 n_evicted += 1;
             }
         }
@@ -138,22 +180,9 @@ impl PrefixCacheManager {
             return Ok(0);
         }
         // Intentionally evict the first ones first, as they are the oldest
-        for (cache, xlora_cache) in &self.eviction_cache_ptrs {
-            if !matches!(
-                get_mut_arcmutex!(cache.as_ref())[0]
-                    .as_ref()
-                    .unwrap()
-                    .0
-                    .device(),
-                Device::Cpu
-            ) {
-                let mut cache = get_mut_arcmutex!(cache);
-                let mut xlora_cache = xlora_cache.as_ref().map(|c| get_mut_arcmutex!(c));
-
-                Self::cache_to(cache.iter_mut(), &Device::Cpu)?;
-                if let Some(ref mut xlora_cache) = xlora_cache {
-                    Self::cache_to(xlora_cache.iter_mut(), &Device::Cpu)?;
-                }
+        for group in &self.eviction_cache_ptrs {
+            if !Self::is_on_cpu(group) {
+                Self::group_to_cpu(group)?;
             }
         }
         Ok(self.caches.len())
@@ -168,11 +197,19 @@ impl PrefixCacheManager {
         let toks = Tokens(toks.to_vec());
         if let Some(cache) = self.caches.get(&toks) {
             Self::cache_to(get_mut_arcmutex!(cache.as_ref()).iter_mut(), &self.device)?;
-            let cache = get_mut_arcmutex!(cache.as_ref()).clone();
+            // The sequences extending the cache must not write to its buffers.
+            let cache = share_layer_caches(&get_mut_arcmutex!(cache.as_ref()));
             let xlora_cache = if let Some(ref xlora_caches) = self.xlora_caches {
                 let mut xlora_cache = get_mut_arcmutex!(xlora_caches.get(&toks).unwrap().as_ref());
                 Self::cache_to(xlora_cache.iter_mut(), &self.device)?;
-                Some(xlora_cache.clone())
+                Some(share_layer_caches(&xlora_cache))
+            } else {
+                None
+            };
+            let recurrent_states = if let Some(states) = self.recurrent_states.get(&toks) {
+                let mut states = get_mut_arcmutex!(states.as_ref());
+                Self::states_to(states.iter_mut(), &self.device)?;
+                Some(states.clone())
             } else {
                 None
             };
@@ -187,6 +224,7 @@ impl PrefixCacheManager {
             Ok(Some(MatchingCache {
                 normal: cache,
                 xlora: xlora_cache,
+                recurrent_states,
                 toks: toks.0[ancestor.len()..].to_vec(),
             }))
         } else {
@@ -4,6 +4,7 @@ use mistralrs_quant::IsqType;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    json_schema::JsonSchema,
     response::Response,
     sampler::SamplingParams,
     tools::{Tool, ToolChoice},
@@ -13,10 +14,12 @@ use std::{fmt::Debug, sync::Arc};
 use tokio::sync::mpsc::Sender;
 
 #[derive(Clone)]
-/// Control the constraint with Regex or Yacc.
+/// Control the constraint with Regex, Yacc, or a JSON Schema.
 pub enum Constraint {
     Regex(String),
     Yacc(String),
+    /// The output must be a JSON document which validates against this JSON Schema.
+    JsonSchema(JsonSchema),
     None,
 }
 
@@ -28,6 +31,60 @@ pub enum ImageGenerationResponseFormat {
     B64Json,
 }
 
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass(eq, eq_int))]
+#[serde(rename_all = "snake_case")]
+/// How the hidden states of each prompt token are pooled into a single embedding.
+pub enum EmbeddingPooling {
+    /// Average over all tokens.
+    Mean,
+    /// Hidden state of the last token.
+    LastToken,
+    /// Hidden state of the first token.
+    Cls,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// Embedding parameters for [`RequestMessage::Embedding`].
+pub struct EmbeddingParams {
+    pub pooling: EmbeddingPooling,
+    /// L2 normalize the pooled embedding.
+    pub normalize: bool,
+}
+
+impl Default for EmbeddingParams {
+    fn default() -> Self {
+        Self {
+            pooling: EmbeddingPooling::Mean,
+            normalize: true,
+        }
+    }
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "snake_case")]
+/// Priority class of a request. How it is used depends on the `SchedulingPolicy` of the default scheduler.
+/// The PagedAttention scheduler always preempts lower priorities first.
+pub enum RequestPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl RequestPriority {
+    /// Share of the service a request of this class gets under `SchedulingPolicy::WeightedFair`.
+    pub(crate) fn weight(&self) -> f64 {
+        match self {
+            Self::Low => 1.,
+            Self::Normal => 2.,
+            Self::High => 4.,
+        }
+    }
+}
+
 pub type MessageContent = Either<String, Vec<IndexMap<String, String>>>;
 
 #[derive(Clone, Debug)]
@@ -49,6 +106,14 @@ pub enum RequestMessage {
         format: ImageGenerationResponseFormat,
         generation_params: DiffusionGenerationParams,
     },
+    Embedding {
+        prompt: String,
+        embedding_params: EmbeddingParams,
+    },
+    /// Score a text or the token ids of a text, without generating.
+    Scoring {
+        prompt: Either<String, Vec<u32>>,
+    },
 }
 
 #[derive(Clone)]
@@ -64,6 +129,8 @@ pub enum RequestMessage {
 /// - `adapters`: Adapters to use in this request
 /// - `tools`: Tools available in this request
 /// - `tool_choice`: Choice of tools
+/// - `priority`: Priority class of the request, used by the scheduling policy
+/// - `tenant`: Tenant which sent the request, used for fair scheduling between tenants
 /// - `logits_processors`: Custom logits processors. Order of application:
 ///     1) Apply penalties from `sampling_params`
 ///     2) Apply these custom logits processors sequentially
@@ -82,6 +149,8 @@ pub struct NormalRequest {
     pub tools: Option<Vec<Tool>>,
     pub tool_choice: Option<ToolChoice>,
     pub logits_processors: Option<Vec<Arc<dyn CustomLogitsProcessor>>>,
+    pub priority: RequestPriority,
+    pub tenant: Option<String>,
 }
 
 impl NormalRequest {
@@ -106,6 +175,8 @@ impl NormalRequest {
             suffix: None,
             adapters: None,
             logits_processors: None,
+            priority: RequestPriority::default(),
+            tenant: None,
         }
     }
 }
@@ -117,6 +188,9 @@ pub enum Request {
     Normal(NormalRequest),
     ReIsq(IsqType),
     ActivateAdapters(Vec<String>),
+    // Cancel the sequences of the `NormalRequest` with this id. They finish with `StopReason::Canceled`
+    // and a final response is sent for them.
+    Cancel { id: usize },
     // Sending a terminate request causes the `run` function to return to the thread created in `MistralRs::new`,
     // and then Engine will be dropped.
     Terminate,
@@ -144,6 +218,7 @@ impl Debug for Request {
             Request::ReIsq(tp) => {
                 write!(f, "Re ISQ Request {tp:?}",)
             }
+            Request::Cancel { id } => write!(f, "Cancel Request {id}"),
             Request::Terminate => write!(f, "Termination Request"),
         }
     }
@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     error::Error,
     fmt::{Debug, Display},
 };
@@ -7,7 +8,10 @@ use std::{
 use pyo3::{pyclass, pymethods};
 use serde::Serialize;
 
-use crate::{sampler::TopLogprob, tools::ToolCallResponse};
+use crate::{
+    sampler::TopLogprob,
+    tools::{ToolCallDelta, ToolCallResponse},
+};
 
 pub const SYSTEM_FINGERPRINT: &str = "local";
 
@@ -42,6 +46,7 @@ generate_repr!(ResponseMessage);
 pub struct Delta {
     pub content: String,
     pub role: String,
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
 }
 
 generate_repr!(Delta);
@@ -75,6 +80,8 @@ generate_repr!(Logprobs);
 /// Chat completion choice.
 pub struct Choice {
     pub finish_reason: String,
+    /// The stop sequence which stopped the generation, if any.
+    pub stop_sequence: Option<String>,
     pub index: usize,
     pub message: ResponseMessage,
     pub logprobs: Option<Logprobs>,
@@ -88,6 +95,8 @@ generate_repr!(Choice);
 /// Chat completion streaming chunk choice.
 pub struct ChunkChoice {
     pub finish_reason: Option<String>,
+    /// The stop sequence which stopped the generation, if any.
+    pub stop_sequence: Option<String>,
     pub index: usize,
     pub delta: Delta,
     pub logprobs: Option<ResponseLogprob>,
@@ -104,6 +113,8 @@ pub struct CompletionChunkChoice {
     pub index: usize,
     pub logprobs: Option<ResponseLogprob>,
     pub finish_reason: Option<String>,
+    /// The stop sequence which stopped the generation, if any.
+    pub stop_sequence: Option<String>,
 }
 
 generate_repr!(CompletionChunkChoice);
@@ -122,10 +133,26 @@ pub struct Usage {
     pub total_time_sec: f32,
     pub total_prompt_time_sec: f32,
     pub total_completion_time_sec: f32,
+    /// Only present if speculative decoding was used.
+    pub speculative: Option<SpeculativeUsage>,
 }
 
 generate_repr!(Usage);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// Speculative decoding statistics during a request.
+pub struct SpeculativeUsage {
+    /// Tokens proposed by the draft model or by prompt lookup.
+    pub draft_tokens: usize,
+    /// Draft tokens which were accepted by the target model.
+    pub accepted_draft_tokens: usize,
+    pub acceptance_rate: f32,
+}
+
+generate_repr!(SpeculativeUsage);
+
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
 #[derive(Debug, Clone, Serialize)]
@@ -157,15 +184,31 @@ pub struct ChatCompletionChunkResponse {
 
 generate_repr!(ChatCompletionChunkResponse);
 
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// Logprobs of the tokens of a completion choice, including the prompt tokens if it is echoed.
+/// The first prompt token has no logprob.
+pub struct CompletionLogprobs {
+    pub tokens: Vec<String>,
+    pub token_logprobs: Vec<Option<f32>>,
+    pub top_logprobs: Vec<Option<HashMap<String, f32>>>,
+    pub text_offset: Vec<usize>,
+}
+
+generate_repr!(CompletionLogprobs);
+
 #[cfg_attr(feature = "pyo3_macros", pyclass)]
 #[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
 #[derive(Debug, Clone, Serialize)]
 /// Completion request choice.
 pub struct CompletionChoice {
     pub finish_reason: String,
+    /// The stop sequence which stopped the generation, if any.
+    pub stop_sequence: Option<String>,
     pub index: usize,
     pub text: String,
-    pub logprobs: Option<()>,
+    pub logprobs: Option<CompletionLogprobs>,
 }
 
 generate_repr!(CompletionChoice);
@@ -221,10 +264,78 @@ pub struct ImageGenerationResponse {
 
 generate_repr!(ImageGenerationResponse);
 
-/// The response enum contains 3 types of variants:
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// A single embedding.
+pub struct EmbeddingData {
+    pub object: String,
+    pub embedding: Vec<f32>,
+    pub index: usize,
+}
+
+generate_repr!(EmbeddingData);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// Usage of an embedding request.
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+generate_repr!(EmbeddingUsage);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// An OpenAI compatible embedding response.
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+generate_repr!(EmbeddingResponse);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// A token of a scored prompt.
+pub struct ScoredToken {
+    pub token: u32,
+    pub text: String,
+    /// `None` for the first token, which has nothing to be predicted from.
+    pub logprob: Option<f32>,
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+generate_repr!(ScoredToken);
+
+#[cfg_attr(feature = "pyo3_macros", pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Debug, Clone, Serialize)]
+/// The logprobs of the tokens of a prompt, and its perplexity.
+pub struct ScoringResponse {
+    pub object: String,
+    pub model: String,
+    pub tokens: Vec<ScoredToken>,
+    /// Sum of the logprobs of the tokens after the first.
+    pub total_logprob: f32,
+    pub perplexity: f32,
+}
+
+generate_repr!(ScoringResponse);
+
+/// The response enum contains these types of variants:
 /// - Error (-Error suffix)
 /// - Chat (no prefix)
 /// - Completion (Completion- prefix)
+/// - Image generation
+/// - Embedding
+/// - Scoring
 pub enum Response {
     InternalError(Box<dyn Error + Send + Sync>),
     ValidationError(Box<dyn Error + Send + Sync>),
@@ -238,6 +349,10 @@ pub enum Response {
     CompletionChunk(CompletionChunkResponse),
     // Image generation
     ImageGeneration(ImageGenerationResponse),
+    // Embedding
+    Embedding(EmbeddingResponse),
+    // Scoring
+    Scoring(ScoringResponse),
 }
 
 #[derive(Debug, Clone)]
@@ -250,6 +365,10 @@ pub enum ResponseOk {
     CompletionChunk(CompletionChunkResponse),
     // Image generation
     ImageGeneration(ImageGenerationResponse),
+    // Embedding
+    Embedding(EmbeddingResponse),
+    // Scoring
+    Scoring(ScoringResponse),
 }
 
 pub enum ResponseErr {
@@ -312,6 +431,8 @@ impl Response {
                 Err(Box::new(ResponseErr::CompletionModelError(e, x)))
             }
             Self::ImageGeneration(x) => Ok(ResponseOk::ImageGeneration(x)),
+            Self::Embedding(x) => Ok(ResponseOk::Embedding(x)),
+            Self::Scoring(x) => Ok(ResponseOk::Scoring(x)),
         }
     }
 }
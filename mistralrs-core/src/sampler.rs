@@ -6,7 +6,7 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use candle_core::{Device, Error, Result, Tensor, D};
+use candle_core::{DType, Device, Error, Result, Tensor, D};
 #[cfg(feature = "pyo3_macros")]
 use pyo3::pyclass;
 
@@ -33,20 +33,39 @@ pub struct SamplingParams {
     pub temperature: Option<f64>,
     pub top_k: Option<usize>,
     pub top_p: Option<f64>,
+    /// Remove the tokens whose probability is at most `min_p` times that of the most likely token.
+    /// Like the other samplers of the chain, it is applied whether or not `top_p` is set.
     pub min_p: Option<f64>,
     pub top_n_logprobs: usize,
     pub frequency_penalty: Option<f32>,
     pub presence_penalty: Option<f32>,
+    /// Multiplicative penalty for tokens which occur in the context: positive logits are divided
+    /// by it and negative logits multiplied.
+    pub repetition_penalty: Option<f32>,
+    /// Only the last `repeat_last_n` tokens of the context are penalized by the repetition,
+    /// frequency and presence penalties. Defaults to the whole context.
+    pub repeat_last_n: Option<usize>,
     pub stop_toks: Option<StopTokens>,
     pub max_len: Option<usize>,
     pub logits_bias: Option<HashMap<u32, f32>>,
     pub n_choices: usize,
+    /// Seed of the RNG used to sample the sequences of this request. The sampled output then does
+    /// not depend on the other requests being processed.
+    pub seed: Option<u64>,
     pub dry_params: Option<DrySamplingParams>,
+    pub beam_search: Option<BeamSearchParams>,
+    pub typical_p: Option<f64>,
+    pub top_a: Option<f64>,
+    pub tfs_z: Option<f64>,
+    pub mirostat: Option<MirostatParams>,
+    /// The order in which the samplers are applied. Samplers which are not listed are not applied.
+    /// Defaults to `DEFAULT_SAMPLER_ORDER`.
+    pub sampler_order: Option<Vec<SamplerStep>>,
 }
 
 impl SamplingParams {
     /// This sets up the parameters so that there is:
-    /// - No temperature, topk, topp, minp
+    /// - No temperature, topk, topp, minp, typical-p, top-a, tail free sampling or Mirostat
     /// - No penalties, stop tokens, or logit bias
     /// - No maximum length
     pub fn deterministic() -> Self {
@@ -58,11 +77,141 @@ impl SamplingParams {
             top_n_logprobs: 0,
             frequency_penalty: None,
             presence_penalty: None,
+            repetition_penalty: None,
+            repeat_last_n: None,
             stop_toks: None,
             max_len: None,
             logits_bias: None,
             n_choices: 1,
+            seed: None,
             dry_params: None,
+            beam_search: None,
+            typical_p: None,
+            top_a: None,
+            tfs_z: None,
+            mirostat: None,
+            sampler_order: None,
+        }
+    }
+}
+
+#[cfg_attr(feature = "pyo3_macros", pyclass(eq, eq_int))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+/// A sampler of the sampler chain, which is applied after the penalties and logits processors.
+pub enum SamplerStep {
+    Temperature,
+    TopK,
+    TopP,
+    MinP,
+    TypicalP,
+    TopA,
+    TailFree,
+}
+
+/// The sampler order used if a request does not specify one.
+pub const DEFAULT_SAMPLER_ORDER: [SamplerStep; 7] = [
+    SamplerStep::Temperature,
+    SamplerStep::TopK,
+    SamplerStep::TailFree,
+    SamplerStep::TypicalP,
+    SamplerStep::TopP,
+    SamplerStep::MinP,
+    SamplerStep::TopA,
+];
+
+#[cfg_attr(feature = "pyo3_macros", pyclass(eq, eq_int))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MirostatVersion {
+    V1,
+    V2,
+}
+
+#[derive(Clone, Debug)]
+/// Mirostat sampling truncates the distribution so that the surprise of the sampled tokens stays
+/// close to `tau`, learning the truncation with rate `eta` as the sequence is generated. It
+/// replaces the sampler chain except for temperature.
+pub struct MirostatParams {
+    pub version: MirostatVersion,
+    /// Target surprise in bits.
+    pub tau: f32,
+    pub eta: f32,
+}
+
+impl MirostatParams {
+    pub fn new_with_defaults(version: MirostatVersion, tau: Option<f32>, eta: Option<f32>) -> Self {
+        Self {
+            version,
+            tau: tau.unwrap_or(5.0),
+            eta: eta.unwrap_or(0.1),
+        }
+    }
+
+    /// Create the params from a llama.cpp style mode: 0 disables Mirostat, 1 and 2 select the version.
+    pub fn from_mode(
+        mode: usize,
+        tau: Option<f32>,
+        eta: Option<f32>,
+    ) -> anyhow::Result<Option<Self>> {
+        let version = match mode {
+            0 => return Ok(None),
+            1 => MirostatVersion::V1,
+            2 => MirostatVersion::V2,
+            _ => anyhow::bail!("Mirostat mode must be 0, 1 or 2, got {mode}."),
+        };
+        Ok(Some(Self::new_with_defaults(version, tau, eta)))
+    }
+}
+
+/// Mirostat params with the learned maximum surprise `mu` of a sequence.
+struct Mirostat {
+    params: MirostatParams,
+    mu: Mutex<f32>,
+}
+
+impl Mirostat {
+    fn new(params: MirostatParams) -> Self {
+        Self {
+            mu: Mutex::new(2. * params.tau),
+            params,
+        }
+    }
+}
+
+// Each sequence gets a clone of the sampler of its request, which continues from the state of
+// the original instead of sharing it.
+impl Clone for Mirostat {
+    fn clone(&self) -> Self {
+        Self {
+            params: self.params.clone(),
+            mu: Mutex::new(*self.mu.lock().expect("could not lock mirostat mutex")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Beam search decoding. Instead of sampling, the `beam_width` most likely continuations are kept
+/// at every step, and the `n_choices` best finished beams are returned.
+pub struct BeamSearchParams {
+    pub beam_width: usize,
+    /// Finished beams are ranked by `cumulative logprob / length^length_penalty`, so values above 1
+    /// favor longer outputs.
+    pub length_penalty: f32,
+    /// Stop as soon as `beam_width` beams have finished instead of when no running beam can
+    /// reach a better score.
+    pub early_stopping: bool,
+}
+
+impl BeamSearchParams {
+    pub fn new_with_defaults(
+        beam_width: usize,
+        length_penalty: Option<f32>,
+        early_stopping: Option<bool>,
+    ) -> Self {
+        Self {
+            beam_width,
+            length_penalty: length_penalty.unwrap_or(1.0),
+            early_stopping: early_stopping.unwrap_or(false),
         }
     }
 }
@@ -185,10 +334,17 @@ pub struct Sampler {
     tokenizer: Option<Arc<Tokenizer>>,
     frequency_penalty: Option<f32>,
     presence_penalty: Option<f32>,
+    repetition_penalty: Option<f32>,
+    repeat_last_n: Option<usize>,
     dry_params: Option<DrySamplingParamsInner>,
     top_k: i64,
     top_p: f64,
     min_p: f64,
+    typical_p: f64,
+    top_a: f64,
+    tfs_z: f64,
+    mirostat: Option<Mirostat>,
+    sampler_order: Vec<SamplerStep>,
     logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
 }
 
@@ -214,6 +370,164 @@ fn argmax_sample_last_dim(logits: &Tensor) -> Result<Tensor> {
     logits.argmax(D::Minus1)
 }
 
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut probs = logits.iter().map(|x| (x - max).exp()).collect::<Vec<_>>();
+    let sum = probs.iter().sum::<f32>();
+    probs.iter_mut().for_each(|p| *p /= sum);
+    probs
+}
+
+/// Indices sorted by descending probability.
+fn argsort_descending(probs: &[f32]) -> Vec<usize> {
+    let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
+    argsort_indices
+        .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
+    argsort_indices
+}
+
+/// A token remaining in the distribution during the sampler chain, with its logit.
+type Candidate = (usize, f32);
+
+/// The tokens sorted by descending logit. The samplers keep this order, so the distribution is only
+/// sorted once for the whole sampler chain.
+fn sorted_candidates(logits: &[f32]) -> Vec<Candidate> {
+    let mut candidates = logits.iter().copied().enumerate().collect::<Vec<_>>();
+    candidates.sort_unstable_by(|(_, a), (_, b)| b.total_cmp(a));
+    candidates
+}
+
+/// Probabilities of the candidates, in the same order.
+fn candidate_probs(candidates: &[Candidate]) -> Vec<f32> {
+    softmax(
+        &candidates
+            .iter()
+            .map(|(_, logit)| *logit)
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// The probabilities over the vocabulary of the remaining candidates, and the tokens sorted by
+/// descending probability.
+fn candidate_distribution(candidates: &[Candidate], n_vocab: usize) -> (Vec<f32>, Vec<usize>) {
+    let mut probs = vec![0.; n_vocab];
+    let mut kept = vec![false; n_vocab];
+    let mut argsort_indices = Vec::with_capacity(n_vocab);
+    for ((index, _), p) in candidates.iter().zip(candidate_probs(candidates)) {
+        probs[*index] = p;
+        kept[*index] = true;
+        argsort_indices.push(*index);
+    }
+    argsort_indices.extend((0..n_vocab).filter(|index| !kept[*index]));
+    (probs, argsort_indices)
+}
+
+fn apply_top_k(candidates: &mut Vec<Candidate>, top_k: usize) {
+    candidates.truncate(top_k);
+}
+
+// top-p sampling (or "nucleus sampling") samples from the smallest set of
+// tokens that exceed probability top_p. This way we never sample tokens that
+// have very low probabilities and are less likely to go "off the rails".
+fn apply_top_p(candidates: &mut Vec<Candidate>, top_p: f32) {
+    let mut cumsum = 0.;
+    let keep = candidate_probs(candidates)
+        .into_iter()
+        .take_while(|p| {
+            let keep = cumsum < top_p;
+            cumsum += p;
+            keep
+        })
+        .count();
+    candidates.truncate(keep);
+}
+
+// min-p sampling samples from the tokens whose prob are greater than
+// (max prob of token in dist) * min_p
+fn apply_min_p(candidates: &mut Vec<Candidate>, min_p: f32) {
+    let probs = candidate_probs(candidates);
+    let max_p = probs.first().copied().unwrap_or(0.);
+    let keep = probs.iter().take_while(|p| **p > max_p * min_p).count();
+    candidates.truncate(keep);
+}
+
+// top-a sampling removes the tokens whose prob are less than top_a * (max prob)^2, so it
+// truncates more when the model is confident.
+fn apply_top_a(candidates: &mut Vec<Candidate>, top_a: f32) {
+    let probs = candidate_probs(candidates);
+    let max_p = probs.first().copied().unwrap_or(0.);
+    let keep = probs
+        .iter()
+        .take_while(|p| **p >= top_a * max_p * max_p)
+        .count();
+    candidates.truncate(keep.max(1));
+}
+
+// Locally typical sampling samples from the smallest set of tokens, taken in order of how close
+// their surprise is to the entropy of the distribution, which exceed probability typical_p.
+// See https://arxiv.org/abs/2202.00666
+fn apply_typical_p(candidates: &mut Vec<Candidate>, typical_p: f32) {
+    let probs = candidate_probs(candidates);
+    let entropy = -probs
+        .iter()
+        .filter(|p| **p > 0.)
+        .map(|p| p * p.ln())
+        .sum::<f32>();
+    let shifted = probs
+        .iter()
+        .map(|p| (-p.ln() - entropy).abs())
+        .collect::<Vec<_>>();
+    // This order is only used to pick the tokens, which are kept in the order of the candidates.
+    let mut indices = (0..probs.len()).collect::<Vec<_>>();
+    indices.sort_unstable_by(|&i, &j| shifted[i].total_cmp(&shifted[j]));
+
+    let mut cumsum = 0.;
+    let keep = indices
+        .iter()
+        .take_while(|index| {
+            let keep = cumsum <= typical_p;
+            cumsum += probs[**index];
+            keep
+        })
+        .count();
+    let mut kept = vec![false; probs.len()];
+    for index in indices.iter().take(keep.max(1)) {
+        kept[*index] = true;
+    }
+    let mut kept = kept.into_iter();
+    candidates.retain(|_| kept.next().unwrap_or(false));
+}
+
+// Tail free sampling cuts off the tail of the sorted distribution where the second derivative
+// of the probabilities, normalized to sum to 1, accumulates beyond tfs_z.
+// See https://www.trentonbricken.com/Tail-Free-Sampling/
+fn apply_tail_free(candidates: &mut Vec<Candidate>, tfs_z: f32) {
+    let sorted = candidate_probs(candidates);
+    if sorted.len() <= 2 {
+        return;
+    }
+
+    let first_derivatives = sorted.windows(2).map(|w| w[0] - w[1]).collect::<Vec<_>>();
+    let mut second_derivatives = first_derivatives
+        .windows(2)
+        .map(|w| (w[0] - w[1]).abs())
+        .collect::<Vec<_>>();
+    let sum = second_derivatives.iter().sum::<f32>();
+    if sum > 1e-6 {
+        second_derivatives.iter_mut().for_each(|d| *d /= sum);
+    }
+
+    let mut cumsum = 0.;
+    let keep = second_derivatives
+        .iter()
+        .position(|d| {
+            cumsum += d;
+            cumsum > tfs_z
+        })
+        .unwrap_or(sorted.len());
+    candidates.truncate(keep.max(1));
+}
+
 impl Sampler {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -222,10 +536,17 @@ impl Sampler {
         tokenizer: Option<Arc<Tokenizer>>,
         frequency_penalty: Option<f32>,
         presence_penalty: Option<f32>,
+        repetition_penalty: Option<f32>,
+        repeat_last_n: Option<usize>,
         dry_params: Option<DrySamplingParams>,
         top_k: i64,
         top_p: f64,
         min_p: f64,
+        typical_p: f64,
+        top_a: f64,
+        tfs_z: f64,
+        mirostat: Option<MirostatParams>,
+        sampler_order: Option<Vec<SamplerStep>>,
         logits_processors: Vec<Arc<dyn CustomLogitsProcessor>>,
     ) -> anyhow::Result<Self> {
         let temperature = if temperature.map_or(true, |v| v < 1e-7) {
@@ -248,10 +569,17 @@ impl Sampler {
             tokenizer,
             frequency_penalty,
             presence_penalty,
+            repetition_penalty,
+            repeat_last_n,
             dry_params,
             top_k,
             top_p,
             min_p,
+            typical_p,
+            top_a,
+            tfs_z,
+            mirostat: mirostat.map(Mirostat::new),
+            sampler_order: sampler_order.unwrap_or(DEFAULT_SAMPLER_ORDER.to_vec()),
             logits_processors,
         })
     }
@@ -306,6 +634,38 @@ impl Sampler {
         }
     }
 
+    /// Compute the logprobs of the given `tokens` under the raw (unprocessed) distributions of
+    /// the rows of `logits`, which must have shape `(tokens.len(), vocab)`.
+    pub(crate) fn logprobs_of(&self, logits: &Tensor, tokens: &[u32]) -> Result<Vec<Logprobs>> {
+        let rows: Vec<Vec<f32>> = logits.to_dtype(DType::F32)?.to_vec2()?;
+        rows.par_iter()
+            .zip(tokens)
+            .map(|(row, &token)| {
+                let probs = softmax(row);
+                let top_logprobs = if self.top_n_logprobs > 0 {
+                    self.get_top_logprobs(&probs, &argsort_descending(&probs))?
+                } else {
+                    Vec::new()
+                };
+                let bytes = if let Some(tokenizer) = &self.tokenizer {
+                    Some(
+                        tokenizer
+                            .decode(&[token], false)
+                            .map_err(|x| Error::Msg(x.to_string()))?,
+                    )
+                } else {
+                    None
+                };
+                Ok(Logprobs {
+                    token,
+                    logprob: probs[token as usize].log(10.0),
+                    bytes,
+                    top_logprobs: Some(top_logprobs),
+                })
+            })
+            .collect()
+    }
+
     fn sample_argmax(&self, logits: Tensor, return_logprobs: bool) -> Result<Logprobs> {
         let next_token = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
 
@@ -459,69 +819,127 @@ impl Sampler {
         })
     }
 
-    fn sample_top_kp_min_p(
+    fn sample_greedy(
         &self,
-        probs: &mut Vec<f32>,
-        top_k: i64,
-        top_p: f32,
-        min_p: f32,
+        probs: &[f32],
+        argsort_indices: &[usize],
         return_logprobs: bool,
-        rng: Arc<Mutex<Isaac64Rng>>,
     ) -> Result<Logprobs> {
-        let mut argsort_indices = (0..probs.len()).collect::<Vec<_>>();
-        // Sort by descending probability.
-        argsort_indices
-            .sort_unstable_by(|&i, &j| probs[j].partial_cmp(&probs[i]).expect("No ordering."));
-
-        if top_k > 0 {
-            // Clamp smaller probabilities to zero.
-            for (index, val) in argsort_indices.iter().enumerate() {
-                if index >= top_k as usize {
-                    probs[*val] = 0.0;
-                }
-            }
-        }
+        let next_token = argsort_indices[0];
+        let logprob = probs[next_token].log(10.0);
 
-        if top_p <= 0.0 || top_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
-        }
+        let top_logprobs = if return_logprobs {
+            Some(self.get_top_logprobs(probs, argsort_indices)?)
+        } else {
+            None
+        };
 
-        // TOP P
+        let bytes = if let Some(tokenizer) = &self.tokenizer {
+            Some(
+                tokenizer
+                    .decode(&[next_token as u32], false)
+                    .map_err(|x| Error::Msg(x.to_string()))?,
+            )
+        } else {
+            None
+        };
 
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability top_p. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
+        Ok(Logprobs {
+            token: next_token as u32,
+            logprob,
+            top_logprobs,
+            bytes,
+        })
+    }
 
-        // Clamp smaller probabilities to zero.
-        let mut cumsum = 0.;
-        for index in &argsort_indices {
-            if cumsum >= top_p {
-                probs[*index] = 0.0;
-            } else {
-                cumsum += probs[*index];
+    /// Apply the sampler chain, returning the remaining tokens sorted by descending probability.
+    fn apply_sampler_chain(&self, logits: &[f32], temperature: f32) -> Vec<Candidate> {
+        let mut candidates = sorted_candidates(logits);
+        for step in &self.sampler_order {
+            match step {
+                SamplerStep::Temperature => candidates
+                    .iter_mut()
+                    .for_each(|(_, logit)| *logit /= temperature),
+                SamplerStep::TopK if self.top_k > 0 => {
+                    apply_top_k(&mut candidates, self.top_k as usize)
+                }
+                SamplerStep::TopP if self.top_p > 0.0 && self.top_p < 1.0 => {
+                    apply_top_p(&mut candidates, self.top_p as f32)
+                }
+                SamplerStep::MinP if self.min_p > 0.0 && self.min_p < 1.0 => {
+                    apply_min_p(&mut candidates, self.min_p as f32)
+                }
+                SamplerStep::TypicalP if self.typical_p > 0.0 && self.typical_p < 1.0 => {
+                    apply_typical_p(&mut candidates, self.typical_p as f32)
+                }
+                SamplerStep::TopA if self.top_a > 0.0 => {
+                    apply_top_a(&mut candidates, self.top_a as f32)
+                }
+                SamplerStep::TailFree if self.tfs_z > 0.0 && self.tfs_z < 1.0 => {
+                    apply_tail_free(&mut candidates, self.tfs_z as f32)
+                }
+                _ => (),
             }
         }
+        candidates
+    }
 
-        if min_p <= 0.0 || min_p >= 1.0 {
-            return self.sample_multinomial(probs, argsort_indices, return_logprobs, rng);
-        }
-
-        let max_p = probs[argsort_indices[0]];
-
-        // MIN P
-
-        // min-p sampling samples from the tokens whose prob are greater than
-        // (max prob of token in dist) * min_p
-
-        // Clamp smaller probabilities to zero.
-        for index in &argsort_indices {
-            if max_p * min_p >= probs[*index] {
-                probs[*index] = 0.0;
+    fn sample_mirostat(
+        &self,
+        logits: &[f32],
+        mirostat: &Mirostat,
+        return_logprobs: bool,
+        rng: Arc<Mutex<Isaac64Rng>>,
+    ) -> Result<Logprobs> {
+        let MirostatParams { version, tau, eta } = mirostat.params;
+        let mut mu = mirostat.mu.lock().expect("could not lock mirostat mutex");
+
+        let mut probs = softmax(logits);
+        let argsort_indices = argsort_descending(&probs);
+        let n_vocab = probs.len();
+        let keep = match version {
+            MirostatVersion::V1 => {
+                // Estimate the Zipf exponent of the distribution from its most likely tokens to
+                // find the top-k which gives a surprise of mu.
+                let m = 100.min(n_vocab - 1);
+                let (mut num, mut den) = (0f32, 0f32);
+                for i in 0..m.saturating_sub(1) {
+                    let (p, p_next) = (probs[argsort_indices[i]], probs[argsort_indices[i + 1]]);
+                    if p_next <= 0. {
+                        break;
+                    }
+                    let t = ((i + 2) as f32 / (i + 1) as f32).ln();
+                    num += t * (p / p_next).ln();
+                    den += t * t;
+                }
+                let s_hat = if den > 0. { num / den } else { 1. };
+                let epsilon = s_hat - 1.;
+                let k = ((epsilon * 2f32.powf(*mu)) / (1. - (n_vocab as f32).powf(-epsilon)))
+                    .powf(1. / s_hat);
+                if k.is_finite() {
+                    (k.round() as usize).clamp(1, n_vocab)
+                } else {
+                    n_vocab
+                }
             }
+            MirostatVersion::V2 => argsort_indices
+                .iter()
+                .take_while(|index| -probs[**index].log2() <= *mu)
+                .count()
+                .max(1),
+        };
+
+        for index in argsort_indices.iter().skip(keep) {
+            probs[*index] = 0.;
         }
+        let sum = probs.iter().sum::<f32>();
+        probs.iter_mut().for_each(|p| *p /= sum);
 
-        // Sample with clamped probabilities.
-        self.sample_multinomial(probs, argsort_indices, return_logprobs, rng)
+        let next_token =
+            self.sample_multinomial(&mut probs, argsort_indices, return_logprobs, rng)?;
+        let surprise = -probs[next_token.token as usize].log2();
+        *mu -= eta * (surprise - tau);
+        Ok(next_token)
     }
 
     fn apply_penalties(&self, mut logits: Vec<f32>, context: &[u32]) -> Result<Tensor> {
@@ -532,8 +950,16 @@ impl Sampler {
         // Dry penalty
         self.apply_dry_penalty(&mut logits, context)?;
 
+        let penalty_context = match self.repeat_last_n {
+            Some(repeat_last_n) => &context[context.len().saturating_sub(repeat_last_n)..],
+            None => context,
+        };
+
         // Frequency and Presence penalty
-        self.apply_freq_presc_penalty(&mut logits, context)?;
+        self.apply_freq_presc_penalty(&mut logits, penalty_context)?;
+
+        // Repetition penalty
+        self.apply_repetition_penalty(&mut logits, penalty_context);
 
         let vocab_size = logits.len();
         Tensor::from_vec(logits, vocab_size, &Device::Cpu)
@@ -565,6 +991,25 @@ impl Sampler {
         Ok(())
     }
 
+    fn apply_repetition_penalty(&self, logits: &mut [f32], context: &[u32]) {
+        if let Some(penalty) = self.repetition_penalty {
+            let mut penalized = vec![false; logits.len()];
+            for ctx in context {
+                // Llama 3.2 uses a hack triggering this error... we wouldn't want a weight on it anyway
+                if *ctx as usize >= logits.len() || penalized[*ctx as usize] {
+                    continue;
+                }
+                penalized[*ctx as usize] = true;
+                let logit = &mut logits[*ctx as usize];
+                if *logit < 0. {
+                    *logit *= penalty;
+                } else {
+                    *logit /= penalty;
+                }
+            }
+        }
+    }
+
     fn apply_dry_penalty(&self, logits: &mut [f32], context: &[u32]) -> Result<()> {
         if let Some(ref params) = self.dry_params {
             let match_indices = context
@@ -635,8 +1080,9 @@ impl Sampler {
 
     /// Sample the provided tokens.
     ///
-    /// If the temperature is `None`, argmax sampling is used. Otherwise, the selected sampling is used.
-    /// With `top-p` sampling, if the `top-p` value is `<= 0.0` or `>= 1.0`, multinomial sampling is used.
+    /// If the temperature is `None`, argmax sampling is used. Otherwise, the sampler chain is applied in the
+    /// configured order, or Mirostat if it is enabled, and the token is sampled from what remains. Samplers
+    /// with values which would not remove any tokens, such as a `top-p` value of `>= 1.0`, are skipped.
     pub fn sample(
         &self,
         logits: Tensor,
@@ -650,44 +1096,38 @@ impl Sampler {
         for processor in &self.logits_processors {
             logits = processor.apply(&logits, context)?;
         }
-        let next_token = if sample_speculative {
-            match self.temperature {
-                None => self.sample_speculative_top_kp_min_p(
-                    logits,
-                    return_logprobs,
-                    self.top_k,
-                    self.top_p as f32,
-                    self.min_p as f32,
-                )?,
-                Some(temperature) => {
-                    let logits = (&logits / temperature)?;
-                    let probs = candle_nn::ops::softmax_last_dim(&logits)?;
-
-                    self.sample_speculative_top_kp_min_p(
-                        probs,
-                        return_logprobs,
-                        self.top_k,
-                        self.top_p as f32,
-                        self.min_p as f32,
-                    )?
-                }
-            }
-        } else {
-            match self.temperature {
-                None => self.sample_argmax(logits, return_logprobs)?,
-                Some(temperature) => {
-                    let logits = (&logits / temperature)?;
-                    let probs = candle_nn::ops::softmax_last_dim(&logits)?;
-                    let mut probs: Vec<f32> = probs.to_vec1()?;
-
-                    self.sample_top_kp_min_p(
-                        &mut probs,
-                        self.top_k,
-                        self.top_p as f32,
-                        self.min_p as f32,
-                        return_logprobs,
-                        rng,
-                    )?
+        let next_token = match self.temperature {
+            None if sample_speculative => self.sample_speculative_top_kp_min_p(
+                logits,
+                return_logprobs,
+                self.top_k,
+                self.top_p as f32,
+                self.min_p as f32,
+            )?,
+            None => self.sample_argmax(logits, return_logprobs)?,
+            Some(temperature) => {
+                let mut logits: Vec<f32> = logits.to_vec1()?;
+                match &self.mirostat {
+                    // Speculative sampling is greedy, which truncating the distribution does not change.
+                    Some(mirostat) if !sample_speculative => {
+                        logits.iter_mut().for_each(|x| *x /= temperature as f32);
+                        self.sample_mirostat(&logits, mirostat, return_logprobs, rng)?
+                    }
+                    _ => {
+                        let candidates = self.apply_sampler_chain(&logits, temperature as f32);
+                        let (mut probs, argsort_indices) =
+                            candidate_distribution(&candidates, logits.len());
+                        if sample_speculative {
+                            self.sample_greedy(&probs, &argsort_indices, return_logprobs)?
+                        } else {
+                            self.sample_multinomial(
+                                &mut probs,
+                                argsort_indices,
+                                return_logprobs,
+                                rng,
+                            )?
+                        }
+                    }
                 }
             }
         };
@@ -728,9 +1168,16 @@ mod tests {
             None,
             None,
             None,
+            None,
+            None,
             32,
             0.1,
             0.05,
+            1.0,
+            0.0,
+            1.0,
+            None,
+            None,
             vec![],
         )
         .unwrap();
@@ -760,9 +1207,16 @@ mod tests {
             None,
             None,
             None,
+            None,
+            None,
             32,
             0.1,
             0.05,
+            1.0,
+            0.0,
+            1.0,
+            None,
+            None,
             vec![],
         )
         .unwrap();
@@ -775,4 +1229,176 @@ mod tests {
         assert_eq!(res.top_logprobs, None);
         assert_eq!(res.logprob, 1023f64.log(10.) as f32)
     }
+
+    #[test]
+    fn test_truncation_samplers() {
+        use super::{apply_tail_free, apply_top_a, apply_typical_p, sorted_candidates, Candidate};
+
+        let logits = [0.5f32, 0.3, 0.15, 0.05].map(f32::ln);
+        let kept = |apply: &dyn Fn(&mut Vec<Candidate>)| {
+            let mut candidates = sorted_candidates(&logits);
+            apply(&mut candidates);
+            candidates
+                .into_iter()
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>()
+        };
+
+        // 0.05 < 0.5 * 0.5^2
+        assert_eq!(kept(&|l| apply_top_a(l, 0.5)), vec![0, 1, 2]);
+        // The entropy is ~1.14 nats, closest to the surprise of 0.3 and then 0.5.
+        assert_eq!(kept(&|l| apply_typical_p(l, 0.5)), vec![0, 1]);
+        // The normalized second derivatives are [0.5, 0.5].
+        assert_eq!(kept(&|l| apply_tail_free(l, 0.4)), vec![0]);
+    }
+
+    #[test]
+    fn test_temperature_last() {
+        use super::{Sampler, SamplerStep};
+
+        let sampler = |sampler_order| {
+            Sampler::new(
+                Some(2.0),
+                0,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                -1,
+                1.0,
+                0.5,
+                1.0,
+                0.0,
+                1.0,
+                None,
+                sampler_order,
+                vec![],
+            )
+            .unwrap()
+        };
+        let kept = |sampler: Sampler| {
+            let logits = [0.15f32, 0.5, 0.05, 0.3].map(f32::ln);
+            sampler
+                .apply_sampler_chain(&logits, 2.0)
+                .into_iter()
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>()
+        };
+
+        // A high temperature flattens the distribution before min-p is applied. Min-p is applied
+        // although top-p is disabled, and the tokens are kept sorted by descending probability.
+        let temperature_last = vec![SamplerStep::MinP, SamplerStep::Temperature];
+        assert_eq!(kept(sampler(None)), vec![1, 3, 0]);
+        assert_eq!(kept(sampler(Some(temperature_last))), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_mirostat() {
+        use super::{MirostatParams, MirostatVersion, Sampler};
+        use candle_core::{Device, Tensor};
+        use rand::SeedableRng;
+        use rand_isaac::Isaac64Rng;
+        use std::sync::{Arc, Mutex};
+
+        let probs = [0.5f32, 0.3, 0.15, 0.05];
+        // Sample a token with Mirostat starting from `mu = 2 * tau`, returning it and the updated `mu`.
+        let sample = |version, tau: f32| {
+            let sampler = Sampler::new(
+                Some(1.0),
+                0,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                -1,
+                1.0,
+                0.0,
+                1.0,
+                0.0,
+                1.0,
+                Some(MirostatParams {
+                    version,
+                    tau,
+                    eta: 0.1,
+                }),
+                None,
+                vec![],
+            )
+            .unwrap();
+            let logits = Tensor::new(&probs.map(f32::ln), &Device::Cpu).unwrap();
+            let rng = Arc::new(Mutex::new(Isaac64Rng::seed_from_u64(42)));
+            let token = sampler
+                .sample(logits, &[0], false, rng, false)
+                .unwrap()
+                .token;
+            let mu = *sampler.mirostat.as_ref().unwrap().mu.lock().unwrap();
+            (token, mu)
+        };
+        // `mu` moves towards making the surprise of the sampled token, in the truncated
+        // distribution of the first `keep` tokens, equal to `tau`.
+        let expected_mu = |tau: f32, token: u32, keep: usize| {
+            let p = probs[usize::try_from(token).unwrap()] / probs[..keep].iter().sum::<f32>();
+            2. * tau - 0.1 * (-p.log2() - tau)
+        };
+
+        for version in [MirostatVersion::V1, MirostatVersion::V2] {
+            // With `mu = 1`, v1 estimates a top-k of ~1.4. With `mu = 1.5`, v2 keeps the tokens
+            // with a surprise of at most 1.5 bits, so only the most likely one.
+            let tau = match version {
+                MirostatVersion::V1 => 0.5,
+                MirostatVersion::V2 => 0.75,
+            };
+            let (token, mu) = sample(version, tau);
+            assert_eq!(token, 0);
+            assert!((mu - expected_mu(tau, 0, 1)).abs() < 1e-5);
+            assert!(mu > 2. * tau);
+
+            // With `mu = 2`, v1 estimates a top-k of ~2.9. With `mu = 3`, v2 keeps the tokens with
+            // a surprise of at most 3 bits.
+            let tau = match version {
+                MirostatVersion::V1 => 1.,
+                MirostatVersion::V2 => 1.5,
+            };
+            let (token, mu) = sample(version, tau);
+            assert!(token < 3);
+            assert!((mu - expected_mu(tau, token, 3)).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_repetition_penalty() {
+        use super::Sampler;
+
+        let sampler = Sampler::new(
+            Some(1.0),
+            0,
+            None,
+            None,
+            None,
+            Some(2.0),
+            Some(3),
+            None,
+            -1,
+            1.0,
+            0.0,
+            1.0,
+            0.0,
+            1.0,
+            None,
+            None,
+            vec![],
+        )
+        .unwrap();
+        let logits = sampler
+            .apply_penalties(vec![1.0, -1.0, 1.0, -1.0], &[0, 1, 2, 2])
+            .unwrap()
+            .to_vec1::<f32>()
+            .unwrap();
+        // Token 0 is outside of the window and tokens which repeat are only penalized once.
+        assert_eq!(logits, vec![1.0, -2.0, 0.5, -1.0]);
+    }
 }
@@ -1,7 +1,9 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    cmp,
+    collections::{HashMap, HashSet, VecDeque},
     num::NonZeroUsize,
-    sync::atomic::Ordering,
+    str::FromStr,
+    sync::{atomic::Ordering, Arc, Mutex},
 };
 
 use crate::{
@@ -18,6 +20,7 @@ pub trait FcfsBacker: Default {
     fn into_iter(self) -> impl Iterator<Item = Sequence>;
     fn len(&self) -> usize;
     fn sort_ascending_ids(&mut self);
+    fn iter(&self) -> impl Iterator<Item = &Sequence>;
 }
 
 impl FcfsBacker for VecDeque<Sequence> {
@@ -37,6 +40,9 @@ impl FcfsBacker for VecDeque<Sequence> {
     fn len(&self) -> usize {
         VecDeque::len(self)
     }
+    fn iter(&self) -> impl Iterator<Item = &Sequence> {
+        VecDeque::iter(self)
+    }
 }
 
 pub struct DefaultSchedulerOutput<'a> {
@@ -53,6 +59,35 @@ pub enum DefaultSchedulerMethod {
     Fixed(NonZeroUsize),
 }
 
+/// The scheduling policy controls the order in which waiting sequences are allowed to run
+/// when not all of them fit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SchedulingPolicy {
+    /// First come, first served.
+    #[default]
+    Fcfs,
+    /// Sequences of higher priority requests run first. Running sequences of a lower priority
+    /// are moved back to the waiting list to make room for them, keeping their KV cache.
+    StrictPriority,
+    /// Weighted fair queueing between tenants. The tenant which has been served the fewest tokens,
+    /// scaled by the weight of the request priority, runs first.
+    WeightedFair,
+}
+
+impl FromStr for SchedulingPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fcfs" => Ok(Self::Fcfs),
+            "strict-priority" => Ok(Self::StrictPriority),
+            "weighted-fair" => Ok(Self::WeightedFair),
+            other => Err(format!(
+                "Unexpected scheduling policy `{other}`, expected `fcfs`, `strict-priority` or `weighted-fair`."
+            )),
+        }
+    }
+}
+
 pub struct BucketedSeqs<Backer: FcfsBacker> {
     running: Vec<Sequence>,
     waiting: Backer,
@@ -68,9 +103,11 @@ pub trait BucketingManager<Backer: FcfsBacker> {
     ) -> BucketedSeqs<Backer>;
 }
 
-// (adapters, cache length, (has_imgs && is_prompt))
+// (adapters, cache length, (has_imgs && is_prompt), is_embedding, needs_prompt_logprobs)
 // Buckey by that metric for images because if we are not a prompt, then this doesn't apply
-type BucketKey = (Option<Vec<String>>, usize, bool);
+// Embedding sequences are never batched with others because they run a different forward pass
+// Prompts returning prompt logprobs need the logits of every position, which are only computed for them
+type BucketKey = (Option<Vec<String>>, usize, bool, bool, bool);
 
 struct FixedBucketingManager;
 
@@ -88,43 +125,34 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
         let mut seq_buckets: HashMap<BucketKey, Vec<Sequence>> = HashMap::new();
         let mut seq_priorities: HashMap<BucketKey, f64> = HashMap::new();
         for seq in running {
-            let len = seq.len();
-            match seq_buckets.get_mut(&(
+            // Recurrent states have a fixed size, so the completions of any length are batched.
+            // Prompts scan every token they are fed into the state, padding included, so they are only
+            // batched with prompts feeding as many tokens. This is not the prompt length if the state was
+            // restored from the prefix cache.
+            let len = match (seq.is_recurrent(), seq.is_prompt()) {
+                (true, false) => 0,
+                (true, true) => seq.get_toks().len(),
+                (false, _) => seq.len(),
+            };
+            let key = (
                 seq.get_adapters(),
                 len,
                 seq.images().is_some() && seq.is_prompt(),
-            )) {
+                seq.is_embedding(),
+                seq.needs_prompt_logprobs(),
+            );
+            match seq_buckets.get_mut(&key) {
                 Some(bucket) => {
                     if !discrete {
-                        *seq_priorities
-                            .get_mut(&(
-                                seq.get_adapters(),
-                                len,
-                                seq.images().is_some() && seq.is_prompt(),
-                            ))
-                            .unwrap() += seq.compute_priority();
+                        *seq_priorities.get_mut(&key).unwrap() += seq.compute_priority();
                     }
                     bucket.push(seq);
                 }
                 None => {
                     if !discrete {
-                        seq_priorities.insert(
-                            (
-                                seq.get_adapters(),
-                                len,
-                                seq.images().is_some() && seq.is_prompt(),
-                            ),
-                            seq.compute_priority(),
-                        );
+                        seq_priorities.insert(key.clone(), seq.compute_priority());
                     }
-                    seq_buckets.insert(
-                        (
-                            seq.get_adapters(),
-                            len,
-                            seq.images().is_some() && seq.is_prompt(),
-                        ),
-                        vec![seq],
-                    );
+                    seq_buckets.insert(key, vec![seq]);
                 }
             }
         }
@@ -140,7 +168,7 @@ impl<Backer: FcfsBacker> BucketingManager<Backer> for FixedBucketingManager {
             // Allow the min seqs to catch up.
             let min = seq_buckets
                 .keys()
-                .min_by_key(|(_, x, _)| *x)
+                .min_by_key(|(_, x, _, _)| *x)
                 .expect("No sequence buckets.")
                 .clone();
             let len = if !discrete {
@@ -176,10 +204,14 @@ pub struct DefaultScheduler<Backer: FcfsBacker> {
     running: Vec<Sequence>,
     method: DefaultSchedulerMethod,
     bucketing_manager: Box<dyn BucketingManager<Backer>>,
+    policy: SchedulingPolicy,
+    /// Virtual time of each tenant with sequences: the tokens it was served, divided by the priority weight.
+    /// Only used with `SchedulingPolicy::WeightedFair`.
+    tenant_service: HashMap<Option<String>, f64>,
 }
 
 impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
-    pub fn new(method: DefaultSchedulerMethod) -> Self {
+    pub fn new(method: DefaultSchedulerMethod, policy: SchedulingPolicy) -> Self {
         let bucketing_manager: Box<dyn BucketingManager<_>> = match method {
             DefaultSchedulerMethod::Fixed(_) => Box::new(FixedBucketingManager),
         };
@@ -188,9 +220,110 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             waiting: Backer::new(),
             method,
             bucketing_manager,
+            policy,
+            tenant_service: HashMap::new(),
+        }
+    }
+
+    /// Virtual time of a tenant. Tenants which had no sequences start at the lowest virtual time of the
+    /// others so that they do not get to catch up on the time they were idle.
+    fn tenant_service(&self, tenant: Option<&str>) -> f64 {
+        self.tenant_service
+            .get(&tenant.map(ToString::to_string))
+            .copied()
+            .unwrap_or_else(|| {
+                self.tenant_service
+                    .values()
+                    .copied()
+                    .min_by(f64::total_cmp)
+                    .unwrap_or(0.)
+            })
+    }
+
+    /// Order the waiting sequences by which should run first according to the policy.
+    fn order_waiting(&self, mut waiting: Backer) -> Vec<Sequence> {
+        waiting.sort_ascending_ids();
+        let mut waiting = waiting.into_iter().collect::<Vec<_>>();
+        match self.policy {
+            SchedulingPolicy::Fcfs => waiting,
+            SchedulingPolicy::StrictPriority => {
+                // Stable, so the order within a priority class is kept.
+                waiting.sort_by_key(|seq| cmp::Reverse(seq.priority()));
+                waiting
+            }
+            SchedulingPolicy::WeightedFair => {
+                waiting.sort_by_key(|seq| cmp::Reverse(seq.priority()));
+                let mut queues: HashMap<Option<String>, (f64, VecDeque<Sequence>)> = HashMap::new();
+                for seq in waiting {
+                    let tenant = seq.tenant().map(ToString::to_string);
+                    queues
+                        .entry(tenant)
+                        .or_insert_with(|| (self.tenant_service(seq.tenant()), VecDeque::new()))
+                        .1
+                        .push_back(seq);
+                }
+
+                // Repeatedly take the next sequence of the tenant with the lowest virtual time,
+                // charging it the prompt of that sequence.
+                let mut ordered = Vec::new();
+                while let Some((_, (service, queue))) = queues
+                    .iter_mut()
+                    .filter(|(_, (_, queue))| !queue.is_empty())
+                    .min_by(|(_, (a, _)), (_, (b, _))| a.total_cmp(b))
+                {
+                    let seq = queue.pop_front().unwrap();
+                    #[allow(clippy::cast_precision_loss)]
+                    let cost = seq.len() as f64 / seq.priority().weight();
+                    *service += cost;
+                    ordered.push(seq);
+                }
+                ordered
+            }
+        }
+    }
+
+    /// With `SchedulingPolicy::StrictPriority`, make room for a sequence by moving the newest running
+    /// sequence of the lowest priority back to the waiting list, if it has a lower priority than `seq`.
+    fn preempt_for(&self, running: &mut Vec<Sequence>, seq: &Sequence) -> Option<Sequence> {
+        if self.policy != SchedulingPolicy::StrictPriority {
+            return None;
+        }
+        let (idx, victim) = running
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, s)| (s.priority(), cmp::Reverse(*s.id())))?;
+        if victim.priority() < seq.priority() {
+            Some(running.remove(idx))
+        } else {
+            None
         }
     }
 
+    /// Charge each tenant for the tokens its running sequences process in this step.
+    fn charge_tenants(&mut self) {
+        if self.policy != SchedulingPolicy::WeightedFair {
+            return;
+        }
+        for seq in &self.running {
+            let n_toks = if seq.is_prompt() { seq.len() } else { 1 };
+            #[allow(clippy::cast_precision_loss)]
+            let cost = n_toks as f64 / seq.priority().weight();
+            let service = self.tenant_service(seq.tenant());
+            *self
+                .tenant_service
+                .entry(seq.tenant().map(ToString::to_string))
+                .or_insert(service) += cost;
+        }
+
+        // Forget tenants without sequences.
+        let mut active = HashSet::new();
+        for seq in self.running.iter().chain(self.waiting.iter()) {
+            active.insert(seq.tenant().map(ToString::to_string));
+        }
+        self.tenant_service
+            .retain(|tenant, _| active.contains(tenant));
+    }
+
     /// Move the seuqences into buckets, and run the ones with the shortest lengths.
     /// The others are moved to the waiting list (retaining high priority due to start time),
     /// without a state modification.
@@ -223,12 +356,16 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
             }
             (_, 0) => {
                 for seq in waiting.into_iter() {
-                    seq.set_state(SequenceState::RunningPrompt);
+                    // Sequences which were moved back to the waiting list keep their state.
+                    if seq.is_waiting() {
+                        seq.set_state(SequenceState::RunningPrompt);
+                    }
                     self.running.push(seq);
                 }
                 self.waiting = Backer::new();
                 let running = std::mem::take(&mut self.running);
                 self.running = self.bucket_and_waitlist_seqs(running);
+                self.charge_tenants();
                 return DefaultSchedulerOutput {
                     prompt: self.running.iter_mut().collect::<Vec<_>>().into(),
                     completion: vec![].into(),
@@ -242,6 +379,7 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
                         .for_each(|seq| seq.set_state(SequenceState::Done(StopReason::Canceled)));
                     TERMINATE_ALL_NEXT_STEP.store(false, Ordering::SeqCst);
                 }
+                self.charge_tenants();
                 return DefaultSchedulerOutput {
                     prompt: vec![].into(),
                     completion: self.running.iter_mut().collect::<Vec<_>>().into(),
@@ -251,16 +389,23 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
         }
 
         // Sort the waiting seqs
-        waiting.sort_ascending_ids();
+        let waiting = self.order_waiting(waiting);
 
         // If the waiting sequence will fit, add it. Otherwise remove it
         let mut new_waiting = Backer::new();
-        for seq in waiting.into_iter() {
+        for seq in waiting {
             if self.sequence_fits(&running, &seq) {
                 if seq.is_waiting() {
                     seq.set_state(SequenceState::RunningPrompt);
                 }
                 running.push(seq);
+            } else if let Some(preempted) = self.preempt_for(&mut running, &seq) {
+                // The state is not changed, so it continues where it left off once it runs again.
+                new_waiting.add(preempted);
+                if seq.is_waiting() {
+                    seq.set_state(SequenceState::RunningPrompt);
+                }
+                running.push(seq);
             } else {
                 new_waiting.add(seq);
             }
@@ -275,6 +420,7 @@ impl<Backer: FcfsBacker> DefaultScheduler<Backer> {
 
         self.running = running;
         self.waiting = new_waiting;
+        self.charge_tenants();
 
         let mut completion = Vec::new();
         let mut prompt = Vec::new();
@@ -326,7 +472,270 @@ impl Scheduler for DefaultScheduler<VecDeque<Sequence>> {
         None
     }
     fn free_finished_sequence_groups(&mut self) {}
+    fn cancel_request(&mut self, request_id: usize) -> Vec<Arc<Mutex<Sequence>>> {
+        // The KV cache of the default scheduler is stored in the sequence, so dropping it frees the cache.
+        let (mut canceled, running): (Vec<_>, Vec<_>) = self
+            .running
+            .drain(..)
+            .partition(|seq| seq.request_id() == request_id);
+        self.running = running;
+        let (waiting_canceled, waiting): (Vec<_>, Vec<_>) = self
+            .waiting
+            .drain(..)
+            .partition(|seq| seq.request_id() == request_id);
+        self.waiting = waiting.into();
+        canceled.extend(waiting_canceled);
+        canceled
+            .into_iter()
+            .map(|seq| Arc::new(Mutex::new(seq)))
+            .collect()
+    }
     fn block_engine(&mut self) -> Option<&mut BlockEngine> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use candle_core::{DType, Device, Tensor};
+
+    use super::{
+        BucketedSeqs, BucketingManager, DefaultScheduler, DefaultSchedulerMethod,
+        FixedBucketingManager, SchedulingPolicy,
+    };
+    use crate::{
+        pipeline::RecurrentState,
+        scheduler::Scheduler,
+        sequence::{Sequence, SequenceState, TestSequence},
+        RequestPriority,
+    };
+
+    fn scheduler(
+        max_seqs: usize,
+        policy: SchedulingPolicy,
+    ) -> DefaultScheduler<VecDeque<Sequence>> {
+        DefaultScheduler::new(
+            DefaultSchedulerMethod::Fixed(max_seqs.try_into().unwrap()),
+            policy,
+        )
+    }
+
+    /// A waiting sequence with a prompt of `len` tokens.
+    fn seq(id: usize, priority: RequestPriority, tenant: Option<&str>, len: usize) -> Sequence {
+        let (seq, _rx) = TestSequence {
+            id,
+            tokens: vec![1; len],
+            priority,
+            tenant: tenant.map(ToString::to_string),
+            ..Default::default()
+        }
+        .build();
+        seq
+    }
+
+    fn ids<'a>(seqs: impl IntoIterator<Item = &'a Sequence>) -> Vec<usize> {
+        seqs.into_iter().map(|seq| *seq.id()).collect()
+    }
+
+    fn recurrent_prompt(id: usize, tokens: Vec<u32>) -> Sequence {
+        let (seq, _rx) = TestSequence {
+            id,
+            tokens,
+            ..Default::default()
+        }
+        .build();
+        let seq = seq.with_recurrent_states();
+        seq.set_state(SequenceState::RunningPrompt);
+        seq
+    }
+
+    #[test]
+    fn test_prefilled_recurrent_prompt_is_not_padded() -> candle_core::Result<()> {
+        let dev = Device::Cpu;
+        let state = RecurrentState::new(
+            Tensor::zeros((1, 4, 3), DType::F32, &dev)?,
+            Tensor::zeros((1, 4, 8), DType::F32, &dev)?,
+        );
+        // The state of the first 5 tokens was restored from the prefix cache, so only 2 are fed.
+        let prefilled = recurrent_prompt(0, vec![1, 2, 3, 4, 5, 6, 7]).prefill(
+            vec![None],
+            None,
+            Some(vec![Some(state)]),
+            vec![6, 7],
+        );
+        let fresh_same_len = recurrent_prompt(1, vec![1, 2, 3, 4, 5, 6, 8]);
+        let fresh_same_fed = recurrent_prompt(2, vec![9, 10]);
+
+        let BucketedSeqs { running, waiting } = FixedBucketingManager
+            .bucket_and_waitlist_seqs_waiting(
+                vec![prefilled, fresh_same_len, fresh_same_fed],
+                VecDeque::new(),
+                true,
+            );
+
+        // Only the prompts feeding as many tokens are batched, none of them is padded.
+        let mut running_ids = running.iter().map(|seq| *seq.id()).collect::<Vec<_>>();
+        running_ids.sort_unstable();
+        assert_eq!(running_ids, vec![0, 2]);
+        assert!(running.iter().all(|seq| seq.get_toks().len() == 2));
+        assert_eq!(
+            waiting.iter().map(|seq| *seq.id()).collect::<Vec<_>>(),
+            vec![1]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_prompt_logprobs_are_not_batched_with_other_prompts() {
+        let prompt = |id, prompt_logprobs| {
+            let (seq, _rx) = TestSequence {
+                id,
+                tokens: vec![1, 2, 3],
+                ..Default::default()
+            }
+            .build();
+            let seq = if prompt_logprobs {
+                seq.with_prompt_logprobs()
+            } else {
+                seq
+            };
+            seq.set_state(SequenceState::RunningPrompt);
+            seq
+        };
+
+        let BucketedSeqs { running, waiting } = FixedBucketingManager
+            .bucket_and_waitlist_seqs_waiting(
+                vec![prompt(0, true), prompt(1, false), prompt(2, true)],
+                VecDeque::new(),
+                true,
+            );
+        let mut running_ids = ids(&running);
+        running_ids.sort_unstable();
+        let mut waiting_ids = ids(&waiting);
+        waiting_ids.sort_unstable();
+        assert!(
+            (running_ids == [0, 2] && waiting_ids == [1])
+                || (running_ids == [1] && waiting_ids == [0, 2])
+        );
+    }
+
+    #[test]
+    fn test_order_waiting() {
+        use RequestPriority::*;
+        let waiting = || {
+            VecDeque::from([
+                seq(2, Low, None, 4),
+                seq(0, Normal, None, 4),
+                seq(3, High, None, 4),
+                seq(1, High, None, 4),
+            ])
+        };
+        // First come, first served, by the order of the ids.
+        let fcfs = scheduler(8, SchedulingPolicy::Fcfs);
+        assert_eq!(ids(&fcfs.order_waiting(waiting())), vec![0, 1, 2, 3]);
+        // Higher priorities first, in the order they came within a priority class.
+        let strict = scheduler(8, SchedulingPolicy::StrictPriority);
+        assert_eq!(ids(&strict.order_waiting(waiting())), vec![1, 3, 0, 2]);
+    }
+
+    #[test]
+    fn test_order_waiting_weighted_fair() {
+        use RequestPriority::*;
+        let mut scheduler = scheduler(8, SchedulingPolicy::WeightedFair);
+        scheduler.tenant_service.insert(Some("a".to_string()), 0.);
+        scheduler.tenant_service.insert(Some("b".to_string()), 0.5);
+        let waiting = VecDeque::from([
+            seq(0, High, Some("a"), 4),
+            seq(1, Normal, Some("b"), 4),
+            seq(2, High, Some("a"), 4),
+            seq(3, Normal, Some("b"), 4),
+            seq(4, High, Some("a"), 4),
+        ]);
+        // Tenant `a` is charged 4 / 4 = 1 for each sequence, tenant `b` 4 / 2 = 2. The tenant with the lowest
+        // virtual time goes next: a (0 -> 1), b (0.5 -> 2.5), a (1 -> 2), a (2 -> 3), b.
+        assert_eq!(ids(&scheduler.order_waiting(waiting)), vec![0, 1, 2, 4, 3]);
+
+        // A tenant without sequences starts at the lowest virtual time of the others.
+        assert_eq!(scheduler.tenant_service(Some("c")), 0.);
+        scheduler.tenant_service.insert(Some("a".to_string()), 3.);
+        assert_eq!(scheduler.tenant_service(Some("c")), 0.5);
+    }
+
+    #[test]
+    fn test_preempt_for() {
+        use RequestPriority::*;
+        let running = || {
+            vec![
+                seq(0, Normal, None, 4),
+                seq(1, Low, None, 4),
+                seq(2, Low, None, 4),
+            ]
+        };
+        let strict = scheduler(3, SchedulingPolicy::StrictPriority);
+
+        // The newest sequence of the lowest priority is the victim.
+        let mut running_seqs = running();
+        let victim = strict.preempt_for(&mut running_seqs, &seq(3, High, None, 4));
+        assert_eq!(victim.map(|seq| *seq.id()), Some(2));
+        assert_eq!(ids(&running_seqs), vec![0, 1]);
+
+        // Only sequences of a lower priority are preempted.
+        let mut running_seqs = running();
+        assert!(strict
+            .preempt_for(&mut running_seqs, &seq(3, Low, None, 4))
+            .is_none());
+        assert_eq!(running_seqs.len(), 3);
+
+        // Other policies never preempt.
+        let fcfs = scheduler(3, SchedulingPolicy::Fcfs);
+        let mut running_seqs = running();
+        assert!(fcfs
+            .preempt_for(&mut running_seqs, &seq(3, High, None, 4))
+            .is_none());
+    }
+
+    #[test]
+    fn test_charge_tenants() {
+        use RequestPriority::*;
+        let mut scheduler = scheduler(8, SchedulingPolicy::WeightedFair);
+        let prompt = seq(0, High, Some("a"), 8);
+        prompt.set_state(SequenceState::RunningPrompt);
+        let completion = seq(1, Normal, Some("b"), 8);
+        completion.set_state(SequenceState::RunningCompletion);
+        scheduler.running = vec![prompt, completion];
+
+        // A prompt is charged all of its tokens, a completion one token, divided by the priority weight.
+        scheduler.charge_tenants();
+        assert_eq!(scheduler.tenant_service(Some("a")), 2.);
+        assert_eq!(scheduler.tenant_service(Some("b")), 0.5);
+
+        // Tenants without sequences are forgotten.
+        scheduler.running.truncate(1);
+        scheduler.charge_tenants();
+        assert_eq!(scheduler.tenant_service(Some("a")), 4.);
+        assert!(!scheduler
+            .tenant_service
+            .contains_key(&Some("b".to_string())));
+    }
+
+    #[test]
+    fn test_strict_priority_schedule() {
+        use RequestPriority::*;
+        let mut scheduler = scheduler(2, SchedulingPolicy::StrictPriority);
+        scheduler.add_seq(seq(0, Low, None, 4));
+        scheduler.add_seq(seq(1, Low, None, 4));
+        let output = scheduler.schedule();
+        assert_eq!(ids(output.prompt.iter().map(|seq| &**seq)), vec![0, 1]);
+
+        // The high priority sequence takes the place of the newest low priority one, which keeps its state.
+        scheduler.add_seq(seq(2, High, None, 4));
+        let output = scheduler.schedule();
+        let mut running = ids(output.prompt.iter().map(|seq| &**seq));
+        running.sort_unstable();
+        assert_eq!(running, vec![0, 2]);
+        assert_eq!(ids(scheduler.waiting.iter()), vec![1]);
+        assert!(scheduler.waiting.iter().all(Sequence::is_prompt));
+    }
+}
@@ -1,6 +1,10 @@
 mod default_scheduler;
 
-pub use default_scheduler::{DefaultScheduler, DefaultSchedulerMethod, DefaultSchedulerOutput};
+use std::sync::{Arc, Mutex};
+
+pub use default_scheduler::{
+    DefaultScheduler, DefaultSchedulerMethod, DefaultSchedulerOutput, SchedulingPolicy,
+};
 
 use crate::{
     paged_attention::{
@@ -22,14 +26,25 @@ pub enum SchedulerConfig {
 }
 
 impl SchedulerConfig {
-    pub fn into_scheduler(self) -> Box<dyn Scheduler> {
+    /// `policy` orders the waiting sequences of the default scheduler. `prefix_caching` shares the KV cache
+    /// blocks of common prompt prefixes between sequences, which is only done by the PagedAttention scheduler.
+    /// Otherwise, prefix caching is handled by the engine.
+    pub fn into_scheduler(
+        self,
+        policy: SchedulingPolicy,
+        prefix_caching: bool,
+    ) -> Box<dyn Scheduler> {
         match self {
-            Self::DefaultScheduler { method } => Box::new(DefaultScheduler::new(method)),
+            Self::DefaultScheduler { method } => Box::new(DefaultScheduler::new(method, policy)),
             Self::PagedAttentionMeta {
                 max_num_seqs,
                 config,
-            } => Box::new(PagedAttentionScheduler::new(
-                PagedAttentionSchedulerConfig { max_num_seqs },
+            } => Box::new(PagedAttentionScheduler::<Sequence>::new(
+                PagedAttentionSchedulerConfig {
+                    max_num_seqs,
+                    preemption_mode: config.preemption_mode,
+                    prefix_caching,
+                },
                 config,
             )),
         }
@@ -52,6 +67,9 @@ pub trait Scheduler {
     fn add_seq(&mut self, seq: Sequence);
     /// This may do nothing. It depends on the implementation
     fn free_finished_sequence_groups(&mut self);
+    /// Remove all sequences of the request with this id, freeing their resources. The removed sequences are returned
+    /// so that the final responses can be sent.
+    fn cancel_request(&mut self, request_id: usize) -> Vec<Arc<Mutex<Sequence>>>;
 
     // PagedAttention metadata
     fn block_tables(&self) -> Option<&BlockTables>;
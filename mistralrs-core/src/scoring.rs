@@ -0,0 +1,125 @@
+use std::sync::Arc;
+
+use candle_core::Result;
+use tokenizers::Tokenizer;
+
+use crate::{
+    sampler::Logprobs,
+    sequence::{Sequence, SequenceState, StopReason},
+    Response, ScoredToken, ScoringResponse,
+};
+
+/// The total logprob of the scored tokens and the perplexity, which is the inverse of their
+/// geometric mean probability. The logprobs are base 10.
+fn total_logprob_and_perplexity(prompt_logprobs: &[Logprobs]) -> (f32, f32) {
+    let total_logprob = prompt_logprobs
+        .iter()
+        .map(|logprob| logprob.logprob)
+        .sum::<f32>();
+    #[allow(clippy::cast_precision_loss)]
+    let mean_logprob = total_logprob / prompt_logprobs.len() as f32;
+    (total_logprob, 10f32.powf(-mean_logprob))
+}
+
+fn scoring_response(
+    seq: &Sequence,
+    tokenizer: Option<&Tokenizer>,
+    model: String,
+) -> Result<ScoringResponse> {
+    let prompt_toks = &seq.get_toks()[..seq.prompt_tokens()];
+    let prompt_logprobs = seq.prompt_logprobs();
+    if prompt_logprobs.len() + 1 != prompt_toks.len() {
+        candle_core::bail!(
+            "Scoring sequence {} got the logprobs of {} of its {} prompt tokens, the model may not support scoring.",
+            seq.id(),
+            prompt_logprobs.len() + 1,
+            prompt_toks.len()
+        );
+    }
+
+    let first_text = match tokenizer {
+        Some(tokenizer) => tokenizer
+            .decode(&prompt_toks[..1], false)
+            .map_err(candle_core::Error::msg)?,
+        None => String::new(),
+    };
+    let mut tokens = vec![ScoredToken {
+        token: prompt_toks[0],
+        text: first_text,
+        logprob: None,
+        top_logprobs: Vec::new(),
+    }];
+    tokens.extend(prompt_logprobs.iter().map(|logprob| ScoredToken {
+        token: logprob.token,
+        text: logprob.bytes.clone().unwrap_or_default(),
+        logprob: Some(logprob.logprob),
+        top_logprobs: logprob.top_logprobs.clone().unwrap_or_default(),
+    }));
+
+    let (total_logprob, perplexity) = total_logprob_and_perplexity(prompt_logprobs);
+    Ok(ScoringResponse {
+        object: "score".to_string(),
+        model,
+        tokens,
+        total_logprob,
+        perplexity,
+    })
+}
+
+/// Send the scoring responses of sequences whose prompt logprobs have been computed. A sequence
+/// which cannot be scored gets an error response without failing the others.
+pub async fn send_scoring_responses(
+    input_seqs: &mut [&mut Sequence],
+    tokenizer: Option<Arc<Tokenizer>>,
+    model: String,
+) -> Result<()> {
+    for seq in input_seqs.iter_mut() {
+        let (response, state) = match scoring_response(seq, tokenizer.as_deref(), model.clone()) {
+            Ok(response) => (
+                Response::Scoring(response),
+                SequenceState::Done(StopReason::GeneratedScore),
+            ),
+            Err(e) => (Response::InternalError(e.into()), SequenceState::Error),
+        };
+        // If we can't send the response, the client is gone and there is nothing left to do.
+        let _ = seq.responder().send(response).await;
+        seq.set_state(state);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::total_logprob_and_perplexity;
+    use crate::sampler::Logprobs;
+
+    fn logprobs(values: &[f32]) -> Vec<Logprobs> {
+        values
+            .iter()
+            .map(|&logprob| Logprobs {
+                token: 0,
+                logprob,
+                bytes: None,
+                top_logprobs: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_perplexity() {
+        // Every token has a probability of 1/10.
+        let (total, perplexity) = total_logprob_and_perplexity(&logprobs(&[-1., -1., -1.]));
+        assert!((total + 3.).abs() < 1e-6);
+        assert!((perplexity - 10.).abs() < 1e-4);
+
+        // The perplexity is the inverse of the geometric mean probability, 1/sqrt(1 * 1/100).
+        let (total, perplexity) = total_logprob_and_perplexity(&logprobs(&[0., -2.]));
+        assert!((total + 2.).abs() < 1e-6);
+        assert!((perplexity - 10.).abs() < 1e-4);
+
+        // A certain prompt has a perplexity of 1.
+        let (_, perplexity) = total_logprob_and_perplexity(&logprobs(&[0., 0.]));
+        assert!((perplexity - 1.).abs() < 1e-6);
+    }
+}
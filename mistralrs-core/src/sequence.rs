@@ -1,5 +1,6 @@
 use std::{
     fmt::Display,
+    hash::{DefaultHasher, Hash, Hasher},
     sync::{Arc, RwLock},
     time::{SystemTime, UNIX_EPOCH},
 };
@@ -13,18 +14,23 @@ use crate::{
     paged_attention::{BlockEngineSequence, LogicalTokenBlock},
     pipeline::DiffusionGenerationParams,
     response::CompletionChoice,
-    tools::ToolCallingMatcher,
-    CompletionChunkChoice, CompletionChunkResponse, CompletionResponse, ImageChoice,
-    ImageGenerationResponse, ImageGenerationResponseFormat,
+    tools::{ToolCallDelta, ToolCallStreamUpdate, ToolCallStreamer, ToolCallingMatcher},
+    CompletionChunkChoice, CompletionChunkResponse, CompletionResponse, EmbeddingParams,
+    ImageChoice, ImageGenerationResponse, ImageGenerationResponseFormat, RequestPriority,
 };
 use crate::{
     get_mut_group,
-    pipeline::LayerCaches,
-    response::{ChatCompletionChunkResponse, Choice, ChunkChoice, Response, SYSTEM_FINGERPRINT},
+    pipeline::{share_layer_caches, LayerCaches, LayerStates},
+    response::{
+        ChatCompletionChunkResponse, Choice, ChunkChoice, Delta, Response, ResponseMessage,
+        SpeculativeUsage, SYSTEM_FINGERPRINT,
+    },
     sampler::{Logprobs, Sampler},
     ChatCompletionResponse, Usage,
 };
 use candle_core::Tensor;
+use rand::SeedableRng;
+use rand_isaac::Isaac64Rng;
 use regex_automata::util::primitives::StateID;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -39,6 +45,8 @@ pub enum StopReason {
     },
     Canceled,
     GeneratedImage,
+    GeneratedEmbedding,
+    GeneratedScore,
 }
 
 impl Display for StopReason {
@@ -49,6 +57,8 @@ impl Display for StopReason {
             StopReason::StopTok(_) | StopReason::StopString { .. } => write!(f, "stop"),
             StopReason::Canceled => write!(f, "canceled"),
             StopReason::GeneratedImage => write!(f, "generated-image"),
+            StopReason::GeneratedEmbedding => write!(f, "generated-embedding"),
+            StopReason::GeneratedScore => write!(f, "generated-score"),
         }
     }
 }
@@ -65,6 +75,8 @@ pub enum SequenceState {
     FinishedAborted,
     FinishedIgnored,
     Swapped,
+    // For beam search: the sequence was replaced by its continuations.
+    Forked,
 }
 
 pub enum SequenceRecognizer {
@@ -73,6 +85,7 @@ pub enum SequenceRecognizer {
     None,
 }
 
+#[derive(Clone)]
 enum SequenceCustomMetadata {
     PagedAttention {
         logical_token_blocks: Vec<LogicalTokenBlock>,
@@ -147,6 +160,28 @@ impl SequenceCustomMetadata {
     }
 }
 
+/// Length of the longest suffix of `bytes` which is a proper prefix of one of the stop strings.
+fn stop_string_prefix_len(bytes: &[u8], stop_strings: &[String]) -> usize {
+    stop_strings
+        .iter()
+        .filter_map(|stop| {
+            let stop = stop.as_bytes();
+            (1..stop.len().min(bytes.len() + 1))
+                .rev()
+                .find(|n| bytes.ends_with(&stop[..*n]))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// The SplitMix64 finalizer, which maps close seeds to uncorrelated ones.
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
 #[derive(Clone, Copy)]
 pub enum SeqStepType {
     PromptAndDecode,
@@ -156,10 +191,15 @@ pub enum SeqStepType {
 pub struct Sequence {
     // Metadata, const
     id: usize,
+    request_id: usize,
+    priority: RequestPriority,
+    tenant: Option<String>,
     prompt_len: usize,
     max_len: Option<usize>,
     timestamp: u128,
     sampler: Arc<Sampler>,
+    /// The RNG of a seeded request. Otherwise, the RNG of the engine is used.
+    rng: Option<Arc<std::sync::Mutex<Isaac64Rng>>>,
     stop_tokens: Vec<u32>,
     stop_strings: Vec<String>,
     return_logprobs: bool,
@@ -173,6 +213,9 @@ pub struct Sequence {
     image_gen_response_format: Option<ImageGenerationResponseFormat>,
     diffusion_params: Option<DiffusionGenerationParams>,
 
+    // Embedding
+    embedding_params: Option<EmbeddingParams>,
+
     // Grammars
     pub(crate) tok_trie: Option<TokTrie>,
 
@@ -182,6 +225,9 @@ pub struct Sequence {
 
     // Speculative
     is_tmp: bool,
+    speculative_gamma: Option<usize>,
+    num_draft_toks: usize,
+    num_accepted_draft_toks: usize,
 
     // Prefix caching
     prefill_prompt_toks: Option<Vec<u32>>,
@@ -189,15 +235,25 @@ pub struct Sequence {
     // Adapter dynamic config
     adapters: Option<Vec<String>>,
 
+    // Beam search
+    beam_width: Option<usize>,
+    beam_candidates: Option<Vec<Logprobs>>,
+
     // Cache
     scaling_cache: Option<Tensor>,
     cache: LayerCaches,
     draft_cache: LayerCaches,
     xlora_cache: Option<LayerCaches>,
+    recurrent_states: Option<LayerStates>,
 
     // Mutables
     tokens: Vec<u32>,
     logprobs: Vec<Logprobs>,
+    return_prompt_logprobs: bool,
+    // Scoring sequences only compute the logprobs of their prompt.
+    scoring: bool,
+    /// Logprobs of the prompt tokens after the first one.
+    prompt_logprobs: Vec<Logprobs>,
     cumulative_logprob: f32,
     last_logprob: f32,
     last_completion_bytes_len: usize,
@@ -219,6 +275,7 @@ pub struct Sequence {
 
     // Tool calls
     pub tools: Option<Arc<ToolCallingMatcher>>,
+    tool_call_streamer: Option<ToolCallStreamer>,
 }
 
 impl BlockEngineSequence for Sequence {
@@ -247,6 +304,36 @@ impl BlockEngineSequence for Sequence {
             SequenceCustomMetadata::None => unreachable!(),
         }
     }
+
+    fn num_tokens(&self) -> usize {
+        self.tokens.len()
+    }
+
+    fn block_hashes(&self) -> Vec<u64> {
+        // The KV cache of images and of embedding sequences is not determined by the tokens alone.
+        if self.input_images.is_some() || self.is_embedding() {
+            return Vec::new();
+        }
+        match &self.custom_metadata {
+            SequenceCustomMetadata::PagedAttention {
+                logical_token_blocks,
+                block_size: _,
+            } => {
+                let mut hasher = DefaultHasher::new();
+                self.adapters.hash(&mut hasher);
+                let mut hash = hasher.finish();
+                logical_token_blocks
+                    .iter()
+                    .take_while(|block| block.is_full())
+                    .map(|block| {
+                        hash = block.hash_with_prefix(hash);
+                        hash
+                    })
+                    .collect()
+            }
+            SequenceCustomMetadata::None => unreachable!(),
+        }
+    }
 }
 
 impl Sequence {
@@ -255,6 +342,9 @@ impl Sequence {
         tokens: Vec<u32>,
         prompt: String,
         id: usize,
+        request_id: usize,
+        priority: RequestPriority,
+        tenant: Option<String>,
         timestamp: u128,
         layers: usize,
         responder: Sender<Response>,
@@ -280,6 +370,7 @@ impl Sequence {
         image_gen_response_format: Option<ImageGenerationResponseFormat>,
         sequence_stepping_type: SeqStepType,
         diffusion_params: Option<DiffusionGenerationParams>,
+        embedding_params: Option<EmbeddingParams>,
     ) -> Self {
         let prompt_len = tokens.len();
         let mut custom_metadata = if let Some(block_size) = block_size {
@@ -296,8 +387,14 @@ impl Sequence {
             tokens,
             prompt,
             logprobs: Vec::new(),
+            return_prompt_logprobs: false,
+            scoring: false,
+            prompt_logprobs: Vec::new(),
             prompt_len,
             id,
+            request_id,
+            priority,
+            tenant,
             timestamp,
             state: RwLock::new(SequenceState::Waiting),
             cache: vec![None; layers],
@@ -307,8 +404,10 @@ impl Sequence {
             } else {
                 None
             },
+            recurrent_states: None,
             responder,
             sampler: sampler.into(),
+            rng: None,
             stop_tokens,
             stop_strings,
             max_len,
@@ -330,18 +429,143 @@ impl Sequence {
             last_logprob: 0.0,
             last_is_done: None,
             is_tmp: false,
+            speculative_gamma: None,
+            num_draft_toks: 0,
+            num_accepted_draft_toks: 0,
             scheduling_urgency: 0,
             adapters,
+            beam_width: None,
+            beam_candidates: None,
             input_images,
             custom_metadata,
             tok_trie,
+            tool_call_streamer: tools.as_ref().and_then(|matcher| matcher.streamer()),
             tools,
             image_gen_response_format,
             sequence_stepping_type,
             diffusion_params,
+            embedding_params,
+        }
+    }
+
+    /// Decode this sequence as the first beam of a beam search of the given width.
+    pub fn with_beam_width(mut self, beam_width: usize) -> Self {
+        self.beam_width = Some(beam_width);
+        self
+    }
+
+    /// Compute the logprobs of the prompt tokens when the prompt is processed.
+    pub fn with_prompt_logprobs(mut self) -> Self {
+        self.return_prompt_logprobs = true;
+        self
+    }
+
+    /// Only score the prompt: the sequence is done once the logprobs of its prompt are computed.
+    pub fn with_scoring(mut self) -> Self {
+        self.scoring = true;
+        self.with_prompt_logprobs()
+    }
+
+    /// Sample this sequence with its own RNG, so that the output does not depend on the other
+    /// sequences which are sampled. The seed is mixed with the response index, so that each choice
+    /// of a request gets a different, reproducible seed.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        let seed = splitmix64(splitmix64(seed).wrapping_add(self.response_index as u64));
+        self.rng = Some(Arc::new(std::sync::Mutex::new(Isaac64Rng::seed_from_u64(
+            seed,
+        ))));
+        self
+    }
+
+    /// Keep the recurrent states of a state space model such as Mamba, instead of a KV cache.
+    pub fn with_recurrent_states(mut self) -> Self {
+        self.recurrent_states = Some(vec![None; self.cache.len()]);
+        self
+    }
+
+    /// Create a copy of this sequence with a new id, for a beam search continuation. The KV cache
+    /// buffers are shared with this sequence until the copy writes to them.
+    pub(crate) fn fork(&self, id: usize) -> Self {
+        Self {
+            id,
+            request_id: self.request_id,
+            priority: self.priority,
+            tenant: self.tenant.clone(),
+            prompt_len: self.prompt_len,
+            max_len: self.max_len,
+            timestamp: self.timestamp,
+            sampler: self.sampler.clone(),
+            rng: self.rng.clone(),
+            stop_tokens: self.stop_tokens.clone(),
+            stop_strings: self.stop_strings.clone(),
+            return_logprobs: self.return_logprobs,
+            responder: self.responder.clone(),
+            response_index: self.response_index,
+            creation_time: self.creation_time,
+            prompt: self.prompt.clone(),
+            sequence_stepping_type: self.sequence_stepping_type,
+            image_gen_response_format: self.image_gen_response_format,
+            diffusion_params: self.diffusion_params.clone(),
+            embedding_params: self.embedding_params,
+            tok_trie: self.tok_trie.clone(),
+            suffix: self.suffix.clone(),
+            prefix: self.prefix.clone(),
+            is_tmp: self.is_tmp,
+            speculative_gamma: self.speculative_gamma,
+            num_draft_toks: self.num_draft_toks,
+            num_accepted_draft_toks: self.num_accepted_draft_toks,
+            prefill_prompt_toks: self.prefill_prompt_toks.clone(),
+            adapters: self.adapters.clone(),
+            beam_width: self.beam_width,
+            beam_candidates: None,
+            scaling_cache: self.scaling_cache.clone(),
+            cache: share_layer_caches(&self.cache),
+            draft_cache: share_layer_caches(&self.draft_cache),
+            xlora_cache: self.xlora_cache.as_ref().map(share_layer_caches),
+            // The states are replaced rather than written to, so they can be shared.
+            recurrent_states: self.recurrent_states.clone(),
+            tokens: self.tokens.clone(),
+            logprobs: self.logprobs.clone(),
+            return_prompt_logprobs: self.return_prompt_logprobs,
+            scoring: self.scoring,
+            prompt_logprobs: self.prompt_logprobs.clone(),
+            cumulative_logprob: self.cumulative_logprob,
+            last_logprob: self.last_logprob,
+            last_completion_bytes_len: self.last_completion_bytes_len,
+            last_is_done: self.last_is_done,
+            completion_bytes: self.completion_bytes.clone(),
+            stream_idx: self.stream_idx,
+            // Beam search does not support grammars.
+            recognizer: SequenceRecognizer::None,
+            scheduling_urgency: self.scheduling_urgency,
+            input_images: self.input_images.clone(),
+            prompt_tok_per_sec: self.prompt_tok_per_sec,
+            prompt_timestamp: self.prompt_timestamp,
+            group: self.group.clone(),
+            state: RwLock::new(self.getstate()),
+            custom_metadata: self.custom_metadata.clone(),
+            tools: self.tools.clone(),
+            tool_call_streamer: self.tools.as_ref().and_then(|matcher| matcher.streamer()),
         }
     }
 
+    pub fn beam_width(&self) -> Option<usize> {
+        self.beam_width
+    }
+
+    /// Set the candidates for the next token of a beam search sequence, instead of adding a token.
+    pub(crate) fn set_beam_candidates(&mut self, candidates: Vec<Logprobs>) {
+        self.beam_candidates = Some(candidates);
+    }
+
+    pub(crate) fn take_beam_candidates(&mut self) -> Option<Vec<Logprobs>> {
+        self.beam_candidates.take()
+    }
+
+    pub fn cumulative_logprob(&self) -> f32 {
+        self.cumulative_logprob
+    }
+
     pub fn add_urgency(mut self) -> Self {
         self.scheduling_urgency += 1;
         self
@@ -364,10 +588,14 @@ impl Sequence {
         mut self,
         cache: LayerCaches,
         xlora_cache: Option<LayerCaches>,
+        recurrent_states: Option<LayerStates>,
         toks: Vec<u32>,
     ) -> Self {
         self.cache = cache;
         self.xlora_cache = xlora_cache;
+        if recurrent_states.is_some() {
+            self.recurrent_states = recurrent_states;
+        }
         self.prefill_prompt_toks = Some(toks);
         self.set_state(SequenceState::RunningPrefillPrompt);
         self
@@ -386,11 +614,10 @@ impl Sequence {
             self.xlora_cache.as_ref().unwrap()[0]
                 .as_ref()
                 .unwrap()
-                .0
-                .dims()[2]
+                .current_seq_len()
                 + 1
-        } else if let Some((_, x)) = &self.cache[0] {
-            x.dims()[2] + 1
+        } else if let Some(cache) = &self.cache[0] {
+            cache.current_seq_len() + 1
         } else {
             self.tokens.len()
         }
@@ -400,6 +627,19 @@ impl Sequence {
         &self.id
     }
 
+    /// The id of the `NormalRequest` this sequence was created for.
+    pub fn request_id(&self) -> usize {
+        self.request_id
+    }
+
+    pub fn priority(&self) -> RequestPriority {
+        self.priority
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
     pub fn is_running(&self) -> bool {
         matches!(
             *self.state.read().unwrap(),
@@ -469,15 +709,15 @@ impl Sequence {
         &self.completion_bytes
     }
 
-    pub fn cache(&mut self) -> &mut Vec<Option<(Tensor, Tensor)>> {
+    pub fn cache(&mut self) -> &mut LayerCaches {
         &mut self.cache
     }
 
-    pub fn draft_cache(&mut self) -> &mut Vec<Option<(Tensor, Tensor)>> {
+    pub fn draft_cache(&mut self) -> &mut LayerCaches {
         &mut self.draft_cache
     }
 
-    pub fn xlora_cache(&mut self) -> &mut Vec<Option<(Tensor, Tensor)>> {
+    pub fn xlora_cache(&mut self) -> &mut LayerCaches {
         self.xlora_cache.as_mut().expect("No X-LoRA cache.")
     }
 
@@ -489,10 +729,27 @@ impl Sequence {
         self.xlora_cache.is_some()
     }
 
+    /// # Panics
+    /// If the sequence does not keep recurrent states
+    pub fn recurrent_states(&mut self) -> &mut LayerStates {
+        self.recurrent_states
+            .as_mut()
+            .expect("No recurrent states.")
+    }
+
+    pub fn is_recurrent(&self) -> bool {
+        self.recurrent_states.is_some()
+    }
+
     pub fn sampler(&mut self) -> Arc<Sampler> {
         self.sampler.clone()
     }
 
+    /// The RNG of this sequence if it is seeded.
+    pub fn rng(&self) -> Option<Arc<std::sync::Mutex<Isaac64Rng>>> {
+        self.rng.clone()
+    }
+
     /// Add a some prefill tokens. Only meant for internal speculative decoding usage.
     pub fn set_prefill_toks(&mut self, toks: Vec<u32>) {
         self.prefill_prompt_toks = Some(toks)
@@ -511,6 +768,24 @@ impl Sequence {
         self.custom_metadata.append_token_to_blocks(tok as usize);
     }
 
+    /// The number of draft tokens to propose in the next speculative decoding step, if adapted
+    /// for this sequence.
+    pub(crate) fn speculative_gamma(&self) -> Option<usize> {
+        self.speculative_gamma
+    }
+
+    /// Record a speculative decoding step and the number of draft tokens for the next step.
+    pub(crate) fn add_speculative_step(
+        &mut self,
+        next_gamma: usize,
+        num_draft_toks: usize,
+        num_accepted_draft_toks: usize,
+    ) {
+        self.speculative_gamma = Some(next_gamma);
+        self.num_draft_toks += num_draft_toks;
+        self.num_accepted_draft_toks += num_accepted_draft_toks;
+    }
+
     /// Internal api to remove n raw tokens.
     pub(crate) fn remove_tmp_tok(&mut self, n: usize) {
         self.is_tmp = false;
@@ -594,18 +869,33 @@ impl Sequence {
         } else if self.tokens.len().saturating_sub(self.prompt_len) == max_model_len {
             Some(StopReason::ModelLength(max_model_len))
         } else {
-            if !self.stop_strings.is_empty() {
-                for (idx, s) in self.stop_strings.iter().enumerate() {
-                    if let Some(pos) = galil_seiferas::gs_find(&self.completion_bytes, s.as_bytes())
-                    {
-                        return Some(StopReason::StopString {
-                            stop_string_idx: idx,
-                            completion_bytes_pos: pos,
-                        });
-                    }
-                }
-            }
-            None
+            // A token may complete several stop strings, the output is cut at the first one.
+            self.stop_strings
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, s)| {
+                    galil_seiferas::gs_find(&self.completion_bytes, s.as_bytes())
+                        .map(|pos| (idx, pos))
+                })
+                .min_by_key(|(_, pos)| *pos)
+                .map(|(idx, pos)| StopReason::StopString {
+                    stop_string_idx: idx,
+                    completion_bytes_pos: pos,
+                })
+        }
+    }
+
+    /// The stop string, or the text of the stop token, which stopped the sequence.
+    pub fn matched_stop_sequence(&self, reason: &StopReason) -> Option<String> {
+        match reason {
+            StopReason::StopString {
+                stop_string_idx, ..
+            } => self.stop_strings.get(*stop_string_idx).cloned(),
+            StopReason::StopTok(tok) => self
+                .tok_trie
+                .as_ref()
+                .map(|tok_trie| tok_trie.decode_str(&[*tok])),
+            _ => None,
         }
     }
 
@@ -617,6 +907,52 @@ impl Sequence {
         self.return_logprobs
     }
 
+    pub fn is_scoring(&self) -> bool {
+        self.scoring
+    }
+
+    pub fn returns_prompt_logprobs(&self) -> bool {
+        self.return_prompt_logprobs
+    }
+
+    pub fn prompt_logprobs(&self) -> &[Logprobs] {
+        &self.prompt_logprobs
+    }
+
+    /// Text prepended to the completion choice, such as the echoed prompt.
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    /// Whether the logits of every prompt position should be computed for this sequence.
+    pub(crate) fn needs_prompt_logprobs(&self) -> bool {
+        self.return_prompt_logprobs && self.prompt_logprobs.len() + 1 < self.prompt_len
+    }
+
+    /// Record the prompt logprobs from the logits of the positions `start..start + logits.dim(0)`.
+    /// Positions whose logprobs were already recorded, or which are not in the prompt, are skipped.
+    pub(crate) fn add_prompt_logprobs(
+        &mut self,
+        logits: &Tensor,
+        start: usize,
+    ) -> candle_core::Result<()> {
+        if !self.return_prompt_logprobs {
+            return Ok(());
+        }
+        // The logits at a position give the logprob of the token after it.
+        let first = self.prompt_logprobs.len();
+        let end = (start + logits.dim(0)?).min(self.prompt_len.saturating_sub(1));
+        if first < start || first >= end {
+            return Ok(());
+        }
+        let logprobs = self.sampler.logprobs_of(
+            &logits.narrow(0, first - start, end - first)?,
+            &self.tokens[first + 1..end + 1],
+        )?;
+        self.prompt_logprobs.extend(logprobs);
+        Ok(())
+    }
+
     pub fn prompt_tokens(&self) -> usize {
         self.prompt_len
     }
@@ -625,17 +961,48 @@ impl Sequence {
         &self.stop_strings
     }
 
+    /// Length of the completion bytes which may be sent to the client. A matched stop string and the
+    /// text after it are never sent, and while the sequence is running, text which could be the
+    /// start of a stop string is held back. A stop string completed by the last token is only
+    /// matched by `is_done` at the next step, so it is held back too.
+    fn streamable_len(&self) -> usize {
+        let done = self.last_is_done.or(match *self.state.read().unwrap() {
+            SequenceState::Done(reason) => Some(reason),
+            _ => None,
+        });
+        match done {
+            Some(StopReason::StopString {
+                completion_bytes_pos,
+                ..
+            }) => completion_bytes_pos,
+            Some(_) => self.completion_bytes.len(),
+            None => {
+                let partial_match = self.completion_bytes.len()
+                    - stop_string_prefix_len(&self.completion_bytes, &self.stop_strings);
+                self.stop_strings
+                    .iter()
+                    .filter_map(|s| galil_seiferas::gs_find(&self.completion_bytes, s.as_bytes()))
+                    .fold(partial_match, usize::min)
+            }
+        }
+    }
+
     /// Returns the delta between the last two decoded sequences
     pub fn get_delta(
         &mut self,
     ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
         let is_first = self.stream_idx == 0;
-        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..]);
+        let end = self.streamable_len().max(self.stream_idx);
+        let new_decoded = String::from_utf8_lossy(&self.completion_bytes[self.stream_idx..end]);
         // Check if the sequence ends with valid utf8, if not skip it as it probably is a multi token sequence
         if new_decoded.ends_with('�') {
             return Ok(None);
         }
-        self.stream_idx = self.completion_bytes.len();
+        // Everything new may be held back, but the final delta must still be sent.
+        if new_decoded.is_empty() && self.last_is_done.is_none() {
+            return Ok(None);
+        }
+        self.stream_idx = end;
 
         // The first token usually starts with a space. We don't want to add that to the delta.
         // Since we're using the completion_bytes, we need to take care of that ourselves.
@@ -646,6 +1013,34 @@ impl Sequence {
         Ok(Some(new_decoded.to_string()))
     }
 
+    /// Split a streaming delta into content and tool call deltas. While the output is or may become
+    /// a tool call, the content is held back. If it turns out not to call a tool, all of the output
+    /// so far is returned as the content.
+    pub fn get_tool_call_delta(
+        &mut self,
+        delta: String,
+        is_done: bool,
+    ) -> (String, Option<Vec<ToolCallDelta>>) {
+        let streamable_len = self.streamable_len();
+        let Some(streamer) = &mut self.tool_call_streamer else {
+            return (delta, None);
+        };
+        let text = String::from_utf8_lossy(&self.completion_bytes[..streamable_len]);
+        match streamer.update(&text, is_done) {
+            ToolCallStreamUpdate::Content => (delta, None),
+            ToolCallStreamUpdate::ToolCalls(calls) => (String::new(), Some(calls)),
+            ToolCallStreamUpdate::Hold => (String::new(), None),
+            ToolCallStreamUpdate::Flush => (text.trim_start().to_string(), None),
+        }
+    }
+
+    /// Whether tool calls have been streamed for this sequence.
+    pub fn has_streamed_tool_calls(&self) -> bool {
+        self.tool_call_streamer
+            .as_ref()
+            .is_some_and(|streamer| streamer.has_calls())
+    }
+
     pub fn timestamp(&self) -> u128 {
         self.timestamp
     }
@@ -669,6 +1064,13 @@ impl Sequence {
 
         get_mut_group!(self).total_prompt_toks += self.prompt_len;
         get_mut_group!(self).total_toks += self.len();
+
+        if self.speculative_gamma.is_some() {
+            let mut group = get_mut_group!(self);
+            let (draft_toks, accepted_draft_toks) = group.speculative_toks.get_or_insert((0, 0));
+            *draft_toks += self.num_draft_toks;
+            *accepted_draft_toks += self.num_accepted_draft_toks;
+        }
     }
 
     pub fn add_image_choice_to_group(&self, choice: ImageChoice) {
@@ -698,6 +1100,10 @@ impl Sequence {
         self.response_index
     }
 
+    pub(crate) fn set_response_index(&mut self, response_index: usize) {
+        self.response_index = response_index;
+    }
+
     pub fn get_mut_group(&self) -> MutexGuard<'_, SequenceGroup> {
         get_mut_group!(self)
     }
@@ -710,6 +1116,100 @@ impl Sequence {
         get_mut_group!(self).completion_streaming_chunks.push(chunk);
     }
 
+    /// Finish a sequence which was removed from the scheduler because its request was canceled.
+    /// The final response contains the output generated so far.
+    pub async fn finish_canceled(&mut self, model: String) -> Result<(), Box<SendError<Response>>> {
+        self.set_state(SequenceState::Done(StopReason::Canceled));
+        let finish_reason = StopReason::Canceled.to_string();
+        let is_chat = get_mut_group!(self).is_chat;
+
+        if get_mut_group!(self).is_streaming {
+            // Flush anything held back by the streaming rate limit.
+            let delta = self.get_delta().ok().flatten().unwrap_or_default();
+            if is_chat {
+                let (content, tool_calls) = self.get_tool_call_delta(delta, true);
+                self.add_streaming_chunk_choice_to_group(ChunkChoice {
+                    delta: Delta {
+                        content,
+                        role: "assistant".to_string(),
+                        tool_calls,
+                    },
+                    index: self.response_index,
+                    finish_reason: Some(finish_reason),
+                    stop_sequence: None,
+                    logprobs: None,
+                });
+            } else {
+                self.add_streaming_completion_chunk_choice_to_group(CompletionChunkChoice {
+                    text: delta,
+                    index: self.response_index,
+                    finish_reason: Some(finish_reason),
+                    stop_sequence: None,
+                    logprobs: None,
+                });
+            }
+            return get_mut_group!(self)
+                .maybe_send_streaming_response(self, model)
+                .await;
+        }
+
+        let text = String::from_utf8_lossy(&self.completion_bytes)
+            .trim_start()
+            .to_string();
+        if is_chat {
+            self.add_choice_to_group(Choice {
+                finish_reason,
+                stop_sequence: None,
+                index: self.response_index,
+                message: ResponseMessage {
+                    content: Some(text),
+                    role: "assistant".to_string(),
+                    tool_calls: Vec::new(),
+                },
+                logprobs: None,
+            });
+            let group = get_mut_group!(self);
+            group
+                .maybe_send_chat_done_response(
+                    ChatCompletionResponse {
+                        id: self.id.to_string(),
+                        choices: group.get_choices().to_vec(),
+                        created: self.creation_time,
+                        model,
+                        system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                        object: "chat.completion".to_string(),
+                        usage: group.get_usage(),
+                    },
+                    self.responder(),
+                )
+                .await
+                .map_err(Box::new)
+        } else {
+            self.add_completion_choice_to_group(CompletionChoice {
+                finish_reason,
+                stop_sequence: None,
+                index: self.response_index,
+                text,
+                logprobs: None,
+            });
+            let group = get_mut_group!(self);
+            group
+                .maybe_send_completion_done_response(
+                    CompletionResponse {
+                        id: self.id.to_string(),
+                        choices: group.get_completion_choices().to_vec(),
+                        created: self.creation_time,
+                        model,
+                        system_fingerprint: SYSTEM_FINGERPRINT.to_string(),
+                        object: "text_completion".to_string(),
+                        usage: group.get_usage(),
+                    },
+                    self.responder(),
+                )
+                .await
+        }
+    }
+
     pub fn get_adapters(&self) -> Option<Vec<String>> {
         self.adapters.clone()
     }
@@ -733,6 +1233,14 @@ impl Sequence {
     pub fn get_diffusion_diffusion_params(&self) -> Option<DiffusionGenerationParams> {
         self.diffusion_params.clone()
     }
+
+    pub fn embedding_params(&self) -> Option<EmbeddingParams> {
+        self.embedding_params
+    }
+
+    pub fn is_embedding(&self) -> bool {
+        self.embedding_params.is_some()
+    }
 }
 
 pub struct SequenceGroup {
@@ -743,6 +1251,8 @@ pub struct SequenceGroup {
     pub total_prompt_time: u128,
     pub total_time: u128,
     pub total_completion_time: u128,
+    /// Number of draft tokens and accepted draft tokens, if speculative decoding was used.
+    speculative_toks: Option<(usize, usize)>,
     choices: Vec<Choice>,
     image_choices: Vec<ImageChoice>,
     completion_choices: Vec<(f32, CompletionChoice)>,
@@ -764,6 +1274,7 @@ impl SequenceGroup {
             total_prompt_time: 0,
             total_time: 0,
             total_completion_time: 0,
+            speculative_toks: None,
             chat_streaming_chunks: Vec::new(),
             completion_streaming_chunks: Vec::new(),
             is_streaming,
@@ -808,6 +1319,17 @@ impl SequenceGroup {
             total_time_sec: self.total_time as f32 / 1000.,
             total_completion_time_sec: self.total_completion_time as f32 / 1000.,
             total_prompt_time_sec: self.total_prompt_time as f32 / 1000.,
+            speculative: self
+                .speculative_toks
+                .map(|(draft_tokens, accepted_draft_tokens)| SpeculativeUsage {
+                    draft_tokens,
+                    accepted_draft_tokens,
+                    acceptance_rate: if draft_tokens == 0 {
+                        0.
+                    } else {
+                        accepted_draft_tokens as f32 / draft_tokens as f32
+                    },
+                }),
         }
     }
 
@@ -888,3 +1410,276 @@ impl SequenceGroup {
         Ok(())
     }
 }
+
+/// Builds a [`Sequence`] for tests. The defaults give the single, non streaming completion choice
+/// of a text prompt, sampled greedily.
+#[cfg(test)]
+pub(crate) struct TestSequence {
+    pub id: usize,
+    pub tokens: Vec<u32>,
+    pub prompt: String,
+    pub priority: RequestPriority,
+    pub tenant: Option<String>,
+    pub timestamp: u128,
+    pub sampler: Option<Sampler>,
+    pub stop_strings: Vec<String>,
+    pub prefix: Option<String>,
+    pub return_logprobs: bool,
+    pub is_streaming: bool,
+    pub is_chat: bool,
+    pub n_choices: usize,
+    pub response_index: usize,
+    pub block_size: Option<usize>,
+    pub layers: usize,
+}
+
+#[cfg(test)]
+impl Default for TestSequence {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            tokens: vec![0],
+            prompt: String::new(),
+            priority: RequestPriority::default(),
+            tenant: None,
+            timestamp: 0,
+            sampler: None,
+            stop_strings: Vec::new(),
+            prefix: None,
+            return_logprobs: false,
+            is_streaming: false,
+            is_chat: false,
+            n_choices: 1,
+            response_index: 0,
+            block_size: None,
+            layers: 1,
+        }
+    }
+}
+
+#[cfg(test)]
+impl TestSequence {
+    /// The sequence and the receiver of its responses.
+    pub(crate) fn build(self) -> (Sequence, tokio::sync::mpsc::Receiver<Response>) {
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        let sampler = self.sampler.unwrap_or_else(|| {
+            Sampler::new(
+                None,
+                0,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                -1,
+                1.0,
+                0.0,
+                1.0,
+                0.0,
+                1.0,
+                None,
+                None,
+                vec![],
+            )
+            .unwrap()
+        });
+        let group = Arc::new(Mutex::new(SequenceGroup::new(
+            self.n_choices,
+            self.is_streaming,
+            self.is_chat,
+            self.n_choices,
+        )));
+        let seq = Sequence::new_waiting(
+            self.tokens,
+            self.prompt,
+            self.id,
+            self.id,
+            self.priority,
+            self.tenant,
+            self.timestamp,
+            self.layers,
+            tx,
+            sampler,
+            vec![],
+            self.stop_strings,
+            None,
+            self.return_logprobs,
+            false,
+            group,
+            self.response_index,
+            0,
+            SequenceRecognizer::None,
+            None,
+            self.prefix,
+            None,
+            None,
+            self.block_size,
+            None,
+            None,
+            None,
+            SeqStepType::PromptAndDecode,
+            None,
+            None,
+        );
+        (seq, rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{stop_string_prefix_len, Sequence, StopReason, TestSequence};
+    use crate::sampler::Logprobs;
+
+    const EOS: u32 = 2;
+
+    fn seq_with_stop_strings(stop_strings: &[&str]) -> Sequence {
+        let (seq, _rx) = TestSequence {
+            stop_strings: stop_strings.iter().map(ToString::to_string).collect(),
+            is_streaming: true,
+            ..Default::default()
+        }
+        .build();
+        seq
+    }
+
+    /// Add a generated token as the pipeline does: the stop check runs before the token is added.
+    fn push(seq: &mut Sequence, tok: u32, bytes: &[u8]) -> Option<StopReason> {
+        let is_done = seq.is_done(tok, Some(&[EOS]), usize::MAX);
+        seq.add_token(
+            Logprobs {
+                token: tok,
+                logprob: 0.,
+                bytes: None,
+                top_logprobs: None,
+            },
+            bytes.to_vec(),
+            &is_done,
+        );
+        is_done
+    }
+
+    fn delta(seq: &mut Sequence) -> Option<String> {
+        seq.get_delta().unwrap()
+    }
+
+    #[test]
+    fn test_stop_string_prefix_len() {
+        let stop = |s: &[&str]| s.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(stop_string_prefix_len(b"hello </", &stop(&["</s>"])), 2);
+        assert_eq!(stop_string_prefix_len(b"<", &stop(&["</s>"])), 1);
+        assert_eq!(stop_string_prefix_len(b"hello", &stop(&["</s>"])), 0);
+        // A complete stop string is not a proper prefix.
+        assert_eq!(stop_string_prefix_len(b"hello </s>", &stop(&["</s>"])), 0);
+        // The longest prefix of any of the stop strings.
+        assert_eq!(stop_string_prefix_len(b"ab xy", &stop(&["yes", "xyz"])), 2);
+    }
+
+    #[test]
+    fn test_stop_string_split_across_tokens() {
+        let mut seq = seq_with_stop_strings(&["</s>"]);
+        assert_eq!(push(&mut seq, 10, b" Hello"), None);
+        assert_eq!(delta(&mut seq).as_deref(), Some("Hello"));
+
+        // Could be the start of the stop string.
+        assert_eq!(push(&mut seq, 11, b"</"), None);
+        assert_eq!(delta(&mut seq), None);
+
+        // Completes the stop string, which is matched at the next step but must not be sent now.
+        assert_eq!(push(&mut seq, 12, b"s>"), None);
+        assert_eq!(delta(&mut seq), None);
+
+        let reason = push(&mut seq, 13, b" more");
+        assert_eq!(
+            reason,
+            Some(StopReason::StopString {
+                stop_string_idx: 0,
+                completion_bytes_pos: 6,
+            })
+        );
+        // The final delta is sent even though it is empty.
+        assert_eq!(delta(&mut seq).as_deref(), Some(""));
+        assert_eq!(
+            seq.matched_stop_sequence(&reason.unwrap()).as_deref(),
+            Some("</s>")
+        );
+    }
+
+    #[test]
+    fn test_partial_stop_string_is_flushed() {
+        let mut seq = seq_with_stop_strings(&["</s>"]);
+        push(&mut seq, 10, b" a");
+        assert_eq!(delta(&mut seq).as_deref(), Some("a"));
+        push(&mut seq, 11, b"<");
+        assert_eq!(delta(&mut seq), None);
+        // The held back text is sent once it can no longer be a stop string.
+        push(&mut seq, 12, b"b");
+        assert_eq!(delta(&mut seq).as_deref(), Some("<b"));
+
+        // Or when the sequence finishes without matching it.
+        push(&mut seq, 13, b"</");
+        assert_eq!(delta(&mut seq), None);
+        let reason = push(&mut seq, EOS, b"");
+        assert_eq!(reason, Some(StopReason::Eos));
+        assert_eq!(delta(&mut seq).as_deref(), Some("</"));
+        assert_eq!(seq.matched_stop_sequence(&reason.unwrap()), None);
+    }
+
+    #[test]
+    fn test_multi_byte_stop_string() {
+        let e_acute = "é".as_bytes();
+        let mut seq = seq_with_stop_strings(&["éx"]);
+        push(&mut seq, 10, b" caf");
+        assert_eq!(delta(&mut seq).as_deref(), Some("caf"));
+
+        // The first byte of `é` is the start of the stop string, and an incomplete character.
+        push(&mut seq, 11, &e_acute[..1]);
+        assert_eq!(delta(&mut seq), None);
+        push(&mut seq, 12, &e_acute[1..]);
+        assert_eq!(delta(&mut seq), None);
+        push(&mut seq, 13, b"s");
+        assert_eq!(delta(&mut seq).as_deref(), Some("és"));
+
+        // A stop string cut right after a multi-byte character.
+        push(&mut seq, 14, "ééx".as_bytes());
+        assert_eq!(delta(&mut seq).as_deref(), Some("é"));
+        let reason = push(&mut seq, 15, b"!").unwrap();
+        assert_eq!(
+            reason,
+            StopReason::StopString {
+                stop_string_idx: 0,
+                completion_bytes_pos: " cafés".len() + "é".len(),
+            }
+        );
+        assert_eq!(delta(&mut seq).as_deref(), Some(""));
+    }
+
+    #[test]
+    fn test_incomplete_character_is_not_sent() {
+        let e_acute = "é".as_bytes();
+        let mut seq = seq_with_stop_strings(&[]);
+        push(&mut seq, 10, b" caf");
+        assert_eq!(delta(&mut seq).as_deref(), Some("caf"));
+        push(&mut seq, 11, &e_acute[..1]);
+        assert_eq!(delta(&mut seq), None);
+        push(&mut seq, 12, &e_acute[1..]);
+        assert_eq!(delta(&mut seq).as_deref(), Some("é"));
+    }
+
+    #[test]
+    fn test_earliest_stop_string_match() {
+        let mut seq = seq_with_stop_strings(&["world", "lo w"]);
+        push(&mut seq, 10, b" hello world");
+        let reason = push(&mut seq, 11, b"!").unwrap();
+        // Both stop strings match, the output is cut at the one which starts first.
+        assert_eq!(
+            reason,
+            StopReason::StopString {
+                stop_string_idx: 1,
+                completion_bytes_pos: 4,
+            }
+        );
+        assert_eq!(seq.matched_stop_sequence(&reason).as_deref(), Some("lo w"));
+        assert_eq!(delta(&mut seq).as_deref(), Some("hel"));
+    }
+}
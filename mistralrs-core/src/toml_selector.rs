@@ -1,19 +1,27 @@
-use std::{fs::File, num::NonZeroUsize, path::PathBuf};
+use std::{
+    fs::{self, File},
+    num::NonZeroUsize,
+    path::PathBuf,
+};
 
 use serde::Deserialize;
 
 use crate::{
-    amoe::AnyMoeConfig, pipeline::IsqOrganization, AnyMoeLoader, GGMLLoaderBuilder,
-    GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, Loader, ModelDType,
-    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, SpeculativeConfig,
-    SpeculativeLoader, Topology, VisionLoaderBuilder, VisionLoaderType, VisionSpecificConfig,
-    GGUF_MULTI_FILE_DELIMITER,
+    amoe::AnyMoeConfig, pipeline::IsqOrganization, AdaptiveGamma, AnyMoeLoader, GGMLLoaderBuilder,
+    GGMLSpecificConfig, GGUFLoaderBuilder, GGUFSpecificConfig, KvCacheType, Loader, ModelDType,
+    NormalLoaderBuilder, NormalLoaderType, NormalSpecificConfig, PromptLookupConfig,
+    PromptLookupLoader, SpeculativeConfig, SpeculativeLoader, Topology, VisionLoaderBuilder,
+    VisionLoaderType, VisionSpecificConfig, GGUF_MULTI_FILE_DELIMITER,
 };
 
 fn default_one() -> usize {
     1
 }
 
+fn default_prompt_lookup_max_ngram() -> usize {
+    3
+}
+
 fn default_dtype() -> ModelDType {
     ModelDType::Auto
 }
@@ -22,7 +30,7 @@ fn default_empty_vec_usize() -> Vec<usize> {
     Vec::new()
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum TomlModelSelected {
     /// Select a plain model, without quantization or adapters
@@ -284,16 +292,42 @@ pub enum TomlModelSelected {
     },
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct SpeculativeTomlModelSelected {
     /// Gamma value for the model
     gamma: usize,
 
+    /// Adapt gamma to the acceptance rate, down to this value (default 1)
+    min_gamma: Option<usize>,
+
+    /// Adapt gamma to the acceptance rate, up to this value (default `gamma`)
+    max_gamma: Option<usize>,
+
     /// Base model
     draft_model: TomlModelSelected,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
+pub struct PromptLookupTomlSelected {
+    /// Maximum number of draft tokens
+    gamma: usize,
+
+    /// Adapt gamma to the acceptance rate, down to this value (default 1)
+    min_gamma: Option<usize>,
+
+    /// Adapt gamma to the acceptance rate, up to this value (default `gamma`)
+    max_gamma: Option<usize>,
+
+    /// Smallest n-gram to look up
+    #[serde(default = "default_one")]
+    min_ngram: usize,
+
+    /// Largest n-gram to look up
+    #[serde(default = "default_prompt_lookup_max_ngram")]
+    max_ngram: usize,
+}
+
+#[derive(Clone, Deserialize)]
 pub struct AnyMoeTomlModelSelected {
     /// Config
     config: AnyMoeConfig,
@@ -315,7 +349,7 @@ pub struct AnyMoeTomlModelSelected {
     layers: Vec<usize>,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct TomlSelector {
     /// Path to local tokenizer.json file. If this is specified it is used over any remote file.
     tokenizer_json: Option<String>,
@@ -326,8 +360,71 @@ pub struct TomlSelector {
     /// Speculative model selector
     speculative: Option<SpeculativeTomlModelSelected>,
 
+    /// Speculative decoding by prompt lookup, without a draft model
+    prompt_lookup: Option<PromptLookupTomlSelected>,
+
     /// AnyMoE config
     anymoe: Option<AnyMoeTomlModelSelected>,
+
+    /// KV cache type: `auto`, `q8_0` or `f8e4m3`. This takes precedence over the type given to the loader.
+    kv_cache_type: Option<KvCacheType>,
+}
+
+/// One model served by a multi-model server. The model is selected in the same way as for a single
+/// model, with the `TomlSelector` fields flattened into the entry.
+#[derive(Clone, Deserialize)]
+pub struct TomlServedModel {
+    /// Name of the model, matched against the `model` field of requests.
+    pub name: String,
+
+    /// Only load the model when it is first requested.
+    #[serde(default)]
+    pub lazy: bool,
+
+    /// Unload the model after it has not been used for this many seconds. It is loaded again on the next request.
+    pub idle_timeout_secs: Option<u64>,
+
+    /// Model selector
+    #[serde(flatten)]
+    pub selector: TomlSelector,
+}
+
+impl TomlServedModel {
+    pub fn get_dtype(&self) -> ModelDType {
+        get_toml_selected_model_dtype(&self.selector)
+    }
+
+    pub fn get_tgt_non_granular_index(&self) -> Option<usize> {
+        get_toml_tgt_non_granular_index(&self.selector)
+    }
+
+    pub fn get_loader(&self, args: TomlLoaderArgs) -> anyhow::Result<Box<dyn Loader>> {
+        (self.selector.clone(), args).try_into()
+    }
+}
+
+/// A set of models to serve from a single server, listed as `[[models]]`.
+#[derive(Clone, Deserialize)]
+pub struct TomlMultiModelSelector {
+    pub models: Vec<TomlServedModel>,
+}
+
+impl TomlMultiModelSelector {
+    pub fn from_file(file: &str) -> anyhow::Result<Self> {
+        let selector: Self = toml::from_str(
+            &fs::read_to_string(file)
+                .map_err(|e| anyhow::anyhow!("Could not load multi-model file at {file}: {e}"))?,
+        )?;
+        if selector.models.is_empty() {
+            anyhow::bail!("Expected at least one model in {file}.");
+        }
+        for (i, model) in selector.models.iter().enumerate() {
+            if selector.models[..i].iter().any(|m| m.name == model.name) {
+                anyhow::bail!("Duplicate model name `{}` in {file}.", model.name);
+            }
+        }
+        Ok(selector)
+    }
 }
 
 #[derive(Clone)]
@@ -337,6 +434,7 @@ struct TomlLoaderInnerParams {
     no_kv_cache: bool,
     tokenizer_json: Option<String>,
     prompt_batchsize: Option<NonZeroUsize>,
+    kv_cache_type: KvCacheType,
 }
 
 pub struct TomlLoaderArgs {
@@ -344,6 +442,32 @@ pub struct TomlLoaderArgs {
     pub chat_template: Option<String>,
     pub no_kv_cache: bool,
     pub prompt_batchsize: Option<NonZeroUsize>,
+    pub kv_cache_type: KvCacheType,
+}
+
+/// The `tgt_non_granular_index` of an X-LoRA model, which limits the running sequences to 1.
+pub fn get_toml_tgt_non_granular_index(model: &TomlSelector) -> Option<usize> {
+    match model.model {
+        TomlModelSelected::XLora {
+            tgt_non_granular_index,
+            ..
+        }
+        | TomlModelSelected::XLoraGGUF {
+            tgt_non_granular_index,
+            ..
+        }
+        | TomlModelSelected::XLoraGGML {
+            tgt_non_granular_index,
+            ..
+        } => tgt_non_granular_index,
+        TomlModelSelected::Plain { .. }
+        | TomlModelSelected::Lora { .. }
+        | TomlModelSelected::GGUF { .. }
+        | TomlModelSelected::LoraGGUF { .. }
+        | TomlModelSelected::GGML { .. }
+        | TomlModelSelected::LoraGGML { .. }
+        | TomlModelSelected::VisionPlain { .. } => None,
+    }
 }
 
 pub fn get_toml_selected_model_dtype(model: &TomlSelector) -> ModelDType {
@@ -379,6 +503,7 @@ fn loader_from_selected(
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
                 organization: organization.unwrap_or_default(),
                 write_uqff,
@@ -403,6 +528,7 @@ fn loader_from_selected(
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
                 write_uqff,
@@ -435,6 +561,7 @@ fn loader_from_selected(
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
                 write_uqff,
@@ -467,6 +594,7 @@ fn loader_from_selected(
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -489,6 +617,7 @@ fn loader_from_selected(
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -519,6 +648,7 @@ fn loader_from_selected(
                 .collect::<Vec<_>>(),
             GGUFSpecificConfig {
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -540,6 +670,7 @@ fn loader_from_selected(
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -562,6 +693,7 @@ fn loader_from_selected(
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -592,6 +724,7 @@ fn loader_from_selected(
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
             args.chat_template,
@@ -619,6 +752,7 @@ fn loader_from_selected(
             VisionSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize: args.prompt_batchsize,
+                kv_cache_type: args.kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
                 write_uqff,
                 from_uqff,
@@ -632,6 +766,21 @@ fn loader_from_selected(
     Ok(loader)
 }
 
+/// Gamma is adapted if either bound is specified.
+fn adaptive_gamma(
+    gamma: usize,
+    min_gamma: Option<usize>,
+    max_gamma: Option<usize>,
+) -> Option<AdaptiveGamma> {
+    if min_gamma.is_none() && max_gamma.is_none() {
+        return None;
+    }
+    Some(AdaptiveGamma {
+        min_gamma: min_gamma.unwrap_or(1),
+        max_gamma: max_gamma.unwrap_or(gamma),
+    })
+}
+
 impl TryInto<Box<dyn Loader>> for (TomlSelector, TomlLoaderArgs) {
     type Error = anyhow::Error;
     fn try_into(self) -> Result<Box<dyn Loader>, Self::Error> {
@@ -642,8 +791,12 @@ impl TryInto<Box<dyn Loader>> for (TomlSelector, TomlLoaderArgs) {
             no_kv_cache: args.no_kv_cache,
             tokenizer_json: selector.tokenizer_json,
             prompt_batchsize: args.prompt_batchsize,
+            kv_cache_type: selector.kv_cache_type.unwrap_or(args.kv_cache_type),
         };
         let loader = loader_from_selected(args.clone(), selector.model)?;
+        if selector.speculative.is_some() && selector.prompt_lookup.is_some() {
+            anyhow::bail!("Only one of `speculative` and `prompt_lookup` may be specified.");
+        }
         let loader = if let Some(speculative) = selector.speculative {
             let draft_loader = loader_from_selected(args, speculative.draft_model)?;
             Box::new(SpeculativeLoader {
@@ -651,6 +804,25 @@ impl TryInto<Box<dyn Loader>> for (TomlSelector, TomlLoaderArgs) {
                 draft: draft_loader,
                 config: SpeculativeConfig {
                     gamma: speculative.gamma,
+                    adaptive_gamma: adaptive_gamma(
+                        speculative.gamma,
+                        speculative.min_gamma,
+                        speculative.max_gamma,
+                    ),
+                },
+            })
+        } else if let Some(prompt_lookup) = selector.prompt_lookup {
+            Box::new(PromptLookupLoader {
+                target: loader,
+                config: PromptLookupConfig {
+                    gamma: prompt_lookup.gamma,
+                    adaptive_gamma: adaptive_gamma(
+                        prompt_lookup.gamma,
+                        prompt_lookup.min_gamma,
+                        prompt_lookup.max_gamma,
+                    ),
+                    min_ngram: prompt_lookup.min_ngram,
+                    max_ngram: prompt_lookup.max_ngram,
                 },
             })
         } else {
@@ -1,16 +1,75 @@
 mod request;
 mod response;
+mod stream;
 
 pub use request::*;
 pub use response::*;
 use serde_json::Value;
 use std::collections::HashMap;
+pub(crate) use stream::{ToolCallStreamUpdate, ToolCallStreamer};
 use uuid::Uuid;
 
+use crate::{
+    json_schema::{escape_literal, json_schema_to_regex},
+    Constraint,
+};
+
+/// Whitespace allowed between JSON tokens of a tool call.
+const WHITESPACE: &str = r"[ \t\n]*";
+
 pub struct ToolCallingMatcher {
     tool_choice: ToolChoice,
 }
 
+/// A single call of `tool`, accepting either `arguments` or `parameters` as the key
+/// for the arguments (see [`ToolCallingMatcher::get_call`]).
+fn tool_call_regex(tool: &Tool) -> anyhow::Result<String> {
+    let name = escape_literal(&serde_json::to_string(&tool.function.name)?);
+    let arguments = match &tool.function.parameters {
+        Some(parameters) => json_schema_to_regex(&serde_json::to_value(parameters)?.into())?,
+        None => format!(r"\{{{WHITESPACE}\}}"),
+    };
+    Ok(format!(
+        r#"\{{{WHITESPACE}"name"{WHITESPACE}:{WHITESPACE}{name}{WHITESPACE},{WHITESPACE}"(arguments|parameters)"{WHITESPACE}:{WHITESPACE}({arguments}){WHITESPACE}\}}"#
+    ))
+}
+
+/// Regex for the text which does not start with `prefix`, including the proper prefixes of it.
+/// Each byte of `prefix` may be preceded by whitespace if its flag is set.
+fn not_starting_with(prefix: &[(bool, u8)]) -> String {
+    prefix
+        .iter()
+        .rev()
+        .fold(None, |rest, (whitespace, byte)| {
+            let literal = format!(r"\x{byte:02X}");
+            let excluded = if *whitespace {
+                format!(r"{literal}\x20\x09\x0A")
+            } else {
+                literal.clone()
+            };
+            let diverged = format!(r"[^{excluded}][\x00-\xFF]*");
+            let next = match rest {
+                Some(rest) => format!("({diverged}|{literal}{rest})?"),
+                None => format!("({diverged})?"),
+            };
+            Some(if *whitespace {
+                format!("{WHITESPACE}{next}")
+            } else {
+                next
+            })
+        })
+        .unwrap_or_default()
+}
+
+/// Regex for plain text which is not the start of a tool call. Text starting with `{"name"` or
+/// `[{"name"` is committed to be a tool call, other text starting with `{` or `[` is allowed.
+fn plain_text_regex() -> String {
+    let name = br#""name""#.iter().enumerate().map(|(i, byte)| (i == 0, *byte)).collect::<Vec<_>>();
+    let object = not_starting_with(&name);
+    let array = not_starting_with(&[[(true, b'{')].as_slice(), &name].concat());
+    format!(r"([^\x7B\x5B][\x00-\xFF]*|\x7B{object}|\x5B{array})?")
+}
+
 // Same as CalledFunction, but uses `parameters`
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct CalledFunctionParameters {
@@ -30,6 +89,40 @@ impl ToolCallingMatcher {
         Ok(Self { tool_choice })
     }
 
+    /// Create a streamer which detects tool calls in streamed output, unless tools are disabled.
+    pub(crate) fn streamer(&self) -> Option<ToolCallStreamer> {
+        match self.tool_choice {
+            ToolChoice::None => None,
+            ToolChoice::Auto | ToolChoice::Tool(_) => Some(ToolCallStreamer::default()),
+        }
+    }
+
+    /// Build a constraint which forces the output to be a well-formed call to one of the `tools`,
+    /// with arguments matching the function's `parameters` schema.
+    ///
+    /// - `ToolChoice::Tool` requires exactly one call to that tool.
+    /// - `ToolChoice::Auto` allows one call or an array of calls to any of the `tools`, or plain
+    ///   text. Once the output starts like a call, it must be a call.
+    pub fn constraint(&self, tools: &[Tool]) -> anyhow::Result<Constraint> {
+        match &self.tool_choice {
+            ToolChoice::None => Ok(Constraint::None),
+            ToolChoice::Tool(tool) => Ok(Constraint::Regex(tool_call_regex(tool)?)),
+            ToolChoice::Auto if tools.is_empty() => Ok(Constraint::None),
+            ToolChoice::Auto => {
+                let calls = tools
+                    .iter()
+                    .map(|tool| Ok(format!("({})", tool_call_regex(tool)?)))
+                    .collect::<anyhow::Result<Vec<_>>>()?
+                    .join("|");
+                let sep = format!("{WHITESPACE},{WHITESPACE}");
+                let text = plain_text_regex();
+                Ok(Constraint::Regex(format!(
+                    r"(({calls})|(\[{WHITESPACE}({calls})({sep}({calls}))*{WHITESPACE}\])|{text})"
+                )))
+            }
+        }
+    }
+
     pub fn get_call(&self, message: &str) -> anyhow::Result<Vec<ToolCallResponse>> {
         if matches!(self.tool_choice, ToolChoice::None) {
             return Ok(Vec::new());
@@ -93,3 +186,76 @@ impl ToolCallingMatcher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use regex::bytes::Regex;
+    use serde_json::json;
+
+    use super::{Function, Tool, ToolCallingMatcher, ToolChoice, ToolType};
+    use crate::Constraint;
+
+    fn tool(name: &str) -> Tool {
+        Tool {
+            tp: ToolType::Function,
+            function: Function {
+                description: None,
+                name: name.to_string(),
+                parameters: Some(
+                    serde_json::from_value(json!({
+                        "type": "object",
+                        "properties": {"location": {"type": "string"}},
+                        "required": ["location"]
+                    }))
+                    .unwrap(),
+                ),
+            },
+        }
+    }
+
+    #[test]
+    fn test_auto_tool_call_constraint() {
+        let tools = vec![tool("get_weather"), tool("get_time")];
+        let matcher = ToolCallingMatcher::new(ToolChoice::Auto).unwrap();
+        let Constraint::Regex(rx) = matcher.constraint(&tools).unwrap() else {
+            panic!("Expected a regex constraint.");
+        };
+        let rx = Regex::new(&format!("^(?-u:{rx})$")).unwrap();
+
+        let call = r#"{"name": "get_weather", "arguments": {"location": "Paris"}}"#;
+        assert!(rx.is_match(call.as_bytes()));
+        assert_eq!(
+            matcher.get_call(call).unwrap()[0].function.name,
+            "get_weather"
+        );
+        assert!(rx.is_match(br#"[{"name": "get_time", "parameters": {"location": "Paris"}}]"#));
+        assert!(rx.is_match(b"It is sunny."));
+        assert!(rx.is_match(b""));
+        assert!(!rx.is_match(br#"{"name": "get_stock", "arguments": {"location": "Paris"}}"#));
+        assert!(!rx.is_match(br#"{"name": "get_weather", "arguments": {}}"#));
+    }
+
+    #[test]
+    fn test_auto_plain_text_starting_like_json() {
+        let tools = vec![tool("get_weather")];
+        let matcher = ToolCallingMatcher::new(ToolChoice::Auto).unwrap();
+        let Constraint::Regex(rx) = matcher.constraint(&tools).unwrap() else {
+            panic!("Expected a regex constraint.");
+        };
+        let rx = Regex::new(&format!("^(?-u:{rx})$")).unwrap();
+
+        // Text starting with `[` or `{` which is not a call.
+        assert!(rx.is_match(b"[1] It is sunny."));
+        assert!(rx.is_match(b"[ ]"));
+        assert!(rx.is_match(b"{}"));
+        assert!(rx.is_match(br#"{"location": "Paris"}"#));
+        assert!(rx.is_match(br#"[{"location": "Paris"}]"#));
+        assert!(rx.is_match(br#"{"nam"#));
+        assert!(rx.is_match(b"{ \n"));
+        // Once the output starts like a call, it must be a valid call.
+        assert!(!rx.is_match(br#"{"name": "get_stock"}"#));
+        assert!(!rx.is_match(br#"{ "name" is a key"#));
+        assert!(!rx.is_match(br#"[ {"name": "get_weather", "arguments": {}}]"#));
+        assert!(rx.is_match(br#"[ {"name": "get_weather", "arguments": {"location": "Paris"}}]"#));
+    }
+}
@@ -23,3 +23,29 @@ pub struct ToolCallResponse {
     pub tp: ToolCallType,
     pub function: CalledFunction,
 }
+
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Clone, Debug, serde::Serialize)]
+/// Incremental part of a called function for a streaming response.
+pub struct CalledFunctionDelta {
+    /// Only present in the first delta of a tool call.
+    pub name: Option<String>,
+    /// Continuation of the JSON arguments.
+    pub arguments: String,
+}
+
+#[cfg_attr(feature = "pyo3_macros", pyo3::pyclass)]
+#[cfg_attr(feature = "pyo3_macros", pyo3(get_all))]
+#[derive(Clone, Debug, serde::Serialize)]
+/// Incremental part of a tool call for a streaming response.
+pub struct ToolCallDelta {
+    /// Index of the tool call this delta belongs to.
+    pub index: usize,
+    /// Only present in the first delta of a tool call.
+    pub id: Option<String>,
+    /// Only present in the first delta of a tool call.
+    #[serde(rename = "type")]
+    pub tp: Option<ToolCallType>,
+    pub function: CalledFunctionDelta,
+}
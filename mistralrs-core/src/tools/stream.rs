@@ -0,0 +1,360 @@
+use uuid::Uuid;
+
+use super::{CalledFunctionDelta, ToolCallDelta, ToolCallType};
+
+/// What to stream for the current output of a sequence which may call tools.
+pub(crate) enum ToolCallStreamUpdate {
+    /// The output is not a tool call, stream the content as usual.
+    Content,
+    /// The output is a tool call, stream these deltas instead of the content.
+    ToolCalls(Vec<ToolCallDelta>),
+    /// The output may still become a tool call, hold back the content.
+    Hold,
+    /// The output looked like a tool call but is not one, or finished without naming a tool. The
+    /// held back content should be streamed.
+    Flush,
+}
+
+/// Incrementally detects tool calls in the output of a sequence so that they can be streamed as
+/// `tool_calls` deltas.
+///
+/// A tool call is recognized once the output starts with `{"name"` or `[{"name"`, which commits it
+/// to be a call under [`ToolCallingMatcher::constraint`](super::ToolCallingMatcher::constraint),
+/// and is parsed in the same formats as [`ToolCallingMatcher::get_call`](super::ToolCallingMatcher::get_call).
+/// The output is only held back while it may still start like that. The arguments are streamed as
+/// raw JSON text, so the concatenated deltas form the complete arguments.
+#[derive(Default)]
+pub(crate) struct ToolCallStreamer {
+    is_tool_call: Option<bool>,
+    /// For each call which has been started: its id and the length of the arguments sent so far.
+    calls: Vec<(String, usize)>,
+}
+
+impl ToolCallStreamer {
+    /// Update with the complete output so far.
+    pub(crate) fn update(&mut self, text: &str, is_done: bool) -> ToolCallStreamUpdate {
+        let is_tool_call = match self.is_tool_call {
+            Some(is_tool_call) => is_tool_call,
+            None => match match_call_prefix(text) {
+                CallPrefix::Partial if !is_done => return ToolCallStreamUpdate::Hold,
+                CallPrefix::Partial | CallPrefix::Diverged => {
+                    self.is_tool_call = Some(false);
+                    return ToolCallStreamUpdate::Flush;
+                }
+                CallPrefix::Complete => *self.is_tool_call.insert(true),
+            },
+        };
+        if !is_tool_call {
+            return ToolCallStreamUpdate::Content;
+        }
+
+        let mut deltas = Vec::new();
+        for (index, call) in parse_partial_calls(text).into_iter().enumerate() {
+            let Some(name) = call.name else {
+                break;
+            };
+            let arguments = call.arguments.unwrap_or_default();
+            if let Some((_, sent)) = self.calls.get_mut(index) {
+                if arguments.len() > *sent {
+                    deltas.push(ToolCallDelta {
+                        index,
+                        id: None,
+                        tp: None,
+                        function: CalledFunctionDelta {
+                            name: None,
+                            arguments: arguments[*sent..].to_string(),
+                        },
+                    });
+                    *sent = arguments.len();
+                }
+            } else {
+                let id = format!("call-{}", Uuid::new_v4());
+                deltas.push(ToolCallDelta {
+                    index,
+                    id: Some(id.clone()),
+                    tp: Some(ToolCallType::Function),
+                    function: CalledFunctionDelta {
+                        name: Some(name),
+                        arguments: arguments.to_string(),
+                    },
+                });
+                self.calls.push((id, arguments.len()));
+            }
+        }
+
+        if is_done && self.calls.is_empty() {
+            ToolCallStreamUpdate::Flush
+        } else {
+            ToolCallStreamUpdate::ToolCalls(deltas)
+        }
+    }
+
+    /// Whether any tool calls have been streamed.
+    pub(crate) fn has_calls(&self) -> bool {
+        !self.calls.is_empty()
+    }
+}
+
+/// How the output compares to the start of a tool call.
+enum CallPrefix {
+    /// The output is a proper prefix of the start of a tool call.
+    Partial,
+    /// The output starts like a tool call.
+    Complete,
+    /// The output cannot be a tool call.
+    Diverged,
+}
+
+/// Match the output against `{"name"` or `[{"name"`, with whitespace allowed between the tokens.
+fn match_call_prefix(text: &str) -> CallPrefix {
+    let bytes = text.as_bytes();
+    let tokens: &[&[u8]] = if bytes.get(skip_whitespace(bytes, 0)) == Some(&b'[') {
+        &[b"[", b"{", br#""name""#]
+    } else {
+        &[b"{", br#""name""#]
+    };
+    let mut pos = 0;
+    for token in tokens {
+        pos = skip_whitespace(bytes, pos);
+        let rest = &bytes[pos..];
+        if rest.len() < token.len() {
+            return if token.starts_with(rest) {
+                CallPrefix::Partial
+            } else {
+                CallPrefix::Diverged
+            };
+        }
+        if !rest.starts_with(token) {
+            return CallPrefix::Diverged;
+        }
+        pos += token.len();
+    }
+    CallPrefix::Complete
+}
+
+#[derive(Default)]
+struct PartialCall<'a> {
+    name: Option<String>,
+    /// Raw JSON text of the arguments, which may be incomplete.
+    arguments: Option<&'a str>,
+}
+
+/// Parse the possibly incomplete tool calls from a single call object or an array of them.
+fn parse_partial_calls(text: &str) -> Vec<PartialCall<'_>> {
+    let bytes = text.as_bytes();
+    let mut calls = Vec::new();
+    let mut pos = skip_whitespace(bytes, 0);
+    let is_array = bytes.get(pos) == Some(&b'[');
+    if is_array {
+        pos += 1;
+    }
+    loop {
+        pos = skip_whitespace(bytes, pos);
+        if bytes.get(pos) != Some(&b'{') {
+            break;
+        }
+        let (call, end) = parse_partial_call(text, pos + 1);
+        calls.push(call);
+        let Some(end) = end else {
+            break;
+        };
+        pos = skip_whitespace(bytes, end);
+        if !is_array || bytes.get(pos) != Some(&b',') {
+            break;
+        }
+        pos += 1;
+    }
+    calls
+}
+
+/// Parse the members of a call object starting after its `{`. Returns the end of the object if it
+/// is complete.
+fn parse_partial_call(text: &str, mut pos: usize) -> (PartialCall<'_>, Option<usize>) {
+    let bytes = text.as_bytes();
+    let mut call = PartialCall::default();
+    loop {
+        pos = skip_whitespace(bytes, pos);
+        match bytes.get(pos) {
+            Some(b'}') => return (call, Some(pos + 1)),
+            Some(b',') => {
+                pos += 1;
+                continue;
+            }
+            Some(b'"') => (),
+            _ => return (call, None),
+        }
+        let Some(key_end) = scan_string(bytes, pos) else {
+            return (call, None);
+        };
+        let key = serde_json::from_str::<String>(&text[pos..key_end]).unwrap_or_default();
+        pos = skip_whitespace(bytes, key_end);
+        if bytes.get(pos) != Some(&b':') {
+            return (call, None);
+        }
+        pos = skip_whitespace(bytes, pos + 1);
+        if pos >= bytes.len() {
+            return (call, None);
+        }
+        let end = scan_value(bytes, pos);
+        match key.as_str() {
+            "name" => {
+                if let Some(end) = end {
+                    call.name = serde_json::from_str(&text[pos..end]).ok();
+                }
+            }
+            "arguments" | "parameters" => {
+                call.arguments = Some(&text[pos..end.unwrap_or(bytes.len())]);
+            }
+            _ => (),
+        }
+        match end {
+            Some(end) => pos = end,
+            None => return (call, None),
+        }
+    }
+}
+
+fn skip_whitespace(bytes: &[u8], mut pos: usize) -> usize {
+    while bytes.get(pos).is_some_and(|b| b.is_ascii_whitespace()) {
+        pos += 1;
+    }
+    pos
+}
+
+/// Returns the end of the string starting at `pos`, if it is complete.
+fn scan_string(bytes: &[u8], mut pos: usize) -> Option<usize> {
+    pos += 1;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'\\' => pos += 2,
+            b'"' => return Some(pos + 1),
+            _ => pos += 1,
+        }
+    }
+    None
+}
+
+/// Returns the end of the value starting at `pos`, if it is complete.
+fn scan_value(bytes: &[u8], mut pos: usize) -> Option<usize> {
+    match bytes[pos] {
+        b'"' => scan_string(bytes, pos),
+        b'{' | b'[' => {
+            let mut depth = 0usize;
+            while pos < bytes.len() {
+                match bytes[pos] {
+                    b'"' => {
+                        pos = scan_string(bytes, pos)?;
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(pos + 1);
+                        }
+                    }
+                    _ => (),
+                }
+                pos += 1;
+            }
+            None
+        }
+        _ => {
+            // A scalar is only known to be complete once something follows it.
+            let len = bytes[pos..]
+                .iter()
+                .position(|b| matches!(b, b',' | b'}' | b']') || b.is_ascii_whitespace())?;
+            Some(pos + len)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ToolCallStreamUpdate, ToolCallStreamer};
+
+    #[test]
+    fn test_streamed_arguments_concatenate() {
+        let output = r#"[{"name": "get_weather", "arguments": {"location": "Paris, \"FR\""}}, {"name": "get_time", "arguments": {}}]"#;
+        let mut streamer = ToolCallStreamer::default();
+        let mut calls: Vec<(String, String)> = Vec::new();
+        for end in 1..=output.len() {
+            let deltas = match streamer.update(&output[..end], end == output.len()) {
+                ToolCallStreamUpdate::ToolCalls(deltas) => deltas,
+                // Held back until `[{"name"`.
+                ToolCallStreamUpdate::Hold if end < 8 => continue,
+                _ => panic!("Expected tool call deltas."),
+            };
+            for delta in deltas {
+                if let Some(name) = delta.function.name {
+                    assert_eq!(delta.index, calls.len());
+                    calls.push((name, delta.function.arguments));
+                } else {
+                    calls[delta.index].1.push_str(&delta.function.arguments);
+                }
+            }
+        }
+        assert_eq!(
+            calls,
+            vec![
+                (
+                    "get_weather".to_string(),
+                    r#"{"location": "Paris, \"FR\""}"#.to_string()
+                ),
+                ("get_time".to_string(), "{}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_content_is_not_tool_call() {
+        let mut streamer = ToolCallStreamer::default();
+        assert!(matches!(
+            streamer.update(" Hello", false),
+            ToolCallStreamUpdate::Flush
+        ));
+        assert!(matches!(
+            streamer.update(" Hello there", false),
+            ToolCallStreamUpdate::Content
+        ));
+        let mut streamer = ToolCallStreamer::default();
+        assert!(matches!(
+            streamer.update("{", true),
+            ToolCallStreamUpdate::Flush
+        ));
+    }
+
+    #[test]
+    fn test_content_is_flushed_once_it_cannot_be_tool_call() {
+        let mut streamer = ToolCallStreamer::default();
+        for text in ["{", "{\n \"na"] {
+            assert!(matches!(
+                streamer.update(text, false),
+                ToolCallStreamUpdate::Hold
+            ));
+        }
+        assert!(matches!(
+            streamer.update("{\n \"nam\"", false),
+            ToolCallStreamUpdate::Flush
+        ));
+        assert!(matches!(
+            streamer.update("{\n \"nam\": 1", false),
+            ToolCallStreamUpdate::Content
+        ));
+
+        let mut streamer = ToolCallStreamer::default();
+        assert!(matches!(
+            streamer.update("[1, 2]", false),
+            ToolCallStreamUpdate::Flush
+        ));
+        let mut streamer = ToolCallStreamer::default();
+        assert!(matches!(
+            streamer.update("[ {", false),
+            ToolCallStreamUpdate::Hold
+        ));
+        assert!(matches!(
+            streamer.update(r#"[ {"name""#, false),
+            ToolCallStreamUpdate::ToolCalls(_)
+        ));
+    }
+}
@@ -110,6 +110,7 @@ macro_rules! handle_pipeline_forward_error {
                     if seq.get_mut_group().is_chat {
                         let choice = Choice {
                             finish_reason: "error".to_string(),
+                            stop_sequence: None,
                             index: seq.get_response_index(),
                             message: ResponseMessage {
                                 content: Some(res),
@@ -122,6 +123,7 @@ macro_rules! handle_pipeline_forward_error {
                     } else {
                         let choice = CompletionChoice {
                             finish_reason: "error".to_string(),
+                            stop_sequence: None,
                             index: seq.get_response_index(),
                             text: res,
                             logprobs: None,
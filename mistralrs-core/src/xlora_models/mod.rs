@@ -28,7 +28,10 @@ pub(crate) use quantized_phi3::ModelWeights as XLoraQPhi3;
 pub(crate) use starcoder2::Model as XLoraStarcoder2;
 use tokio::sync::Mutex;
 
-use crate::{get_mut_arcmutex, pipeline::Cache};
+use crate::{
+    get_mut_arcmutex,
+    pipeline::{Cache, KvCache},
+};
 
 use self::classifier::XLoraClassifier;
 
@@ -105,10 +108,8 @@ trait ScalingsMaker {
 
             let mut new_cache = Vec::new();
             for _ in 0..self.get_cache().xlora_lock().len() {
-                new_cache.push(Some((
-                    Tensor::zeros((1,), DType::U8, &Device::Cpu)?,
-                    Tensor::zeros((1,), DType::U8, &Device::Cpu)?,
-                )));
+                let dummy = Tensor::zeros((1, 1, 1, 1), DType::U8, &Device::Cpu)?;
+                new_cache.push(Some(KvCache::new(&dummy, &dummy)?));
             }
             self.get_cache().lock().clone_from(&new_cache);
 
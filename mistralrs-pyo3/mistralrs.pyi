@@ -7,6 +7,16 @@ class ToolChoice(Enum):
     NoTools = "None"
     Auto = "Auto"
 
+@dataclass
+class SamplerStep(Enum):
+    Temperature = "temperature"
+    TopK = "top_k"
+    TopP = "top_p"
+    MinP = "min_p"
+    TypicalP = "typical_p"
+    TopA = "top_a"
+    TailFree = "tail_free"
+
 @dataclass
 class ChatCompletionRequest:
     """
@@ -39,6 +49,16 @@ class ChatCompletionRequest:
     min_p: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    seed: int | None = None
+    repetition_penalty: float | None = None
+    repeat_last_n: int | None = None
+    typical_p: float | None = None
+    top_a: float | None = None
+    tfs_z: float | None = None
+    mirostat: int | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    sampler_order: list[SamplerStep] | None = None
 
 @dataclass
 class CompletionRequest:
@@ -67,6 +87,17 @@ class CompletionRequest:
     min_p: float | None = None
     tool_schemas: list[str] | None = None
     tool_choice: ToolChoice | None = None
+    seed: int | None = None
+    repetition_penalty: float | None = None
+    repeat_last_n: int | None = None
+    typical_p: float | None = None
+    top_a: float | None = None
+    tfs_z: float | None = None
+    mirostat: int | None = None
+    mirostat_tau: float | None = None
+    mirostat_eta: float | None = None
+    sampler_order: list[SamplerStep] | None = None
+    logprobs: int | None = None
 
 @dataclass
 class Architecture(Enum):
@@ -79,6 +110,8 @@ class Architecture(Enum):
     Gemma2 = "gemma2"
     Starcoder2 = "starcoder2"
     Phi3_5MoE = "phi3.5moe"
+    Mamba = "mamba"
+    DeepSeekV2 = "deepseekv2"
 
 @dataclass
 class VisionArchitecture(Enum):
@@ -238,15 +271,19 @@ class Runner:
         prefix_cache_n: int = 16,
         token_source: str = "cache",
         speculative_gamma: int = 32,
+        speculative_gamma_bounds: tuple[int, int] | None = None,
         which_draft: Which | None = None,
+        prompt_lookup: bool = False,
         chat_template: str | None = None,
         num_device_layers: list[str] | None = None,
         in_situ_quant: str | None = None,
         anymoe_config: AnyMoeConfig | None = None,
         pa_gpu_mem: int | float | None = None,
         pa_blk_size: int | None = None,
+        pa_preemption_mode: str | None = None,
         no_paged_attn: bool = False,
         prompt_batchsize: int | None = None,
+        kv_cache_type: str | None = None,
         seed: int | None = None,
     ) -> None:
         """
@@ -260,8 +297,12 @@ class Runner:
             The token source follows the following format: "literal:<value>", "env:<value>", "path:<value>", "cache" to use a cached token or "none" to use no token.
         - `speculative_gamma` specifies the `gamma` parameter for specuative decoding, the ratio of draft tokens to generate before calling
             the target model. If `which_draft` is not specified, this is ignored.
+        - `speculative_gamma_bounds` specifies the `(min, max)` bounds for adapting `gamma` to the acceptance rate of each sequence.
+            If this is not specified, `gamma` is fixed.
         - `which_draft` specifies which draft model to load. Setting this parameter will cause a speculative decoding model to be loaded,
             with `which` as the target (higher quality) model and `which_draft` as the draft (lower quality) model.
+        - `prompt_lookup` enables speculative decoding without a draft model: up to `speculative_gamma` draft tokens are copied from
+            the prompt where the last tokens appeared before. This is ignored if `which_draft` is specified.
         - `chat_template` specifies an optional JINJA chat template.
             The JINJA template should have `messages`, `add_generation_prompt`, `bos_token`, `eos_token`, and `unk_token` as inputs.
             It is used if the automatic deserialization fails. If this ends with `.json` (ie., it is a file) then that template is loaded.
@@ -282,8 +323,14 @@ class Runner:
             The priority is as follows: `pa-gpu-mem-usage` (default = 0.9) > `pa-ctxt-len` > `pa-gpu-mem`.
         - `pa_blk_size` sets the block size (number of tokens per block) for PagedAttention. If this is not set and the device is CUDA,
             it will default to 32. PagedAttention is only supported on CUDA and is always automatically activated.
+        - `pa_preemption_mode` sets how a running sequence gives up its KV cache blocks when PagedAttention runs out of GPU memory:
+            "swap" (the default) moves them to the CPU swap space, falling back to recomputation once it is full, and "recompute" frees
+            them and runs the sequence through the model again when it resumes.
         - `no_paged_attn` disables PagedAttention on CUDA
         - `prompt_batchsize` Number of tokens to batch the prompt step into. This can help with OOM errors when in the prompt step, but reduces performance.
+        - `kv_cache_type` sets the type in which the KV cache is stored when not using PagedAttention: "auto" (the model dtype, the default),
+            "q8_0" (8-bit integers with a scale per block of 32 values) or "f8e4m3". A quantized cache is dequantized one layer at a time
+            when attending, which is slower and briefly uses memory for that layer's full precision cache.
         - `seed`, used to ensure reproducible random number generation.
         """
         ...
@@ -312,6 +359,14 @@ class Runner:
         Generate an image.
         """
 
+    def score(
+        self, prompt: str | list[int], top_logprobs: int = 0
+    ) -> ScoringResponse:
+        """
+        Score a text, or its token ids, without generating. This returns the logprob of each token
+        given the ones before it, and the perplexity of the text.
+        """
+
     def send_re_isq(self, dtype: str) -> CompletionResponse:
         """
         Send a request to re-ISQ the model. If the model was loaded as GGUF or GGML then nothing will happen.
@@ -384,6 +439,13 @@ class Usage:
     total_time_sec: float
     total_prompt_time_sec: float
     total_completion_time_sec: float
+    speculative: SpeculativeUsage | None
+
+@dataclass
+class SpeculativeUsage:
+    draft_tokens: int
+    accepted_draft_tokens: int
+    acceptance_rate: float
 
 @dataclass
 class ToolCallType(Enum):
@@ -400,6 +462,18 @@ class ToolCallResponse:
     type: ToolCallType
     function: CalledFunction
 
+@dataclass
+class CalledFunctionDelta:
+    name: str | None
+    arguments: str
+
+@dataclass
+class ToolCallDelta:
+    index: int
+    id: str | None
+    type: ToolCallType | None
+    function: CalledFunctionDelta
+
 @dataclass
 class ResponseMessage:
     content: str
@@ -426,6 +500,7 @@ class Logprobs:
 @dataclass
 class Choice:
     finish_reason: str
+    stop_sequence: str | None
     index: int
     message: ResponseMessage
     logprobs: Logprobs
@@ -444,10 +519,12 @@ class ChatCompletionResponse:
 class Delta:
     content: str
     role: str
+    tool_calls: list[ToolCallDelta] | None
 
 @dataclass
 class ChunkChoice:
     finish_reason: str | None
+    stop_sequence: str | None
     index: int
     delta: Delta
     logprobs: ResponseLogprob | None
@@ -461,12 +538,20 @@ class ChatCompletionChunkResponse:
     system_fingerprint: str
     object: str
 
+@dataclass
+class CompletionLogprobs:
+    tokens: list[str]
+    token_logprobs: list[float | None]
+    top_logprobs: list[dict[str, float] | None]
+    text_offset: list[int]
+
 @dataclass
 class CompletionChoice:
     finish_reason: str
+    stop_sequence: str | None
     index: int
     text: str
-    # NOTE(EricLBuehler): `logprobs` in undocumented
+    logprobs: CompletionLogprobs | None
 
 @dataclass
 class CompletionResponse:
@@ -478,6 +563,21 @@ class CompletionResponse:
     object: str
     usage: Usage
 
+@dataclass
+class ScoredToken:
+    token: int
+    text: str
+    logprob: float | None
+    top_logprobs: list[TopLogprob]
+
+@dataclass
+class ScoringResponse:
+    object: str
+    model: str
+    tokens: list[ScoredToken]
+    total_logprob: float
+    perplexity: float
+
 @dataclass
 class ImageChoice:
     url: str | None
@@ -18,15 +18,17 @@ use util::{PyApiErr, PyApiResult};
 
 use candle_core::{Device, Result};
 use mistralrs_core::{
-    initialize_logging, paged_attn_supported, parse_isq_value, AnyMoeLoader,
+    initialize_logging, paged_attn_supported, parse_isq_value, AdaptiveGamma, AnyMoeLoader,
     ChatCompletionResponse, CompletionResponse, Constraint, DefaultSchedulerMethod,
     DeviceLayerMapMetadata, DeviceMapMetadata, DiffusionGenerationParams, DiffusionLoaderBuilder,
     DiffusionSpecificConfig, DrySamplingParams, GGMLLoaderBuilder, GGMLSpecificConfig,
     GGUFLoaderBuilder, GGUFSpecificConfig, ImageGenerationResponse, ImageGenerationResponseFormat,
-    Loader, MemoryGpuConfig, MistralRs, MistralRsBuilder, NormalLoaderBuilder, NormalRequest,
-    NormalSpecificConfig, PagedAttentionConfig, Request as _Request, RequestMessage, Response,
-    ResponseOk, SamplingParams, SchedulerConfig, SpeculativeConfig, SpeculativeLoader, StopTokens,
-    TokenSource, Tool, Topology, VisionLoaderBuilder, VisionSpecificConfig,
+    KvCacheType, Loader, MemoryGpuConfig, MirostatParams, MistralRs, MistralRsBuilder,
+    NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, PagedAttentionConfig, PreemptionMode,
+    PromptLookupConfig, PromptLookupLoader, Request as _Request, RequestMessage, RequestPriority,
+    Response, ResponseOk, SamplingParams, SchedulerConfig, ScoringResponse, SpeculativeConfig,
+    SpeculativeLoader, StopTokens, TokenSource, Tool, Topology, VisionLoaderBuilder,
+    VisionSpecificConfig,
 };
 use pyo3::prelude::*;
 use std::fs::File;
@@ -74,6 +76,7 @@ fn parse_which(
     no_kv_cache: bool,
     chat_template: Option<String>,
     prompt_batchsize: Option<NonZeroUsize>,
+    kv_cache_type: KvCacheType,
 ) -> PyApiResult<Box<dyn Loader>> {
     #[cfg(not(feature = "flash-attn"))]
     let use_flash_attn = false;
@@ -94,6 +97,7 @@ fn parse_which(
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize,
+                kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
                 organization: organization.map(Into::into).unwrap_or(Default::default()),
                 write_uqff,
@@ -120,6 +124,7 @@ fn parse_which(
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize,
+                kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
                 write_uqff,
@@ -154,6 +159,7 @@ fn parse_which(
             NormalSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize,
+                kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
                 organization: Default::default(),
                 write_uqff,
@@ -185,6 +191,7 @@ fn parse_which(
             quantized_filename.map_left(|f| vec![f]).into_inner(),
             GGUFSpecificConfig {
                 prompt_batchsize,
+                kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -206,6 +213,7 @@ fn parse_which(
             quantized_filename.map_left(|f| vec![f]).into_inner(),
             GGUFSpecificConfig {
                 prompt_batchsize,
+                kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -235,6 +243,7 @@ fn parse_which(
             quantized_filename.map_left(|f| vec![f]).into_inner(),
             GGUFSpecificConfig {
                 prompt_batchsize,
+                kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
         )
@@ -259,6 +268,7 @@ fn parse_which(
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize,
+                kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
             chat_template,
@@ -284,6 +294,7 @@ fn parse_which(
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize,
+                kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
             chat_template,
@@ -317,6 +328,7 @@ fn parse_which(
             GGMLSpecificConfig {
                 gqa,
                 prompt_batchsize,
+                kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
             },
             chat_template,
@@ -346,6 +358,7 @@ fn parse_which(
             VisionSpecificConfig {
                 use_flash_attn,
                 prompt_batchsize,
+                kv_cache_type,
                 topology: Topology::from_option_path(topology)?,
                 write_uqff,
                 from_uqff,
@@ -376,7 +389,9 @@ impl Runner {
         prefix_cache_n = 16,
         token_source = "cache",
         speculative_gamma = 32,
+        speculative_gamma_bounds = None,
         which_draft = None,
+        prompt_lookup = false,
         chat_template = None,
         num_device_layers = None,
         in_situ_quant = None,
@@ -385,8 +400,10 @@ impl Runner {
         pa_gpu_mem_usage = None,
         pa_ctxt_len = None,
         pa_blk_size = None,
+        pa_preemption_mode = None,
         no_paged_attn = false,
         prompt_batchsize = None,
+        kv_cache_type = None,
         seed = None,
     ))]
     fn new(
@@ -396,7 +413,9 @@ impl Runner {
         prefix_cache_n: usize,
         token_source: &str,
         speculative_gamma: usize,
+        speculative_gamma_bounds: Option<(usize, usize)>,
         which_draft: Option<Which>,
+        prompt_lookup: bool,
         chat_template: Option<String>,
         num_device_layers: Option<Vec<String>>,
         in_situ_quant: Option<String>,
@@ -405,8 +424,10 @@ impl Runner {
         pa_gpu_mem_usage: Option<f32>,
         pa_ctxt_len: Option<usize>,
         pa_blk_size: Option<usize>,
+        pa_preemption_mode: Option<String>,
         no_paged_attn: bool,
         prompt_batchsize: Option<usize>,
+        kv_cache_type: Option<String>,
         seed: Option<u64>,
     ) -> PyApiResult<Self> {
         let tgt_non_granular_index = match which {
@@ -460,14 +481,53 @@ impl Runner {
             None => None,
         };
 
-        let loader = parse_which(which, no_kv_cache, chat_template.clone(), prompt_batchsize)?;
+        let kv_cache_type = match kv_cache_type {
+            Some(kv_cache_type) => KvCacheType::from_str(&kv_cache_type).map_err(PyApiErr::from)?,
+            None => KvCacheType::Auto,
+        };
+
+        let pa_preemption_mode = match pa_preemption_mode {
+            Some(pa_preemption_mode) => {
+                PreemptionMode::from_str(&pa_preemption_mode).map_err(PyApiErr::from)?
+            }
+            None => PreemptionMode::default(),
+        };
+
+        let loader = parse_which(
+            which,
+            no_kv_cache,
+            chat_template.clone(),
+            prompt_batchsize,
+            kv_cache_type,
+        )?;
+        let adaptive_gamma = speculative_gamma_bounds.map(|(min_gamma, max_gamma)| AdaptiveGamma {
+            min_gamma,
+            max_gamma,
+        });
         let loader = if let Some(draft_which) = which_draft {
-            let draft = parse_which(draft_which, no_kv_cache, chat_template, prompt_batchsize)?;
+            let draft = parse_which(
+                draft_which,
+                no_kv_cache,
+                chat_template,
+                prompt_batchsize,
+                kv_cache_type,
+            )?;
             Box::new(SpeculativeLoader {
                 target: loader,
                 draft,
                 config: SpeculativeConfig {
                     gamma: speculative_gamma,
+                    adaptive_gamma,
+                },
+            })
+        } else if prompt_lookup {
+            Box::new(PromptLookupLoader {
+                target: loader,
+                config: PromptLookupConfig {
+                    gamma: speculative_gamma,
+                    adaptive_gamma,
+                    min_ngram: 1,
+                    max_ngram: 3,
                 },
             })
         } else {
@@ -540,46 +600,63 @@ impl Runner {
             None => DeviceMapMetadata::dummy(),
         };
 
-        // Allocate 0.5 GB of CPU memory just as a placeholder.
-        // Nothing happens here as we have no `swap_out`, see `_preempt_by_swap`.
-        let cache_config =
-            match (
-                pa_blk_size,
-                pa_gpu_mem,
-                pa_gpu_mem_usage,
-                pa_ctxt_len,
-                paged_attn_supported(),
-                no_paged_attn,
-            ) {
-                (block_size, None, None, None, true, false) => Some(PagedAttentionConfig::new(
+        // Allocate 0.5 GB of CPU memory as swap space for sequences preempted by the scheduler.
+        let cache_config = match (
+            pa_blk_size,
+            pa_gpu_mem,
+            pa_gpu_mem_usage,
+            pa_ctxt_len,
+            paged_attn_supported(),
+            no_paged_attn,
+        ) {
+            (block_size, None, None, None, true, false) => Some(PagedAttentionConfig::new(
+                block_size,
+                512,
+                MemoryGpuConfig::Utilization(0.9), // NOTE(EricLBuehler): default is to use 90% of memory
+                pa_preemption_mode,
+            )?),
+            (block_size, None, None, Some(ctxt), true, false) => Some(PagedAttentionConfig::new(
+                block_size,
+                512,
+                MemoryGpuConfig::ContextSize(ctxt),
+                pa_preemption_mode,
+            )?),
+            (block_size, None, Some(f), None, true, false) => Some(PagedAttentionConfig::new(
+                block_size,
+                512,
+                MemoryGpuConfig::Utilization(f),
+                pa_preemption_mode,
+            )?),
+            (block_size, Some(m), None, None, true, false) => Some(PagedAttentionConfig::new(
+                block_size,
+                512,
+                MemoryGpuConfig::Amount(m),
+                pa_preemption_mode,
+            )?),
+            (block_size, Some(_m), Some(f), None, true, false) => Some(PagedAttentionConfig::new(
+                block_size,
+                512,
+                MemoryGpuConfig::Utilization(f),
+                pa_preemption_mode,
+            )?),
+            (block_size, Some(_m), None, Some(ctxt), true, false) => {
+                Some(PagedAttentionConfig::new(
                     block_size,
                     512,
-                    MemoryGpuConfig::Utilization(0.9), // NOTE(EricLBuehler): default is to use 90% of memory
-                )?),
-                (block_size, None, None, Some(ctxt), true, false) => Some(
-                    PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::ContextSize(ctxt))?,
-                ),
-                (block_size, None, Some(f), None, true, false) => Some(PagedAttentionConfig::new(
+                    MemoryGpuConfig::ContextSize(ctxt),
+                    pa_preemption_mode,
+                )?)
+            }
+            (block_size, None, Some(f), Some(_ctxt), true, false) => {
+                Some(PagedAttentionConfig::new(
                     block_size,
                     512,
                     MemoryGpuConfig::Utilization(f),
-                )?),
-                (block_size, Some(m), None, None, true, false) => Some(PagedAttentionConfig::new(
-                    block_size,
-                    512,
-                    MemoryGpuConfig::Amount(m),
-                )?),
-                (block_size, Some(_m), Some(f), None, true, false) => Some(
-                    PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::Utilization(f))?,
-                ),
-                (block_size, Some(_m), None, Some(ctxt), true, false) => Some(
-                    PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::ContextSize(ctxt))?,
-                ),
-                (block_size, None, Some(f), Some(_ctxt), true, false) => Some(
-                    PagedAttentionConfig::new(block_size, 512, MemoryGpuConfig::Utilization(f))?,
-                ),
-                (_, _, _, _, _, _) => None,
-            };
+                    pa_preemption_mode,
+                )?)
+            }
+            (_, _, _, _, _, _) => None,
+        };
 
         let pipeline = loader
             .load_model_from_hf(
@@ -653,9 +730,16 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyApiErr::from(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::JsonSchema(serde_json::from_str(request.grammar.as_ref().unwrap())?)
             } else if request.grammar_type.is_some() {
                 return Err(PyApiErr::from(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc`, or `json_schema`",
                 ));
             } else {
                 Constraint::None
@@ -846,12 +930,25 @@ impl Runner {
                     top_n_logprobs: request.top_logprobs.unwrap_or(1),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    repetition_penalty: request.repetition_penalty,
+                    repeat_last_n: request.repeat_last_n,
                     max_len: request.max_tokens,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
+                    seed: request.seed,
                     min_p: request.min_p,
                     dry_params,
+                    beam_search: None,
+                    typical_p: request.typical_p,
+                    top_a: request.top_a,
+                    tfs_z: request.tfs_z,
+                    mirostat: MirostatParams::from_mode(
+                        request.mirostat.unwrap_or(0),
+                        request.mirostat_tau,
+                        request.mirostat_eta,
+                    )?,
+                    sampler_order: request.sampler_order.clone(),
                 },
                 response: tx,
                 return_logprobs: request.logprobs,
@@ -862,6 +959,8 @@ impl Runner {
                 tool_choice,
                 tools,
                 logits_processors: None,
+                priority: RequestPriority::default(),
+                tenant: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -884,6 +983,8 @@ impl Runner {
                     Response::CompletionModelError(_, _) => unreachable!(),
                     Response::CompletionChunk(_) => unreachable!(),
                     Response::ImageGeneration(_) => unreachable!(),
+                    Response::Embedding(_) => unreachable!(),
+                    Response::Scoring(_) => unreachable!(),
                 }
             }
         })
@@ -915,9 +1016,16 @@ impl Runner {
                     ));
                 }
                 Constraint::Yacc(request.grammar.as_ref().unwrap().clone())
+            } else if request.grammar_type == Some("json_schema".to_string()) {
+                if request.grammar.is_none() {
+                    return Err(PyApiErr::from(
+                        "Grammar type is specified but not grammar text",
+                    ));
+                }
+                Constraint::JsonSchema(serde_json::from_str(request.grammar.as_ref().unwrap())?)
             } else if request.grammar_type.is_some() {
                 return Err(PyApiErr::from(
-                    "Grammar type is specified but is not `regex` or `yacc`",
+                    "Grammar type is specified but is not `regex`, `yacc`, or `json_schema`",
                 ));
             } else {
                 Constraint::None
@@ -966,18 +1074,31 @@ impl Runner {
                     temperature: request.temperature,
                     top_k: request.top_k,
                     top_p: request.top_p,
-                    top_n_logprobs: 1,
+                    top_n_logprobs: request.logprobs.unwrap_or(0),
                     frequency_penalty: request.frequency_penalty,
                     presence_penalty: request.presence_penalty,
+                    repetition_penalty: request.repetition_penalty,
+                    repeat_last_n: request.repeat_last_n,
                     max_len: request.max_tokens,
                     stop_toks,
                     logits_bias: request.logit_bias.clone(),
                     n_choices: request.n_choices,
+                    seed: request.seed,
                     min_p: request.min_p,
                     dry_params,
+                    beam_search: None,
+                    typical_p: request.typical_p,
+                    top_a: request.top_a,
+                    tfs_z: request.tfs_z,
+                    mirostat: MirostatParams::from_mode(
+                        request.mirostat.unwrap_or(0),
+                        request.mirostat_tau,
+                        request.mirostat_eta,
+                    )?,
+                    sampler_order: request.sampler_order.clone(),
                 },
                 response: tx,
-                return_logprobs: false,
+                return_logprobs: request.logprobs.is_some(),
                 is_streaming: false,
                 constraint,
                 suffix: request.suffix.clone(),
@@ -985,6 +1106,8 @@ impl Runner {
                 tool_choice,
                 tools,
                 logits_processors: None,
+                priority: RequestPriority::default(),
+                tenant: None,
             });
 
             MistralRs::maybe_log_request(self.runner.clone(), format!("{request:?}"));
@@ -1003,6 +1126,8 @@ impl Runner {
                 Response::ModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embedding(_) => unreachable!(),
+                Response::Scoring(_) => unreachable!(),
             }
         })
     }
@@ -1040,6 +1165,8 @@ impl Runner {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            priority: RequestPriority::default(),
+            tenant: None,
         });
 
         let sender = self.runner.get_sender()?;
@@ -1056,6 +1183,56 @@ impl Runner {
         Ok(response)
     }
 
+    /// Score a text, or its token ids, without generating. This returns the logprob of each token
+    /// given the ones before it, and the perplexity of the text.
+    #[pyo3(signature = (prompt, top_logprobs = 0))]
+    fn score(
+        &self,
+        prompt: Either<String, Vec<u32>>,
+        top_logprobs: usize,
+    ) -> PyApiResult<ScoringResponse> {
+        let (tx, mut rx) = channel(1);
+
+        let request = _Request::Normal(NormalRequest {
+            id: {
+                let l = NEXT_REQUEST_ID.lock().unwrap();
+                let last = &mut *l.borrow_mut();
+                let last_v = *last;
+                *last += 1;
+                last_v
+            },
+            messages: RequestMessage::Scoring { prompt },
+            sampling_params: SamplingParams {
+                top_n_logprobs: top_logprobs,
+                ..SamplingParams::deterministic()
+            },
+            response: tx,
+            return_logprobs: true,
+            is_streaming: false,
+            suffix: None,
+            constraint: Constraint::None,
+            adapters: None,
+            tool_choice: None,
+            tools: None,
+            logits_processors: None,
+            priority: RequestPriority::default(),
+            tenant: None,
+        });
+
+        let sender = self.runner.get_sender()?;
+        sender.blocking_send(request).unwrap();
+
+        let ResponseOk::Scoring(response) = rx
+            .blocking_recv()
+            .context("Channel was erroneously closed!")?
+            .as_result()?
+        else {
+            return Err(PyApiErr::from("Got unexpected response type."));
+        };
+
+        Ok(response)
+    }
+
     /// Send a request to re-ISQ the model. If the model was loaded as GGUF or GGML
     /// then nothing will happen.
     fn send_re_isq(&self, dtype: String) -> PyApiResult<()> {
@@ -1088,6 +1265,7 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<AnyMoeConfig>()?;
     m.add_class::<AnyMoeExpertType>()?;
     m.add_class::<ToolChoice>()?;
+    m.add_class::<mistralrs_core::SamplerStep>()?;
 
     m.add_class::<mistralrs_core::ResponseMessage>()?;
     m.add_class::<mistralrs_core::Delta>()?;
@@ -1096,10 +1274,14 @@ fn mistralrs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<mistralrs_core::Choice>()?;
     m.add_class::<mistralrs_core::ChunkChoice>()?;
     m.add_class::<mistralrs_core::Usage>()?;
+    m.add_class::<mistralrs_core::SpeculativeUsage>()?;
     m.add_class::<mistralrs_core::ChatCompletionResponse>()?;
     m.add_class::<mistralrs_core::ChatCompletionChunkResponse>()?;
+    m.add_class::<mistralrs_core::CompletionLogprobs>()?;
     m.add_class::<mistralrs_core::CompletionChoice>()?;
     m.add_class::<mistralrs_core::CompletionResponse>()?;
+    m.add_class::<mistralrs_core::ScoredToken>()?;
+    m.add_class::<mistralrs_core::ScoringResponse>()?;
     m.add_class::<mistralrs_core::TopLogprob>()?;
     m.add_class::<mistralrs_core::ModelDType>()?;
     m.add_class::<mistralrs_core::ImageGenerationResponseFormat>()?;
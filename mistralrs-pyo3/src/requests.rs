@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use either::Either;
+use mistralrs_core::SamplerStep;
 use pyo3::{
     exceptions::PyTypeError,
     pyclass, pymethods,
@@ -43,6 +44,17 @@ pub struct CompletionRequest {
     pub(crate) dry_base: Option<f32>,
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) seed: Option<u64>,
+    pub(crate) repetition_penalty: Option<f32>,
+    pub(crate) repeat_last_n: Option<usize>,
+    pub(crate) typical_p: Option<f64>,
+    pub(crate) top_a: Option<f64>,
+    pub(crate) tfs_z: Option<f64>,
+    pub(crate) mirostat: Option<usize>,
+    pub(crate) mirostat_tau: Option<f32>,
+    pub(crate) mirostat_eta: Option<f32>,
+    pub(crate) sampler_order: Option<Vec<SamplerStep>>,
+    pub(crate) logprobs: Option<usize>,
 }
 
 #[pymethods]
@@ -73,6 +85,17 @@ impl CompletionRequest {
         dry_base=None,
         dry_allowed_length=None,
         dry_sequence_breakers=None,
+        seed=None,
+        repetition_penalty=None,
+        repeat_last_n=None,
+        typical_p=None,
+        top_a=None,
+        tfs_z=None,
+        mirostat=None,
+        mirostat_tau=None,
+        mirostat_eta=None,
+        sampler_order=None,
+        logprobs=None,
     ))]
     fn new(
         prompt: String,
@@ -99,6 +122,17 @@ impl CompletionRequest {
         dry_base: Option<f32>,
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
+        seed: Option<u64>,
+        repetition_penalty: Option<f32>,
+        repeat_last_n: Option<usize>,
+        typical_p: Option<f64>,
+        top_a: Option<f64>,
+        tfs_z: Option<f64>,
+        mirostat: Option<usize>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        sampler_order: Option<Vec<SamplerStep>>,
+        logprobs: Option<usize>,
     ) -> PyResult<Self> {
         Ok(Self {
             prompt,
@@ -125,6 +159,17 @@ impl CompletionRequest {
             dry_allowed_length,
             dry_base,
             dry_sequence_breakers,
+            seed,
+            repetition_penalty,
+            repeat_last_n,
+            typical_p,
+            top_a,
+            tfs_z,
+            mirostat,
+            mirostat_tau,
+            mirostat_eta,
+            sampler_order,
+            logprobs,
         })
     }
 }
@@ -166,6 +211,16 @@ pub struct ChatCompletionRequest {
     pub(crate) dry_base: Option<f32>,
     pub(crate) dry_allowed_length: Option<usize>,
     pub(crate) dry_sequence_breakers: Option<Vec<String>>,
+    pub(crate) seed: Option<u64>,
+    pub(crate) repetition_penalty: Option<f32>,
+    pub(crate) repeat_last_n: Option<usize>,
+    pub(crate) typical_p: Option<f64>,
+    pub(crate) top_a: Option<f64>,
+    pub(crate) tfs_z: Option<f64>,
+    pub(crate) mirostat: Option<usize>,
+    pub(crate) mirostat_tau: Option<f32>,
+    pub(crate) mirostat_eta: Option<f32>,
+    pub(crate) sampler_order: Option<Vec<SamplerStep>>,
 }
 
 #[pymethods]
@@ -196,6 +251,16 @@ impl ChatCompletionRequest {
         dry_base=None,
         dry_allowed_length=None,
         dry_sequence_breakers=None,
+        seed=None,
+        repetition_penalty=None,
+        repeat_last_n=None,
+        typical_p=None,
+        top_a=None,
+        tfs_z=None,
+        mirostat=None,
+        mirostat_tau=None,
+        mirostat_eta=None,
+        sampler_order=None,
     ))]
     fn new(
         messages: Py<PyAny>,
@@ -222,6 +287,16 @@ impl ChatCompletionRequest {
         dry_base: Option<f32>,
         dry_allowed_length: Option<usize>,
         dry_sequence_breakers: Option<Vec<String>>,
+        seed: Option<u64>,
+        repetition_penalty: Option<f32>,
+        repeat_last_n: Option<usize>,
+        typical_p: Option<f64>,
+        top_a: Option<f64>,
+        tfs_z: Option<f64>,
+        mirostat: Option<usize>,
+        mirostat_tau: Option<f32>,
+        mirostat_eta: Option<f32>,
+        sampler_order: Option<Vec<SamplerStep>>,
     ) -> PyResult<Self> {
         let messages = Python::with_gil(|py| {
             if let Ok(messages) = messages.bind(py).downcast_exact::<PyList>() {
@@ -296,6 +371,16 @@ impl ChatCompletionRequest {
             dry_allowed_length,
             dry_base,
             dry_sequence_breakers,
+            seed,
+            repetition_penalty,
+            repeat_last_n,
+            typical_p,
+            top_a,
+            tfs_z,
+            mirostat,
+            mirostat_tau,
+            mirostat_eta,
+            sampler_order,
         })
     }
 }
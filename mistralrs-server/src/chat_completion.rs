@@ -11,7 +11,8 @@ use std::{
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 use crate::{
-    openai::{ChatCompletionRequest, Grammar, MessageInnerContent, StopTokens},
+    model_registry::ModelRegistry,
+    openai::{ChatCompletionRequest, Grammar, MessageInnerContent, ResponseFormat, StopTokens},
     util,
 };
 use anyhow::{Context as _, Result};
@@ -26,8 +27,9 @@ use axum::{
 use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
-    ChatCompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
-    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
+    ChatCompletionResponse, Constraint, DrySamplingParams, MirostatParams, MistralRs,
+    NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
 
@@ -44,6 +46,20 @@ pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
     state: Arc<MistralRs>,
+    request_id: usize,
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) {
+        // The client disconnected before the stream finished, so stop generating for it.
+        if !self.is_done {
+            if let Ok(sender) = self.state.get_sender() {
+                let _ = sender.try_send(Request::Cancel {
+                    id: self.request_id,
+                });
+            }
+        }
+    }
 }
 
 impl futures::Stream for Streamer {
@@ -81,6 +97,8 @@ impl futures::Stream for Streamer {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embedding(_) => unreachable!(),
+                Response::Scoring(_) => unreachable!(),
             },
             Err(_) => Poll::Pending,
         }
@@ -296,6 +314,27 @@ async fn parse_request(
         None
     };
 
+    let constraint = match (oairequest.grammar, oairequest.response_format) {
+        (Some(_), Some(ResponseFormat::JsonSchema { .. })) => {
+            anyhow::bail!(
+                "Only one of `grammar` and a `json_schema` `response_format` may be specified."
+            )
+        }
+        (Some(Grammar::Yacc(yacc)), _) => Constraint::Yacc(yacc),
+        (Some(Grammar::Regex(regex)), _) => Constraint::Regex(regex),
+        (Some(Grammar::JsonSchema(schema)), _) => Constraint::JsonSchema(schema),
+        (None, Some(ResponseFormat::JsonSchema { json_schema })) => {
+            Constraint::JsonSchema(json_schema.schema)
+        }
+        (None, Some(ResponseFormat::Text) | None) => Constraint::None,
+    };
+
+    let mirostat = MirostatParams::from_mode(
+        oairequest.mirostat.unwrap_or(0),
+        oairequest.mirostat_tau,
+        oairequest.mirostat_eta,
+    )?;
+
     let is_streaming = oairequest.stream.unwrap_or(false);
     Ok((
         Request::Normal(NormalRequest {
@@ -309,25 +348,32 @@ async fn parse_request(
                 top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
+                repetition_penalty: oairequest.repetition_penalty,
+                repeat_last_n: oairequest.repeat_last_n,
                 max_len: oairequest.max_tokens,
                 stop_toks,
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
+                seed: oairequest.seed,
                 dry_params,
+                beam_search: None,
+                typical_p: oairequest.typical_p,
+                top_a: oairequest.top_a,
+                tfs_z: oairequest.tfs_z,
+                mirostat,
+                sampler_order: oairequest.sampler_order,
             },
             response: tx,
             return_logprobs: oairequest.logprobs,
             is_streaming,
             suffix: None,
-            constraint: match oairequest.grammar {
-                Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
-                Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
-                None => Constraint::None,
-            },
+            constraint,
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
             logits_processors: None,
+            priority: oairequest.priority,
+            tenant: oairequest.user,
         }),
         is_streaming,
     ))
@@ -341,9 +387,13 @@ async fn parse_request(
     responses((status = 200, description = "Chat completions"))
 )]
 pub async fn chatcompletions(
-    State(state): State<Arc<MistralRs>>,
+    State(registry): State<Arc<ModelRegistry>>,
     Json(oairequest): Json<ChatCompletionRequest>,
 ) -> ChatCompletionResponder {
+    let state = match registry.get(&oairequest.model).await {
+        Ok(state) => state,
+        Err(e) => return ChatCompletionResponder::ValidationError(e.into()),
+    };
     let (tx, mut rx) = channel(10_000);
     let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
         Ok(x) => x,
@@ -353,6 +403,10 @@ pub async fn chatcompletions(
             return ChatCompletionResponder::InternalError(e.into());
         }
     };
+    let request_id = match &request {
+        Request::Normal(request) => request.id,
+        _ => unreachable!(),
+    };
     let sender = state.get_sender().unwrap();
 
     if let Err(e) = sender.send(request).await {
@@ -366,6 +420,7 @@ pub async fn chatcompletions(
             rx,
             is_done: false,
             state,
+            request_id,
         };
 
         ChatCompletionResponder::Sse(
@@ -409,6 +464,8 @@ pub async fn chatcompletions(
             Response::CompletionModelError(_, _) => unreachable!(),
             Response::CompletionChunk(_) => unreachable!(),
             Response::ImageGeneration(_) => unreachable!(),
+            Response::Embedding(_) => unreachable!(),
+            Response::Scoring(_) => unreachable!(),
         }
     }
 }
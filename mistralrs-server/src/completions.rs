@@ -9,7 +9,10 @@ use std::{
 };
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::openai::{CompletionRequest, Grammar, StopTokens};
+use crate::{
+    model_registry::ModelRegistry,
+    openai::{CompletionRequest, Grammar, StopTokens},
+};
 use axum::{
     extract::{Json, State},
     http::{self, StatusCode},
@@ -19,11 +22,11 @@ use axum::{
     },
 };
 use mistralrs_core::{
-    CompletionResponse, Constraint, DrySamplingParams, MistralRs, NormalRequest, Request,
-    RequestMessage, Response, SamplingParams, StopTokens as InternalStopTokens,
+    BeamSearchParams, CompletionResponse, Constraint, DrySamplingParams, MirostatParams, MistralRs,
+    NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    StopTokens as InternalStopTokens,
 };
 use serde::Serialize;
-use tracing::warn;
 
 #[derive(Debug)]
 struct ModelErrorMessage(String);
@@ -38,6 +41,20 @@ pub struct Streamer {
     rx: Receiver<Response>,
     is_done: bool,
     state: Arc<MistralRs>,
+    request_id: usize,
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) {
+        // The client disconnected before the stream finished, so stop generating for it.
+        if !self.is_done {
+            if let Ok(sender) = self.state.get_sender() {
+                let _ = sender.try_send(Request::Cancel {
+                    id: self.request_id,
+                });
+            }
+        }
+    }
 }
 
 impl futures::Stream for Streamer {
@@ -75,6 +92,8 @@ impl futures::Stream for Streamer {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::Chunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embedding(_) => unreachable!(),
+                Response::Scoring(_) => unreachable!(),
             },
             Err(_) => Poll::Pending,
         }
@@ -157,10 +176,6 @@ fn parse_request(
         None => None,
     };
 
-    if oairequest.logprobs.is_some() {
-        warn!("Completion requests do not support logprobs.");
-    }
-
     let is_streaming = oairequest.stream.unwrap_or(false);
 
     let dry_params = if let Some(dry_multiplier) = oairequest.dry_multiplier {
@@ -173,6 +188,18 @@ fn parse_request(
     } else {
         None
     };
+    let mirostat = MirostatParams::from_mode(
+        oairequest.mirostat.unwrap_or(0),
+        oairequest.mirostat_tau,
+        oairequest.mirostat_eta,
+    )?;
+    let beam_search = oairequest.beam_width.map(|beam_width| {
+        BeamSearchParams::new_with_defaults(
+            beam_width,
+            oairequest.length_penalty,
+            oairequest.early_stopping,
+        )
+    });
     Ok((
         Request::Normal(NormalRequest {
             id: state.next_request_id(),
@@ -186,28 +213,40 @@ fn parse_request(
                 top_k: oairequest.top_k,
                 top_p: oairequest.top_p,
                 min_p: oairequest.min_p,
-                top_n_logprobs: 1,
+                top_n_logprobs: oairequest.logprobs.unwrap_or(0),
                 frequency_penalty: oairequest.frequency_penalty,
                 presence_penalty: oairequest.presence_penalty,
+                repetition_penalty: oairequest.repetition_penalty,
+                repeat_last_n: oairequest.repeat_last_n,
                 max_len: oairequest.max_tokens,
                 stop_toks,
                 logits_bias: oairequest.logit_bias,
                 n_choices: oairequest.n_choices,
+                seed: oairequest.seed,
                 dry_params,
+                beam_search,
+                typical_p: oairequest.typical_p,
+                top_a: oairequest.top_a,
+                tfs_z: oairequest.tfs_z,
+                mirostat,
+                sampler_order: oairequest.sampler_order,
             },
             response: tx,
-            return_logprobs: false,
+            return_logprobs: oairequest.logprobs.is_some(),
             is_streaming,
             suffix: oairequest.suffix,
             constraint: match oairequest.grammar {
                 Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
                 Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
+                Some(Grammar::JsonSchema(schema)) => Constraint::JsonSchema(schema),
                 None => Constraint::None,
             },
             adapters: oairequest.adapters,
             tool_choice: oairequest.tool_choice,
             tools: oairequest.tools,
             logits_processors: None,
+            priority: oairequest.priority,
+            tenant: oairequest.user,
         }),
         is_streaming,
     ))
@@ -222,16 +261,14 @@ fn parse_request(
 )]
 
 pub async fn completions(
-    State(state): State<Arc<MistralRs>>,
+    State(registry): State<Arc<ModelRegistry>>,
     Json(oairequest): Json<CompletionRequest>,
 ) -> CompletionResponder {
+    let state = match registry.get(&oairequest.model).await {
+        Ok(state) => state,
+        Err(e) => return CompletionResponder::ValidationError(e.into()),
+    };
     let (tx, mut rx) = channel(10_000);
-    if oairequest.logprobs.is_some() {
-        return CompletionResponder::ValidationError(
-            "Completion requests do not support logprobs.".into(),
-        );
-    }
-
     let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx) {
         Ok(x) => x,
         Err(e) => {
@@ -240,6 +277,10 @@ pub async fn completions(
             return CompletionResponder::InternalError(e.into());
         }
     };
+    let request_id = match &request {
+        Request::Normal(request) => request.id,
+        _ => unreachable!(),
+    };
     let sender = state.get_sender().unwrap();
 
     if let Err(e) = sender.send(request).await {
@@ -253,6 +294,7 @@ pub async fn completions(
             rx,
             is_done: false,
             state,
+            request_id,
         };
 
         CompletionResponder::Sse(
@@ -296,6 +338,8 @@ pub async fn completions(
             Response::Done(_) => unreachable!(),
             Response::ModelError(_, _) => unreachable!(),
             Response::ImageGeneration(_) => unreachable!(),
+            Response::Embedding(_) => unreachable!(),
+            Response::Scoring(_) => unreachable!(),
         }
     }
 }
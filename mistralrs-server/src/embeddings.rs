@@ -0,0 +1,198 @@
+use anyhow::Result;
+use either::Either;
+use std::{error::Error, sync::Arc};
+use tokio::sync::mpsc::{channel, Receiver};
+
+use crate::{model_registry::ModelRegistry, openai::EmbeddingRequest};
+use axum::{
+    extract::{Json, State},
+    http::{self, StatusCode},
+    response::IntoResponse,
+};
+use mistralrs_core::{
+    Constraint, EmbeddingParams, EmbeddingResponse, MistralRs, NormalRequest, Request,
+    RequestMessage, Response, SamplingParams,
+};
+use serde::Serialize;
+
+pub enum EmbeddingResponder {
+    Json(EmbeddingResponse),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+}
+
+trait ErrorToResponse: Serialize {
+    fn to_response(&self, code: StatusCode) -> axum::response::Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = code;
+        r
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+impl JsonError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+impl ErrorToResponse for JsonError {}
+
+impl IntoResponse for EmbeddingResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            EmbeddingResponder::Json(s) => Json(s).into_response(),
+            EmbeddingResponder::InternalError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            EmbeddingResponder::ValidationError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        }
+    }
+}
+
+/// Build one request per input so that the scheduler can batch them. Each request gets its own
+/// response channel so that the embeddings can be returned in input order.
+fn parse_request(
+    oairequest: EmbeddingRequest,
+    state: Arc<MistralRs>,
+) -> Result<Vec<(Request, Receiver<Response>)>> {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    if let Some(format) = &oairequest.encoding_format {
+        if format != "float" {
+            anyhow::bail!("Only the `float` encoding format is supported, got `{format}`.");
+        }
+    }
+
+    let inputs = match oairequest.input {
+        Either::Left(input) => vec![input],
+        Either::Right(inputs) => inputs,
+    };
+    if inputs.is_empty() {
+        anyhow::bail!("Expected at least one input.");
+    }
+
+    let embedding_params = EmbeddingParams {
+        pooling: oairequest.pooling,
+        normalize: oairequest.normalize,
+    };
+    Ok(inputs
+        .into_iter()
+        .map(|prompt| {
+            let (tx, rx) = channel(1);
+            let request = Request::Normal(NormalRequest {
+                id: state.next_request_id(),
+                messages: RequestMessage::Embedding {
+                    prompt,
+                    embedding_params,
+                },
+                sampling_params: SamplingParams::deterministic(),
+                response: tx,
+                return_logprobs: false,
+                is_streaming: false,
+                suffix: None,
+                constraint: Constraint::None,
+                adapters: None,
+                tool_choice: None,
+                tools: None,
+                logits_processors: None,
+                priority: oairequest.priority,
+                tenant: oairequest.user.clone(),
+            });
+            (request, rx)
+        })
+        .collect())
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/embeddings",
+    request_body = EmbeddingRequest,
+    responses((status = 200, description = "Embeddings"))
+)]
+
+pub async fn embeddings(
+    State(registry): State<Arc<ModelRegistry>>,
+    Json(oairequest): Json<EmbeddingRequest>,
+) -> EmbeddingResponder {
+    let state = match registry.get(&oairequest.model).await {
+        Ok(state) => state,
+        Err(e) => return EmbeddingResponder::ValidationError(e.into()),
+    };
+    let requests = match parse_request(oairequest, state.clone()) {
+        Ok(x) => x,
+        Err(e) => {
+            let e = anyhow::Error::msg(e.to_string());
+            MistralRs::maybe_log_error(state, &*e);
+            return EmbeddingResponder::ValidationError(e.into());
+        }
+    };
+    let sender = state.get_sender().unwrap();
+
+    let mut receivers = Vec::with_capacity(requests.len());
+    for (request, rx) in requests {
+        if let Err(e) = sender.send(request).await {
+            let e = anyhow::Error::msg(e.to_string());
+            MistralRs::maybe_log_error(state, &*e);
+            return EmbeddingResponder::InternalError(e.into());
+        }
+        receivers.push(rx);
+    }
+
+    let mut merged: Option<EmbeddingResponse> = None;
+    for (index, mut rx) in receivers.into_iter().enumerate() {
+        let response = match rx.recv().await {
+            Some(response) => response,
+            None => {
+                let e = anyhow::Error::msg("No response received from the model.");
+                MistralRs::maybe_log_error(state, &*e);
+                return EmbeddingResponder::InternalError(e.into());
+            }
+        };
+
+        match response {
+            Response::InternalError(e) => {
+                MistralRs::maybe_log_error(state, &*e);
+                return EmbeddingResponder::InternalError(e);
+            }
+            Response::ValidationError(e) => return EmbeddingResponder::ValidationError(e),
+            Response::Embedding(mut response) => {
+                for data in &mut response.data {
+                    data.index = index;
+                }
+                match &mut merged {
+                    Some(merged) => {
+                        merged.data.extend(response.data);
+                        merged.usage.prompt_tokens += response.usage.prompt_tokens;
+                        merged.usage.total_tokens += response.usage.total_tokens;
+                    }
+                    None => merged = Some(response),
+                }
+            }
+            Response::CompletionModelError(m, _) => {
+                let e = anyhow::Error::msg(m.to_string());
+                MistralRs::maybe_log_error(state, &*e);
+                return EmbeddingResponder::InternalError(e.into());
+            }
+            Response::CompletionDone(_) => unreachable!(),
+            Response::CompletionChunk(_) => unreachable!(),
+            Response::Chunk(_) => unreachable!(),
+            Response::Done(_) => unreachable!(),
+            Response::ModelError(_, _) => unreachable!(),
+            Response::ImageGeneration(_) => unreachable!(),
+            Response::Scoring(_) => unreachable!(),
+        }
+    }
+
+    // `parse_request` guarantees at least one input.
+    let response = merged.unwrap();
+    MistralRs::maybe_log_response(state, &response);
+    EmbeddingResponder::Json(response)
+}
@@ -2,7 +2,7 @@ use anyhow::Result;
 use std::{error::Error, sync::Arc};
 use tokio::sync::mpsc::{channel, Sender};
 
-use crate::openai::ImageGenerationRequest;
+use crate::{model_registry::ModelRegistry, openai::ImageGenerationRequest};
 use axum::{
     extract::{Json, State},
     http::{self, StatusCode},
@@ -10,7 +10,7 @@ use axum::{
 };
 use mistralrs_core::{
     Constraint, DiffusionGenerationParams, ImageGenerationResponse, MistralRs, NormalRequest,
-    Request, RequestMessage, Response, SamplingParams,
+    Request, RequestMessage, RequestPriority, Response, SamplingParams,
 };
 use serde::Serialize;
 
@@ -82,6 +82,8 @@ fn parse_request(
         tool_choice: None,
         tools: None,
         logits_processors: None,
+        priority: RequestPriority::default(),
+        tenant: None,
     }))
 }
 
@@ -94,9 +96,13 @@ fn parse_request(
 )]
 
 pub async fn image_generation(
-    State(state): State<Arc<MistralRs>>,
+    State(registry): State<Arc<ModelRegistry>>,
     Json(oairequest): Json<ImageGenerationRequest>,
 ) -> ImageGenerationResponder {
+    let state = match registry.get(&oairequest.model).await {
+        Ok(state) => state,
+        Err(e) => return ImageGenerationResponder::ValidationError(e.into()),
+    };
     let (tx, mut rx) = channel(10_000);
 
     let request = match parse_request(oairequest, state.clone(), tx) {
@@ -144,5 +150,7 @@ pub async fn image_generation(
         Response::Chunk(_) => unreachable!(),
         Response::Done(_) => unreachable!(),
         Response::ModelError(_, _) => unreachable!(),
+        Response::Embedding(_) => unreachable!(),
+        Response::Scoring(_) => unreachable!(),
     }
 }
@@ -2,8 +2,8 @@ use either::Either;
 use indexmap::IndexMap;
 use mistralrs_core::{
     Constraint, DiffusionGenerationParams, DrySamplingParams, ImageGenerationResponseFormat,
-    MessageContent, MistralRs, ModelCategory, NormalRequest, Request, RequestMessage, Response,
-    ResponseOk, SamplingParams, TERMINATE_ALL_NEXT_STEP,
+    MessageContent, MistralRs, ModelCategory, NormalRequest, Request, RequestMessage,
+    RequestPriority, Response, ResponseOk, SamplingParams, TERMINATE_ALL_NEXT_STEP,
 };
 use once_cell::sync::Lazy;
 use std::{
@@ -88,11 +88,20 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repeat_last_n: None,
         max_len: Some(4096),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
+        seed: None,
         dry_params: Some(DrySamplingParams::default()),
+        beam_search: None,
+        typical_p: None,
+        top_a: None,
+        tfs_z: None,
+        mirostat: None,
+        sampler_order: None,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -174,6 +183,8 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            priority: RequestPriority::default(),
+            tenant: None,
         });
         sender.send(req).await.unwrap();
 
@@ -215,6 +226,8 @@ async fn text_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embedding(_) => unreachable!(),
+                Response::Scoring(_) => unreachable!(),
             }
         }
         if throughput {
@@ -244,11 +257,20 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
         top_n_logprobs: 0,
         frequency_penalty: Some(0.1),
         presence_penalty: Some(0.1),
+        repetition_penalty: None,
+        repeat_last_n: None,
         max_len: Some(4096),
         stop_toks: None,
         logits_bias: None,
         n_choices: 1,
+        seed: None,
         dry_params: Some(DrySamplingParams::default()),
+        beam_search: None,
+        typical_p: None,
+        top_a: None,
+        tfs_z: None,
+        mirostat: None,
+        sampler_order: None,
     };
 
     info!("Starting interactive loop with sampling params: {sampling_params:?}");
@@ -358,6 +380,8 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            priority: RequestPriority::default(),
+            tenant: None,
         });
         sender.send(req).await.unwrap();
 
@@ -399,6 +423,8 @@ async fn vision_interactive_mode(mistralrs: Arc<MistralRs>, throughput: bool) {
                 Response::CompletionModelError(_, _) => unreachable!(),
                 Response::CompletionChunk(_) => unreachable!(),
                 Response::ImageGeneration(_) => unreachable!(),
+                Response::Embedding(_) => unreachable!(),
+                Response::Scoring(_) => unreachable!(),
             }
         }
         if throughput {
@@ -481,6 +507,8 @@ async fn diffusion_interactive_mode(mistralrs: Arc<MistralRs>) {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            priority: RequestPriority::default(),
+            tenant: None,
         });
         sender.send(req).await.unwrap();
 
@@ -1,7 +1,8 @@
 use anyhow::Result;
 use axum::{
-    extract::{DefaultBodyLimit, Json, State},
+    extract::{DefaultBodyLimit, Json, Query, State},
     http::{self, Method},
+    response::IntoResponse,
     routing::{get, post},
     Router,
 };
@@ -9,30 +10,36 @@ use candle_core::Device;
 use clap::Parser;
 use mistralrs_core::{
     get_model_dtype, get_tgt_non_granular_index, initialize_logging, paged_attn_supported,
-    parse_isq_value, DefaultSchedulerMethod, DeviceLayerMapMetadata, DeviceMapMetadata, IsqType,
-    Loader, LoaderBuilder, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelSelected,
-    PagedAttentionConfig, Request, SchedulerConfig, TokenSource,
+    parse_isq_value, DeviceLayerMapMetadata, DeviceMapMetadata, IsqType, KvCacheType, Loader,
+    LoaderBuilder, MemoryGpuConfig, MistralRs, ModelSelected, PagedAttentionConfig, PreemptionMode,
+    Request, SchedulingPolicy, TokenSource, TomlMultiModelSelector,
 };
 use openai::{
-    ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, Message, ModelObjects,
-    StopTokens,
+    ChatCompletionRequest, CompletionRequest, EmbeddingRequest, ImageGenerationRequest, Message,
+    ModelObjects, ScoringRequest, StopTokens,
 };
 use serde::{Deserialize, Serialize};
 use std::{num::NonZeroUsize, sync::Arc};
 
 mod chat_completion;
 mod completions;
+mod embeddings;
 mod image_generation;
 mod interactive_mode;
+mod model_registry;
 mod openai;
 mod printer;
+mod scoring;
 mod util;
 
-use crate::openai::ModelObject;
+use crate::model_registry::{ModelLoadSettings, ModelRegistry};
+use crate::openai::{default_model, ModelObject};
 use crate::{
     chat_completion::{__path_chatcompletions, chatcompletions},
     completions::completions,
+    embeddings::embeddings,
     image_generation::image_generation,
+    scoring::scoring,
 };
 
 use interactive_mode::interactive_mode;
@@ -49,6 +56,18 @@ fn parse_token_source(s: &str) -> Result<TokenSource, String> {
     s.parse()
 }
 
+fn parse_scheduling_policy(s: &str) -> Result<SchedulingPolicy, String> {
+    s.parse()
+}
+
+fn parse_preemption_mode(s: &str) -> Result<PreemptionMode, String> {
+    s.parse()
+}
+
+fn parse_kv_cache_type(s: &str) -> Result<KvCacheType, String> {
+    s.parse()
+}
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -76,16 +95,32 @@ struct Args {
 
     /// Model selector
     #[clap(subcommand)]
-    model: ModelSelected,
+    model: Option<ModelSelected>,
+
+    /// TOML file describing a set of models to serve, routed by the `model` field of requests.
+    /// Use this instead of a model selector.
+    #[arg(long)]
+    models_config: Option<String>,
 
     /// Maximum running sequences at any time. If the `tgt_non_granular_index` flag is set for X-LoRA models, this will be set to 1.
     #[arg(long, default_value_t = 16)]
     max_seqs: usize,
 
+    /// Order in which waiting sequences run when not using PagedAttention: `fcfs`, `strict-priority`
+    /// (by the `priority` of requests), or `weighted-fair` (fair sharing between the `user`s of requests, weighted by `priority`).
+    #[arg(long, default_value = "fcfs", value_parser = parse_scheduling_policy)]
+    scheduling_policy: SchedulingPolicy,
+
     /// Use no KV cache.
     #[arg(long, default_value_t = false)]
     no_kv_cache: bool,
 
+    /// Type in which the KV cache is stored when not using PagedAttention: `auto` (the model dtype), `q8_0` (8-bit integers
+    /// with a scale per block of 32 values) or `f8e4m3`. Quantizing the KV cache roughly halves its memory usage, but each
+    /// layer's cache is dequantized when it is attended to, which is slower and briefly uses memory for one layer's full cache.
+    #[arg(long = "kv-cache-type", default_value = "auto", value_parser = parse_kv_cache_type)]
+    kv_cache_type: KvCacheType,
+
     /// JINJA chat template with `messages`, `add_generation_prompt`, `bos_token`, `eos_token`, and `unk_token` as inputs.
     /// Used if the automatic deserialization fails. If this ends with `.json` (ie., it is a file) then that template is loaded.
     #[arg(short, long)]
@@ -139,6 +174,12 @@ struct Args {
     #[arg(long = "pa-blk-size")]
     paged_attn_block_size: Option<usize>,
 
+    /// How a running sequence gives up its KV cache blocks when PagedAttention runs out of GPU memory: `swap` moves
+    /// them to the CPU swap space, falling back to recomputation once it is full, and `recompute` frees them and runs
+    /// the sequence through the model again when it resumes.
+    #[arg(long = "pa-preemption-mode", default_value = "swap", value_parser = parse_preemption_mode)]
+    paged_attn_preemption_mode: PreemptionMode,
+
     /// Disable PagedAttention on CUDA.
     #[arg(long = "no-paged-attn", default_value_t = false)]
     no_paged_attn: bool,
@@ -158,15 +199,20 @@ struct Args {
     path = "/v1/models",
     responses((status = 200, description = "Served model info", body = ModelObjects))
 )]
-async fn models(State(state): State<Arc<MistralRs>>) -> Json<ModelObjects> {
+async fn models(State(registry): State<Arc<ModelRegistry>>) -> Json<ModelObjects> {
     Json(ModelObjects {
         object: "list",
-        data: vec![ModelObject {
-            id: state.get_id(),
-            object: "model",
-            created: state.get_creation_time(),
-            owned_by: "local",
-        }],
+        data: registry
+            .list()
+            .await
+            .into_iter()
+            .map(|(id, created)| ModelObject {
+                id,
+                object: "model",
+                created,
+                owned_by: "local",
+            })
+            .collect(),
     })
 }
 
@@ -180,10 +226,41 @@ async fn health() -> &'static str {
     "OK"
 }
 
+#[derive(Debug, Clone, Deserialize)]
+struct MetricsQuery {
+    #[serde(default = "default_model")]
+    model: String,
+}
+
+#[utoipa::path(
+    get,
+    tag = "Mistral.rs",
+    path = "/metrics",
+    params(("model" = Option<String>, Query, description = "Model to report the metrics of, when serving several models")),
+    responses((status = 200, description = "Engine metrics in the Prometheus text format"))
+)]
+async fn metrics(
+    State(registry): State<Arc<ModelRegistry>>,
+    Query(query): Query<MetricsQuery>,
+) -> axum::response::Response {
+    // Do not load a model just to report its metrics.
+    match registry.get_loaded(&query.model).await {
+        Ok(state) => (
+            [(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            state.map(|state| state.get_metrics()).unwrap_or_default(),
+        )
+            .into_response(),
+        Err(e) => (http::StatusCode::NOT_FOUND, e.to_string()).into_response(),
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 struct AdapterActivationRequest {
     #[schema(example = json!(vec!["adapter_1","adapter_2"]))]
     adapter_names: Vec<String>,
+    #[schema(example = "default")]
+    #[serde(default = "default_model")]
+    model: String,
 }
 
 #[utoipa::path(
@@ -194,20 +271,27 @@ struct AdapterActivationRequest {
     responses((status = 200, description = "Activate a set of pre-loaded LoRA adapters"))
 )]
 async fn activate_adapters(
-    State(state): State<Arc<MistralRs>>,
+    State(registry): State<Arc<ModelRegistry>>,
     Json(request): Json<AdapterActivationRequest>,
-) -> String {
+) -> Result<String, String> {
+    let state = registry
+        .get(&request.model)
+        .await
+        .map_err(|e| e.to_string())?;
     let repr = format!("Adapter activation: {:?}", request.adapter_names);
     MistralRs::maybe_log_request(state.clone(), repr.clone());
     let request = Request::ActivateAdapters(request.adapter_names);
     state.get_sender().unwrap().send(request).await.unwrap();
-    repr
+    Ok(repr)
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 struct ReIsqRequest {
     #[schema(example = "Q4K")]
     ggml_type: String,
+    #[schema(example = "default")]
+    #[serde(default = "default_model")]
+    model: String,
 }
 
 #[utoipa::path(
@@ -218,9 +302,13 @@ struct ReIsqRequest {
     responses((status = 200, description = "Reapply ISQ to a non GGUF or GGML model."))
 )]
 async fn re_isq(
-    State(state): State<Arc<MistralRs>>,
+    State(registry): State<Arc<ModelRegistry>>,
     Json(request): Json<ReIsqRequest>,
 ) -> Result<String, String> {
+    let state = registry
+        .get(&request.model)
+        .await
+        .map_err(|e| e.to_string())?;
     let repr = format!("Re ISQ: {:?}", request.ggml_type);
     MistralRs::maybe_log_request(state.clone(), repr.clone());
     let request = Request::ReIsq(parse_isq_value(&request.ggml_type)?);
@@ -228,12 +316,12 @@ async fn re_isq(
     Ok(repr)
 }
 
-fn get_router(state: Arc<MistralRs>) -> Router {
+fn get_router(state: Arc<ModelRegistry>) -> Router {
     #[derive(OpenApi)]
     #[openapi(
-        paths(models, health, chatcompletions),
+        paths(models, health, metrics, chatcompletions),
         components(
-            schemas(ModelObjects, ModelObject, ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, StopTokens, Message)),
+            schemas(ModelObjects, ModelObject, ChatCompletionRequest, CompletionRequest, ImageGenerationRequest, EmbeddingRequest, ScoringRequest, StopTokens, Message)),
         tags(
             (name = "Mistral.rs", description = "Mistral.rs API")
         ),
@@ -260,10 +348,13 @@ fn get_router(state: Arc<MistralRs>) -> Router {
         .route("/v1/completions", post(completions))
         .route("/v1/models", get(models))
         .route("/health", get(health))
+        .route("/metrics", get(metrics))
         .route("/", get(health))
         .route("/activate_adapters", post(activate_adapters))
         .route("/re_isq", post(re_isq))
         .route("/v1/images/generations", post(image_generation))
+        .route("/v1/embeddings", post(embeddings))
+        .route("/v1/score", post(scoring))
         .layer(cors_layer)
         .layer(DefaultBodyLimit::max(N_INPUT_SIZE * MB_TO_B))
         .with_state(state)
@@ -271,7 +362,7 @@ fn get_router(state: Arc<MistralRs>) -> Router {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let mut args = Args::parse();
+    let args = Args::parse();
     initialize_logging();
 
     #[cfg(not(feature = "flash-attn"))]
@@ -279,11 +370,8 @@ async fn main() -> Result<()> {
     #[cfg(feature = "flash-attn")]
     let use_flash_attn = true;
 
-    let tgt_non_granular_index = get_tgt_non_granular_index(&args.model);
-    let dtype = get_model_dtype(&args.model)?;
-
-    if tgt_non_granular_index.is_some() {
-        args.max_seqs = 1;
+    if args.model.is_some() == args.models_config.is_some() {
+        anyhow::bail!("Expected exactly one of a model selector or `--models-config`.");
     }
 
     let prompt_batchsize = match args.prompt_batchsize {
@@ -294,12 +382,27 @@ async fn main() -> Result<()> {
         None => None,
     };
 
-    let loader: Box<dyn Loader> = LoaderBuilder::new(args.model)
-        .with_no_kv_cache(args.no_kv_cache)
-        .with_chat_template(args.chat_template)
-        .with_use_flash_attn(use_flash_attn)
-        .with_prompt_batchsize(prompt_batchsize)
-        .build()?;
+    let single = match args.model {
+        Some(model) => {
+            let tgt_non_granular_index = get_tgt_non_granular_index(&model);
+            let dtype = get_model_dtype(&model)?;
+            let max_seqs = if tgt_non_granular_index.is_some() {
+                1
+            } else {
+                args.max_seqs
+            };
+
+            let loader: Box<dyn Loader> = LoaderBuilder::new(model)
+                .with_no_kv_cache(args.no_kv_cache)
+                .with_chat_template(args.chat_template.clone())
+                .with_use_flash_attn(use_flash_attn)
+                .with_prompt_batchsize(prompt_batchsize)
+                .with_kv_cache_type(args.kv_cache_type)
+                .build()?;
+            Some((loader, dtype, max_seqs))
+        }
+        None => None,
+    };
 
     #[cfg(feature = "metal")]
     let device = Device::new_metal(0)?;
@@ -321,10 +424,12 @@ async fn main() -> Result<()> {
     if use_flash_attn {
         info!("Using flash attention.");
     }
-    if use_flash_attn && loader.get_kind().is_quantized() {
-        warn!("Using flash attention with a quantized model has no effect!")
+    if let Some((loader, _, _)) = &single {
+        if use_flash_attn && loader.get_kind().is_quantized() {
+            warn!("Using flash attention with a quantized model has no effect!")
+        }
+        info!("Model kind is: {}", loader.get_kind().to_string());
     }
-    info!("Model kind is: {}", loader.get_kind().to_string());
 
     // Parse device mapper
     let mapper = if let Some(device_layers) = args.num_device_layers {
@@ -363,8 +468,7 @@ async fn main() -> Result<()> {
         DeviceMapMetadata::dummy()
     };
 
-    // Allocate 0.5 GB of CPU memory just as a placeholder.
-    // Nothing happens here as we have no `swap_out`, see `_preempt_by_swap`.
+    // Allocate 0.5 GB of CPU memory as swap space for sequences preempted by the scheduler.
     let cache_config = match (
         args.paged_attn_block_size,
         args.paged_attn_gpu_mem,
@@ -377,21 +481,25 @@ async fn main() -> Result<()> {
             block_size,
             512,
             MemoryGpuConfig::Utilization(0.9), // NOTE(EricLBuehler): default is to use 90% of memory
+            args.paged_attn_preemption_mode,
         )?),
         (block_size, None, None, Some(ctxt), true, false) => Some(PagedAttentionConfig::new(
             block_size,
             512,
             MemoryGpuConfig::ContextSize(ctxt),
+            args.paged_attn_preemption_mode,
         )?),
         (block_size, None, Some(f), None, true, false) => Some(PagedAttentionConfig::new(
             block_size,
             512,
             MemoryGpuConfig::Utilization(f),
+            args.paged_attn_preemption_mode,
         )?),
         (block_size, Some(m), None, None, true, false) => Some(PagedAttentionConfig::new(
             block_size,
             512,
             MemoryGpuConfig::Amount(m),
+            args.paged_attn_preemption_mode,
         )?),
         (block_size, Some(_m), Some(f), None, true, false) => {
             info!("Both memory size, and usage were specified, defaulting to the usage value.");
@@ -399,6 +507,7 @@ async fn main() -> Result<()> {
                 block_size,
                 512,
                 MemoryGpuConfig::Utilization(f),
+                args.paged_attn_preemption_mode,
             )?)
         }
         (block_size, Some(_m), None, Some(ctxt), true, false) => {
@@ -407,6 +516,7 @@ async fn main() -> Result<()> {
                 block_size,
                 512,
                 MemoryGpuConfig::ContextSize(ctxt),
+                args.paged_attn_preemption_mode,
             )?)
         }
         (block_size, None, Some(f), Some(_ctxt), true, false) => {
@@ -415,62 +525,65 @@ async fn main() -> Result<()> {
                 block_size,
                 512,
                 MemoryGpuConfig::Utilization(f),
+                args.paged_attn_preemption_mode,
             )?)
         }
         (_, _, _, _, _, _) => None,
     };
 
-    let pipeline = loader.load_model_from_hf(
-        None,
-        args.token_source,
-        &dtype,
-        &device,
-        false,
+    let settings = ModelLoadSettings {
+        token_source: args.token_source,
+        device,
         mapper,
-        args.in_situ_quant,
+        in_situ_quant: args.in_situ_quant,
         cache_config,
-    )?;
-    info!("Model loaded.");
-
-    let scheduler_config = if cache_config.is_some() {
-        // Handle case where we may have device mapping
-        if let Some(ref cache_config) = pipeline.lock().await.get_metadata().cache_config {
-            SchedulerConfig::PagedAttentionMeta {
-                max_num_seqs: args.max_seqs,
-                config: cache_config.clone(),
-            }
-        } else {
-            SchedulerConfig::DefaultScheduler {
-                method: DefaultSchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
+        max_seqs: args.max_seqs,
+        scheduling_policy: args.scheduling_policy,
+        use_flash_attn,
+        chat_template: args.chat_template,
+        no_kv_cache: args.no_kv_cache,
+        prompt_batchsize,
+        kv_cache_type: args.kv_cache_type,
+        log: args.log,
+        truncate_sequence: args.truncate_sequence,
+        prefix_cache_n: args.prefix_cache_n,
+        throughput_log: args.throughput_log,
+    };
+
+    let registry = match single {
+        Some((loader, dtype, max_seqs)) => {
+            let builder = settings
+                .load(loader, dtype, max_seqs, settings.cache_config)
+                .await?;
+
+            if args.interactive_mode {
+                interactive_mode(builder.build(), args.throughput_log).await;
+                return Ok(());
             }
+
+            // Throughput logging in the server
+            let builder = if args.throughput_log {
+                builder.with_throughput_logging()
+            } else {
+                builder
+            };
+            ModelRegistry::single(builder.build())
         }
-    } else {
-        SchedulerConfig::DefaultScheduler {
-            method: DefaultSchedulerMethod::Fixed(args.max_seqs.try_into().unwrap()),
+        None => {
+            if args.interactive_mode {
+                anyhow::bail!("Interactive mode requires a single model selector.");
+            }
+            let file = args
+                .models_config
+                .expect("Checked that a model or multi-model file was specified.");
+            ModelRegistry::from_selector(TomlMultiModelSelector::from_file(&file)?, settings)
+                .await?
         }
     };
-    // Throughput logging in the server
-    let builder = MistralRsBuilder::new(pipeline, scheduler_config)
-        .with_opt_log(args.log)
-        .with_truncate_sequence(args.truncate_sequence)
-        .with_no_kv_cache(args.no_kv_cache)
-        .with_prefix_cache_n(args.prefix_cache_n);
-
-    if args.interactive_mode {
-        interactive_mode(builder.build(), args.throughput_log).await;
-        return Ok(());
-    }
-
-    let builder = if args.throughput_log {
-        builder.with_throughput_logging()
-    } else {
-        builder
-    };
-    let mistralrs = builder.build();
 
     let port = args.port.expect("Interactive mode was not specified, so expected port to be specified. Perhaps you forgot `-i` or `--port`?");
 
-    let app = get_router(mistralrs);
+    let app = get_router(registry);
 
     let ip = if let Some(ref ip) = args.serve_ip {
         ip.to_string()
@@ -0,0 +1,310 @@
+use std::{
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use candle_core::Device;
+use mistralrs_core::{
+    DefaultSchedulerMethod, DeviceMapMetadata, IsqType, KvCacheType, Loader, MistralRs,
+    MistralRsBuilder, ModelDType, PagedAttentionConfig, SchedulerConfig, SchedulingPolicy,
+    TokenSource, TomlLoaderArgs, TomlMultiModelSelector, TomlServedModel,
+};
+use tokio::sync::Mutex;
+use tracing::info;
+
+/// How often idle models are checked for unloading.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings from the command line which apply to every served model.
+pub struct ModelLoadSettings {
+    pub token_source: TokenSource,
+    pub device: Device,
+    pub mapper: DeviceMapMetadata,
+    pub in_situ_quant: Option<IsqType>,
+    pub cache_config: Option<PagedAttentionConfig>,
+    pub max_seqs: usize,
+    pub scheduling_policy: SchedulingPolicy,
+    pub use_flash_attn: bool,
+    pub chat_template: Option<String>,
+    pub no_kv_cache: bool,
+    pub prompt_batchsize: Option<NonZeroUsize>,
+    pub kv_cache_type: KvCacheType,
+    pub log: Option<String>,
+    pub truncate_sequence: bool,
+    pub prefix_cache_n: usize,
+    pub throughput_log: bool,
+}
+
+impl ModelLoadSettings {
+    /// Load the model and prepare the `MistralRsBuilder` for it. Throughput logging is left to the caller.
+    pub async fn load(
+        &self,
+        loader: Box<dyn Loader>,
+        dtype: ModelDType,
+        max_seqs: usize,
+        cache_config: Option<PagedAttentionConfig>,
+    ) -> Result<MistralRsBuilder> {
+        // Loading may take a long time, so do not hold up the other tasks on this worker.
+        let pipeline = tokio::task::block_in_place(|| {
+            loader.load_model_from_hf(
+                None,
+                self.token_source.clone(),
+                &dtype,
+                &self.device,
+                false,
+                self.mapper.clone(),
+                self.in_situ_quant,
+                cache_config,
+            )
+        })?;
+        info!("Model loaded.");
+
+        let scheduler_config = if cache_config.is_some() {
+            // Handle case where we may have device mapping
+            if let Some(ref cache_config) = pipeline.lock().await.get_metadata().cache_config {
+                SchedulerConfig::PagedAttentionMeta {
+                    max_num_seqs: max_seqs,
+                    config: cache_config.clone(),
+                }
+            } else {
+                SchedulerConfig::DefaultScheduler {
+                    method: DefaultSchedulerMethod::Fixed(max_seqs.try_into().unwrap()),
+                }
+            }
+        } else {
+            SchedulerConfig::DefaultScheduler {
+                method: DefaultSchedulerMethod::Fixed(max_seqs.try_into().unwrap()),
+            }
+        };
+        Ok(MistralRsBuilder::new(pipeline, scheduler_config)
+            .with_opt_log(self.log.clone())
+            .with_scheduling_policy(self.scheduling_policy)
+            .with_truncate_sequence(self.truncate_sequence)
+            .with_no_kv_cache(self.no_kv_cache)
+            .with_prefix_cache_n(self.prefix_cache_n))
+    }
+
+    async fn load_served(
+        &self,
+        model: &TomlServedModel,
+        cache_config: Option<PagedAttentionConfig>,
+    ) -> Result<Arc<MistralRs>> {
+        info!("Loading model `{}`.", model.name);
+        let loader = model.get_loader(TomlLoaderArgs {
+            use_flash_attn: self.use_flash_attn,
+            chat_template: self.chat_template.clone(),
+            no_kv_cache: self.no_kv_cache,
+            prompt_batchsize: self.prompt_batchsize,
+            kv_cache_type: self.kv_cache_type,
+        })?;
+        // The non-granular X-LoRA scalings only support one running sequence.
+        let max_seqs = if model.get_tgt_non_granular_index().is_some() {
+            1
+        } else {
+            self.max_seqs
+        };
+        let builder = self
+            .load(loader, model.get_dtype(), max_seqs, cache_config)
+            .await?;
+        let builder = if self.throughput_log {
+            builder.with_throughput_logging()
+        } else {
+            builder
+        };
+        Ok(builder.build())
+    }
+}
+
+struct ModelState {
+    mistralrs: Option<Arc<MistralRs>>,
+    last_used: Instant,
+}
+
+struct ServedModel {
+    name: String,
+    /// `None` if the model was loaded from the command line, in which case it is never unloaded.
+    selector: Option<TomlServedModel>,
+    /// The PagedAttention config of this model, whose share of the memory is kept for it while it
+    /// is unloaded.
+    cache_config: Option<PagedAttentionConfig>,
+    state: Mutex<ModelState>,
+    /// Held while the model is loaded, so that other requests for it wait on this rather than on
+    /// `state`, which is also used to list the models.
+    loading: Mutex<()>,
+}
+
+impl ServedModel {
+    /// The engine if the model is loaded, marking the model as used.
+    async fn use_loaded(&self) -> Option<Arc<MistralRs>> {
+        let mut state = self.state.lock().await;
+        state.last_used = Instant::now();
+        state.mistralrs.clone()
+    }
+}
+
+/// The models served by the server, looked up by the `model` field of a request.
+pub struct ModelRegistry {
+    models: Vec<ServedModel>,
+    settings: Option<ModelLoadSettings>,
+    creation_time: u64,
+}
+
+impl ModelRegistry {
+    /// Serve a single model, which is used for every request regardless of the requested model name.
+    pub fn single(mistralrs: Arc<MistralRs>) -> Arc<Self> {
+        Arc::new(Self {
+            creation_time: mistralrs.get_creation_time(),
+            models: vec![ServedModel {
+                name: mistralrs.get_id(),
+                selector: None,
+                cache_config: None,
+                state: Mutex::new(ModelState {
+                    mistralrs: Some(mistralrs),
+                    last_used: Instant::now(),
+                }),
+                loading: Mutex::new(()),
+            }],
+            settings: None,
+        })
+    }
+
+    /// Serve the models described by a multi-model TOML file. Models which are not `lazy` are loaded now.
+    pub async fn from_selector(
+        selector: TomlMultiModelSelector,
+        settings: ModelLoadSettings,
+    ) -> Result<Arc<Self>> {
+        // The PagedAttention memory is shared by the models loaded now. Lazily loaded models would
+        // need memory reserved up front for them, so they do not use PagedAttention.
+        let n_eager = selector.models.iter().filter(|m| !m.lazy).count();
+        let mut models = Vec::new();
+        for model in selector.models {
+            let (mistralrs, cache_config) = if model.lazy {
+                (None, None)
+            } else {
+                let cache_config = settings.cache_config.map(|c| c.shared_by(n_eager));
+                (
+                    Some(settings.load_served(&model, cache_config).await?),
+                    cache_config,
+                )
+            };
+            models.push(ServedModel {
+                name: model.name.clone(),
+                selector: Some(model),
+                cache_config,
+                state: Mutex::new(ModelState {
+                    mistralrs,
+                    last_used: Instant::now(),
+                }),
+                loading: Mutex::new(()),
+            });
+        }
+
+        let this = Arc::new(Self {
+            models,
+            settings: Some(settings),
+            creation_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time travel has occurred!")
+                .as_secs(),
+        });
+        if this.models.iter().any(|m| {
+            m.selector
+                .as_ref()
+                .is_some_and(|s| s.idle_timeout_secs.is_some())
+        }) {
+            tokio::spawn(this.clone().unload_idle());
+        }
+        Ok(this)
+    }
+
+    fn find(&self, name: &str) -> Result<&ServedModel> {
+        if self.models.len() == 1 && self.models[0].selector.is_none() {
+            return Ok(&self.models[0]);
+        }
+        if name == "default" {
+            return Ok(&self.models[0]);
+        }
+        self.models.iter().find(|m| m.name == name).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Model `{name}` is not served. Available models: {}.",
+                self.names().join(", ")
+            )
+        })
+    }
+
+    /// Get the engine for the requested model, loading it if needed. `default` selects the first model.
+    pub async fn get(&self, name: &str) -> Result<Arc<MistralRs>> {
+        let model = self.find(name)?;
+        if let Some(mistralrs) = model.use_loaded().await {
+            return Ok(mistralrs);
+        }
+
+        // Requests for the same model wait until it is loaded, then use it.
+        let _loading = model.loading.lock().await;
+        if let Some(mistralrs) = model.use_loaded().await {
+            return Ok(mistralrs);
+        }
+        let (Some(settings), Some(selector)) = (&self.settings, &model.selector) else {
+            unreachable!("Only models from a multi-model file can be unloaded.");
+        };
+        let mistralrs = settings.load_served(selector, model.cache_config).await?;
+        let mut state = model.state.lock().await;
+        state.mistralrs = Some(mistralrs.clone());
+        state.last_used = Instant::now();
+        Ok(mistralrs)
+    }
+
+    /// Get the engine for the requested model only if it is currently loaded.
+    pub async fn get_loaded(&self, name: &str) -> Result<Option<Arc<MistralRs>>> {
+        let model = self.find(name)?;
+        Ok(model.state.lock().await.mistralrs.clone())
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.models.iter().map(|m| m.name.clone()).collect()
+    }
+
+    /// Names of all served models with the time they were created, or the server start time if they are not loaded.
+    pub async fn list(&self) -> Vec<(String, u64)> {
+        let mut models = Vec::new();
+        for model in &self.models {
+            let created = match &model.state.lock().await.mistralrs {
+                Some(mistralrs) => mistralrs.get_creation_time(),
+                None => self.creation_time,
+            };
+            models.push((model.name.clone(), created));
+        }
+        models
+    }
+
+    /// Drop models which have not been used for their idle timeout. A model which still has requests
+    /// in flight holds other references to its engine and is kept.
+    async fn unload_idle(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for model in &self.models {
+                let Some(timeout) = model
+                    .selector
+                    .as_ref()
+                    .and_then(|s| s.idle_timeout_secs)
+                    .map(Duration::from_secs)
+                else {
+                    continue;
+                };
+                let mut state = model.state.lock().await;
+                let in_use = state
+                    .mistralrs
+                    .as_ref()
+                    .is_some_and(|m| Arc::strong_count(m) > 1);
+                if state.mistralrs.is_some() && !in_use && state.last_used.elapsed() >= timeout {
+                    info!("Unloading idle model `{}`.", model.name);
+                    // Dropping the last reference terminates the engine and frees the model.
+                    state.mistralrs = None;
+                }
+            }
+        }
+    }
+}
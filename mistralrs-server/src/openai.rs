@@ -1,5 +1,8 @@
 use either::Either;
-use mistralrs_core::{ImageGenerationResponseFormat, Tool, ToolChoice};
+use mistralrs_core::{
+    EmbeddingPooling, ImageGenerationResponseFormat, JsonSchema, RequestPriority, SamplerStep,
+    Tool, ToolChoice,
+};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, ops::Deref};
 use utoipa::ToSchema;
@@ -59,7 +62,7 @@ fn default_1280usize() -> usize {
     1280
 }
 
-fn default_model() -> String {
+pub fn default_model() -> String {
     "default".to_string()
 }
 
@@ -67,6 +70,14 @@ fn default_response_format() -> ImageGenerationResponseFormat {
     ImageGenerationResponseFormat::Url
 }
 
+fn default_pooling() -> EmbeddingPooling {
+    EmbeddingPooling::Mean
+}
+
+fn default_true() -> bool {
+    true
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 #[serde(tag = "type", content = "value")]
 pub enum Grammar {
@@ -74,6 +85,27 @@ pub enum Grammar {
     Regex(String),
     #[serde(rename = "yacc")]
     Yacc(String),
+    #[serde(rename = "json_schema")]
+    #[schema(value_type = Object)]
+    JsonSchema(JsonSchema),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct JsonSchemaResponseFormat {
+    pub name: String,
+    #[schema(value_type = Object)]
+    pub schema: JsonSchema,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "type")]
+pub enum ResponseFormat {
+    #[serde(rename = "text")]
+    Text,
+    #[serde(rename = "json_schema")]
+    JsonSchema {
+        json_schema: JsonSchemaResponseFormat,
+    },
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -114,8 +146,17 @@ pub struct ChatCompletionRequest {
     pub tools: Option<Vec<Tool>>,
     #[schema(example = json!(Option::None::<ToolChoice>))]
     pub tool_choice: Option<ToolChoice>,
+    #[schema(example = json!(Option::None::<ResponseFormat>))]
+    pub response_format: Option<ResponseFormat>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
 
     // mistral.rs additional
+    #[serde(default)]
+    #[schema(example = "normal")]
+    pub priority: RequestPriority,
     #[schema(example = json!(Option::None::<usize>))]
     pub top_k: Option<usize>,
     #[schema(example = json!(Option::None::<Grammar>))]
@@ -132,6 +173,25 @@ pub struct ChatCompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub repeat_last_n: Option<usize>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub tfs_z: Option<f64>,
+    /// 0 disables Mirostat, 1 and 2 select Mirostat v1 or v2.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub mirostat: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    #[schema(example = json!(Option::None::<Vec<SamplerStep>>))]
+    pub sampler_order: Option<Vec<SamplerStep>>,
 }
 
 #[derive(Debug, Serialize, ToSchema)]
@@ -186,14 +246,19 @@ pub struct CompletionRequest {
     pub top_p: Option<f64>,
     #[schema(example = json!(Option::None::<String>))]
     pub suffix: Option<String>,
-    #[serde(rename = "user")]
-    pub _user: Option<String>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
+    #[schema(example = json!(Option::None::<u64>))]
+    pub seed: Option<u64>,
     #[schema(example = json!(Option::None::<Vec<Tool>>))]
     pub tools: Option<Vec<Tool>>,
     #[schema(example = json!(Option::None::<ToolChoice>))]
     pub tool_choice: Option<ToolChoice>,
 
     // mistral.rs additional
+    #[serde(default)]
+    #[schema(example = "normal")]
+    pub priority: RequestPriority,
     #[schema(example = json!(Option::None::<usize>))]
     pub top_k: Option<usize>,
     #[schema(example = json!(Option::None::<Grammar>))]
@@ -210,6 +275,31 @@ pub struct CompletionRequest {
     pub dry_allowed_length: Option<usize>,
     #[schema(example = json!(Option::None::<String>))]
     pub dry_sequence_breakers: Option<Vec<String>>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub repetition_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub repeat_last_n: Option<usize>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub typical_p: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub top_a: Option<f64>,
+    #[schema(example = json!(Option::None::<f64>))]
+    pub tfs_z: Option<f64>,
+    /// 0 disables Mirostat, 1 and 2 select Mirostat v1 or v2.
+    #[schema(example = json!(Option::None::<usize>))]
+    pub mirostat: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_tau: Option<f32>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub mirostat_eta: Option<f32>,
+    #[schema(example = json!(Option::None::<Vec<SamplerStep>>))]
+    pub sampler_order: Option<Vec<SamplerStep>>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub beam_width: Option<usize>,
+    #[schema(example = json!(Option::None::<f32>))]
+    pub length_penalty: Option<f32>,
+    #[schema(example = json!(Option::None::<bool>))]
+    pub early_stopping: Option<bool>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -232,3 +322,44 @@ pub struct ImageGenerationRequest {
     #[schema(example = 1280)]
     pub width: usize,
 }
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct EmbeddingRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[schema(example = "The food was delicious and the waiter was friendly.")]
+    #[serde(with = "either::serde_untagged")]
+    pub input: Either<String, Vec<String>>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub encoding_format: Option<String>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
+
+    // mistral.rs additional
+    #[serde(default)]
+    #[schema(example = "normal")]
+    pub priority: RequestPriority,
+    #[serde(default = "default_pooling")]
+    pub pooling: EmbeddingPooling,
+    #[serde(default = "default_true")]
+    #[schema(example = true)]
+    pub normalize: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct ScoringRequest {
+    #[schema(example = "mistral")]
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[schema(example = "The food was delicious and the waiter was friendly.")]
+    #[serde(with = "either::serde_untagged")]
+    pub prompt: Either<String, Vec<u32>>,
+    #[schema(example = json!(Option::None::<usize>))]
+    pub top_logprobs: Option<usize>,
+    #[schema(example = json!(Option::None::<String>))]
+    pub user: Option<String>,
+    #[serde(default)]
+    #[schema(example = "normal")]
+    pub priority: RequestPriority,
+}
@@ -0,0 +1,153 @@
+use anyhow::Result;
+use std::{error::Error, sync::Arc};
+use tokio::sync::mpsc::{channel, Receiver};
+
+use crate::{model_registry::ModelRegistry, openai::ScoringRequest};
+use axum::{
+    extract::{Json, State},
+    http::{self, StatusCode},
+    response::IntoResponse,
+};
+use mistralrs_core::{
+    Constraint, MistralRs, NormalRequest, Request, RequestMessage, Response, SamplingParams,
+    ScoringResponse,
+};
+use serde::Serialize;
+
+pub enum ScoringResponder {
+    Json(ScoringResponse),
+    InternalError(Box<dyn Error>),
+    ValidationError(Box<dyn Error>),
+}
+
+trait ErrorToResponse: Serialize {
+    fn to_response(&self, code: StatusCode) -> axum::response::Response {
+        let mut r = Json(self).into_response();
+        *r.status_mut() = code;
+        r
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    message: String,
+}
+
+impl JsonError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+impl ErrorToResponse for JsonError {}
+
+impl IntoResponse for ScoringResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ScoringResponder::Json(s) => Json(s).into_response(),
+            ScoringResponder::InternalError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+            }
+            ScoringResponder::ValidationError(e) => {
+                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        }
+    }
+}
+
+fn parse_request(
+    oairequest: ScoringRequest,
+    state: Arc<MistralRs>,
+) -> Result<(Request, Receiver<Response>)> {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    MistralRs::maybe_log_request(state.clone(), repr);
+
+    let (tx, rx) = channel(1);
+    let request = Request::Normal(NormalRequest {
+        id: state.next_request_id(),
+        messages: RequestMessage::Scoring {
+            prompt: oairequest.prompt,
+        },
+        sampling_params: SamplingParams {
+            top_n_logprobs: oairequest.top_logprobs.unwrap_or(0),
+            ..SamplingParams::deterministic()
+        },
+        response: tx,
+        return_logprobs: true,
+        is_streaming: false,
+        suffix: None,
+        constraint: Constraint::None,
+        adapters: None,
+        tool_choice: None,
+        tools: None,
+        logits_processors: None,
+        priority: oairequest.priority,
+        tenant: oairequest.user,
+    });
+    Ok((request, rx))
+}
+
+#[utoipa::path(
+    post,
+    tag = "Mistral.rs",
+    path = "/v1/score",
+    request_body = ScoringRequest,
+    responses((status = 200, description = "Token logprobs and perplexity of the prompt"))
+)]
+
+pub async fn scoring(
+    State(registry): State<Arc<ModelRegistry>>,
+    Json(oairequest): Json<ScoringRequest>,
+) -> ScoringResponder {
+    let state = match registry.get(&oairequest.model).await {
+        Ok(state) => state,
+        Err(e) => return ScoringResponder::ValidationError(e.into()),
+    };
+    let (request, mut rx) = match parse_request(oairequest, state.clone()) {
+        Ok(x) => x,
+        Err(e) => {
+            let e = anyhow::Error::msg(e.to_string());
+            MistralRs::maybe_log_error(state, &*e);
+            return ScoringResponder::ValidationError(e.into());
+        }
+    };
+    let sender = state.get_sender().unwrap();
+
+    if let Err(e) = sender.send(request).await {
+        let e = anyhow::Error::msg(e.to_string());
+        MistralRs::maybe_log_error(state, &*e);
+        return ScoringResponder::InternalError(e.into());
+    }
+
+    let response = match rx.recv().await {
+        Some(response) => response,
+        None => {
+            let e = anyhow::Error::msg("No response received from the model.");
+            MistralRs::maybe_log_error(state, &*e);
+            return ScoringResponder::InternalError(e.into());
+        }
+    };
+
+    match response {
+        Response::InternalError(e) => {
+            MistralRs::maybe_log_error(state, &*e);
+            ScoringResponder::InternalError(e)
+        }
+        Response::ValidationError(e) => ScoringResponder::ValidationError(e),
+        Response::Scoring(response) => {
+            MistralRs::maybe_log_response(state, &response);
+            ScoringResponder::Json(response)
+        }
+        Response::CompletionModelError(m, _) => {
+            let e = anyhow::Error::msg(m.to_string());
+            MistralRs::maybe_log_error(state, &*e);
+            ScoringResponder::InternalError(e.into())
+        }
+        Response::CompletionDone(_) => unreachable!(),
+        Response::CompletionChunk(_) => unreachable!(),
+        Response::Chunk(_) => unreachable!(),
+        Response::Done(_) => unreachable!(),
+        Response::ModelError(_, _) => unreachable!(),
+        Response::ImageGeneration(_) => unreachable!(),
+        Response::Embedding(_) => unreachable!(),
+    }
+}
@@ -0,0 +1,26 @@
+use anyhow::Result;
+use mistralrs::{EmbeddingParams, EmbeddingPooling, TextModelBuilder};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let model = TextModelBuilder::new("microsoft/Phi-3.5-mini-instruct")
+        .with_logging()
+        .build()
+        .await?;
+
+    let params = EmbeddingParams {
+        pooling: EmbeddingPooling::Mean,
+        normalize: true,
+    };
+    let a = model.embed("What is Rust?", params).await?;
+    let b = model
+        .embed("Rust is a systems programming language.", params)
+        .await?;
+
+    // The embeddings are normalized, so the dot product is the cosine similarity.
+    let similarity = a.iter().zip(&b).map(|(x, y)| x * y).sum::<f32>();
+    println!("Embedding dimension: {}", a.len());
+    println!("Cosine similarity: {similarity}");
+
+    Ok(())
+}
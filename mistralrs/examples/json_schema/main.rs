@@ -0,0 +1,36 @@
+use anyhow::Result;
+use mistralrs::{
+    IsqType, PagedAttentionMetaBuilder, RequestBuilder, TextMessageRole, TextModelBuilder,
+};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let model = TextModelBuilder::new("microsoft/Phi-3.5-mini-instruct")
+        .with_isq(IsqType::Q4K)
+        .with_logging()
+        .with_paged_attn(|| PagedAttentionMetaBuilder::default().build())?
+        .build()
+        .await?;
+
+    let request = RequestBuilder::new()
+        // Deserialize the schema to keep the order of the properties, a `serde_json::Value`
+        // would sort them.
+        .set_constraint(mistralrs::Constraint::JsonSchema(serde_json::from_str(
+            r#"{
+                "type": "object",
+                "properties": {
+                    "street": {"type": "string"},
+                    "city": {"type": "string"},
+                    "zip": {"type": "string", "pattern": "[0-9]{5}"}
+                },
+                "required": ["street", "city", "zip"]
+            }"#,
+        )?))
+        .add_message(TextMessageRole::User, "Give me a sample address.");
+
+    let response = model.send_chat_request(request).await?;
+
+    println!("{}", response.choices[0].message.content.as_ref().unwrap());
+
+    Ok(())
+}
@@ -6,8 +6,8 @@ use tokio::sync::mpsc::channel;
 use mistralrs::{
     AnyMoeConfig, AnyMoeExpertType, AnyMoeLoader, Constraint, DefaultSchedulerMethod, Device,
     DeviceMapMetadata, Loader, MistralRs, MistralRsBuilder, ModelDType, NormalLoaderBuilder,
-    NormalRequest, NormalSpecificConfig, Request, RequestMessage, ResponseOk, Result,
-    SamplingParams, SchedulerConfig, TokenSource,
+    NormalRequest, NormalSpecificConfig, Request, RequestMessage, RequestPriority, ResponseOk,
+    Result, SamplingParams, SchedulerConfig, TokenSource,
 };
 
 /// Gets the best device, cpu, cuda if compiled with CUDA
@@ -28,6 +28,7 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         NormalSpecificConfig {
             use_flash_attn: false,
             prompt_batchsize: None,
+            kv_cache_type: Default::default(),
             topology: None,
             organization: Default::default(),
             write_uqff: None,
@@ -97,6 +98,8 @@ fn main() -> anyhow::Result<()> {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        priority: RequestPriority::default(),
+        tenant: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -6,8 +6,8 @@ use tokio::sync::mpsc::channel;
 use mistralrs::{
     initialize_logging, ChatCompletionResponse, Constraint, Device, DeviceMapMetadata,
     GGUFLoaderBuilder, GGUFSpecificConfig, MemoryGpuConfig, MistralRs, MistralRsBuilder,
-    ModelDType, NormalRequest, PagedAttentionConfig, Request, RequestMessage, ResponseOk,
-    SamplingParams, SchedulerConfig, TokenSource, Usage,
+    ModelDType, NormalRequest, PagedAttentionConfig, PreemptionMode, Request, RequestMessage,
+    RequestPriority, ResponseOk, SamplingParams, SchedulerConfig, TokenSource, Usage,
 };
 
 async fn setup() -> anyhow::Result<Arc<MistralRs>> {
@@ -20,6 +20,7 @@ async fn setup() -> anyhow::Result<Arc<MistralRs>> {
         vec!["mistral-7b-instruct-v0.1.Q4_K_M.gguf".to_string()],
         GGUFSpecificConfig {
             prompt_batchsize: None,
+            kv_cache_type: Default::default(),
             topology: None,
         },
     )
@@ -37,6 +38,7 @@ async fn setup() -> anyhow::Result<Arc<MistralRs>> {
             Some(32),
             500,
             MemoryGpuConfig::Utilization(0.9),
+            PreemptionMode::Swap,
         )?), // No PagedAttention.
     )?;
     let config = pipeline
@@ -85,6 +87,8 @@ async fn bench_mistralrs(n_requests: usize) -> anyhow::Result<()> {
             tools: None,
             tool_choice: None,
             logits_processors: None,
+            priority: RequestPriority::default(),
+            tenant: None,
         });
         mistralrs.get_sender()?.send(request).await?;
         handles.push(rx);
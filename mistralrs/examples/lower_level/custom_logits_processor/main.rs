@@ -7,8 +7,8 @@ use tokio::sync::mpsc::channel;
 use mistralrs::{
     Constraint, CustomLogitsProcessor, DefaultSchedulerMethod, Device, DeviceMapMetadata,
     MistralRs, MistralRsBuilder, ModelDType, NormalLoaderBuilder, NormalRequest,
-    NormalSpecificConfig, Request, RequestMessage, ResponseOk, Result, SamplingParams,
-    SchedulerConfig, Tensor, TokenSource,
+    NormalSpecificConfig, Request, RequestMessage, RequestPriority, ResponseOk, Result,
+    SamplingParams, SchedulerConfig, Tensor, TokenSource,
 };
 
 struct ThresholdLogitsProcessor {
@@ -41,6 +41,7 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         NormalSpecificConfig {
             use_flash_attn: false,
             prompt_batchsize: None,
+            kv_cache_type: Default::default(),
             topology: None,
             organization: Default::default(),
             write_uqff: None,
@@ -99,6 +100,8 @@ fn main() -> anyhow::Result<()> {
             Arc::new(move |logits: &Tensor, _context: &[u32]| logits * random_value),
             Arc::new(ThresholdLogitsProcessor { threshold }),
         ]),
+        priority: RequestPriority::default(),
+        tenant: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -6,7 +6,8 @@ use tokio::sync::mpsc::channel;
 use mistralrs::{
     Constraint, DefaultSchedulerMethod, Device, DeviceMapMetadata, GGUFLoaderBuilder,
     GGUFSpecificConfig, MistralRs, MistralRsBuilder, ModelDType, NormalRequest, Request,
-    RequestMessage, ResponseOk, Result, SamplingParams, SchedulerConfig, TokenSource,
+    RequestMessage, RequestPriority, ResponseOk, Result, SamplingParams, SchedulerConfig,
+    TokenSource,
 };
 
 /// Gets the best device, cpu, cuda if compiled with CUDA
@@ -33,6 +34,7 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         vec!["mistral-7b-instruct-v0.1.Q4_K_M.gguf".to_string()],
         GGUFSpecificConfig {
             prompt_batchsize: None,
+            kv_cache_type: Default::default(),
             topology: None,
         },
     )
@@ -78,6 +80,8 @@ fn main() -> anyhow::Result<()> {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        priority: RequestPriority::default(),
+        tenant: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
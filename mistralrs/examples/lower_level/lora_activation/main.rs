@@ -6,7 +6,7 @@ use tokio::sync::mpsc::channel;
 use mistralrs::{
     Constraint, DefaultSchedulerMethod, Device, DeviceMapMetadata, MistralRs, MistralRsBuilder,
     ModelDType, NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, Request, RequestMessage,
-    ResponseOk, Result, SamplingParams, SchedulerConfig, TokenSource,
+    RequestPriority, ResponseOk, Result, SamplingParams, SchedulerConfig, TokenSource,
 };
 
 /// Gets the best device, cpu, cuda if compiled with CUDA
@@ -28,6 +28,7 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
             NormalSpecificConfig {
                 use_flash_attn: false,
                 prompt_batchsize: None,
+                kv_cache_type: Default::default(),
                 topology: None,
                 organization: Default::default(),
                 write_uqff: None,
@@ -85,6 +86,8 @@ fn main() -> anyhow::Result<()> {
         tool_choice: None,
         tools: None,
         logits_processors: None,
+        priority: RequestPriority::default(),
+        tenant: None,
     });
 
     mistralrs.get_sender()?.blocking_send(request)?;
@@ -12,7 +12,8 @@ use tokio::sync::mpsc::channel;
 use mistralrs::{
     Constraint, Device, DeviceMapMetadata, MemoryGpuConfig, MistralRs, MistralRsBuilder,
     ModelDType, NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, PagedAttentionConfig,
-    Request, RequestMessage, ResponseOk, Result, SamplingParams, SchedulerConfig, TokenSource,
+    PreemptionMode, Request, RequestMessage, RequestPriority, ResponseOk, Result, SamplingParams,
+    SchedulerConfig, TokenSource,
 };
 
 /// Gets the best device, cpu, cuda if compiled with CUDA
@@ -33,6 +34,7 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
         NormalSpecificConfig {
             use_flash_attn: false,
             prompt_batchsize: None,
+            kv_cache_type: Default::default(),
             topology: None,
             organization: Default::default(),
             write_uqff: None,
@@ -56,6 +58,7 @@ fn setup() -> anyhow::Result<Arc<MistralRs>> {
             Some(32),
             1024,
             MemoryGpuConfig::Utilization(0.9),
+            PreemptionMode::Swap,
         )?), // Automatically determine memory usage
     )?;
     let config = pipeline
@@ -96,6 +99,8 @@ fn main() -> anyhow::Result<()> {
         tools: None,
         tool_choice: None,
         logits_processors: None,
+        priority: RequestPriority::default(),
+        tenant: None,
     });
     mistralrs.get_sender()?.blocking_send(request)?;
 
@@ -0,0 +1,21 @@
+use anyhow::Result;
+use mistralrs::TextModelBuilder;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let model = TextModelBuilder::new("microsoft/Phi-3.5-mini-instruct")
+        .with_logging()
+        .build()
+        .await?;
+
+    let response = model
+        .score("Rust is a systems programming language.", 3)
+        .await?;
+
+    for token in &response.tokens {
+        println!("{:?}: {:?}", token.text, token.logprob);
+    }
+    println!("Perplexity: {}", response.perplexity);
+
+    Ok(())
+}
@@ -43,6 +43,7 @@ impl AnyMoeModelBuilder {
         let config = NormalSpecificConfig {
             use_flash_attn: self.base.use_flash_attn,
             prompt_batchsize: self.base.prompt_batchsize,
+            kv_cache_type: self.base.kv_cache_type,
             topology: self.base.topology,
             organization: self.base.organization,
             write_uqff: self.base.write_uqff,
@@ -17,6 +17,7 @@ pub struct GgufModelBuilder {
 
     // Model running
     pub(crate) prompt_batchsize: Option<NonZeroUsize>,
+    pub(crate) kv_cache_type: KvCacheType,
     pub(crate) force_cpu: bool,
     pub(crate) topology: Option<Topology>,
 
@@ -38,6 +39,7 @@ impl GgufModelBuilder {
             model_id: model_id.to_string(),
             files: files.into_iter().map(|f| f.to_string()).collect::<Vec<_>>(),
             prompt_batchsize: None,
+            kv_cache_type: KvCacheType::Auto,
             chat_template: None,
             tokenizer_json: None,
             force_cpu: false,
@@ -66,6 +68,13 @@ impl GgufModelBuilder {
         self
     }
 
+    /// Set the type in which the KV cache is stored when not using PagedAttention, such as
+    /// [`KvCacheType::Q8_0`] to store it in 8 bits.
+    pub fn with_kv_cache_type(mut self, kv_cache_type: KvCacheType) -> Self {
+        self.kv_cache_type = kv_cache_type;
+        self
+    }
+
     /// Set the model topology for use during loading. If there is an overlap, the topology type is used over the ISQ type.
     pub fn with_topology(mut self, topology: Topology) -> Self {
         self.topology = Some(topology);
@@ -154,6 +163,7 @@ impl GgufModelBuilder {
     pub async fn build(self) -> anyhow::Result<Model> {
         let config = GGUFSpecificConfig {
             prompt_batchsize: self.prompt_batchsize,
+            kv_cache_type: self.kv_cache_type,
             topology: self.topology,
         };
 
@@ -25,6 +25,7 @@ impl GgufLoraModelBuilder {
     pub async fn build(self) -> anyhow::Result<Model> {
         let config = GGUFSpecificConfig {
             prompt_batchsize: self.gguf_model.prompt_batchsize,
+            kv_cache_type: self.gguf_model.kv_cache_type,
             topology: self.gguf_model.topology,
         };
 
@@ -32,6 +32,7 @@ impl GgufXLoraModelBuilder {
     pub async fn build(self) -> anyhow::Result<Model> {
         let config = GGUFSpecificConfig {
             prompt_batchsize: self.gguf_model.prompt_batchsize,
+            kv_cache_type: self.gguf_model.kv_cache_type,
             topology: self.gguf_model.topology,
         };
 
@@ -434,6 +434,17 @@ impl RequestBuilder {
         self
     }
 
+    pub fn set_sampler_repetition_penalty(mut self, repetition_penalty: f32) -> Self {
+        self.sampling_params.repetition_penalty = Some(repetition_penalty);
+        self
+    }
+
+    /// Only penalize the last `repeat_last_n` tokens of the context.
+    pub fn set_sampler_repeat_last_n(mut self, repeat_last_n: usize) -> Self {
+        self.sampling_params.repeat_last_n = Some(repeat_last_n);
+        self
+    }
+
     pub fn set_sampler_stop_toks(mut self, stop_toks: StopTokens) -> Self {
         self.sampling_params.stop_toks = Some(stop_toks);
         self
@@ -454,10 +465,47 @@ impl RequestBuilder {
         self
     }
 
+    /// Seed the sampling of this request, so that its output is reproducible.
+    pub fn set_sampler_seed(mut self, seed: u64) -> Self {
+        self.sampling_params.seed = Some(seed);
+        self
+    }
+
     pub fn set_sampler_dry_params(mut self, dry_params: DrySamplingParams) -> Self {
         self.sampling_params.dry_params = Some(dry_params);
         self
     }
+
+    pub fn set_sampler_beam_search(mut self, beam_search: BeamSearchParams) -> Self {
+        self.sampling_params.beam_search = Some(beam_search);
+        self
+    }
+
+    pub fn set_sampler_typical_p(mut self, typical_p: f64) -> Self {
+        self.sampling_params.typical_p = Some(typical_p);
+        self
+    }
+
+    pub fn set_sampler_top_a(mut self, top_a: f64) -> Self {
+        self.sampling_params.top_a = Some(top_a);
+        self
+    }
+
+    pub fn set_sampler_tfs_z(mut self, tfs_z: f64) -> Self {
+        self.sampling_params.tfs_z = Some(tfs_z);
+        self
+    }
+
+    pub fn set_sampler_mirostat(mut self, mirostat: MirostatParams) -> Self {
+        self.sampling_params.mirostat = Some(mirostat);
+        self
+    }
+
+    /// Set the order in which the samplers are applied. Samplers which are not listed are not applied.
+    pub fn set_sampler_order(mut self, sampler_order: Vec<SamplerStep>) -> Self {
+        self.sampling_params.sampler_order = Some(sampler_order);
+        self
+    }
 }
 
 impl RequestLike for RequestBuilder {
@@ -1,5 +1,6 @@
 use anyhow::Context;
 use candle_core::{Device, Result};
+use either::Either;
 use mistralrs_core::*;
 use std::sync::Arc;
 use tokio::sync::mpsc::channel;
@@ -76,6 +77,8 @@ impl Model {
             tools,
             tool_choice,
             logits_processors: request.take_logits_processors(),
+            priority: RequestPriority::default(),
+            tenant: None,
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -117,6 +120,8 @@ impl Model {
             tool_choice: None,
             tools: None,
             logits_processors: None,
+            priority: RequestPriority::default(),
+            tenant: None,
         });
 
         self.runner.get_sender()?.send(request).await?;
@@ -133,6 +138,114 @@ impl Model {
         Ok(response)
     }
 
+    /// Embed a prompt by pooling the model's final hidden states.
+    pub async fn embed(
+        &self,
+        prompt: impl ToString,
+        embedding_params: EmbeddingParams,
+    ) -> anyhow::Result<Vec<f32>> {
+        let (tx, mut rx) = channel(1);
+
+        let request = Request::Normal(NormalRequest {
+            id: 0,
+            messages: RequestMessage::Embedding {
+                prompt: prompt.to_string(),
+                embedding_params,
+            },
+            sampling_params: SamplingParams::deterministic(),
+            response: tx,
+            return_logprobs: false,
+            is_streaming: false,
+            suffix: None,
+            constraint: Constraint::None,
+            adapters: None,
+            tool_choice: None,
+            tools: None,
+            logits_processors: None,
+            priority: RequestPriority::default(),
+            tenant: None,
+        });
+
+        self.runner.get_sender()?.send(request).await?;
+
+        let ResponseOk::Embedding(response) = rx
+            .recv()
+            .await
+            .context("Channel was erroneously closed!")?
+            .as_result()?
+        else {
+            anyhow::bail!("Got unexpected response type.")
+        };
+
+        response
+            .data
+            .into_iter()
+            .next()
+            .map(|data| data.embedding)
+            .context("Embedding response had no data.")
+    }
+
+    /// Score a text without generating: get the logprob of each token given the ones before it
+    /// and the perplexity of the text. `top_logprobs` alternatives are returned for each token.
+    pub async fn score(
+        &self,
+        text: impl ToString,
+        top_logprobs: usize,
+    ) -> anyhow::Result<ScoringResponse> {
+        self.score_prompt(Either::Left(text.to_string()), top_logprobs)
+            .await
+    }
+
+    /// Score the token ids of a text without generating, like [`Model::score`].
+    pub async fn score_tokens(
+        &self,
+        tokens: Vec<u32>,
+        top_logprobs: usize,
+    ) -> anyhow::Result<ScoringResponse> {
+        self.score_prompt(Either::Right(tokens), top_logprobs).await
+    }
+
+    async fn score_prompt(
+        &self,
+        prompt: Either<String, Vec<u32>>,
+        top_logprobs: usize,
+    ) -> anyhow::Result<ScoringResponse> {
+        let (tx, mut rx) = channel(1);
+
+        let request = Request::Normal(NormalRequest {
+            id: 0,
+            messages: RequestMessage::Scoring { prompt },
+            sampling_params: SamplingParams {
+                top_n_logprobs: top_logprobs,
+                ..SamplingParams::deterministic()
+            },
+            response: tx,
+            return_logprobs: true,
+            is_streaming: false,
+            suffix: None,
+            constraint: Constraint::None,
+            adapters: None,
+            tool_choice: None,
+            tools: None,
+            logits_processors: None,
+            priority: RequestPriority::default(),
+            tenant: None,
+        });
+
+        self.runner.get_sender()?.send(request).await?;
+
+        let ResponseOk::Scoring(response) = rx
+            .recv()
+            .await
+            .context("Channel was erroneously closed!")?
+            .as_result()?
+        else {
+            anyhow::bail!("Got unexpected response type.")
+        };
+
+        Ok(response)
+    }
+
     /// Activate certain adapters on the model, they will be used for requests which do not specify unique adapters.
     pub async fn activate_adapters<A: ToString>(&self, adapters: Vec<A>) -> anyhow::Result<()> {
         let request = Request::ActivateAdapters(
@@ -18,6 +18,7 @@ pub struct TextModelBuilder {
     // Model running
     pub(crate) use_flash_attn: bool,
     pub(crate) prompt_batchsize: Option<NonZeroUsize>,
+    pub(crate) kv_cache_type: KvCacheType,
     pub(crate) topology: Option<Topology>,
     pub(crate) organization: IsqOrganization,
     pub(crate) loader_type: Option<NormalLoaderType>,
@@ -38,6 +39,7 @@ pub struct PagedAttentionMetaBuilder {
     block_size: Option<usize>,
     mem_cpu: usize,
     mem_gpu: MemoryGpuConfig,
+    preemption_mode: PreemptionMode,
 }
 
 impl Default for PagedAttentionMetaBuilder {
@@ -46,6 +48,7 @@ impl Default for PagedAttentionMetaBuilder {
             block_size: None,
             mem_cpu: 64,
             mem_gpu: MemoryGpuConfig::Utilization(0.9),
+            preemption_mode: PreemptionMode::default(),
         }
     }
 }
@@ -61,8 +64,19 @@ impl PagedAttentionMetaBuilder {
         self
     }
 
+    /// Set how a running sequence gives up its KV cache blocks when the GPU blocks run out.
+    pub fn with_preemption_mode(mut self, preemption_mode: PreemptionMode) -> Self {
+        self.preemption_mode = preemption_mode;
+        self
+    }
+
     pub fn build(self) -> anyhow::Result<PagedAttentionConfig> {
-        PagedAttentionConfig::new(self.block_size, self.mem_cpu, self.mem_gpu)
+        PagedAttentionConfig::new(
+            self.block_size,
+            self.mem_cpu,
+            self.mem_gpu,
+            self.preemption_mode,
+        )
     }
 }
 
@@ -77,6 +91,7 @@ impl TextModelBuilder {
             model_id: model_id.to_string(),
             use_flash_attn: cfg!(feature = "flash-attn"),
             prompt_batchsize: None,
+            kv_cache_type: KvCacheType::Auto,
             topology: None,
             organization: IsqOrganization::Default,
             write_uqff: None,
@@ -104,6 +119,13 @@ impl TextModelBuilder {
         self
     }
 
+    /// Set the type in which the KV cache is stored when not using PagedAttention, such as
+    /// [`KvCacheType::Q8_0`] to store it in 8 bits.
+    pub fn with_kv_cache_type(mut self, kv_cache_type: KvCacheType) -> Self {
+        self.kv_cache_type = kv_cache_type;
+        self
+    }
+
     /// Set the model topology for use during loading. If there is an overlap, the topology type is used over the ISQ type.
     pub fn with_topology(mut self, topology: Topology) -> Self {
         self.topology = Some(topology);
@@ -235,6 +257,7 @@ impl TextModelBuilder {
         let config = NormalSpecificConfig {
             use_flash_attn: self.use_flash_attn,
             prompt_batchsize: self.prompt_batchsize,
+            kv_cache_type: self.kv_cache_type,
             topology: self.topology,
             organization: self.organization,
             write_uqff: self.write_uqff,
@@ -18,6 +18,7 @@ pub struct VisionModelBuilder {
     // Model running
     pub(crate) use_flash_attn: bool,
     pub(crate) prompt_batchsize: Option<NonZeroUsize>,
+    pub(crate) kv_cache_type: KvCacheType,
     pub(crate) topology: Option<Topology>,
     pub(crate) loader_type: VisionLoaderType,
     pub(crate) dtype: ModelDType,
@@ -41,6 +42,7 @@ impl VisionModelBuilder {
             write_uqff: None,
             from_uqff: None,
             prompt_batchsize: None,
+            kv_cache_type: KvCacheType::Auto,
             chat_template: None,
             tokenizer_json: None,
             loader_type,
@@ -61,6 +63,13 @@ impl VisionModelBuilder {
         self
     }
 
+    /// Set the type in which the KV cache is stored when not using PagedAttention, such as
+    /// [`KvCacheType::Q8_0`] to store it in 8 bits.
+    pub fn with_kv_cache_type(mut self, kv_cache_type: KvCacheType) -> Self {
+        self.kv_cache_type = kv_cache_type;
+        self
+    }
+
     /// Set the model topology for use during loading. If there is an overlap, the topology type is used over the ISQ type.
     pub fn with_topology(mut self, topology: Topology) -> Self {
         self.topology = Some(topology);
@@ -151,6 +160,7 @@ impl VisionModelBuilder {
         let config = VisionSpecificConfig {
             use_flash_attn: self.use_flash_attn,
             prompt_batchsize: self.prompt_batchsize,
+            kv_cache_type: self.kv_cache_type,
             topology: self.topology,
             write_uqff: self.write_uqff,
             from_uqff: self.from_uqff,
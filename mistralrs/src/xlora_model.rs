@@ -33,6 +33,7 @@ impl XLoraModelBuilder {
         let config = NormalSpecificConfig {
             use_flash_attn: self.text_model.use_flash_attn,
             prompt_batchsize: self.text_model.prompt_batchsize,
+            kv_cache_type: self.text_model.kv_cache_type,
             topology: self.text_model.topology,
             organization: self.text_model.organization,
             write_uqff: self.text_model.write_uqff,